@@ -0,0 +1,91 @@
+// Packs the `initramfs` directory into a USTAR archive that the kernel embeds.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const BLOCK_SIZE: usize = 512;
+/// Size of the scratch disks the tests attach.
+const TEST_DISK_SIZE: u64 = 4 * 1024 * 1024;
+
+fn main() {
+    let source = Path::new("initramfs");
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let mut archive = Vec::new();
+    if source.is_dir() {
+        add_directory(&mut archive, source, "").expect("failed to pack initramfs");
+    }
+    // the archive ends with two zero blocks
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    fs::write(out_dir.join("initramfs.tar"), archive).expect("failed to write initramfs");
+
+    // the IDE primary slave, a modern and a legacy virtio-blk and an AHCI disk
+    for name in ["disk.img", "virtio.img", "virtio-legacy.img", "sata.img"] {
+        create_test_disk(name).expect("failed to create a test disk");
+    }
+}
+
+/// Create an empty raw image for `test-args` to attach, unless one of the
+/// right size exists.
+///
+/// The path is fixed by `test-args`, which is relative to the manifest
+/// directory whatever `CARGO_TARGET_DIR` says. QEMU opens the images with
+/// `snapshot=on`, so tests never change them.
+fn create_test_disk(name: &str) -> io::Result<()> {
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let path = manifest_dir.join("target").join(name);
+    // recreate the image if it is deleted
+    println!("cargo:rerun-if-changed={}", path.display());
+    if fs::metadata(&path).map_or(true, |metadata| metadata.len() != TEST_DISK_SIZE) {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::File::create(&path)?.set_len(TEST_DISK_SIZE)?;
+    }
+    Ok(())
+}
+
+fn add_directory(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    // sort for reproducible images
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if path.is_dir() {
+            let name = format!("{}/", name);
+            add_entry(archive, &name, b'5', &[]);
+            add_directory(archive, &path, &name)?;
+        } else {
+            add_entry(archive, &name, b'0', &fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+fn add_entry(archive: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+    assert!(name.len() < 100, "initramfs path too long: {}", name);
+
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let mode: &[u8] = if kind == b'5' { b"0000755" } else { b"0000644" };
+    header[100..107].copy_from_slice(mode);
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // the checksum is computed with its own field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    archive.resize(archive.len() + padding, 0);
+}
@@ -0,0 +1,535 @@
+// ACPI tables
+use alloc::vec::Vec;
+use core::mem::size_of;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::{log, memory, warn};
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the following fields only exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const RSDP_V1_LENGTH: usize = 20;
+/// Tables claiming to be longer than this are treated as corrupt.
+const MAX_TABLE_LENGTH: usize = 1 << 20;
+
+/// Read a `T` from physical memory through the physical memory mapping.
+///
+/// The caller must make sure that `addr` points to a valid `T`.
+unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    let ptr: *const T = memory::phys_to_virt(addr).as_ptr();
+    ptr.read_unaligned()
+}
+
+fn phys_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    let ptr: *const u8 = memory::phys_to_virt(addr).as_ptr();
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Checks a 64-bit physical address read from a firmware table.
+fn firmware_addr(what: &str, addr: u64) -> Option<PhysAddr> {
+    let checked = PhysAddr::try_new(addr).ok();
+    if checked.is_none() {
+        warn!("Ignoring invalid ACPI {} address {:#x}", what, addr);
+    }
+    checked
+}
+
+/// Search the BIOS areas for the Root System Description Pointer.
+pub fn find_rsdp() -> Option<PhysAddr> {
+    // the first KiB of the extended BIOS data area, whose segment is stored at 0x40E
+    let ebda = unsafe { read_phys::<u16>(PhysAddr::new(0x40E)) } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            if phys_bytes(addr, 8) == b"RSD PTR " && checksum_ok(phys_bytes(addr, RSDP_V1_LENGTH))
+            {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the bytes of the table at `addr` if its length and checksum are valid.
+fn sdt_bytes(addr: PhysAddr) -> Option<&'static [u8]> {
+    let header: SdtHeader = unsafe { read_phys(addr) };
+    let length = header.length as usize;
+    if !(size_of::<SdtHeader>()..=MAX_TABLE_LENGTH).contains(&length) {
+        return None;
+    }
+    let bytes = phys_bytes(addr, length);
+    if checksum_ok(bytes) {
+        Some(bytes)
+    } else {
+        let signature = header.signature;
+        warn!(
+            "ACPI table {} has an invalid checksum",
+            core::str::from_utf8(&signature).unwrap_or("????")
+        );
+        None
+    }
+}
+
+/// Returns the physical addresses of all tables listed in the RSDT or XSDT.
+pub fn table_addresses() -> Vec<PhysAddr> {
+    let rsdp_addr = match find_rsdp() {
+        Some(addr) => addr,
+        None => return Vec::new(),
+    };
+    let rsdp: Rsdp = unsafe { read_phys(rsdp_addr) };
+
+    let rsdp_length = rsdp.length as usize;
+    let use_xsdt = rsdp.revision >= 2
+        && rsdp.xsdt_address != 0
+        && (size_of::<Rsdp>()..=MAX_TABLE_LENGTH).contains(&rsdp_length)
+        && checksum_ok(phys_bytes(rsdp_addr, rsdp_length));
+    let xsdt = if use_xsdt {
+        firmware_addr("XSDT", rsdp.xsdt_address)
+    } else {
+        None
+    };
+    let (root, entry_size) = match xsdt {
+        Some(xsdt) => (xsdt, 8),
+        None => (PhysAddr::new(rsdp.rsdt_address as u64), 4),
+    };
+    let root = match sdt_bytes(root) {
+        Some(root) => root,
+        None => return Vec::new(),
+    };
+
+    root[size_of::<SdtHeader>()..]
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            if entry_size == 8 {
+                firmware_addr("table", u64_at(entry, 0))
+            } else {
+                Some(PhysAddr::new(u32_at(entry, 0) as u64))
+            }
+        })
+        .collect()
+}
+
+/// Find the table with the given signature, e.g. `b"APIC"` for the MADT.
+///
+/// Tables with an invalid checksum are skipped.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses().into_iter().find_map(|addr| {
+        let header: SdtHeader = unsafe { read_phys(addr) };
+        if &header.signature == signature {
+            sdt_bytes(addr)
+        } else {
+            None
+        }
+    })
+}
+
+/// A processor local APIC listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// An I/O APIC listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// A remapping of a legacy ISA IRQ to a global system interrupt.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// Where an ISA IRQ is wired to, and how it is signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The contents of the Multiple APIC Description Table.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether the system also has 8259 PICs that must be disabled.
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Returns where the given ISA IRQ is routed, taking overrides into account.
+    pub fn route_irq(&self, irq: u8) -> IrqRoute {
+        match self.overrides.iter().find(|o| o.source == irq) {
+            Some(o) => IrqRoute {
+                gsi: o.gsi,
+                // 0b11 means active low / level triggered, anything else is
+                // active high / edge triggered for ISA interrupts
+                active_low: o.flags & 0b11 == 0b11,
+                level_triggered: (o.flags >> 2) & 0b11 == 0b11,
+            },
+            None => IrqRoute {
+                gsi: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            },
+        }
+    }
+}
+
+/// Find and parse the MADT.
+pub fn parse_madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    if table.len() < size_of::<SdtHeader>() + 8 {
+        return None;
+    }
+    let body = &table[size_of::<SdtHeader>()..];
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u32_at(body, 0) as u64),
+        has_legacy_pics: u32_at(body, 4) & 1 == 1,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut entries = &body[8..];
+    while entries.len() >= 2 {
+        let (entry_type, len) = (entries[0], entries[1] as usize);
+        if len < 2 || len > entries.len() {
+            break;
+        }
+        let entry = &entries[..len];
+        match entry_type {
+            0 if len >= 8 => madt.processors.push(Processor {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: u32_at(entry, 4) & 1 == 1,
+            }),
+            1 if len >= 12 => madt.io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysAddr::new(u32_at(entry, 4) as u64),
+                gsi_base: u32_at(entry, 8),
+            }),
+            2 if len >= 10 => madt.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: u32_at(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            5 if len >= 12 => {
+                if let Some(addr) = firmware_addr("local APIC", u64_at(entry, 4)) {
+                    madt.local_apic_address = addr;
+                }
+            }
+            _ => {}
+        }
+        entries = &entries[len..];
+    }
+
+    Some(madt)
+}
+
+/// An ACPI Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8]) -> Self {
+        GenericAddress {
+            address_space: bytes[0],
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: u64_at(bytes, 4),
+        }
+    }
+}
+
+/// The power management related parts of the Fixed ACPI Description Table.
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century_register: u8,
+    pub flags: u32,
+    /// Register to write `reset_value` to for a reset, if supported.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// `flags` bit indicating that the reset register is supported.
+    const RESET_REG_SUP: u32 = 1 << 10;
+}
+
+/// Find and parse the FADT.
+pub fn parse_fadt() -> Option<Fadt> {
+    let table = find_table(b"FACP")?;
+    if table.len() < 116 {
+        return None;
+    }
+    let u32_field = |offset: usize| u32_at(table, offset);
+
+    let mut fadt = Fadt {
+        dsdt: PhysAddr::new(u32_field(40) as u64),
+        sci_interrupt: u16::from_le_bytes([table[46], table[47]]),
+        smi_command_port: u32_field(48),
+        acpi_enable: table[52],
+        acpi_disable: table[53],
+        pm1a_event_block: u32_field(56),
+        pm1b_event_block: u32_field(60),
+        pm1a_control_block: u32_field(64),
+        pm1b_control_block: u32_field(68),
+        pm_timer_block: u32_field(76),
+        century_register: table[108],
+        flags: u32_field(112),
+        reset_register: None,
+        reset_value: 0,
+    };
+
+    // fields added in ACPI 2.0
+    if table.len() >= 129 && fadt.flags & Fadt::RESET_REG_SUP != 0 {
+        fadt.reset_register = Some(GenericAddress::parse(&table[116..128]));
+        fadt.reset_value = table[128];
+    }
+    if table.len() >= 148 && u64_at(table, 140) != 0 {
+        if let Some(dsdt) = firmware_addr("DSDT", u64_at(table, 140)) {
+            fadt.dsdt = dsdt;
+        }
+    }
+
+    Some(fadt)
+}
+
+/// Returns the AML bytes of the DSDT referenced by the FADT.
+pub fn dsdt() -> Option<&'static [u8]> {
+    let fadt = info()?.fadt.as_ref()?;
+    sdt_bytes(fadt.dsdt).map(|table| &table[size_of::<SdtHeader>()..])
+}
+
+/// Returns the `SLP_TYPa` and `SLP_TYPb` values for the S5 (soft off) state.
+///
+/// These come from the `\_S5_` package in the DSDT. Instead of interpreting
+/// AML, the package is located by its name and decoded directly, which is
+/// enough for the simple encoding firmware uses for it.
+pub fn s5_sleep_types() -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let aml = dsdt()?;
+    let position = (1..aml.len().saturating_sub(4)).find(|&i| {
+        &aml[i..i + 4] == b"_S5_"
+            && (aml[i - 1] == NAME_OP || (i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == b'\\'))
+            && aml[i + 4] == PACKAGE_OP
+    })?;
+
+    // skip the name, the package op, the package length and the element count
+    let mut i = position + 5;
+    let length_bytes = ((*aml.get(i)? & 0xC0) >> 6) as usize;
+    i += length_bytes + 2;
+
+    fn read_value(aml: &[u8], i: &mut usize) -> Option<u16> {
+        if *aml.get(*i)? == BYTE_PREFIX {
+            *i += 1;
+        }
+        let value = *aml.get(*i)? as u16;
+        *i += 1;
+        Some(value)
+    }
+    let slp_typa = read_value(aml, &mut i)?;
+    let slp_typb = read_value(aml, &mut i)?;
+    Some((slp_typa, slp_typb))
+}
+
+/// The High Precision Event Timer Description Table.
+#[derive(Debug, Clone, Copy)]
+pub struct HpetInfo {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+/// Find and parse the HPET table.
+pub fn parse_hpet() -> Option<HpetInfo> {
+    let table = find_table(b"HPET")?;
+    if table.len() < 56 {
+        return None;
+    }
+    Some(HpetInfo {
+        event_timer_block_id: u32_at(table, 36),
+        base_address: GenericAddress::parse(&table[40..52]),
+        hpet_number: table[52],
+        minimum_tick: u16::from_le_bytes([table[53], table[54]]),
+    })
+}
+
+/// Everything the kernel learned from the ACPI tables.
+#[derive(Debug, Clone)]
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<HpetInfo>,
+}
+
+impl AcpiInfo {
+    /// Number of usable processors, or 1 if the MADT is missing.
+    pub fn cpu_count(&self) -> usize {
+        match &self.madt {
+            Some(madt) => madt.processors.iter().filter(|p| p.enabled).count().max(1),
+            None => 1,
+        }
+    }
+
+    pub fn local_apic_address(&self) -> Option<PhysAddr> {
+        self.madt.as_ref().map(|madt| madt.local_apic_address)
+    }
+
+    pub fn io_apic_addresses(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+        self.madt
+            .iter()
+            .flat_map(|madt| madt.io_apics.iter().map(|io_apic| io_apic.address))
+    }
+
+    /// I/O port of the PM1a control block, used to enter sleep states.
+    pub fn pm1a_control_port(&self) -> Option<u16> {
+        self.fadt
+            .as_ref()
+            .map(|fadt| fadt.pm1a_control_block as u16)
+            .filter(|port| *port != 0)
+    }
+
+    /// I/O port of the PM1b control block, if the system has one.
+    pub fn pm1b_control_port(&self) -> Option<u16> {
+        self.fadt
+            .as_ref()
+            .map(|fadt| fadt.pm1b_control_block as u16)
+            .filter(|port| *port != 0)
+    }
+
+    /// I/O port of the ACPI power management timer.
+    pub fn pm_timer_port(&self) -> Option<u16> {
+        self.fadt
+            .as_ref()
+            .map(|fadt| fadt.pm_timer_block as u16)
+            .filter(|port| *port != 0)
+    }
+}
+
+static ACPI: Once<Option<AcpiInfo>> = Once::new();
+
+/// Parse the ACPI tables. Requires the global kernel memory to be initialized.
+///
+/// Returns `None` if the system has no ACPI tables. Later calls return the
+/// result of the first one.
+pub fn init() -> Option<&'static AcpiInfo> {
+    ACPI.call_once(|| {
+        log!("Initiating ACPI");
+        let rsdp_addr = match find_rsdp() {
+            Some(addr) => addr,
+            None => {
+                warn!("No ACPI RSDP found");
+                return None;
+            }
+        };
+        let rsdp: Rsdp = unsafe { read_phys(rsdp_addr) };
+        let info = AcpiInfo {
+            revision: rsdp.revision,
+            madt: parse_madt(),
+            fadt: parse_fadt(),
+            hpet: parse_hpet(),
+        };
+        log!(
+            "ACPI revision {}: {} CPUs, MADT {}, FADT {}, HPET {}",
+            info.revision,
+            info.cpu_count(),
+            info.madt.is_some(),
+            info.fadt.is_some(),
+            info.hpet.is_some()
+        );
+        Some(info)
+    })
+    .as_ref()
+}
+
+/// Returns the parsed ACPI tables if `init` found any.
+pub fn info() -> Option<&'static AcpiInfo> {
+    ACPI.get().and_then(|info| info.as_ref())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
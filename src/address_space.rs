@@ -0,0 +1,213 @@
+// Address spaces for user programs
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+use crate::memory;
+use crate::usermode::USER_SPACE_END;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A level 4 page table that shares the kernel's mappings and adds user
+/// mappings of its own.
+///
+/// All level 4 entries the kernel uses at creation time are copied, so the
+/// kernel keeps working while the address space is active; the areas it maps
+/// into later have their entries from `memory::reserve_kernel_areas`. User
+/// pages can only be mapped into the remaining level 4 slots, which keeps
+/// them out of the kernel's own page tables.
+///
+/// Dropping an address space returns its user pages and page tables to the
+/// frame allocator, so it must not be active at that point.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Create an address space with the kernel mappings and no user pages.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        memory::with_kernel_memory(|kernel, frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let table: *mut PageTable = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+            unsafe {
+                table.write(PageTable::new());
+                for (entry, kernel_entry) in (*table).iter_mut().zip(kernel.level_4_table().iter())
+                {
+                    if !kernel_entry.is_unused() {
+                        *entry = kernel_entry.clone();
+                    }
+                }
+            }
+            Ok(AddressSpace {
+                level_4_frame: frame,
+            })
+        })
+    }
+
+    /// The frame holding the level 4 table.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table: *mut PageTable =
+            memory::phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr();
+        unsafe { OffsetPageTable::new(&mut *table, memory::physical_memory_offset()) }
+    }
+
+    /// Returns whether `size` bytes at `start` may hold user pages.
+    pub fn is_user_range(&self, start: VirtAddr, size: u64) -> bool {
+        let end = match start.as_u64().checked_add(size) {
+            Some(end) if end <= USER_SPACE_END => end,
+            _ => return false,
+        };
+        if size == 0 {
+            return true;
+        }
+        let first = u16::from(start.p4_index()) as u64;
+        let last = u16::from(VirtAddr::new(end - 1).p4_index()) as u64;
+        memory::with_kernel_memory(|kernel, _| {
+            (first..=last).all(|index| kernel.level_4_table()[index as usize].is_unused())
+        })
+    }
+
+    /// Map `pages` zeroed user pages starting at `start`.
+    ///
+    /// `flags` are added to `PRESENT | USER_ACCESSIBLE`. Pages that are
+    /// already mapped keep their contents and get the more permissive
+    /// combination of both flag sets. The range must pass `is_user_range`.
+    pub fn map_user(
+        &mut self,
+        start: VirtAddr,
+        pages: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            self.is_user_range(start, pages * PAGE_SIZE),
+            "user pages overlap the kernel"
+        );
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let mut mapper = self.mapper();
+        memory::with_kernel_memory(|_, frame_allocator| {
+            for page in Page::range(first, first + pages) {
+                if let TranslateResult::Mapped { flags: old, .. } =
+                    mapper.translate(page.start_address())
+                {
+                    let mut merged = old | flags;
+                    if !old.contains(PageTableFlags::NO_EXECUTE)
+                        || !flags.contains(PageTableFlags::NO_EXECUTE)
+                    {
+                        merged.remove(PageTableFlags::NO_EXECUTE);
+                    }
+                    // the address space isn't active, so there is nothing to flush
+                    if let Ok(flush) = unsafe { mapper.update_flags(page, merged) } {
+                        flush.ignore();
+                    }
+                    continue;
+                }
+
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    let frame_ptr: *mut u8 =
+                        memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+                    core::ptr::write_bytes(frame_ptr, 0, PAGE_SIZE as usize);
+                    mapper.map_to(page, frame, flags, frame_allocator)?.ignore();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Copy `data` to `addr` in this address space, which doesn't need to be
+    /// active. Returns `false` if part of the range isn't mapped.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> bool {
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < data.len() {
+            let target = addr + written as u64;
+            let phys = match mapper.translate_addr(target) {
+                Some(phys) => phys,
+                None => return false,
+            };
+            let in_page = (PAGE_SIZE - u64::from(target.page_offset())) as usize;
+            let len = in_page.min(data.len() - written);
+            unsafe {
+                let dest: *mut u8 = memory::phys_to_virt(phys).as_mut_ptr();
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), dest, len);
+            }
+            written += len;
+        }
+        true
+    }
+
+    /// Switch the executing CPU to this address space and return the one that
+    /// was active before, so that it can be restored with `restore`.
+    ///
+    /// # Safety
+    ///
+    /// The address space must stay alive while it is active.
+    pub unsafe fn activate(&self) -> (PhysFrame, Cr3Flags) {
+        let previous = Cr3::read();
+        Cr3::write(self.level_4_frame, previous.1);
+        previous
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let table: *const PageTable =
+            memory::phys_to_virt(self.level_4_frame.start_address()).as_ptr();
+        memory::with_kernel_memory(|kernel, frame_allocator| unsafe {
+            let kernel_table = kernel.level_4_table();
+            for (entry, kernel_entry) in (*table).iter().zip(kernel_table.iter()) {
+                // tables shared with the kernel stay
+                if entry.is_unused() || entry.addr() == kernel_entry.addr() {
+                    continue;
+                }
+                free_table(
+                    PhysFrame::containing_address(entry.addr()),
+                    3,
+                    frame_allocator,
+                );
+            }
+            frame_allocator.deallocate_frame(self.level_4_frame);
+        });
+    }
+}
+
+/// Free the page table at `level` in `frame` along with all tables and
+/// pages below it. User mappings never use huge pages.
+unsafe fn free_table(
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table: *const PageTable = memory::phys_to_virt(frame.start_address()).as_ptr();
+    for entry in (*table).iter().filter(|entry| !entry.is_unused()) {
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(child, level - 1, frame_allocator);
+        } else {
+            frame_allocator.deallocate_frame(child);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Switch back to an address space returned by `AddressSpace::activate`.
+///
+/// # Safety
+///
+/// `previous` must be a valid level 4 table that maps the kernel.
+pub unsafe fn restore(previous: (PhysFrame, Cr3Flags)) {
+    Cr3::write(previous.0, previous.1);
+}
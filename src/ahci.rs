@@ -0,0 +1,373 @@
+// AHCI driver for SATA disks
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::ata::Identity;
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::pci::{self, Bar, PciDevice, PciDriver};
+use crate::{log, memory, time, warn};
+
+// generic host control registers
+const HBA_CAPABILITIES: u64 = 0x00;
+const HBA_GLOBAL_CONTROL: u64 = 0x04;
+const HBA_INTERRUPT_STATUS: u64 = 0x08;
+const HBA_PORTS_IMPLEMENTED: u64 = 0x0C;
+
+const CAPABILITY_64_BIT: u32 = 1 << 31;
+const GLOBAL_AHCI_ENABLE: u32 = 1 << 31;
+const GLOBAL_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+// port registers, offsets from the port's register block
+const PORT_REGISTERS: u64 = 0x100;
+const PORT_REGISTERS_SIZE: u64 = 0x80;
+const PORT_COMMAND_LIST: u64 = 0x00;
+const PORT_FIS_BASE: u64 = 0x08;
+const PORT_INTERRUPT_STATUS: u64 = 0x10;
+const PORT_INTERRUPT_ENABLE: u64 = 0x14;
+const PORT_COMMAND: u64 = 0x18;
+const PORT_TASK_FILE: u64 = 0x20;
+const PORT_SIGNATURE: u64 = 0x24;
+const PORT_SATA_STATUS: u64 = 0x28;
+const PORT_SATA_ERROR: u64 = 0x30;
+const PORT_COMMAND_ISSUE: u64 = 0x38;
+
+const COMMAND_START: u32 = 1;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+const TASK_FILE_ERR: u32 = 1;
+const TASK_FILE_DRQ: u32 = 1 << 3;
+const TASK_FILE_BSY: u32 = 1 << 7;
+
+/// Device to host register FIS and task file error interrupts.
+const INTERRUPTS_USED: u32 = 1 | 1 << 30;
+/// A device is present and communication is established.
+const SATA_STATUS_PRESENT: u32 = 3;
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// layout of the frame each port gets for its structures
+const COMMAND_LIST_OFFSET: u64 = 0;
+const RECEIVED_FIS_OFFSET: u64 = 1024;
+const COMMAND_TABLE_OFFSET: u64 = 2048;
+const PRDT_OFFSET: u64 = COMMAND_TABLE_OFFSET + 0x80;
+
+const TIMEOUT_NS: u64 = 1_000_000_000;
+
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// One port of the HBA with its command list, received FIS area, command
+/// table and bounce buffer.
+struct Port {
+    registers: VirtAddr,
+    /// Frame with the command list, received FISes and the command table.
+    memory: PhysAddr,
+    /// Data buffer in the frames after `memory`.
+    buffer: PhysAddr,
+}
+
+impl Port {
+    fn read(&self, register: u64) -> u32 {
+        unsafe { memory::read_mmio(self.registers + register) }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { memory::write_mmio(self.registers + register, value) }
+    }
+
+    fn wait_clear(&self, register: u64, bits: u32) -> Result<(), BlockError> {
+        let deadline = time::now_ns() + TIMEOUT_NS;
+        while self.read(register) & bits != 0 {
+            if time::now_ns() > deadline {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Point the port at our structures and start processing commands.
+    fn start(&self) -> Result<(), BlockError> {
+        // the firmware may have left the port running with its own lists
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_START);
+        self.wait_clear(PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_FIS_RECEIVE);
+        self.wait_clear(PORT_COMMAND, COMMAND_FIS_RUNNING)?;
+
+        let command_list = self.memory.as_u64() + COMMAND_LIST_OFFSET;
+        let received_fis = self.memory.as_u64() + RECEIVED_FIS_OFFSET;
+        self.write(PORT_COMMAND_LIST, command_list as u32);
+        self.write(PORT_COMMAND_LIST + 4, (command_list >> 32) as u32);
+        self.write(PORT_FIS_BASE, received_fis as u32);
+        self.write(PORT_FIS_BASE + 4, (received_fis >> 32) as u32);
+        self.write(PORT_SATA_ERROR, 0xFFFF_FFFF);
+        self.write(PORT_INTERRUPT_STATUS, 0xFFFF_FFFF);
+        self.write(PORT_INTERRUPT_ENABLE, INTERRUPTS_USED);
+
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_FIS_RECEIVE);
+        self.wait_clear(PORT_TASK_FILE, TASK_FILE_BSY | TASK_FILE_DRQ)?;
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_START);
+        Ok(())
+    }
+
+    /// Issue `command` in slot 0, moving `sectors` sectors through the
+    /// bounce buffer, and wait for it to complete.
+    fn issue(&self, command: u8, lba: u64, sectors: usize, write: bool) -> Result<(), BlockError> {
+        self.wait_clear(PORT_TASK_FILE, TASK_FILE_BSY | TASK_FILE_DRQ)?;
+        let base = memory::phys_to_virt(self.memory);
+        let length = sectors * SECTOR_SIZE;
+        let table = self.memory.as_u64() + COMMAND_TABLE_OFFSET;
+
+        // command header: FIS length in dwords, direction and PRDT length
+        let entries = (length > 0) as u32;
+        let flags = 5 | (write as u32) << 6 | entries << 16;
+        unsafe {
+            let header = base + COMMAND_LIST_OFFSET;
+            memory::write_mmio(header, flags);
+            memory::write_mmio(header + 4u64, 0u32);
+            memory::write_mmio(header + 8u64, table);
+        }
+
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_HOST_TO_DEVICE;
+        // a command rather than a control register update
+        fis[1] = 0x80;
+        fis[2] = command;
+        if command != CMD_IDENTIFY {
+            // LBA mode
+            fis[7] = 1 << 6;
+        }
+        let lba = lba.to_le_bytes();
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&(sectors as u16).to_le_bytes());
+        unsafe {
+            let cfis: *mut u8 = (base + COMMAND_TABLE_OFFSET).as_mut_ptr();
+            ptr::write_bytes(cfis, 0, 0x80);
+            ptr::copy_nonoverlapping(fis.as_ptr(), cfis, fis.len());
+            if length > 0 {
+                let prdt = base + PRDT_OFFSET;
+                memory::write_mmio(prdt, self.buffer.as_u64());
+                memory::write_mmio(prdt + 8u64, 0u32);
+                // byte count minus one, interrupt when done
+                memory::write_mmio(prdt + 12u64, (length as u32 - 1) | 1 << 31);
+            }
+        }
+
+        self.write(PORT_INTERRUPT_STATUS, 0xFFFF_FFFF);
+        self.write(PORT_COMMAND_ISSUE, 1);
+        // the interrupt wakes us early; without it the timer does
+        let done = time::wait_until(TIMEOUT_NS, || {
+            self.read(PORT_COMMAND_ISSUE) & 1 == 0 || self.read(PORT_TASK_FILE) & TASK_FILE_ERR != 0
+        });
+        let failed = self.read(PORT_TASK_FILE) & TASK_FILE_ERR != 0;
+        if failed || !done {
+            self.recover()?;
+        }
+        if failed {
+            return Err(BlockError::Io);
+        }
+        if !done {
+            return Err(BlockError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Get the port going again after a failed or stuck command: the port
+    /// stops processing commands on an error until it's restarted.
+    fn recover(&self) -> Result<(), BlockError> {
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_START);
+        self.wait_clear(PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        self.write(PORT_SATA_ERROR, 0xFFFF_FFFF);
+        self.write(PORT_INTERRUPT_STATUS, 0xFFFF_FFFF);
+        // a device stuck busy would need a port reset
+        self.wait_clear(PORT_TASK_FILE, TASK_FILE_BSY | TASK_FILE_DRQ)?;
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_START);
+        Ok(())
+    }
+
+    fn buffer_ptr(&self) -> *mut u8 {
+        memory::phys_to_virt(self.buffer).as_mut_ptr()
+    }
+
+    fn copy_to_buffer(&self, data: &[u8]) {
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.buffer_ptr(), data.len()) }
+    }
+
+    fn copy_from_buffer(&self, data: &mut [u8]) {
+        unsafe { ptr::copy_nonoverlapping(self.buffer_ptr(), data.as_mut_ptr(), data.len()) }
+    }
+}
+
+/// A disk on a port of an AHCI controller.
+pub struct AhciDisk {
+    name: String,
+    sectors: u64,
+    model: String,
+    port: Mutex<Port>,
+}
+
+impl AhciDisk {
+    fn new(port: Port) -> Result<AhciDisk, BlockError> {
+        port.start()?;
+        port.issue(CMD_IDENTIFY, 0, 1, false)?;
+        let mut data = [0u8; SECTOR_SIZE];
+        port.copy_from_buffer(&mut data);
+        let identity = Identity::parse(&data);
+
+        let index = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+        Ok(AhciDisk {
+            name: format!("sata{}", index),
+            sectors: identity.sectors,
+            model: identity.model,
+            port: Mutex::new(port),
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        let port = self.port.lock();
+        for (i, chunk) in buf
+            .chunks_mut(block::BOUNCE_SECTORS * SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = lba + (i * block::BOUNCE_SECTORS) as u64;
+            port.issue(CMD_READ_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, false)?;
+            port.copy_from_buffer(chunk);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        let port = self.port.lock();
+        for (i, chunk) in buf.chunks(block::BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * block::BOUNCE_SECTORS) as u64;
+            port.copy_to_buffer(chunk);
+            port.issue(CMD_WRITE_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.port.lock().issue(CMD_CACHE_FLUSH_EXT, 0, 0, false)
+    }
+}
+
+fn is_ahci(device: &PciDevice) -> bool {
+    device.class == 0x01 && device.subclass == 0x06 && device.prog_if == 0x01
+}
+
+fn probe(device: &PciDevice) {
+    let Some(Bar::Memory { address, size, .. }) = device.bars[5] else {
+        warn!("{}: AHCI controller without ABAR", device.address);
+        return;
+    };
+    let Ok(hba) = memory::map_mmio(PhysAddr::new(address), size) else {
+        warn!("{}: failed to map AHCI registers", device.address);
+        return;
+    };
+    device.enable_bus_mastering();
+
+    let read = |register: u64| unsafe { memory::read_mmio::<u32>(hba + register) };
+    let write = |register: u64, value: u32| unsafe { memory::write_mmio(hba + register, value) };
+    write(
+        HBA_GLOBAL_CONTROL,
+        read(HBA_GLOBAL_CONTROL) | GLOBAL_AHCI_ENABLE,
+    );
+    let addresses_64_bit = read(HBA_CAPABILITIES) & CAPABILITY_64_BIT != 0;
+
+    let implemented = read(HBA_PORTS_IMPLEMENTED);
+    for index in (0..32).filter(|index| implemented & (1 << index) != 0) {
+        let registers = hba + PORT_REGISTERS + index * PORT_REGISTERS_SIZE;
+        let present = unsafe { memory::read_mmio::<u32>(registers + PORT_SATA_STATUS) } & 0xF;
+        let signature = unsafe { memory::read_mmio::<u32>(registers + PORT_SIGNATURE) };
+        // ATAPI drives and port multipliers have other signatures
+        if present != SATA_STATUS_PRESENT || signature != SIGNATURE_ATA {
+            continue;
+        }
+        let Some(memory) = memory::allocate_dma(1 + block::BOUNCE_FRAMES) else {
+            warn!("{}: no memory for AHCI port {}", device.address, index);
+            return;
+        };
+        if !addresses_64_bit && memory.as_u64() + (1 + block::BOUNCE_FRAMES as u64) * 4096 > 1 << 32
+        {
+            warn!(
+                "{}: AHCI port {} can't reach its memory",
+                device.address, index
+            );
+            continue;
+        }
+        let port = Port {
+            registers,
+            memory,
+            buffer: memory + 4096u64,
+        };
+        match AhciDisk::new(port) {
+            Ok(disk) => {
+                log!("{}: {} ({} sectors)", disk.name, disk.model, disk.sectors);
+                block::register(Arc::new(disk));
+            }
+            Err(error) => warn!(
+                "{}: AHCI port {} failed: {:?}",
+                device.address, index, error
+            ),
+        }
+    }
+
+    // completions are noticed on the next timer tick if this fails
+    let hba_address = hba.as_u64();
+    pci::register_interrupt_handler(device, move || {
+        let hba = VirtAddr::new(hba_address);
+        unsafe {
+            let pending: u32 = memory::read_mmio(hba + HBA_INTERRUPT_STATUS);
+            for index in (0..32).filter(|index| pending & (1 << index) != 0) {
+                let status =
+                    hba + PORT_REGISTERS + index * PORT_REGISTERS_SIZE + PORT_INTERRUPT_STATUS;
+                memory::write_mmio(status, memory::read_mmio::<u32>(status));
+            }
+            memory::write_mmio(hba + HBA_INTERRUPT_STATUS, pending);
+        }
+    });
+    write(
+        HBA_GLOBAL_CONTROL,
+        read(HBA_GLOBAL_CONTROL) | GLOBAL_INTERRUPT_ENABLE,
+    );
+}
+
+static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: is_ahci,
+    probe,
+};
+
+/// Register the driver with the PCI bus. Requires `pci::init` to have run
+/// for devices to be found right away.
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
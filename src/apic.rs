@@ -0,0 +1,243 @@
+// Local APIC and I/O APIC
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, IrqRoute, Madt};
+use crate::interrupts::{self, InterruptIndex, SPURIOUS_VECTOR};
+use crate::{log, memory, pit, time, warn};
+
+// Local APIC registers, as offsets from the APIC base
+const LAPIC_ID: usize = 0x020;
+const LAPIC_TPR: usize = 0x080;
+const LAPIC_EOI: usize = 0x0B0;
+const LAPIC_SVR: usize = 0x0F0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const SVR_ENABLE: u32 = 1 << 8;
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the timer input clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+const MASKED: u32 = 1 << 16;
+
+// Interrupt command register bits
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// ISA IRQs routed through the I/O APIC
+const KEYBOARD_IRQ: u8 = 1;
+const SERIAL1_IRQ: u8 = 4;
+
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Local APIC timer ticks per millisecond, measured against the PIT.
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns whether interrupts are delivered through the APIC instead of the 8259 PIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+fn lapic_read(reg: usize) -> u32 {
+    let ptr = (LAPIC_BASE.load(Ordering::Relaxed) as usize + reg) as *const u32;
+    unsafe { ptr.read_volatile() }
+}
+
+fn lapic_write(reg: usize, value: u32) {
+    let ptr = (LAPIC_BASE.load(Ordering::Relaxed) as usize + reg) as *mut u32;
+    unsafe { ptr.write_volatile(value) }
+}
+
+/// Returns the APIC ID of the executing CPU.
+pub fn local_apic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+/// Signal the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    lapic_write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+    lapic_write(LAPIC_ICR_LOW, command);
+    while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Send an INIT inter-processor interrupt, which resets the target CPU.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a startup inter-processor interrupt. The target CPU starts executing
+/// in real mode at physical address `page * 0x1000`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+/// An I/O APIC, which routes external interrupts to local APICs.
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    const IOREDTBL: u32 = 0x10;
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            (self.base.as_mut_ptr::<u32>()).write_volatile(reg);
+            (self.base + 0x10u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            (self.base.as_mut_ptr::<u32>()).write_volatile(reg);
+            (self.base + 0x10u64).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    fn set_entry(&self, gsi: u32, low: u32, high: u32) {
+        let reg = Self::IOREDTBL + (gsi - self.gsi_base) * 2;
+        // write the destination first so the entry is never unmasked with a stale one
+        self.write(reg, MASKED);
+        self.write(reg + 1, high);
+        self.write(reg, low);
+    }
+}
+
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Route a global system interrupt to `vector` on the CPU with the given APIC ID.
+pub fn route(route: IrqRoute, vector: u8, apic_id: u8) {
+    let mut low = vector as u32;
+    if route.active_low {
+        low |= 1 << 13;
+    }
+    if route.level_triggered {
+        low |= 1 << 15;
+    }
+    let high = (apic_id as u32) << 24;
+
+    match IO_APICS.lock().iter().find(|io_apic| io_apic.handles(route.gsi)) {
+        Some(io_apic) => io_apic.set_entry(route.gsi, low, high),
+        None => warn!("No I/O APIC handles GSI {}", route.gsi),
+    }
+}
+
+/// Route an ISA IRQ to `vector` on the bootstrap processor.
+pub fn route_isa_irq(madt: &Madt, irq: u8, vector: u8) {
+    route(madt.route_irq(irq), vector, local_apic_id());
+}
+
+/// Switch from the 8259 PIC to the local APIC and I/O APIC.
+///
+/// Returns `false` and leaves the PIC in place if the ACPI tables don't
+/// describe an APIC. Requires `acpi::init` to have run.
+pub fn init() -> bool {
+    let madt = match acpi::info().and_then(|info| info.madt.as_ref()) {
+        Some(madt) if !madt.io_apics.is_empty() => madt,
+        _ => return false,
+    };
+
+    log!("Initiating APIC");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let lapic = memory::map_mmio(madt.local_apic_address, 4096)
+            .expect("failed to map local APIC");
+        LAPIC_BASE.store(lapic.as_u64(), Ordering::SeqCst);
+
+        {
+            let mut io_apics = IO_APICS.lock();
+            for info in &madt.io_apics {
+                let base = memory::map_mmio(info.address, 0x20).expect("failed to map I/O APIC");
+                let mut io_apic = IoApic {
+                    base,
+                    gsi_base: info.gsi_base,
+                    entries: 0,
+                };
+                io_apic.entries = ((io_apic.read(1) >> 16) & 0xFF) + 1;
+                for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                    io_apic.set_entry(gsi, MASKED, 0);
+                }
+                io_apics.push(io_apic);
+            }
+        }
+
+        // the PICs were already remapped by `init`, so masking them is
+        // enough to keep spurious legacy interrupts off the exception vectors
+        log!("Disabling PICS");
+        unsafe { interrupts::PICS.lock().disable() };
+
+        enable_local_apic();
+        ENABLED.store(true, Ordering::SeqCst);
+
+        route_isa_irq(madt, KEYBOARD_IRQ, InterruptIndex::Keyboard.as_u8());
+        route_isa_irq(madt, SERIAL1_IRQ, InterruptIndex::Serial.as_u8());
+
+        calibrate_timer();
+        start_timer();
+    });
+    log!(
+        "APIC enabled: {} CPUs, {} I/O APICs",
+        madt.processors.iter().filter(|p| p.enabled).count(),
+        madt.io_apics.len()
+    );
+    true
+}
+
+/// Enable the local APIC of the executing CPU.
+pub fn enable_local_apic() {
+    unsafe {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        let value = base.read();
+        base.write(value | APIC_BASE_ENABLE);
+    }
+    lapic_write(LAPIC_TPR, 0);
+    lapic_write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+fn calibrate_timer() {
+    const CALIBRATION_MS: u32 = 10;
+
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, MASKED);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    pit::wait_micros(CALIBRATION_MS * 1000);
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+
+    TIMER_TICKS_PER_MS.store(elapsed / CALIBRATION_MS, Ordering::SeqCst);
+}
+
+/// Start the periodic local APIC timer of the executing CPU at `time::TICK_HZ`.
+pub fn start_timer() {
+    let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::SeqCst);
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(
+        LAPIC_LVT_TIMER,
+        InterruptIndex::Timer.as_u8() as u32 | TIMER_PERIODIC,
+    );
+    lapic_write(
+        LAPIC_TIMER_INITIAL,
+        (ticks_per_ms as u64 * 1000 / time::TICK_HZ as u64).max(1) as u32,
+    );
+}
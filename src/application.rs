@@ -4,8 +4,11 @@ use alloc::{
     string::{String, ToString},
 };
 use bootloader::BootInfo;
-use mold_os::{clrscr, console::get_char, print, println, setcolor};
-use x86_64::VirtAddr;
+use mold_os::console::{get_char, get_input, Input};
+use mold_os::graphics::{self, Mode};
+use mold_os::keyboard::{self, KeyCode};
+use mold_os::vga_buffer::Color;
+use mold_os::{cache, clrscr, initramfs, print, println, setcolor, sound};
 
 // Constants for the maze
 const MAZE_WIDTH: usize = 79;
@@ -23,11 +26,29 @@ const FOG_NEAR: char = '.';
 const FOG_MID: char = ',';
 const FOG_FAR: char = '*';
 
+// Sound effects, in `sound::parse` notation
+const CHEST_SOUND: &str = "c5/16 e5/16 g5/16 c6/8";
+const MONSTER_HIT_SOUND: &str = "c3/16 r/32 f#2/8";
+const EXIT_SOUND: &str = "g4/8 c5/8 e5/8 g5/4 e5/8 g5/2";
+const SOUND_TEMPO: u32 = 150;
+
+// Size in pixels of a maze cell on the graphical map
+const MAP_CELL_WIDTH: usize = 4;
+const MAP_CELL_HEIGHT: usize = 7;
+
 // Structure to hold game state
 struct GameState {
     player: Player,
     maze: [[char; MAZE_WIDTH]; MAZE_HEIGHT],
     level: usize,
+    quit: Option<QuitAction>,
+}
+
+/// What to do with the machine after the game ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuitAction {
+    Shutdown,
+    Reboot,
 }
 
 struct Player {
@@ -55,8 +76,9 @@ impl GameState {
                 xp: 0,
                 sword_level: 1,
             },
-            maze: initialize_maze(1),
+            maze: load_maze(1),
             level: 1,
+            quit: None,
         }
     }
 }
@@ -81,6 +103,33 @@ impl Rng {
     }
 }
 
+/// Load the layout of `level` from the initramfs, or generate one if there
+/// is no `levels/<level>.txt`.
+fn load_maze(level: usize) -> [[char; MAZE_WIDTH]; MAZE_HEIGHT] {
+    match initramfs::read_to_str(&format!("levels/{}.txt", level)) {
+        Some(layout) => parse_maze(layout),
+        None => initialize_maze(level),
+    }
+}
+
+/// Turn a level file into a maze. Spaces are floor, everything outside of
+/// the file is wall, and the player always starts in the top left corner.
+fn parse_maze(layout: &str) -> [[char; MAZE_WIDTH]; MAZE_HEIGHT] {
+    let mut maze: [[char; MAZE_WIDTH]; MAZE_HEIGHT] = [[WALL_CHAR; MAZE_WIDTH]; MAZE_HEIGHT];
+
+    for (row, line) in layout.lines().take(MAZE_HEIGHT).enumerate() {
+        for (col, ch) in line.chars().take(MAZE_WIDTH).enumerate() {
+            maze[row][col] = match ch {
+                ' ' | PLAYER_CHAR => UNEXPLORED_CHAR,
+                ch => ch,
+            };
+        }
+    }
+    maze[1][1] = PLAYER_CHAR;
+
+    maze
+}
+
 fn initialize_maze(level: usize) -> [[char; MAZE_WIDTH]; MAZE_HEIGHT] {
     let mut maze: [[char; MAZE_WIDTH]; MAZE_HEIGHT] = [['#'; MAZE_WIDTH]; MAZE_HEIGHT];
 
@@ -116,13 +165,17 @@ fn initialize_maze(level: usize) -> [[char; MAZE_WIDTH]; MAZE_HEIGHT] {
     maze
 }
 
-pub fn run() {
+pub fn run() -> QuitAction {
     let mut game_state = GameState::new();
 
     loop {
         clear_and_draw_maze(&mut game_state);
         draw_player_stats(&game_state);
         handle_player_input(&mut game_state);
+
+        if let Some(action) = game_state.quit {
+            return action;
+        }
     }
 }
 
@@ -130,22 +183,27 @@ fn clear_and_draw_maze(game_state: &mut GameState) {
     clrscr!();
     for row in 0..MAZE_HEIGHT {
         for col in 0..MAZE_WIDTH {
-            let ch = if row == game_state.player.y && col == game_state.player.x {
-                PLAYER_CHAR
-            } else {
-                get_fog_char(
-                    row,
-                    col,
-                    game_state.player.x,
-                    game_state.player.y,
-                    &game_state.maze,
-                )
-            };
+            let ch = get_visible_char(game_state, row, col);
             write_text_at(row, col, &ch.to_string());
         }
     }
 }
 
+// What the player sees of a maze cell
+fn get_visible_char(game_state: &GameState, row: usize, col: usize) -> char {
+    if row == game_state.player.y && col == game_state.player.x {
+        PLAYER_CHAR
+    } else {
+        get_fog_char(
+            row,
+            col,
+            game_state.player.x,
+            game_state.player.y,
+            &game_state.maze,
+        )
+    }
+}
+
 fn get_fog_char(
     row: usize,
     col: usize,
@@ -191,15 +249,91 @@ fn draw_player_stats(game_state: &GameState) {
 }
 
 fn handle_player_input(game_state: &mut GameState) {
-    let input = get_char();
+    let input = match get_input() {
+        Input::Char(character) => character,
+        Input::Key(KeyCode::ArrowUp) => 'w',
+        Input::Key(KeyCode::ArrowDown) => 's',
+        Input::Key(KeyCode::ArrowLeft) => 'a',
+        Input::Key(KeyCode::ArrowRight) => 'd',
+        Input::Key(_) => return,
+    };
 
     match input {
         'w' | 's' | 'a' | 'd' => move_player(game_state, input),
         'i' => inspect_surroundings(game_state),
+        'h' => show_help(),
+        'c' => show_cache_stats(),
+        'l' => switch_keyboard_layout(),
+        'm' => show_map(game_state),
+        'q' => quit_menu(game_state),
         _ => {}
     }
 }
 
+fn switch_keyboard_layout() {
+    let layout = keyboard::layout().next();
+    keyboard::set_layout(layout);
+    display_info_box(&format!("Keyboard layout: {}", layout.name()));
+}
+
+// Draw what the player sees of the maze in VGA mode 13h
+fn show_map(game_state: &GameState) {
+    graphics::set_mode(Mode::Graphics320x200);
+    // not available on the framebuffer console
+    let Some(fb) = graphics::framebuffer() else {
+        display_info_box("The map needs the VGA display.");
+        return;
+    };
+    for row in 0..MAZE_HEIGHT {
+        for col in 0..MAZE_WIDTH {
+            let color = match get_visible_char(game_state, row, col) {
+                PLAYER_CHAR => Color::Yellow,
+                WALL_CHAR => Color::LightGray,
+                CHEST_CHAR => Color::Brown,
+                MONSTER_CHAR => Color::LightRed,
+                EXIT_CHAR => Color::LightGreen,
+                FOG_NEAR => Color::DarkGray,
+                FOG_MID | FOG_FAR => Color::Blue,
+                _ => Color::Black,
+            };
+            fb.fill_rect(
+                col * MAP_CELL_WIDTH,
+                row * MAP_CELL_HEIGHT,
+                MAP_CELL_WIDTH,
+                MAP_CELL_HEIGHT,
+                color as u8,
+            );
+        }
+    }
+    fb.draw_text(
+        48,
+        MAZE_HEIGHT * MAP_CELL_HEIGHT + 8,
+        "Press any key to continue...",
+        Color::White as u8,
+        None,
+    );
+    get_char();
+    graphics::set_mode(Mode::Text);
+}
+
+fn quit_menu(game_state: &mut GameState) {
+    loop {
+        display_info_box("Quit the game? 1. Shut down 2. Reboot 3. Keep playing");
+        match get_char() {
+            '1' => {
+                game_state.quit = Some(QuitAction::Shutdown);
+                break;
+            }
+            '2' => {
+                game_state.quit = Some(QuitAction::Reboot);
+                break;
+            }
+            '3' => break,
+            _ => {}
+        }
+    }
+}
+
 fn move_player(game_state: &mut GameState, direction: char) {
     let (new_x, new_y) = match direction {
         'w' => (game_state.player.x, game_state.player.y.saturating_sub(1)),
@@ -248,6 +382,31 @@ fn inspect_surroundings(game_state: &GameState) {
     display_info_box(&info);
 }
 
+fn show_help() {
+    let help = initramfs::read_to_str("help.txt").unwrap_or("No help available.");
+    clrscr!();
+    for (row, line) in help.lines().enumerate() {
+        write_text_at(2 + row, 6, line);
+    }
+    write_text_at(20, 30, "Press any key to continue...");
+    get_char();
+}
+
+fn show_cache_stats() {
+    let caches = cache::caches();
+    clrscr!();
+    write_text_at(2, 6, "Disk cache");
+    if caches.is_empty() {
+        write_text_at(4, 6, "No disks are mounted.");
+    }
+    for (i, cache) in caches.iter().enumerate() {
+        write_text_at(4 + 3 * i, 6, &format!("/mnt/{}", cache.device().name()));
+        write_text_at(5 + 3 * i, 2, &cache.stats().to_string());
+    }
+    write_text_at(20, 30, "Press any key to continue...");
+    get_char();
+}
+
 fn display_info_box(info: &str) {
     clrscr!();
     write_text_at(11, 6, info);
@@ -349,6 +508,7 @@ fn open_chest(game_state: &mut GameState) {
         (game_state.player.health + health_gain).min(game_state.player.max_health);
     game_state.player.xp += xp_gain;
 
+    play_sound(CHEST_SOUND);
     println!(
         "You found a chest! Gained {} health and {} XP.",
         health_gain, xp_gain
@@ -380,6 +540,7 @@ fn fight_monster(game_state: &mut GameState) {
         // Monster's turn
         let monster_damage = game_state.level * 2 + 5;
         game_state.player.health -= monster_damage as i32;
+        play_sound(MONSTER_HIT_SOUND);
         println!("The monster dealt {} damage to you!", monster_damage);
 
         if game_state.player.health <= 0 {
@@ -392,11 +553,12 @@ fn fight_monster(game_state: &mut GameState) {
 
 fn next_level(game_state: &mut GameState) {
     game_state.level += 1;
-    game_state.maze = initialize_maze(game_state.level);
+    game_state.maze = load_maze(game_state.level);
     game_state.player.x = 1;
     game_state.player.y = 1;
     game_state.player.health = game_state.player.max_health;
 
+    play_sound(EXIT_SOUND);
     println!(
         "You reached the exit! Moving to level {}.",
         game_state.level
@@ -425,6 +587,10 @@ fn next_level(game_state: &mut GameState) {
     }
 }
 
+fn play_sound(notation: &str) {
+    sound::play_notation(notation, SOUND_TEMPO).expect("invalid sound effect");
+}
+
 fn reveal_area(game_state: &mut GameState, new_x: usize, new_y: usize) {
     for row in new_y.saturating_sub(2)..=(new_y + 2).min(MAZE_HEIGHT - 1) {
         for col in new_x.saturating_sub(2)..=(new_x + 2).min(MAZE_WIDTH - 1) {
@@ -436,18 +602,12 @@ fn reveal_area(game_state: &mut GameState, new_x: usize, new_y: usize) {
 }
 
 pub fn start(boot_info: &'static BootInfo) {
-    use mold_os::allocator;
-    use mold_os::memory;
-    use mold_os::memory::BootInfoFrameAllocator;
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = memory::init(phys_mem_offset);
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+    mold_os::init_filesystems();
 
     clrscr!();
-    println!("Welcome to Mold OS Maze Game!");
+    println!("Welcome to Mold OS Maze Game! Press h for help.");
 }
 
 pub fn end() {
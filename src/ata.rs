@@ -0,0 +1,405 @@
+// ATA PIO driver for the legacy IDE channels
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::interrupts::{enable_isa_irq, InterruptIndex};
+use crate::{log, time};
+
+// register offsets from the I/O base
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const CMD_READ_PIO: u8 = 0x20;
+const CMD_READ_PIO_EXT: u8 = 0x24;
+const CMD_WRITE_PIO: u8 = 0x30;
+const CMD_WRITE_PIO_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Largest LBA28 address plus one.
+const LBA28_LIMIT: u64 = 1 << 28;
+/// How long a command may take before it's considered lost.
+const TIMEOUT_NS: u64 = 1_000_000_000;
+
+struct ChannelInfo {
+    io_base: u16,
+    control_base: u16,
+    irq: u8,
+    index: InterruptIndex,
+}
+
+const CHANNELS: [ChannelInfo; 2] = [
+    ChannelInfo {
+        io_base: 0x1F0,
+        control_base: 0x3F6,
+        irq: 14,
+        index: InterruptIndex::PrimaryAta,
+    },
+    ChannelInfo {
+        io_base: 0x170,
+        control_base: 0x376,
+        irq: 15,
+        index: InterruptIndex::SecondaryAta,
+    },
+];
+
+/// Set by the interrupt handler when a channel raised its IRQ.
+static IRQ_PENDING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// Serializes commands on a channel, which both of its drives share.
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+
+/// Called by the IRQ 14 and 15 handlers.
+pub fn handle_interrupt(channel: usize) {
+    // reading the status register acknowledges the interrupt
+    let _: u8 = unsafe { Port::new(CHANNELS[channel].io_base + REG_STATUS).read() };
+    IRQ_PENDING[channel].store(true, Ordering::SeqCst);
+}
+
+/// Registers of one channel, used while its lock is held.
+struct Registers {
+    channel: usize,
+    io_base: u16,
+    control_base: u16,
+}
+
+impl Registers {
+    fn new(channel: usize) -> Self {
+        Registers {
+            channel,
+            io_base: CHANNELS[channel].io_base,
+            control_base: CHANNELS[channel].control_base,
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    /// The status register, read without acknowledging an interrupt.
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control_base).read() }
+    }
+
+    /// Select the master or slave drive and give it time to respond.
+    fn select(&self, slave: bool, bits: u8) {
+        self.write(REG_DRIVE, bits | (slave as u8) << 4);
+        // each status read takes about 100 ns, the drive needs 400 ns
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn read_data(&self, buf: &mut [u8]) {
+        let mut port: Port<u16> = Port::new(self.io_base + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buf: &[u8]) {
+        let mut port: Port<u16> = Port::new(self.io_base + REG_DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    /// Wait until the drive is no longer busy and check for errors.
+    fn wait_ready(&self) -> Result<u8, BlockError> {
+        let deadline = time::now_ns() + TIMEOUT_NS;
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(BlockError::Io);
+                }
+                return Ok(status);
+            }
+            if time::now_ns() > deadline {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait until the drive requests data.
+    fn wait_data_request(&self) -> Result<(), BlockError> {
+        match self.wait_ready()? & STATUS_DRQ {
+            0 => Err(BlockError::Io),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for the channel's IRQ, then for the drive to be ready.
+    ///
+    /// Falls back to polling if the interrupt doesn't arrive, e.g. because
+    /// interrupts are disabled.
+    fn wait_irq(&self) -> Result<u8, BlockError> {
+        let pending = &IRQ_PENDING[self.channel];
+        if interrupts::are_enabled() {
+            let deadline = time::now_ns() + TIMEOUT_NS;
+            loop {
+                interrupts::disable();
+                if pending.swap(false, Ordering::SeqCst) || time::now_ns() > deadline {
+                    interrupts::enable();
+                    break;
+                }
+                interrupts::enable_and_hlt();
+            }
+        }
+        pending.store(false, Ordering::SeqCst);
+        self.wait_ready()
+    }
+
+    /// Program the address and sector count and issue `command`.
+    fn issue(&self, slave: bool, lba: u64, count: u16, lba48: bool, command: u8) {
+        IRQ_PENDING[self.channel].store(false, Ordering::SeqCst);
+        if lba48 {
+            self.select(slave, 0x40);
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(slave, 0xE0 | ((lba >> 24) & 0x0F) as u8);
+        }
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+        self.write(REG_COMMAND, command);
+    }
+}
+
+/// What IDENTIFY DEVICE reports about a drive.
+pub(crate) struct Identity {
+    pub sectors: u64,
+    pub lba48: bool,
+    pub model: String,
+}
+
+impl Identity {
+    pub(crate) fn parse(data: &[u8; SECTOR_SIZE]) -> Identity {
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, i| sectors | (word(100 + i) as u64) << (16 * i))
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+        // the model string is stored with the bytes of each word swapped
+        let model: String = (27..47)
+            .flat_map(|index| {
+                let [low, high] = word(index).to_le_bytes();
+                [high as char, low as char]
+            })
+            .collect();
+        Identity {
+            sectors,
+            lba48,
+            model: String::from(model.trim()),
+        }
+    }
+}
+
+/// A hard disk on one of the IDE channels.
+pub struct AtaDrive {
+    name: String,
+    channel: usize,
+    slave: bool,
+    sectors: u64,
+    lba48: bool,
+    model: String,
+}
+
+impl AtaDrive {
+    /// Send IDENTIFY to a drive and return it if it's an ATA hard disk.
+    fn identify(channel: usize, slave: bool) -> Option<AtaDrive> {
+        let _guard = CHANNEL_LOCKS[channel].lock();
+        let registers = Registers::new(channel);
+        // a floating bus reads as all ones
+        if registers.alternate_status() == 0xFF {
+            return None;
+        }
+        registers.issue(slave, 0, 0, false, CMD_IDENTIFY);
+        if registers.read(REG_STATUS) == 0 {
+            return None;
+        }
+        let deadline = time::now_ns() + TIMEOUT_NS;
+        while registers.alternate_status() & STATUS_BSY != 0 {
+            if time::now_ns() > deadline {
+                return None;
+            }
+        }
+        // ATAPI and SATA devices put a signature in the LBA registers
+        if registers.read(REG_LBA_MID) != 0 || registers.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        registers.wait_data_request().ok()?;
+
+        let mut data = [0u8; SECTOR_SIZE];
+        registers.read_data(&mut data);
+        IRQ_PENDING[channel].store(false, Ordering::SeqCst);
+        let identity = Identity::parse(&data);
+
+        Some(AtaDrive {
+            name: format!("ata{}", channel * 2 + slave as usize),
+            channel,
+            slave,
+            sectors: identity.sectors,
+            lba48: identity.lba48,
+            model: identity.model,
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.lba48
+    }
+
+    /// Largest number of sectors a single command may transfer.
+    fn max_sectors(lba48: bool) -> usize {
+        if lba48 {
+            65536
+        } else {
+            256
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = block::check_request(self, lba, buf.len())?;
+        // drives without LBA48 are never larger than LBA28 can address
+        let lba48 = lba + count > LBA28_LIMIT;
+        let command = if lba48 {
+            CMD_READ_PIO_EXT
+        } else {
+            CMD_READ_PIO
+        };
+
+        let _guard = CHANNEL_LOCKS[self.channel].lock();
+        let registers = Registers::new(self.channel);
+        let chunk_size = Self::max_sectors(lba48) * SECTOR_SIZE;
+        for (index, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let start = lba + (index * Self::max_sectors(lba48)) as u64;
+            let sectors = chunk.len() / SECTOR_SIZE;
+            // a count of 0 means the maximum
+            registers.issue(self.slave, start, sectors as u16, lba48, command);
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                // the drive raises an interrupt whenever a sector is ready
+                registers.wait_irq()?;
+                registers.wait_data_request()?;
+                registers.read_data(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = block::check_request(self, lba, buf.len())?;
+        // drives without LBA48 are never larger than LBA28 can address
+        let lba48 = lba + count > LBA28_LIMIT;
+        let command = if lba48 {
+            CMD_WRITE_PIO_EXT
+        } else {
+            CMD_WRITE_PIO
+        };
+
+        let _guard = CHANNEL_LOCKS[self.channel].lock();
+        let registers = Registers::new(self.channel);
+        let chunk_size = Self::max_sectors(lba48) * SECTOR_SIZE;
+        for (index, chunk) in buf.chunks(chunk_size).enumerate() {
+            let start = lba + (index * Self::max_sectors(lba48)) as u64;
+            let sectors = chunk.len() / SECTOR_SIZE;
+            registers.issue(self.slave, start, sectors as u16, lba48, command);
+            // the first sector is requested without an interrupt, the
+            // following ones and the completion raise one each
+            registers.wait_data_request()?;
+            for (i, sector) in chunk.chunks_exact(SECTOR_SIZE).enumerate() {
+                if i > 0 {
+                    registers.wait_irq()?;
+                    registers.wait_data_request()?;
+                }
+                registers.write_data(sector);
+            }
+            registers.wait_irq()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = if self.lba48 {
+            CMD_CACHE_FLUSH_EXT
+        } else {
+            CMD_CACHE_FLUSH
+        };
+        let _guard = CHANNEL_LOCKS[self.channel].lock();
+        let registers = Registers::new(self.channel);
+        registers.select(self.slave, 0xE0);
+        IRQ_PENDING[self.channel].store(false, Ordering::SeqCst);
+        registers.write(REG_COMMAND, command);
+        registers.wait_irq().map(|_| ())
+    }
+}
+
+/// Probe both IDE channels and register the hard disks found.
+///
+/// Returns the number of drives. Requires the heap and, if the APIC is used,
+/// `apic::init` to have run.
+pub fn init() -> usize {
+    log!("Probing ATA drives");
+    let mut found = 0;
+    for (channel, info) in CHANNELS.iter().enumerate() {
+        // nIEN cleared: the drives may interrupt
+        unsafe { Port::<u8>::new(info.control_base).write(0) };
+        enable_isa_irq(info.irq, info.index);
+        for slave in [false, true] {
+            if let Some(drive) = AtaDrive::identify(channel, slave) {
+                log!(
+                    "{}: {} ({} sectors{})",
+                    drive.name,
+                    drive.model,
+                    drive.sectors,
+                    if drive.lba48 { ", LBA48" } else { "" }
+                );
+                block::register(Arc::new(drive));
+                found += 1;
+            }
+        }
+    }
+    found
+}
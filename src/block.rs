@@ -0,0 +1,152 @@
+// Block devices and the registry of the ones that were found
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::log;
+
+/// Size of a sector on every block device.
+pub const SECTOR_SIZE: usize = 512;
+/// Frames of the bounce buffer that DMA drivers move data through.
+pub const BOUNCE_FRAMES: usize = 16;
+/// Sectors that fit into the bounce buffer, the most one request moves.
+pub const BOUNCE_SECTORS: usize = BOUNCE_FRAMES * 4096 / SECTOR_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    BadBuffer,
+    /// The device reported an error.
+    Io,
+    /// The device didn't answer in time.
+    Timeout,
+    ReadOnly,
+}
+
+/// A device that is read and written in whole sectors.
+pub trait BlockDevice: Send + Sync {
+    /// Name the device is registered under, e.g. `ata1`.
+    fn name(&self) -> &str;
+
+    /// Size of the device in sectors.
+    fn sector_count(&self) -> u64;
+
+    /// Read `buf.len() / SECTOR_SIZE` sectors starting at `lba`.
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / SECTOR_SIZE` sectors starting at `lba`.
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Make sure written sectors reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Check that a transfer of `len` bytes at `lba` fits `device`, and return
+/// the number of sectors it covers.
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// Make `device` available to the rest of the kernel.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    log!(
+        "Block device {}: {} KiB",
+        device.name(),
+        device.sector_count() * SECTOR_SIZE as u64 / 1024
+    );
+    DEVICES.lock().push(device);
+}
+
+/// All registered block devices.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// The block device registered as `name`.
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Names of all registered block devices.
+pub fn names() -> Vec<String> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|device| device.name().into())
+        .collect()
+}
+
+/// A block device in memory. Sectors that were never written, or only with
+/// zeros, read as zeros and take no memory.
+pub struct RamDisk {
+    name: String,
+    sectors: u64,
+    data: Mutex<BTreeMap<u64, Box<[u8; SECTOR_SIZE]>>>,
+}
+
+impl RamDisk {
+    pub fn new(name: &str, sectors: u64) -> Self {
+        RamDisk {
+            name: String::from(name),
+            sectors,
+            data: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let data = self.data.lock();
+        for (sector, chunk) in (lba..).zip(buf.chunks_exact_mut(SECTOR_SIZE)) {
+            match data.get(&sector) {
+                Some(stored) => chunk.copy_from_slice(&stored[..]),
+                None => chunk.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let mut data = self.data.lock();
+        for (sector, chunk) in (lba..).zip(buf.chunks_exact(SECTOR_SIZE)) {
+            if chunk.iter().all(|&byte| byte == 0) {
+                data.remove(&sector);
+            } else {
+                let mut stored = Box::new([0; SECTOR_SIZE]);
+                stored.copy_from_slice(chunk);
+                data.insert(sector, stored);
+            }
+        }
+        Ok(())
+    }
+}
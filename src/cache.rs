@@ -0,0 +1,332 @@
+// Write-back sector cache between file systems and block devices
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::{task, warn};
+
+/// Sectors each mounted volume caches. The heap is small.
+pub const DEFAULT_CAPACITY: usize = 32;
+/// Sectors read beyond a sequential read.
+pub const READ_AHEAD: u64 = 8;
+/// How often dirty sectors are written back.
+pub const FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Counters of one cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Sectors read from the cache.
+    pub hits: u64,
+    /// Sectors that had to be read from the device.
+    pub misses: u64,
+    /// Sectors read from the device ahead of a sequential read.
+    pub read_ahead: u64,
+    /// Dirty sectors written to the device.
+    pub write_backs: u64,
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Share of sector reads served from the cache, in percent.
+    pub fn hit_rate(&self) -> u64 {
+        match self.hits + self.misses {
+            0 => 0,
+            total => self.hits * 100 / total,
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses ({}%), {} read ahead, {} written back, {} evicted",
+            self.hits,
+            self.misses,
+            self.hit_rate(),
+            self.read_ahead,
+            self.write_backs,
+            self.evictions
+        )
+    }
+}
+
+struct Entry {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    /// Value of `State::clock` when the entry was last used.
+    last_used: u64,
+}
+
+struct State {
+    entries: BTreeMap<u64, Entry>,
+    /// Counts accesses, for finding the least recently used entry.
+    clock: u64,
+    /// The sector after the last read, to detect sequential reads.
+    next_sequential: u64,
+    stats: CacheStats,
+}
+
+/// A block device that keeps recently used sectors of another in memory.
+///
+/// Writes only reach the device when their sectors are evicted or the
+/// cache is flushed.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl BlockCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        BlockCache {
+            device,
+            capacity: capacity.max(1),
+            state: Mutex::new(State {
+                entries: BTreeMap::new(),
+                clock: 0,
+                next_sequential: u64::MAX,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    /// Number of sectors held.
+    pub fn len(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dirty_sectors(&self) -> usize {
+        let state = self.state.lock();
+        state.entries.values().filter(|entry| entry.dirty).count()
+    }
+
+    /// Write every dirty sector back, without flushing the device itself.
+    pub fn write_back(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        let dirty: Vec<u64> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&lba, _)| lba)
+            .collect();
+        // neighbouring sectors go out in one request
+        let mut start = 0;
+        while start < dirty.len() {
+            let mut end = start + 1;
+            while end < dirty.len() && dirty[end] == dirty[end - 1] + 1 {
+                end += 1;
+            }
+            let mut buf = vec![0; (end - start) * SECTOR_SIZE];
+            for (chunk, lba) in buf.chunks_mut(SECTOR_SIZE).zip(&dirty[start..end]) {
+                chunk.copy_from_slice(&state.entries[lba].data[..]);
+            }
+            self.device.write_sectors(dirty[start], &buf)?;
+            for lba in &dirty[start..end] {
+                state.entries.get_mut(lba).unwrap().dirty = false;
+            }
+            state.stats.write_backs += (end - start) as u64;
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Make room for one more entry by dropping the least recently used.
+    fn evict(&self, state: &mut State) -> Result<(), BlockError> {
+        while state.entries.len() >= self.capacity {
+            let (&lba, entry) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .unwrap();
+            if entry.dirty {
+                self.device.write_sectors(lba, &entry.data[..])?;
+                state.stats.write_backs += 1;
+            }
+            state.entries.remove(&lba);
+            state.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &self,
+        state: &mut State,
+        lba: u64,
+        data: &[u8],
+        dirty: bool,
+    ) -> Result<(), BlockError> {
+        state.clock += 1;
+        let last_used = state.clock;
+        if let Some(entry) = state.entries.get_mut(&lba) {
+            entry.data.copy_from_slice(data);
+            entry.dirty |= dirty;
+            entry.last_used = last_used;
+            return Ok(());
+        }
+        self.evict(state)?;
+        let mut sector = Box::new([0; SECTOR_SIZE]);
+        sector.copy_from_slice(data);
+        state.entries.insert(
+            lba,
+            Entry {
+                data: sector,
+                dirty,
+                last_used,
+            },
+        );
+        Ok(())
+    }
+
+    /// Copy cached sector `lba` to `buf` if it is present.
+    fn lookup(state: &mut State, lba: u64, buf: &mut [u8]) -> bool {
+        state.clock += 1;
+        let clock = state.clock;
+        match state.entries.get_mut(&lba) {
+            Some(entry) => {
+                entry.last_used = clock;
+                buf.copy_from_slice(&entry.data[..]);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = block::check_request(self, lba, buf.len())?;
+        let mut state = self.state.lock();
+        let sequential = lba == state.next_sequential;
+        state.next_sequential = lba + count;
+
+        // requests bigger than the cache go around it, keeping newer data
+        // from dirty sectors
+        if count as usize > self.capacity {
+            self.device.read_sectors(lba, buf)?;
+            for (&sector, entry) in state.entries.range(lba..lba + count) {
+                let offset = (sector - lba) as usize * SECTOR_SIZE;
+                buf[offset..offset + SECTOR_SIZE].copy_from_slice(&entry.data[..]);
+            }
+            state.stats.misses += count;
+            return Ok(());
+        }
+
+        let mut index = 0;
+        while index < count {
+            let offset = index as usize * SECTOR_SIZE;
+            if Self::lookup(
+                &mut state,
+                lba + index,
+                &mut buf[offset..offset + SECTOR_SIZE],
+            ) {
+                state.stats.hits += 1;
+                index += 1;
+                continue;
+            }
+
+            // read the run of missing sectors in one go
+            let mut end = index + 1;
+            while end < count && !state.entries.contains_key(&(lba + end)) {
+                end += 1;
+            }
+            let mut ahead = 0;
+            if sequential && end == count {
+                let limit = (self.sector_count() - (lba + count)).min(READ_AHEAD);
+                while ahead < limit && !state.entries.contains_key(&(lba + end + ahead)) {
+                    ahead += 1;
+                }
+                // read-ahead must not push out what was just read
+                ahead = ahead.min((self.capacity as u64).saturating_sub(end - index));
+            }
+
+            let mut data = vec![0; ((end - index + ahead) * SECTOR_SIZE as u64) as usize];
+            self.device.read_sectors(lba + index, &mut data)?;
+            for (i, sector) in data.chunks(SECTOR_SIZE).enumerate() {
+                self.insert(&mut state, lba + index + i as u64, sector, false)?;
+            }
+            let requested = ((end - index) * SECTOR_SIZE as u64) as usize;
+            buf[offset..offset + requested].copy_from_slice(&data[..requested]);
+            state.stats.misses += end - index;
+            state.stats.read_ahead += ahead;
+            index = end;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = block::check_request(self, lba, buf.len())?;
+        let mut state = self.state.lock();
+        if count as usize > self.capacity {
+            self.device.write_sectors(lba, buf)?;
+            // cached copies are now up to date and clean
+            for (&sector, entry) in state.entries.range_mut(lba..lba + count) {
+                let offset = (sector - lba) as usize * SECTOR_SIZE;
+                entry
+                    .data
+                    .copy_from_slice(&buf[offset..offset + SECTOR_SIZE]);
+                entry.dirty = false;
+            }
+            return Ok(());
+        }
+        for (i, sector) in buf.chunks(SECTOR_SIZE).enumerate() {
+            self.insert(&mut state, lba + i as u64, sector, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.write_back()?;
+        self.device.flush()
+    }
+}
+
+static CACHES: Mutex<Vec<Arc<BlockCache>>> = Mutex::new(Vec::new());
+
+/// Add `cache` to the caches that are flushed periodically and at shutdown.
+pub fn register(cache: Arc<BlockCache>) {
+    CACHES.lock().push(cache);
+}
+
+pub fn caches() -> Vec<Arc<BlockCache>> {
+    CACHES.lock().clone()
+}
+
+/// Write back and flush every registered cache.
+pub fn flush_all() {
+    for cache in caches() {
+        if let Err(error) = cache.flush() {
+            warn!("Failed to flush {}: {:?}", cache.name(), error);
+        }
+    }
+}
+
+/// Start writing dirty sectors back every `FLUSH_INTERVAL_MS`.
+pub fn init() {
+    task::spawn_periodic("cache flush", FLUSH_INTERVAL_MS, flush_all);
+}
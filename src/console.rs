@@ -1,6 +1,8 @@
 use crate::interrupts::BUFFER;
+use crate::keyboard::{self, KeyCode};
 use crate::print;
 use crate::string::String;
+use crate::task;
 
 /// Clears the BUFFER by setting it to '\0'
 pub fn clear_buffer() {
@@ -23,6 +25,8 @@ fn get_from_stdin_with_delimiters(delimiters: &[char]) -> String {
                 print!("{}", *buffer);
                 buffer_content = *buffer;
             } else {
+                // nothing typed yet, a good time for background work
+                task::run_pending();
                 continue;
             }
             clear_buffer();
@@ -75,6 +79,8 @@ pub fn get_char() -> char {
                 let buffer = BUFFER.lock();
                 buffer_content = *buffer;
             } else {
+                // nothing typed yet, a good time for background work
+                task::run_pending();
                 continue;
             }
             clear_buffer();
@@ -84,3 +90,44 @@ pub fn get_char() -> char {
         return buffer_content;
     }
 }
+
+/// A typed character or a key that doesn't type one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Char(char),
+    Key(KeyCode),
+}
+
+/// Waits for a character like `get_char`, but also returns keys such as
+/// the arrows and function keys.
+pub fn get_input() -> Input {
+    // keys pressed before we started waiting belong to someone else
+    keyboard::clear_events();
+    loop {
+        if !BUFFER.is_locked() && *BUFFER.lock() != '\0' {
+            return Input::Char(get_char());
+        }
+        while let Some(event) = keyboard::poll_event() {
+            if event.pressed && event.character.is_none() && !is_modifier(event.code) {
+                return Input::Key(event.code);
+            }
+        }
+        task::run_pending();
+    }
+}
+
+fn is_modifier(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::LShift
+            | KeyCode::RShift
+            | KeyCode::LControl
+            | KeyCode::RControl
+            | KeyCode::RControl2
+            | KeyCode::LAlt
+            | KeyCode::RAltGr
+            | KeyCode::CapsLock
+            | KeyCode::NumpadLock
+            | KeyCode::ScrollLock
+    )
+}
@@ -0,0 +1,278 @@
+// Loading ELF64 executables into user address spaces
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::address_space::{self, AddressSpace};
+use crate::usermode::{self, ExitStatus};
+
+const PAGE_SIZE: u64 = 4096;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 0x3E;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Address position independent executables are loaded at.
+pub const PIE_BASE: u64 = 0x_1000_0000_0000;
+/// Top of the user stack. The page below the stack is left unmapped as a guard.
+pub const USER_STACK_TOP: u64 = 0x_7fff_ffff_f000;
+/// Size of the user stack in pages.
+pub const USER_STACK_PAGES: u64 = 16;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The image is not a little endian ELF64 file.
+    NotElf,
+    /// The image is not an x86_64 executable.
+    Unsupported,
+    /// A header or segment lies outside the image or has inconsistent sizes.
+    Malformed,
+    /// A segment or the entry point lies outside the user part of the address space.
+    BadAddress,
+    /// The arguments don't fit on the user stack.
+    ArgumentsTooLarge,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+}
+
+struct Header {
+    kind: u16,
+    entry: u64,
+    program_header_offset: u64,
+    program_header_count: u16,
+}
+
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+fn u16_at(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(image[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(image: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < HEADER_SIZE || image[0..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB || image[6] != EV_CURRENT {
+        return Err(ElfError::NotElf);
+    }
+    let kind = u16_at(image, 16);
+    if (kind != ET_EXEC && kind != ET_DYN) || u16_at(image, 18) != EM_X86_64 {
+        return Err(ElfError::Unsupported);
+    }
+    if u16_at(image, 54) as usize != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::Malformed);
+    }
+
+    let header = Header {
+        kind,
+        entry: u64_at(image, 24),
+        program_header_offset: u64_at(image, 32),
+        program_header_count: u16_at(image, 56),
+    };
+    let table_size = header.program_header_count as u64 * PROGRAM_HEADER_SIZE as u64;
+    match header.program_header_offset.checked_add(table_size) {
+        Some(end) if end <= image.len() as u64 => Ok(header),
+        _ => Err(ElfError::Malformed),
+    }
+}
+
+fn segments<'a>(image: &'a [u8], header: &Header) -> impl Iterator<Item = Segment> + 'a {
+    let start = header.program_header_offset as usize;
+    (0..header.program_header_count as usize).map(move |index| {
+        let entry = start + index * PROGRAM_HEADER_SIZE;
+        Segment {
+            kind: u32_at(image, entry),
+            flags: u32_at(image, entry + 4),
+            offset: u64_at(image, entry + 8),
+            vaddr: u64_at(image, entry + 16),
+            file_size: u64_at(image, entry + 32),
+            memory_size: u64_at(image, entry + 40),
+        }
+    })
+}
+
+/// Load an ELF64 executable into a new address space and set up its stack.
+///
+/// Position independent executables (`ET_DYN`) are loaded at `PIE_BASE`;
+/// relocations are not applied. The stack holds `argc`, `argv`, `envp` and
+/// an auxiliary vector as described by the System V ABI.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let header = parse_header(image)?;
+    let bias = if header.kind == ET_DYN { PIE_BASE } else { 0 };
+    let mut address_space = AddressSpace::new()?;
+    let mut program_headers = None;
+
+    for segment in segments(image, &header).filter(|s| s.kind == PT_LOAD) {
+        let file_end = segment
+            .offset
+            .checked_add(segment.file_size)
+            .ok_or(ElfError::Malformed)?;
+        if segment.file_size > segment.memory_size || file_end > image.len() as u64 {
+            return Err(ElfError::Malformed);
+        }
+        let start = segment
+            .vaddr
+            .checked_add(bias)
+            .and_then(|start| VirtAddr::try_new(start).ok())
+            .ok_or(ElfError::BadAddress)?;
+        if !address_space.is_user_range(start, segment.memory_size) {
+            return Err(ElfError::BadAddress);
+        }
+        if segment.memory_size == 0 {
+            continue;
+        }
+
+        let mut flags = PageTableFlags::empty();
+        if segment.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let first_page = start.align_down(PAGE_SIZE);
+        let pages = (start + segment.memory_size - first_page).div_ceil(PAGE_SIZE);
+        address_space.map_user(first_page, pages, flags)?;
+
+        let data = &image[segment.offset as usize..file_end as usize];
+        if !address_space.write(start, data) {
+            return Err(ElfError::Malformed);
+        }
+
+        let table_start = header.program_header_offset;
+        if table_start >= segment.offset && table_start < file_end {
+            program_headers = Some(start.as_u64() + (table_start - segment.offset));
+        }
+    }
+
+    let entry = header.entry.checked_add(bias).ok_or(ElfError::BadAddress)?;
+    if entry >= usermode::USER_SPACE_END {
+        return Err(ElfError::BadAddress);
+    }
+
+    let mut auxv = Vec::new();
+    if let Some(addr) = program_headers {
+        auxv.push((AT_PHDR, addr));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, header.program_header_count as u64));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, entry));
+
+    let stack_top = setup_stack(&mut address_space, argv, envp, &auxv)?;
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(entry),
+        stack_top,
+    })
+}
+
+/// Map the user stack and write the initial process state to it. Returns
+/// the initial stack pointer, which points at `argc`.
+fn setup_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let stack_size = USER_STACK_PAGES * PAGE_SIZE;
+    let stack_bottom = USER_STACK_TOP - stack_size;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    address_space.map_user(VirtAddr::new(stack_bottom), USER_STACK_PAGES, flags)?;
+
+    // strings go to the top of the stack
+    let mut strings = Vec::new();
+    let mut offsets = Vec::new();
+    for string in argv.iter().chain(envp) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = USER_STACK_TOP
+        .checked_sub(strings.len() as u64)
+        .ok_or(ElfError::ArgumentsTooLarge)?
+        & !15;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    let pointers = offsets.iter().map(|offset| strings_start + offset);
+    words.extend(pointers.clone().take(argv.len()));
+    words.push(0);
+    words.extend(pointers.skip(argv.len()));
+    words.push(0);
+    for &(kind, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(kind);
+        words.push(value);
+    }
+
+    let words_size = words.len() as u64 * 8;
+    let room = strings_start
+        .checked_sub(stack_bottom)
+        .ok_or(ElfError::ArgumentsTooLarge)?;
+    if room < words_size + PAGE_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let stack_pointer = (strings_start - words_size) & !15;
+
+    let words: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(strings_start), &strings);
+    address_space.write(VirtAddr::new(stack_pointer), &words);
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// Load an ELF64 executable and run it until it exits.
+pub fn exec(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<ExitStatus, ElfError> {
+    let program = load(image, argv, envp)?;
+    unsafe {
+        let previous = program.address_space.activate();
+        let status = usermode::run(program.entry, program.stack_top, 0);
+        address_space::restore(previous);
+        Ok(status)
+    }
+}
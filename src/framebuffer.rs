@@ -0,0 +1,538 @@
+// Linear framebuffer text console (the `framebuffer` feature)
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::graphics::{self, FONT_SIZE, GLYPH_STRIDE};
+use crate::pci::{self, Bar};
+use crate::psf::Font;
+use crate::vga_buffer::Color;
+use crate::{initramfs, log, memory, warn};
+
+pub const DEFAULT_WIDTH: usize = 1024;
+pub const DEFAULT_HEIGHT: usize = 768;
+
+/// PSF font in the initramfs used instead of the VGA font if it exists.
+pub const FONT_PATH: &str = "fonts/console.psf";
+
+/// The Bochs VBE interface of QEMU's standard VGA and VirtualBox.
+const VBE_INDEX: u16 = 0x01CE;
+const VBE_DATA: u16 = 0x01CF;
+const VBE_ID: u16 = 0;
+const VBE_XRES: u16 = 1;
+const VBE_YRES: u16 = 2;
+const VBE_BPP: u16 = 3;
+const VBE_ENABLE: u16 = 4;
+const VBE_VIRT_WIDTH: u16 = 6;
+/// The first version with 32 bits per pixel.
+const VBE_ID_32BPP: u16 = 0xB0C2;
+const VBE_ENABLED: u16 = 0x01;
+const VBE_LFB_ENABLED: u16 = 0x40;
+
+/// Vendor and device IDs of the adapters with the Bochs VBE interface. The
+/// linear framebuffer is their first BAR.
+const VBE_DEVICES: [(u16, u16); 2] = [(0x1234, 0x1111), (0x80EE, 0xBEEF)];
+
+/// The colours of `vga_buffer::Color` as RGB.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (0, 0, 170),
+    (0, 170, 0),
+    (0, 170, 170),
+    (170, 0, 0),
+    (170, 0, 170),
+    (170, 85, 0),
+    (170, 170, 170),
+    (85, 85, 85),
+    (85, 85, 255),
+    (85, 255, 85),
+    (85, 255, 255),
+    (255, 85, 85),
+    (255, 85, 255),
+    (255, 255, 85),
+    (255, 255, 255),
+];
+
+/// Code page 437, the character set of the VGA font, for bytes below 0x20.
+const CP437_CONTROL: [char; 32] = [
+    ' ', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕',
+    '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Code page 437 for bytes from 0x80.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferError {
+    /// No display adapter with a linear framebuffer was found.
+    NotFound,
+    /// The adapter didn't accept the resolution.
+    ModeNotSet,
+    /// Neither a PSF font in the initramfs nor the VGA font.
+    NoFont,
+    UnsupportedFormat,
+    MapFailed,
+}
+
+/// Order of the colour components in a pixel, lowest address first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+}
+
+/// Where the framebuffer is and how its pixels are laid out, as newer
+/// bootloaders describe it in their boot information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub address: PhysAddr,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels from the start of one line to the start of the next.
+    pub stride: usize,
+    /// 3 or 4.
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+impl FramebufferInfo {
+    pub fn size(&self) -> usize {
+        self.stride * self.height * self.bytes_per_pixel
+    }
+}
+
+/// A mapped linear framebuffer. Drawing outside of it is clipped.
+pub struct LinearFramebuffer {
+    info: FramebufferInfo,
+    memory: *mut u8,
+}
+
+// the framebuffer is only reached through the console lock
+unsafe impl Send for LinearFramebuffer {}
+
+impl LinearFramebuffer {
+    /// Map the framebuffer described by `info` write-combining.
+    pub fn map(info: FramebufferInfo) -> Result<LinearFramebuffer, FramebufferError> {
+        if !matches!(info.bytes_per_pixel, 3 | 4) {
+            return Err(FramebufferError::UnsupportedFormat);
+        }
+        let memory = memory::map_write_combining(info.address, info.size() as u64)
+            .map_err(|_| FramebufferError::MapFailed)?;
+        Ok(LinearFramebuffer {
+            info,
+            memory: memory.as_mut_ptr(),
+        })
+    }
+
+    pub fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
+    fn offset(&self, x: usize, y: usize) -> usize {
+        (y * self.info.stride + x) * self.info.bytes_per_pixel
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, (red, green, blue): (u8, u8, u8)) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let bytes = match self.info.format {
+            PixelFormat::Rgb => [red, green, blue, 0],
+            PixelFormat::Bgr => [blue, green, red, 0],
+        };
+        let pixel = unsafe { self.memory.add(self.offset(x, y)) };
+        if self.info.bytes_per_pixel == 4 {
+            unsafe { (pixel as *mut u32).write_volatile(u32::from_le_bytes(bytes)) };
+            return;
+        }
+        for (index, byte) in bytes.into_iter().take(3).enumerate() {
+            unsafe { pixel.add(index).write_volatile(byte) };
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Option<(u8, u8, u8)> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+        let pixel = unsafe { self.memory.add(self.offset(x, y)) };
+        let [first, green, last] =
+            [0, 1, 2].map(|index| unsafe { pixel.add(index).read_volatile() });
+        Some(match self.info.format {
+            PixelFormat::Rgb => (first, green, last),
+            PixelFormat::Bgr => (last, green, first),
+        })
+    }
+
+    pub fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: (u8, u8, u8),
+    ) {
+        let right = x.saturating_add(width).min(self.info.width);
+        let bottom = y.saturating_add(height).min(self.info.height);
+        for row in y..bottom {
+            for col in x..right {
+                self.set_pixel(col, row, color);
+            }
+        }
+    }
+}
+
+/// The character code page 437 shows for `byte`.
+fn cp437(byte: u8) -> char {
+    match byte {
+        0x00..=0x1F => CP437_CONTROL[byte as usize],
+        0x7F => '⌂',
+        0x80..=0xFF => CP437_HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+/// A character cell of the console and the attribute it's drawn in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    character: u8,
+    attribute: u8,
+}
+
+/// A text console drawn with a bitmap font, with the same interface as
+/// `vga_buffer::Writer`: output goes to the bottom line and scrolls up.
+///
+/// The cells on screen are kept in memory, so that only the ones that change
+/// are drawn and the framebuffer is never read back.
+pub struct Console {
+    framebuffer: LinearFramebuffer,
+    font: Font,
+    cells: Vec<Cell>,
+    column_position: usize,
+    /// Colours as in a VGA text mode attribute: background in the high
+    /// nibble, foreground in the low one.
+    attribute: u8,
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+impl Console {
+    pub fn new(framebuffer: LinearFramebuffer, font: Font) -> Console {
+        let mut console = Console {
+            framebuffer,
+            font,
+            cells: Vec::new(),
+            column_position: 0,
+            attribute: (Color::Black as u8) << 4 | Color::White as u8,
+        };
+        console.cells = vec![console.blank(); console.rows() * console.columns()];
+        console.clear();
+        console
+    }
+
+    pub fn columns(&self) -> usize {
+        self.framebuffer.info.width / self.font.width()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.framebuffer.info.height / self.font.height()
+    }
+
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    pub fn framebuffer(&self) -> &LinearFramebuffer {
+        &self.framebuffer
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.set_attribute((background as u8) << 4 | foreground as u8);
+    }
+
+    pub fn set_attribute(&mut self, attribute: u8) {
+        self.attribute = attribute;
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        PALETTE[(self.attribute >> 4) as usize]
+    }
+
+    /// A blank cell in the current colours.
+    fn blank(&self) -> Cell {
+        Cell {
+            character: b' ',
+            attribute: self.attribute,
+        }
+    }
+
+    /// Draw `character` in the cell at `row`, `col` in the current colours.
+    fn draw_cell(&mut self, row: usize, col: usize, character: u8) {
+        let cell = Cell {
+            character,
+            attribute: self.attribute,
+        };
+        self.put_cell(row, col, cell);
+    }
+
+    /// Draw `cell` at `row`, `col` unless it's already there.
+    fn put_cell(&mut self, row: usize, col: usize, cell: Cell) {
+        let index = row * self.columns() + col;
+        if self.cells[index] == cell {
+            return;
+        }
+        self.cells[index] = cell;
+
+        let (width, height) = (self.font.width(), self.font.height());
+        let foreground = PALETTE[(cell.attribute & 0x0F) as usize];
+        let background = PALETTE[(cell.attribute >> 4) as usize];
+        // fonts with a Unicode table are looked up by the character the byte
+        // stands for, others like the VGA font by the byte
+        let character = if self.font.has_unicode_table() {
+            cp437(cell.character)
+        } else {
+            cell.character as char
+        };
+        let glyph = self
+            .font
+            .glyph_for(character)
+            .or_else(|| self.font.glyph_for('?'));
+        for y in 0..height {
+            for x in 0..width {
+                let set = glyph.is_some_and(|glyph| self.font.is_set(glyph, x, y));
+                let color = if set { foreground } else { background };
+                self.framebuffer
+                    .set_pixel(col * width + x, row * height + y, color);
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        let row = self.rows() - 1;
+        match byte {
+            b'\n' => self.new_line(),
+            b'\x08' => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    self.draw_cell(row, self.column_position, b' ');
+                }
+            }
+            byte => {
+                if self.column_position >= self.columns() {
+                    self.new_line();
+                }
+                self.draw_cell(row, self.column_position, byte);
+                self.column_position += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        let (rows, columns) = (self.rows(), self.columns());
+        let blank = self.blank();
+        // top to bottom, so each row is read before it's overwritten
+        for row in 0..rows {
+            for col in 0..columns {
+                let below = if row + 1 < rows {
+                    self.cells[(row + 1) * columns + col]
+                } else {
+                    blank
+                };
+                self.put_cell(row, col, below);
+            }
+        }
+        self.column_position = 0;
+    }
+
+    pub fn clear(&mut self) {
+        let info = self.framebuffer.info;
+        let background = self.background();
+        self.framebuffer
+            .fill_rect(0, 0, info.width, info.height, background);
+        let blank = self.blank();
+        self.cells.fill(blank);
+        self.column_position = 0;
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            match byte {
+                0x20..=0x7e | b'\n' | b'\x08' => self.write_byte(byte),
+                _ => self.write_byte(b'@'),
+            }
+        }
+    }
+
+    pub fn write_at(&mut self, row: usize, col: usize, character: u8) {
+        if row < self.rows() && col < self.columns() {
+            self.draw_cell(row, col, character);
+        }
+    }
+
+    pub fn write_string_at(&mut self, row: usize, col: usize, s: &str) {
+        for (offset, byte) in s.bytes().enumerate() {
+            if col + offset >= self.columns() {
+                break;
+            }
+            self.write_at(row, col + offset, byte);
+        }
+    }
+
+    pub fn draw_horizontal_line(&mut self, row: usize, start_col: usize, end_col: usize) {
+        for col in start_col..end_col.min(self.columns()) {
+            self.write_at(row, col, b'-');
+        }
+    }
+
+    pub fn draw_vertical_line(&mut self, col: usize, start_row: usize, end_row: usize) {
+        for row in start_row..end_row.min(self.rows()) {
+            self.write_at(row, col, b'|');
+        }
+    }
+
+    pub fn draw_box(&mut self, start_row: usize, start_col: usize, end_row: usize, end_col: usize) {
+        self.draw_horizontal_line(start_row, start_col, end_col);
+        self.draw_horizontal_line(end_row, start_col, end_col);
+        self.draw_vertical_line(start_col, start_row, end_row);
+        self.draw_vertical_line(end_col, start_row, end_row);
+        for (row, col) in [
+            (start_row, start_col),
+            (start_row, end_col),
+            (end_row, start_col),
+            (end_row, end_col),
+        ] {
+            self.write_at(row, col, b'+');
+        }
+    }
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+/// The font of the VGA text mode, read before leaving it.
+static VGA_FONT: Once<([u8; FONT_SIZE], usize)> = Once::new();
+
+/// Whether output goes to the framebuffer console.
+pub fn is_active() -> bool {
+    interrupts::without_interrupts(|| CONSOLE.lock().is_some())
+}
+
+/// Run `f` on the console, or return `None` if it isn't in use.
+pub fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| CONSOLE.lock().as_mut().map(f))
+}
+
+fn vbe_read(index: u16) -> u16 {
+    unsafe {
+        Port::<u16>::new(VBE_INDEX).write(index);
+        Port::<u16>::new(VBE_DATA).read()
+    }
+}
+
+fn vbe_write(index: u16, value: u16) {
+    unsafe {
+        Port::<u16>::new(VBE_INDEX).write(index);
+        Port::<u16>::new(VBE_DATA).write(value);
+    }
+}
+
+/// Switch a Bochs VBE adapter to `width` x `height` with 32 bits per pixel.
+pub fn set_vbe_mode(width: usize, height: usize) -> Result<FramebufferInfo, FramebufferError> {
+    let device = VBE_DEVICES
+        .iter()
+        .find_map(|&(vendor, device)| pci::find(vendor, device))
+        .ok_or(FramebufferError::NotFound)?;
+    let Some(Bar::Memory { address, .. }) = device.bars[0] else {
+        return Err(FramebufferError::NotFound);
+    };
+    if vbe_read(VBE_ID) < VBE_ID_32BPP {
+        return Err(FramebufferError::UnsupportedFormat);
+    }
+
+    vbe_write(VBE_ENABLE, 0);
+    vbe_write(VBE_XRES, width as u16);
+    vbe_write(VBE_YRES, height as u16);
+    vbe_write(VBE_BPP, 32);
+    vbe_write(VBE_ENABLE, VBE_ENABLED | VBE_LFB_ENABLED);
+    if vbe_read(VBE_XRES) as usize != width || vbe_read(VBE_YRES) as usize != height {
+        vbe_write(VBE_ENABLE, 0);
+        return Err(FramebufferError::ModeNotSet);
+    }
+    Ok(FramebufferInfo {
+        address: PhysAddr::new(address),
+        width,
+        height,
+        stride: vbe_read(VBE_VIRT_WIDTH) as usize,
+        bytes_per_pixel: 4,
+        // little endian 0x00RRGGBB
+        format: PixelFormat::Bgr,
+    })
+}
+
+/// The PSF font at `FONT_PATH`, or else the VGA font.
+fn load_font() -> Option<Font> {
+    if let Ok(file) = initramfs::open(FONT_PATH) {
+        match Font::parse(file.contents()) {
+            Ok(font) => return Some(font),
+            Err(error) => warn!("{}: {:?}", FONT_PATH, error),
+        }
+    }
+    let (glyphs, height) = VGA_FONT.get()?;
+    Font::from_bitmaps(glyphs, 256, 8, *height, GLYPH_STRIDE).ok()
+}
+
+/// Read the VGA font while the adapter is still in text mode: it's gone once
+/// a framebuffer is drawn over it.
+fn save_vga_font() {
+    VGA_FONT.call_once(|| {
+        let mut font = [0; FONT_SIZE];
+        let height = graphics::read_text_font(&mut font).unwrap_or(16);
+        (font, height)
+    });
+}
+
+/// Move the console onto the framebuffer described by `info`, such as one
+/// set up by the bootloader. Must be called while the VGA adapter is in text
+/// mode, as its font is used if the initramfs has none.
+pub fn init_with(info: FramebufferInfo) -> Result<(), FramebufferError> {
+    save_vga_font();
+    let font = load_font().ok_or(FramebufferError::NoFont)?;
+    let framebuffer = LinearFramebuffer::map(info)?;
+    let console = Console::new(framebuffer, font);
+    let (columns, rows) = (console.columns(), console.rows());
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+    log!(
+        "Framebuffer console: {}x{}, {} columns and {} rows",
+        info.width,
+        info.height,
+        columns,
+        rows
+    );
+    Ok(())
+}
+
+/// Switch to a `DEFAULT_WIDTH` x `DEFAULT_HEIGHT` linear framebuffer and
+/// move the console onto it, staying in text mode if that fails. Requires
+/// `pci::init`.
+pub fn init() {
+    save_vga_font();
+    let result = set_vbe_mode(DEFAULT_WIDTH, DEFAULT_HEIGHT).and_then(init_with);
+    if let Err(error) = result {
+        warn!("No framebuffer console: {:?}", error);
+    }
+}
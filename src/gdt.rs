@@ -1,4 +1,7 @@
 // Global Descriptor Table
+use alloc::boxed::Box;
+use alloc::format;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
@@ -7,53 +10,157 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 use crate::log;
+use crate::{percpu, stack};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Size of each interrupt stack in pages.
+pub const IST_STACK_PAGES: u64 = 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE.try_into().unwrap();
-            stack_end
-        };
-        tss
-    };
+// The TSS is mutable so that the boot-time interrupt stacks can be replaced
+// by guarded ones once virtual memory is available (see `init_stacks`).
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Stack used for double faults until `init_stacks` runs. It has no guard page.
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+static mut BOOT_DOUBLE_FAULT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+/// Segment selectors of a GDT built by `build`.
+///
+/// All CPUs use the same layout: `syscall`/`sysret` derive the kernel and
+/// user selectors from fixed offsets, so the kernel data segment has to
+/// follow the kernel code segment and the user data segment has to come
+/// before the user code segment.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Append the kernel and user segments and the TSS descriptor to `gdt`.
+///
+/// # Safety
+///
+/// `tss` must live for the whole runtime.
+unsafe fn build(gdt: &mut GlobalDescriptorTable, tss: *const TaskStateSegment) -> Selectors {
+    Selectors {
+        kernel_code: gdt.append(Descriptor::kernel_code_segment()),
+        kernel_data: gdt.append(Descriptor::kernel_data_segment()),
+        user_data: gdt.append(Descriptor::user_data_segment()),
+        user_code: gdt.append(Descriptor::user_code_segment()),
+        tss: gdt.append(Descriptor::tss_segment_unchecked(tss)),
+    }
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        unsafe {
+            let stack_start = VirtAddr::from_ptr(&raw const BOOT_DOUBLE_FAULT_STACK);
+            let stack_end = stack_start + BOOT_STACK_SIZE as u64;
+            (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+                stack_end;
+        }
+
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        // SAFETY: `TSS` is a static, so it lives for the whole runtime.
+        let selectors = unsafe { build(&mut gdt, addr_of!(TSS)) };
+        (gdt, selectors)
     };
 }
 
 pub fn init() {
     log!("Initiating GDT");
-    use x86_64::instructions::segmentation::{Segment, CS};
+    log!("Loading GDT");
+    load(&GDT.0, &GDT.1);
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
-    log!("Loading GDT");
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
 }
+
+/// The segment selectors, which are the same on every CPU.
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// The TSS used by the bootstrap processor.
+pub fn bsp_tss() -> *mut TaskStateSegment {
+    addr_of_mut!(TSS)
+}
+
+/// GDT and TSS of an application processor. The bootstrap processor uses the
+/// statics above instead, since it needs them before the heap exists.
+struct CpuTables {
+    tss: TaskStateSegment,
+    gdt: GlobalDescriptorTable,
+}
+
+/// Create and load a GDT and TSS for the executing application processor.
+///
+/// Every CPU needs its own TSS, because the TSS holds the interrupt stacks
+/// and is marked busy while it is loaded. Returns the new TSS. Requires the
+/// heap and the global kernel memory.
+pub fn init_ap(cpu_id: usize) -> *mut TaskStateSegment {
+    let name = format!("CPU {} double fault stack", cpu_id).leak();
+    let double_fault_stack =
+        stack::allocate(name, IST_STACK_PAGES).expect("double fault stack allocation failed");
+
+    let CpuTables { tss, gdt } = Box::leak(Box::new(CpuTables {
+        tss: TaskStateSegment::new(),
+        gdt: GlobalDescriptorTable::new(),
+    }));
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top();
+    let tss: *mut TaskStateSegment = tss;
+
+    // SAFETY: the tables are leaked, so the TSS lives for the whole runtime.
+    let selectors = unsafe { build(gdt, tss) };
+    load(gdt, &selectors);
+    tss
+}
+
+/// Replace the boot-time interrupt stacks with stacks that have a guard page.
+///
+/// Requires the global kernel memory to be initialized.
+pub fn init_stacks() {
+    log!("Allocating guarded interrupt stacks");
+    let double_fault_stack = stack::allocate("double fault stack", IST_STACK_PAGES)
+        .expect("double fault stack allocation failed");
+    unsafe { set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, double_fault_stack.top()) };
+}
+
+/// Set the stack the executing CPU switches to when an interrupt arrives in
+/// user mode (`rsp0` of its TSS).
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    let tss = if percpu::is_initialized() {
+        percpu::current().tss
+    } else {
+        bsp_tss()
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*tss).privilege_stack_table[0] = stack_top;
+    });
+}
+
+/// Point the given interrupt stack table entry at a new stack top.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must make sure that no
+/// interrupt is currently running on the old stack.
+pub unsafe fn set_interrupt_stack(index: u16, stack_top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = stack_top;
+    });
+}
@@ -0,0 +1,720 @@
+// VGA graphics modes 13h and 12h
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::vga_buffer::{self, Color};
+use crate::{memory, mouse};
+
+const MISC_WRITE: u16 = 0x3C2;
+const MISC_READ: u16 = 0x3CC;
+const SEQ_INDEX: u16 = 0x3C4;
+const SEQ_DATA: u16 = 0x3C5;
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const GC_INDEX: u16 = 0x3CE;
+const GC_DATA: u16 = 0x3CF;
+/// Index and data writes alternate on the same port.
+const AC_WRITE: u16 = 0x3C0;
+const AC_READ: u16 = 0x3C1;
+/// Reading it resets the attribute controller to expect an index.
+const INPUT_STATUS: u16 = 0x3DA;
+const DAC_READ_INDEX: u16 = 0x3C7;
+const DAC_WRITE_INDEX: u16 = 0x3C8;
+const DAC_DATA: u16 = 0x3C9;
+
+const SEQ_MAP_MASK: u8 = 2;
+const SEQ_MEMORY_MODE: u8 = 4;
+const GC_READ_MAP: u8 = 4;
+const GC_MODE: u8 = 5;
+const GC_MISC: u8 = 6;
+const GC_BIT_MASK: u8 = 8;
+const CRTC_MAX_SCAN_LINE: u8 = 9;
+/// Leaves the palette alone and turns the display back on.
+const AC_ENABLE_DISPLAY: u8 = 0x20;
+
+const GRAPHICS_MEMORY: u64 = 0xA0000;
+const TEXT_MEMORY: usize = 0xb8000;
+const TEXT_SIZE: usize = 80 * 25 * 2;
+/// The text font in plane 2: 256 characters of up to 32 lines each.
+pub const FONT_SIZE: usize = 256 * 32;
+pub const GLYPH_STRIDE: usize = 32;
+
+/// Values of the misc, sequencer, CRT controller, graphics controller and
+/// attribute controller registers that make up a mode.
+#[derive(Clone, Copy)]
+struct Registers {
+    misc: u8,
+    seq: [u8; 5],
+    crtc: [u8; 25],
+    gc: [u8; 9],
+    ac: [u8; 21],
+}
+
+const MODE_13H: Registers = Registers {
+    misc: 0x63,
+    seq: [0x03, 0x01, 0x0F, 0x00, 0x0E],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x9C, 0x0E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    gc: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF],
+    ac: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F, 0x41, 0x00, 0x0F, 0x00, 0x00,
+    ],
+};
+
+/// Mode 12h with write mode 2 and the 16 colours mapped straight to the
+/// first DAC entries.
+const MODE_12H: Registers = Registers {
+    misc: 0xE3,
+    seq: [0x03, 0x01, 0x0F, 0x00, 0x06],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0x0B, 0x3E, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xEA, 0x0C, 0xDF, 0x28, 0x00, 0xE7, 0x04, 0xE3, 0xFF,
+    ],
+    gc: [0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x05, 0x0F, 0xFF],
+    ac: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F, 0x01, 0x00, 0x0F, 0x00, 0x00,
+    ],
+};
+
+/// The colours of `vga_buffer::Color`, as 6-bit DAC values.
+const EGA_PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (0, 0, 42),
+    (0, 42, 0),
+    (0, 42, 42),
+    (42, 0, 0),
+    (42, 0, 42),
+    (42, 21, 0),
+    (42, 42, 42),
+    (21, 21, 21),
+    (21, 21, 63),
+    (21, 63, 21),
+    (21, 63, 63),
+    (63, 21, 21),
+    (63, 21, 63),
+    (63, 63, 21),
+    (63, 63, 63),
+];
+
+/// The six steps of each component in the colour cube of mode 13h.
+const CUBE_LEVELS: [u8; 6] = [0, 13, 25, 38, 50, 63];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The 80x25 text mode the kernel boots in.
+    Text,
+    /// Mode 13h: 320x200, 256 colours, one byte per pixel.
+    Graphics320x200,
+    /// Mode 12h: 640x480, 16 colours in four planes.
+    Graphics640x480,
+}
+
+impl Mode {
+    pub fn size(self) -> (usize, usize) {
+        match self {
+            Mode::Text => (80, 25),
+            Mode::Graphics320x200 => (320, 200),
+            Mode::Graphics640x480 => (640, 480),
+        }
+    }
+
+    pub fn colors(self) -> usize {
+        match self {
+            Mode::Text | Mode::Graphics640x480 => 16,
+            Mode::Graphics320x200 => 256,
+        }
+    }
+}
+
+/// The text mode as it was before switching to graphics.
+struct Saved {
+    registers: Registers,
+    font: [u8; FONT_SIZE],
+    text: [u8; TEXT_SIZE],
+    palette: [u8; 256 * 3],
+}
+
+struct Vga {
+    mode: Mode,
+    /// Kept after going back to text mode, as its font is also used to draw
+    /// text on the framebuffer.
+    saved: Saved,
+    /// Lines per character of the saved font, 0 until text mode was saved.
+    font_height: usize,
+}
+
+// Statics rather than the heap, which is too small for these buffers.
+static VGA: Mutex<Vga> = Mutex::new(Vga {
+    mode: Mode::Text,
+    saved: Saved {
+        registers: MODE_13H,
+        font: [0; FONT_SIZE],
+        text: [0; TEXT_SIZE],
+        palette: [0; 256 * 3],
+    },
+    font_height: 0,
+});
+
+/// Held around pixel access and anything else that programs the graphics
+/// controller, since each mode 12h pixel takes a bit mask or read map
+/// write followed by the memory access.
+static PLANES: Mutex<()> = Mutex::new(());
+
+unsafe fn read_indexed(index_port: u16, data_port: u16, index: u8) -> u8 {
+    Port::<u8>::new(index_port).write(index);
+    Port::<u8>::new(data_port).read()
+}
+
+unsafe fn write_indexed(index_port: u16, data_port: u16, index: u8, value: u8) {
+    Port::<u8>::new(index_port).write(index);
+    Port::<u8>::new(data_port).write(value);
+}
+
+unsafe fn read_registers() -> Registers {
+    let mut registers = Registers {
+        misc: Port::<u8>::new(MISC_READ).read(),
+        seq: [0; 5],
+        crtc: [0; 25],
+        gc: [0; 9],
+        ac: [0; 21],
+    };
+    for (index, value) in registers.seq.iter_mut().enumerate() {
+        *value = read_indexed(SEQ_INDEX, SEQ_DATA, index as u8);
+    }
+    for (index, value) in registers.crtc.iter_mut().enumerate() {
+        *value = read_indexed(CRTC_INDEX, CRTC_DATA, index as u8);
+    }
+    for (index, value) in registers.gc.iter_mut().enumerate() {
+        *value = read_indexed(GC_INDEX, GC_DATA, index as u8);
+    }
+    for (index, value) in registers.ac.iter_mut().enumerate() {
+        Port::<u8>::new(INPUT_STATUS).read();
+        Port::<u8>::new(AC_WRITE).write(index as u8);
+        *value = Port::<u8>::new(AC_READ).read();
+    }
+    Port::<u8>::new(INPUT_STATUS).read();
+    Port::<u8>::new(AC_WRITE).write(AC_ENABLE_DISPLAY);
+    registers
+}
+
+unsafe fn write_registers(registers: &Registers) {
+    Port::<u8>::new(MISC_WRITE).write(registers.misc);
+    for (index, &value) in registers.seq.iter().enumerate() {
+        write_indexed(SEQ_INDEX, SEQ_DATA, index as u8, value);
+    }
+    // unlock CRTC registers 0-7 and keep them unlocked
+    let mut crtc = registers.crtc;
+    crtc[0x03] |= 0x80;
+    crtc[0x11] &= !0x80;
+    write_indexed(
+        CRTC_INDEX,
+        CRTC_DATA,
+        0x03,
+        read_indexed(CRTC_INDEX, CRTC_DATA, 0x03) | 0x80,
+    );
+    write_indexed(
+        CRTC_INDEX,
+        CRTC_DATA,
+        0x11,
+        read_indexed(CRTC_INDEX, CRTC_DATA, 0x11) & !0x80,
+    );
+    for (index, &value) in crtc.iter().enumerate() {
+        write_indexed(CRTC_INDEX, CRTC_DATA, index as u8, value);
+    }
+    for (index, &value) in registers.gc.iter().enumerate() {
+        write_indexed(GC_INDEX, GC_DATA, index as u8, value);
+    }
+    for (index, &value) in registers.ac.iter().enumerate() {
+        Port::<u8>::new(INPUT_STATUS).read();
+        Port::<u8>::new(AC_WRITE).write(index as u8);
+        Port::<u8>::new(AC_WRITE).write(value);
+    }
+    Port::<u8>::new(INPUT_STATUS).read();
+    Port::<u8>::new(AC_WRITE).write(AC_ENABLE_DISPLAY);
+}
+
+fn graphics_memory() -> *mut u8 {
+    memory::phys_to_virt(PhysAddr::new(GRAPHICS_MEMORY)).as_mut_ptr()
+}
+
+/// Run `f` with plane 2, where text mode keeps its font, mapped flat at
+/// 0xA0000.
+unsafe fn with_font_plane<R>(f: impl FnOnce(*mut u8) -> R) -> R {
+    let map_mask = read_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MAP_MASK);
+    let memory_mode = read_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MEMORY_MODE);
+    let read_map = read_indexed(GC_INDEX, GC_DATA, GC_READ_MAP);
+    let gc_mode = read_indexed(GC_INDEX, GC_DATA, GC_MODE);
+    let gc_misc = read_indexed(GC_INDEX, GC_DATA, GC_MISC);
+
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MAP_MASK, 1 << 2);
+    // sequential addressing, no odd/even or chain-4
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MEMORY_MODE, 0x06);
+    write_indexed(GC_INDEX, GC_DATA, GC_READ_MAP, 2);
+    write_indexed(GC_INDEX, GC_DATA, GC_MODE, 0x00);
+    // 64 KiB at 0xA0000
+    write_indexed(GC_INDEX, GC_DATA, GC_MISC, 0x04);
+
+    let result = f(graphics_memory());
+
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MAP_MASK, map_mask);
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MEMORY_MODE, memory_mode);
+    write_indexed(GC_INDEX, GC_DATA, GC_READ_MAP, read_map);
+    write_indexed(GC_INDEX, GC_DATA, GC_MODE, gc_mode);
+    write_indexed(GC_INDEX, GC_DATA, GC_MISC, gc_misc);
+    result
+}
+
+unsafe fn read_palette(palette: &mut [u8; 256 * 3]) {
+    Port::<u8>::new(DAC_READ_INDEX).write(0);
+    for value in palette.iter_mut() {
+        *value = Port::<u8>::new(DAC_DATA).read();
+    }
+}
+
+unsafe fn write_palette(first: u8, colors: &[u8]) {
+    Port::<u8>::new(DAC_WRITE_INDEX).write(first);
+    for &value in colors {
+        Port::<u8>::new(DAC_DATA).write(value);
+    }
+}
+
+unsafe fn read_font(font: &mut [u8; FONT_SIZE]) {
+    with_font_plane(|plane| {
+        for (offset, byte) in font.iter_mut().enumerate() {
+            *byte = plane.add(offset).read_volatile();
+        }
+    });
+}
+
+/// Lines per character of the text mode font, from the maximum scan line
+/// register.
+fn font_height(max_scan_line: u8) -> usize {
+    (max_scan_line & 0x1F) as usize + 1
+}
+
+/// Remember the text mode so `set_mode(Mode::Text)` can bring it back.
+unsafe fn save_text_mode(saved: &mut Saved) {
+    saved.registers = read_registers();
+    read_font(&mut saved.font);
+    let text = TEXT_MEMORY as *const u8;
+    for (offset, byte) in saved.text.iter_mut().enumerate() {
+        *byte = text.add(offset).read_volatile();
+    }
+    read_palette(&mut saved.palette);
+}
+
+unsafe fn restore_text_mode(saved: &Saved) {
+    write_registers(&saved.registers);
+    with_font_plane(|plane| {
+        for (offset, &byte) in saved.font.iter().enumerate() {
+            plane.add(offset).write_volatile(byte);
+        }
+    });
+    let text = TEXT_MEMORY as *mut u8;
+    for (offset, &byte) in saved.text.iter().enumerate() {
+        text.add(offset).write_volatile(byte);
+    }
+    write_palette(0, &saved.palette);
+}
+
+/// The default palette of the graphics modes: the 16 text colours, 16
+/// greys and a 6x6x6 colour cube.
+unsafe fn write_default_palette() {
+    for (index, &(red, green, blue)) in EGA_PALETTE.iter().enumerate() {
+        write_palette(index as u8, &[red, green, blue]);
+    }
+    for grey in 0..16u8 {
+        let level = grey * 4 + grey / 4;
+        write_palette(16 + grey, &[level, level, level]);
+    }
+    for index in 0..216u8 {
+        let (red, green, blue) = (index / 36, index / 6 % 6, index % 6);
+        write_palette(
+            32 + index,
+            &[
+                CUBE_LEVELS[red as usize],
+                CUBE_LEVELS[green as usize],
+                CUBE_LEVELS[blue as usize],
+            ],
+        );
+    }
+}
+
+pub fn mode() -> Mode {
+    VGA.lock().mode
+}
+
+/// Switch the display to `mode`.
+///
+/// The text screen, its font and palette are saved when leaving text mode
+/// and restored when coming back to it. Text printed in between is lost.
+/// Must be called after `init_memory`.
+pub fn set_mode(mode: Mode) {
+    #[cfg(feature = "framebuffer")]
+    if mode != Mode::Text && crate::framebuffer::is_active() {
+        crate::warn!("VGA graphics modes aren't available on the framebuffer console");
+        return;
+    }
+    let mut vga = VGA.lock();
+    if vga.mode == mode {
+        return;
+    }
+    let planes = PLANES.lock();
+    // keep the mouse cursor off the screen memory while it is being switched
+    interrupts::without_interrupts(|| unsafe {
+        if vga.mode == Mode::Text {
+            // the snapshot shouldn't contain the mouse cursor
+            vga_buffer::hide_mouse_cursor();
+            save_text_mode(&mut vga.saved);
+            vga.font_height = font_height(vga.saved.registers.crtc[CRTC_MAX_SCAN_LINE as usize]);
+        }
+        match mode {
+            Mode::Text => {
+                restore_text_mode(&vga.saved);
+                // the mouse may have moved while the cursor was invisible
+                if mouse::is_present() {
+                    let (col, row) = mouse::position();
+                    vga_buffer::show_mouse_cursor(row, col);
+                }
+            }
+            Mode::Graphics320x200 => write_registers(&MODE_13H),
+            Mode::Graphics640x480 => write_registers(&MODE_12H),
+        }
+        if mode != Mode::Text {
+            write_default_palette();
+        }
+    });
+    vga.mode = mode;
+    drop(planes);
+    drop(vga);
+    if mode != Mode::Text {
+        framebuffer().unwrap().clear(Color::Black as u8);
+    }
+}
+
+/// Copy the font of the text mode into `font`, `GLYPH_STRIDE` bytes per
+/// character, and return its height. Returns `None` outside of text mode.
+pub fn read_text_font(font: &mut [u8; FONT_SIZE]) -> Option<usize> {
+    let vga = VGA.lock();
+    if vga.mode != Mode::Text {
+        return None;
+    }
+    let _planes = PLANES.lock();
+    interrupts::without_interrupts(|| unsafe {
+        read_font(font);
+        Some(font_height(read_indexed(
+            CRTC_INDEX,
+            CRTC_DATA,
+            CRTC_MAX_SCAN_LINE,
+        )))
+    })
+}
+
+/// The screen in the current graphics mode, or `None` in text mode.
+pub fn framebuffer() -> Option<Framebuffer> {
+    let mode = mode();
+    let (width, height) = mode.size();
+    (mode != Mode::Text).then(|| Framebuffer {
+        mode,
+        width,
+        height,
+        memory: graphics_memory(),
+    })
+}
+
+/// Set palette entry `index` to a colour with 8-bit components.
+///
+/// In 16 colour modes only the first 16 entries are used.
+pub fn set_palette(index: u8, red: u8, green: u8, blue: u8) {
+    unsafe { write_palette(index, &[red >> 2, green >> 2, blue >> 2]) }
+}
+
+/// The 8-bit components of palette entry `index`.
+pub fn palette(index: u8) -> (u8, u8, u8) {
+    let mut data = Port::<u8>::new(DAC_DATA);
+    unsafe {
+        Port::<u8>::new(DAC_READ_INDEX).write(index);
+        let (red, green, blue) = (data.read(), data.read(), data.read());
+        (
+            red << 2 | red >> 4,
+            green << 2 | green >> 4,
+            blue << 2 | blue >> 4,
+        )
+    }
+}
+
+/// Drawing on the screen in a graphics mode. Colours are palette indices;
+/// the first 16 match `vga_buffer::Color`.
+///
+/// Drawing outside the screen is clipped.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    mode: Mode,
+    width: usize,
+    height: usize,
+    memory: *mut u8,
+}
+
+impl Framebuffer {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_pixel(&self, x: usize, y: usize, color: u8) {
+        let _planes = PLANES.lock();
+        self.put_pixel(x, y, color);
+    }
+
+    /// `set_pixel` for callers holding `PLANES`.
+    fn put_pixel(&self, x: usize, y: usize, color: u8) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        unsafe {
+            match self.mode {
+                Mode::Graphics320x200 => self.memory.add(y * self.width + x).write_volatile(color),
+                _ => {
+                    // write mode 2: the latches fill in the other pixels of
+                    // the byte and the bit mask picks this one
+                    let byte = self.memory.add((y * self.width + x) / 8);
+                    write_indexed(GC_INDEX, GC_DATA, GC_BIT_MASK, 0x80 >> (x % 8));
+                    byte.read_volatile();
+                    byte.write_volatile(color & 0x0F);
+                }
+            }
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u8> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let _planes = PLANES.lock();
+        unsafe {
+            match self.mode {
+                Mode::Graphics320x200 => Some(self.memory.add(y * self.width + x).read_volatile()),
+                _ => {
+                    let byte = self.memory.add((y * self.width + x) / 8);
+                    let mut color = 0;
+                    for plane in 0..4 {
+                        write_indexed(GC_INDEX, GC_DATA, GC_READ_MAP, plane);
+                        if byte.read_volatile() & (0x80 >> (x % 8)) != 0 {
+                            color |= 1 << plane;
+                        }
+                    }
+                    Some(color)
+                }
+            }
+        }
+    }
+
+    pub fn clear(&self, color: u8) {
+        let _planes = PLANES.lock();
+        unsafe {
+            match self.mode {
+                Mode::Graphics320x200 => {
+                    for offset in 0..self.width * self.height {
+                        self.memory.add(offset).write_volatile(color);
+                    }
+                }
+                _ => {
+                    write_indexed(GC_INDEX, GC_DATA, GC_BIT_MASK, 0xFF);
+                    for offset in 0..self.width * self.height / 8 {
+                        self.memory.add(offset).write_volatile(color & 0x0F);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        let right = x.saturating_add(width).min(self.width);
+        let bottom = y.saturating_add(height).min(self.height);
+        let _planes = PLANES.lock();
+        for row in y..bottom {
+            for col in x..right {
+                self.put_pixel(col, row, color);
+            }
+        }
+    }
+
+    /// The outline of a rectangle.
+    pub fn rect(&self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.fill_rect(x, y, width, 1, color);
+        self.fill_rect(x, y.saturating_add(height - 1), width, 1, color);
+        self.fill_rect(x, y, 1, height, color);
+        self.fill_rect(x.saturating_add(width - 1), y, 1, height, color);
+    }
+
+    /// A line from `(x0, y0)` to `(x1, y1)`, both ends included.
+    pub fn line(&self, x0: isize, y0: isize, x1: isize, y1: isize, color: u8) {
+        let Some(((x0, y0), (x1, y1))) =
+            self.clip_line((x0 as i128, y0 as i128), (x1 as i128, y1 as i128))
+        else {
+            return;
+        };
+        // Bresenham's algorithm, on ends that are now on the screen
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        let _planes = PLANES.lock();
+        loop {
+            self.put_pixel(x as usize, y as usize, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// The part of a line inside the screen, found with the Cohen-Sutherland
+    /// algorithm, or `None` if the line misses the screen.
+    fn clip_line(
+        &self,
+        mut start: (i128, i128),
+        mut end: (i128, i128),
+    ) -> Option<((i128, i128), (i128, i128))> {
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+        const TOP: u8 = 4;
+        const BOTTOM: u8 = 8;
+        let (right, bottom) = (self.width as i128 - 1, self.height as i128 - 1);
+        let outcode = |(x, y): (i128, i128)| {
+            let mut code = 0;
+            if x < 0 {
+                code |= LEFT;
+            } else if x > right {
+                code |= RIGHT;
+            }
+            if y < 0 {
+                code |= TOP;
+            } else if y > bottom {
+                code |= BOTTOM;
+            }
+            code
+        };
+        // each end is moved at most twice before the check that accepts them;
+        // rounding at a corner can ask for more, and then the line only grazes it
+        for _ in 0..5 {
+            let (start_code, end_code) = (outcode(start), outcode(end));
+            if start_code | end_code == 0 {
+                return Some((start, end));
+            }
+            if start_code & end_code != 0 {
+                return None;
+            }
+            let code = if start_code != 0 {
+                start_code
+            } else {
+                end_code
+            };
+            let ((x0, y0), (x1, y1)) = (start, end);
+            let point = if code & (LEFT | RIGHT) != 0 {
+                let x = if code & LEFT != 0 { 0 } else { right };
+                (x, y0 + scale(y1 - y0, x - x0, x1 - x0))
+            } else {
+                let y = if code & TOP != 0 { 0 } else { bottom };
+                (x0 + scale(x1 - x0, y - y0, y1 - y0), y)
+            };
+            if code == start_code {
+                start = point;
+            } else {
+                end = point;
+            }
+        }
+        None
+    }
+
+    /// Copy an image `width` pixels wide to `(x, y)`, leaving out pixels of
+    /// the `transparent` colour.
+    pub fn blit(&self, x: usize, y: usize, width: usize, pixels: &[u8], transparent: Option<u8>) {
+        if width == 0 {
+            return;
+        }
+        let _planes = PLANES.lock();
+        for (row, line) in pixels.chunks(width).enumerate() {
+            for (col, &color) in line.iter().enumerate() {
+                if Some(color) != transparent {
+                    self.put_pixel(x.saturating_add(col), y.saturating_add(row), color);
+                }
+            }
+        }
+    }
+
+    /// Width and height of a character drawn by `draw_char`, once the font
+    /// is known. It is read from the VGA when leaving text mode.
+    pub fn char_size(&self) -> Option<(usize, usize)> {
+        let height = VGA.lock().font_height;
+        (height != 0).then_some((8, height))
+    }
+
+    /// Draw `character` with its top left corner at `(x, y)`, with the
+    /// background left alone if `background` is `None`.
+    pub fn draw_char(&self, x: usize, y: usize, character: u8, color: u8, background: Option<u8>) {
+        let vga = VGA.lock();
+        let glyph = &vga.saved.font[character as usize * GLYPH_STRIDE..][..vga.font_height];
+        let _planes = PLANES.lock();
+        for (row, &bits) in glyph.iter().enumerate() {
+            let y = y.saturating_add(row);
+            for col in 0..8 {
+                let x = x.saturating_add(col);
+                if bits & (0x80 >> col) != 0 {
+                    self.put_pixel(x, y, color);
+                } else if let Some(background) = background {
+                    self.put_pixel(x, y, background);
+                }
+            }
+        }
+    }
+
+    /// Draw `text` on one line starting at `(x, y)`.
+    pub fn draw_text(&self, x: usize, y: usize, text: &str, color: u8, background: Option<u8>) {
+        let Some((width, _)) = self.char_size() else {
+            return;
+        };
+        for (index, byte) in text.bytes().enumerate() {
+            let x = x.saturating_add(index * width);
+            if x >= self.width {
+                break;
+            }
+            self.draw_char(x, y, byte, color, background);
+        }
+    }
+}
+
+/// `a * b / c` rounded to the nearest integer, for `|b| <= |c|`. Works on the
+/// magnitudes in `u128`, where the product of two `isize` distances fits.
+fn scale(a: i128, b: i128, c: i128) -> i128 {
+    let divisor = c.unsigned_abs();
+    let magnitude = (a.unsigned_abs() * b.unsigned_abs() + divisor / 2) / divisor;
+    if (a < 0) != ((b < 0) != (c < 0)) {
+        -(magnitude as i128)
+    } else {
+        magnitude as i128
+    }
+}
@@ -0,0 +1,87 @@
+// High Precision Event Timer
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+use crate::acpi::{self, GenericAddress};
+use crate::{log, memory, warn};
+
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const ENABLE_CNF: u64 = 1;
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Length of one counter tick in femtoseconds.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: u64) -> u64 {
+    let ptr = (BASE.load(Ordering::Relaxed) + reg) as *const u64;
+    unsafe { ptr.read_volatile() }
+}
+
+fn write(reg: u64, value: u64) {
+    let ptr = (BASE.load(Ordering::Relaxed) + reg) as *mut u64;
+    unsafe { ptr.write_volatile(value) }
+}
+
+/// Returns whether an HPET was found and started.
+pub fn is_available() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// Locate the HPET through the ACPI HPET table and start its main counter.
+///
+/// Requires `acpi::init` to have run. Returns `false` if there is no usable HPET.
+pub fn init() -> bool {
+    let hpet = match acpi::info().and_then(|info| info.hpet) {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+    if hpet.base_address.address_space != GenericAddress::SYSTEM_MEMORY {
+        warn!("HPET is not memory mapped");
+        return false;
+    }
+
+    log!("Initiating HPET");
+    let base = memory::map_mmio(PhysAddr::new(hpet.base_address.address), 1024)
+        .expect("failed to map HPET");
+    BASE.store(base.as_u64(), Ordering::SeqCst);
+
+    let period = read(GENERAL_CAPABILITIES) >> 32;
+    // the specification limits the period to 100 ns
+    if period == 0 || period > 100_000_000 {
+        warn!("HPET reports an invalid period of {} fs", period);
+        return false;
+    }
+
+    // start the main counter without legacy replacement routing
+    write(GENERAL_CONFIGURATION, read(GENERAL_CONFIGURATION) | ENABLE_CNF);
+    PERIOD_FS.store(period, Ordering::SeqCst);
+    log!("HPET running at {} kHz", 1_000_000_000_000 / period);
+    true
+}
+
+/// The current value of the main counter.
+pub fn counter() -> u64 {
+    read(MAIN_COUNTER)
+}
+
+/// Length of one counter tick in femtoseconds, or 0 if the HPET isn't running.
+pub fn period_fs() -> u64 {
+    PERIOD_FS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since the HPET was started.
+pub fn now_ns() -> u64 {
+    (counter() as u128 * period_fs() as u128 / 1_000_000) as u64
+}
+
+/// Busy-wait for `micros` microseconds. The HPET must be running.
+pub fn wait_micros(micros: u64) {
+    let ticks = micros as u128 * 1_000_000_000 / period_fs() as u128;
+    let start = counter();
+    while ((counter().wrapping_sub(start)) as u128) < ticks {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,267 @@
+// Read-only file system backed by a USTAR archive embedded in the kernel
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The archive `build.rs` packs from the `initramfs` directory.
+static IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// The archive is damaged.
+    Corrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub kind: FileKind,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileKind,
+}
+
+/// One member of the archive.
+struct Entry {
+    /// Path without leading or trailing slashes.
+    path: String,
+    kind: FileKind,
+    data: &'static [u8],
+}
+
+/// A USTAR archive.
+pub struct Archive {
+    entries: Vec<Entry>,
+}
+
+/// An open file. Reading starts at the beginning and advances.
+pub struct File {
+    data: &'static [u8],
+    position: usize,
+}
+
+impl File {
+    /// Read up to `buf.len()` bytes and return how many were read, 0 at the end.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let remaining = &self.data[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        len
+    }
+
+    /// The whole contents of the file.
+    pub fn contents(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+/// Parse an octal field, which may be padded with spaces and NUL terminated.
+fn octal(field: &[u8]) -> Option<usize> {
+    let mut digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+    digits.try_fold(0usize, |value, &digit| match digit {
+        b'0'..=b'7' => value.checked_mul(8)?.checked_add((digit - b'0') as usize),
+        _ => None,
+    })
+}
+
+fn field_str(field: &[u8]) -> Result<&str, Error> {
+    let len = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| Error::Corrupt)
+}
+
+/// Strip the leading slash and `.` components from a path.
+fn normalize(path: &str) -> String {
+    let components = path.split('/').filter(|c| !c.is_empty() && *c != ".");
+    components.collect::<Vec<_>>().join("/")
+}
+
+impl Archive {
+    /// Parse the archive in `image`.
+    pub fn new(image: &'static [u8]) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= image.len() {
+            let header = &image[offset..offset + BLOCK_SIZE];
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" {
+                return Err(Error::Corrupt);
+            }
+            let stored_checksum = octal(&header[148..156]).ok_or(Error::Corrupt)?;
+            let checksum: usize = header
+                .iter()
+                .enumerate()
+                .map(|(i, &byte)| if (148..156).contains(&i) { b' ' } else { byte } as usize)
+                .sum();
+            if checksum != stored_checksum {
+                return Err(Error::Corrupt);
+            }
+
+            let size = octal(&header[124..136]).ok_or(Error::Corrupt)?;
+            let data_start = offset + BLOCK_SIZE;
+            let data = image
+                .get(data_start..data_start + size)
+                .ok_or(Error::Corrupt)?;
+
+            let name = field_str(&header[0..100])?;
+            let prefix = field_str(&header[345..500])?;
+            let path = if prefix.is_empty() {
+                normalize(name)
+            } else {
+                normalize(&alloc::format!("{}/{}", prefix, name))
+            };
+            let kind = match header[156] {
+                b'0' | 0 => Some(FileKind::File),
+                b'5' => Some(FileKind::Directory),
+                // links, devices and the like aren't supported
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                entries.push(Entry { path, kind, data });
+            }
+
+            offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        }
+        Ok(Archive { entries })
+    }
+
+    fn is_directory(&self, path: &str) -> bool {
+        // directories don't need an entry of their own, files below them are enough
+        path.is_empty()
+            || self.entries.iter().any(|entry| {
+                (entry.kind == FileKind::Directory && entry.path == path)
+                    || entry
+                        .path
+                        .strip_prefix(path)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    fn find_file(&self, path: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.kind == FileKind::File && entry.path == path)
+    }
+
+    /// Open the file at `path`.
+    pub fn open(&self, path: &str) -> Result<File, Error> {
+        let path = normalize(path);
+        match self.find_file(&path) {
+            Some(entry) => Ok(File {
+                data: entry.data,
+                position: 0,
+            }),
+            None if self.is_directory(&path) => Err(Error::IsADirectory),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Returns the type and size of the file or directory at `path`.
+    pub fn stat(&self, path: &str) -> Result<Stat, Error> {
+        let path = normalize(path);
+        if let Some(entry) = self.find_file(&path) {
+            return Ok(Stat {
+                kind: FileKind::File,
+                size: entry.data.len(),
+            });
+        }
+        if self.is_directory(&path) {
+            return Ok(Stat {
+                kind: FileKind::Directory,
+                size: 0,
+            });
+        }
+        Err(Error::NotFound)
+    }
+
+    /// List the directory at `path`, sorted by name.
+    pub fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        let path = normalize(path);
+        if self.find_file(&path).is_some() {
+            return Err(Error::NotADirectory);
+        }
+        if !self.is_directory(&path) {
+            return Err(Error::NotFound);
+        }
+
+        let mut children: Vec<DirEntry> = Vec::new();
+        for entry in &self.entries {
+            let rest = if path.is_empty() {
+                entry.path.as_str()
+            } else {
+                match entry
+                    .path
+                    .strip_prefix(path.as_str())
+                    .and_then(|r| r.strip_prefix('/'))
+                {
+                    Some(rest) => rest,
+                    None => continue,
+                }
+            };
+            let (name, kind) = match rest.split_once('/') {
+                Some((directory, _)) => (directory, FileKind::Directory),
+                None => (rest, entry.kind),
+            };
+            if !name.is_empty() && !children.iter().any(|child| child.name == name) {
+                children.push(DirEntry {
+                    name: String::from(name),
+                    kind,
+                });
+            }
+        }
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(children)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref ARCHIVE: Archive = Archive::new(IMAGE).expect("initramfs is corrupt");
+}
+
+/// The embedded initramfs.
+pub fn archive() -> &'static Archive {
+    &ARCHIVE
+}
+
+/// Open `path` in the embedded initramfs.
+pub fn open(path: &str) -> Result<File, Error> {
+    ARCHIVE.open(path)
+}
+
+/// Returns the type and size of `path` in the embedded initramfs.
+pub fn stat(path: &str) -> Result<Stat, Error> {
+    ARCHIVE.stat(path)
+}
+
+/// List the directory `path` of the embedded initramfs.
+pub fn readdir(path: &str) -> Result<Vec<DirEntry>, Error> {
+    ARCHIVE.readdir(path)
+}
+
+/// Returns the contents of `path` as text, or `None` if it doesn't exist or
+/// isn't valid UTF-8.
+pub fn read_to_str(path: &str) -> Option<&'static str> {
+    let file = open(path).ok()?;
+    core::str::from_utf8(file.contents()).ok()
+}
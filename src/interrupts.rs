@@ -1,31 +1,62 @@
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
 use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+use crate::acpi;
+use crate::apic;
+use crate::ata;
 use crate::gdt;
+use crate::keyboard;
 use crate::log;
-use crate::print;
+use crate::mouse;
+use crate::pci;
 use crate::println;
+use crate::region;
+use crate::serial;
+use crate::stack;
+use crate::task;
+use crate::time;
+use crate::usermode;
 use pic8259::ChainedPics;
 
 use x86_64::structures::idt::PageFaultErrorCode;
-use crate::hlt_loop;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
-    Mouse,
+    Serial = PIC_1_OFFSET + 4,
+    /// ISA IRQs 5, 9, 10 and 11, which the firmware hands out to PCI devices.
+    Pci5,
+    Pci9 = PIC_2_OFFSET + 1,
+    Pci10,
+    Pci11,
+    Mouse = PIC_2_OFFSET + 4,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
+
+    /// The vector for PCI devices whose interrupt line is ISA IRQ `irq`.
+    pub fn for_pci_irq(irq: u8) -> Option<InterruptIndex> {
+        match irq {
+            5 => Some(InterruptIndex::Pci5),
+            9 => Some(InterruptIndex::Pci9),
+            10 => Some(InterruptIndex::Pci10),
+            11 => Some(InterruptIndex::Pci11),
+            _ => None,
+        }
+    }
 }
 
+/// Vector the local APIC uses for spurious interrupts. These need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
@@ -46,18 +77,21 @@ lazy_static! {
         }
 
         idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Serial.as_u8()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_u8()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_u8()].set_handler_fn(secondary_ata_interrupt_handler);
+        idt[InterruptIndex::Pci5.as_u8()].set_handler_fn(pci5_interrupt_handler);
+        idt[InterruptIndex::Pci9.as_u8()].set_handler_fn(pci9_interrupt_handler);
+        idt[InterruptIndex::Pci10.as_u8()].set_handler_fn(pci10_interrupt_handler);
+        idt[InterruptIndex::Pci11.as_u8()].set_handler_fn(pci11_interrupt_handler);
+        idt[SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
 
         idt
     };
-
-    // Make the KEYBOARD variable public so it can be accessed outside the module.
-    pub static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(
-            ScancodeSet1::new(),
-            layouts::Us104Key,
-            HandleControl::Ignore
-        ));
 }
 
 pub fn init_idt() {
@@ -65,6 +99,56 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Signal the end of an interrupt to whichever interrupt controller is active.
+pub fn notify_end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
+/// Deliver ISA `irq` to the handler of `index`, through the I/O APIC if it's
+/// in use and by unmasking it on the PIC otherwise.
+pub fn enable_isa_irq(irq: u8, index: InterruptIndex) {
+    if apic::is_enabled() {
+        if let Some(madt) = acpi::info().and_then(|info| info.madt.as_ref()) {
+            apic::route_isa_irq(madt, irq, index.as_u8());
+        }
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = pics.read_masks();
+        if irq < 8 {
+            master &= !(1 << irq);
+        } else {
+            slave &= !(1 << (irq - 8));
+            // the slave PIC is cascaded through IRQ 2
+            master &= !(1 << 2);
+        }
+        pics.write_masks(master, slave);
+    });
+}
+
+/// Hand a typed character to the console input buffer.
+pub(crate) fn push_input(character: char) {
+    if BUFFER.is_locked() {
+        unsafe {
+            BUFFER.force_unlock();
+        }
+    }
+
+    *BUFFER.lock() = character;
+}
+
+/// Returns whether the interrupted code was running in ring 3.
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -73,68 +157,128 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // A page fault that can't be delivered because the stack itself overflowed
+    // ends up here, with CR2 still pointing into the guard page.
+    if let Some(name) = Cr2::read().ok().and_then(stack::guard_hit) {
+        panic!("EXCEPTION: DOUBLE FAULT\nstack overflow in {}\n{:#?}", name, stack_frame);
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    time::tick();
+    task::tick();
+    notify_end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                pc_keyboard::DecodedKey::Unicode(character) => {
-                    if BUFFER.is_locked() {
-                        unsafe {
-                            BUFFER.force_unlock();
-                        }
-                    }
-
-                    *BUFFER.lock() = character;
-                }
-                pc_keyboard::DecodedKey::RawKey(_key) => {
-                    // print!("{:?}", key);
-                }
-            }
+    keyboard::handle_interrupt();
+    notify_end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    mouse::handle_interrupt();
+    notify_end_of_interrupt(InterruptIndex::Mouse);
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // input typed into the serial console is treated like keyboard input
+    if let Ok(byte) = serial::SERIAL1.lock().try_receive() {
+        match byte {
+            b'\r' => push_input('\n'),
+            0x7f => push_input('\x08'),
+            byte => push_input(byte as char),
         }
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::Serial);
 }
 
-extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!("k");
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    ata::handle_interrupt(0);
+    notify_end_of_interrupt(InterruptIndex::PrimaryAta);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
-    }
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    ata::handle_interrupt(1);
+    notify_end_of_interrupt(InterruptIndex::SecondaryAta);
+}
+
+extern "x86-interrupt" fn pci5_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    pci::handle_interrupt(5);
+    notify_end_of_interrupt(InterruptIndex::Pci5);
 }
 
+extern "x86-interrupt" fn pci9_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    pci::handle_interrupt(9);
+    notify_end_of_interrupt(InterruptIndex::Pci9);
+}
+
+extern "x86-interrupt" fn pci10_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    pci::handle_interrupt(10);
+    notify_end_of_interrupt(InterruptIndex::Pci10);
+}
+
+extern "x86-interrupt" fn pci11_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    pci::handle_interrupt(11);
+    notify_end_of_interrupt(InterruptIndex::Pci11);
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    if let Ok(addr) = Cr2::read() {
+        if region::handle_page_fault(addr, error_code) {
+            return;
+        }
+        if from_user_mode(&stack_frame) {
+            unsafe { usermode::kill("page fault") }
+        }
+        if let Some(name) = stack::guard_hit(addr) {
+            panic!("EXCEPTION: PAGE FAULT\nstack overflow in {}\n{:#?}", name, stack_frame);
+        }
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
+        Cr2::read(),
+        error_code,
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    if from_user_mode(&stack_frame) {
+        unsafe { usermode::kill("divide error") }
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    if from_user_mode(&stack_frame) {
+        unsafe { usermode::kill("invalid opcode") }
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if from_user_mode(&stack_frame) {
+        unsafe { usermode::kill("general protection fault") }
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
 }
 
 #[test_case]
@@ -0,0 +1,358 @@
+// PS/2 keyboard: layouts, key events and lock LEDs
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pc_keyboard::layouts::{self, AnyLayout};
+use pc_keyboard::{
+    DecodedKey, HandleControl, KeyState, Keyboard, KeyboardLayout, ScancodeSet, ScancodeSet1,
+    ScancodeSet2,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::push_input;
+use crate::ps2::{self, ScancodeMode};
+
+pub use pc_keyboard::KeyCode;
+
+const SET_LEDS: u8 = 0xED;
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Events kept until they are polled. Older ones are dropped.
+const QUEUE_SIZE: usize = 32;
+
+/// The keyboard layouts `pc-keyboard` knows about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    German,
+    French,
+    Norwegian,
+    FinnishSwedish,
+    Japanese,
+    Dvorak,
+    DvorakProgrammer,
+    Colemak,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 10] = [
+        Layout::Us,
+        Layout::Uk,
+        Layout::German,
+        Layout::French,
+        Layout::Norwegian,
+        Layout::FinnishSwedish,
+        Layout::Japanese,
+        Layout::Dvorak,
+        Layout::DvorakProgrammer,
+        Layout::Colemak,
+    ];
+
+    /// Short name, as in `from_name`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::German => "de",
+            Layout::French => "fr",
+            Layout::Norwegian => "no",
+            Layout::FinnishSwedish => "fi",
+            Layout::Japanese => "jp",
+            Layout::Dvorak => "dvorak",
+            Layout::DvorakProgrammer => "dvp",
+            Layout::Colemak => "colemak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    /// The layout after this one in `ALL`, wrapping around.
+    pub fn next(self) -> Layout {
+        Layout::ALL[(self.index() + 1) % Layout::ALL.len()]
+    }
+
+    fn index(self) -> usize {
+        Layout::ALL
+            .iter()
+            .position(|&layout| layout == self)
+            .unwrap()
+    }
+
+    fn decoder(self) -> AnyLayout {
+        match self {
+            Layout::Us => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::German => AnyLayout::De105Key(layouts::De105Key),
+            Layout::French => AnyLayout::Azerty(layouts::Azerty),
+            Layout::Norwegian => AnyLayout::No105Key(layouts::No105Key),
+            Layout::FinnishSwedish => AnyLayout::FiSe105Key(layouts::FiSe105Key),
+            Layout::Japanese => AnyLayout::Jis109Key(layouts::Jis109Key),
+            Layout::Dvorak => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Layout::DvorakProgrammer => AnyLayout::DVP104Key(layouts::DVP104Key),
+            Layout::Colemak => AnyLayout::Colemak(layouts::Colemak),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// A key going down or up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,
+    /// What the key types in the current layout, for presses only.
+    pub character: Option<char>,
+    /// Modifiers after the event.
+    pub modifiers: Modifiers,
+}
+
+/// Index into `Layout::ALL` of the layout keys are mapped with.
+static LAYOUT: AtomicUsize = AtomicUsize::new(0);
+
+/// Maps keys with whichever layout is selected, so switching layouts
+/// keeps the modifier state of the decoder.
+struct CurrentLayout;
+
+impl KeyboardLayout for CurrentLayout {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &pc_keyboard::Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        layout()
+            .decoder()
+            .map_keycode(keycode, modifiers, handle_ctrl)
+    }
+}
+
+/// Decodes whichever scancode set the keyboard was set up for.
+enum Scancodes {
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2),
+}
+
+impl Scancodes {
+    const fn new(mode: ScancodeMode) -> Self {
+        match mode {
+            ScancodeMode::Translated => Scancodes::Set1(ScancodeSet1::new()),
+            ScancodeMode::Set2 => Scancodes::Set2(ScancodeSet2::new()),
+        }
+    }
+}
+
+impl ScancodeSet for Scancodes {
+    fn advance_state(
+        &mut self,
+        code: u8,
+    ) -> Result<Option<pc_keyboard::KeyEvent>, pc_keyboard::Error> {
+        match self {
+            Scancodes::Set1(set) => set.advance_state(code),
+            Scancodes::Set2(set) => set.advance_state(code),
+        }
+    }
+}
+
+struct State {
+    keyboard: Keyboard<CurrentLayout, Scancodes>,
+    scroll_lock: bool,
+    /// One bit per `KeyCode` that is held down.
+    held: [u64; 4],
+    /// LEDs to send once the keyboard acknowledges `SET_LEDS`.
+    pending_leds: Option<u8>,
+    events: [Option<KeyEvent>; QUEUE_SIZE],
+    /// Index of the oldest event.
+    head: usize,
+    queued: usize,
+}
+
+impl State {
+    fn modifiers(&self) -> Modifiers {
+        let modifiers = self.keyboard.get_modifiers();
+        Modifiers {
+            shift: modifiers.lshift || modifiers.rshift,
+            ctrl: modifiers.lctrl || modifiers.rctrl,
+            alt: modifiers.lalt,
+            alt_gr: modifiers.ralt,
+            caps_lock: modifiers.capslock,
+            num_lock: modifiers.numlock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+
+    fn set_held(&mut self, code: KeyCode, held: bool) {
+        let (word, bit) = (code as usize / 64, code as usize % 64);
+        if held {
+            self.held[word] |= 1 << bit;
+        } else {
+            self.held[word] &= !(1 << bit);
+        }
+    }
+
+    fn is_held(&self, code: KeyCode) -> bool {
+        self.held[code as usize / 64] & (1 << (code as usize % 64)) != 0
+    }
+
+    /// Ask the keyboard to show the lock states. The LED byte itself is
+    /// sent when the keyboard acknowledges the command.
+    fn update_leds(&mut self) {
+        let modifiers = self.modifiers();
+        let mut leds = 0;
+        if modifiers.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if modifiers.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if modifiers.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        if ps2::write_data(SET_LEDS).is_ok() {
+            self.pending_leds = Some(leds);
+        }
+    }
+
+    fn push(&mut self, event: KeyEvent) {
+        if self.queued == QUEUE_SIZE {
+            self.head = (self.head + 1) % QUEUE_SIZE;
+            self.queued -= 1;
+        }
+        self.events[(self.head + self.queued) % QUEUE_SIZE] = Some(event);
+        self.queued += 1;
+    }
+
+    fn pop(&mut self) -> Option<KeyEvent> {
+        if self.queued == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.queued -= 1;
+        event
+    }
+}
+
+static KEYBOARD: Mutex<State> = Mutex::new(State {
+    keyboard: Keyboard::new(
+        Scancodes::new(ScancodeMode::Translated),
+        CurrentLayout,
+        HandleControl::Ignore,
+    ),
+    scroll_lock: false,
+    held: [0; 4],
+    pending_leds: None,
+    events: [None; QUEUE_SIZE],
+    head: 0,
+    queued: 0,
+});
+
+pub fn layout() -> Layout {
+    Layout::ALL[LAYOUT.load(Ordering::Relaxed)]
+}
+
+/// Switch the layout characters are typed in. Held keys and lock states
+/// are kept.
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout.index(), Ordering::Relaxed);
+}
+
+/// Decode scancodes as the keyboard sends them in `mode`. Resets the
+/// modifiers and held keys.
+pub(crate) fn set_scancode_mode(mode: ScancodeMode) {
+    interrupts::without_interrupts(|| {
+        let mut state = KEYBOARD.lock();
+        state.keyboard = Keyboard::new(Scancodes::new(mode), CurrentLayout, HandleControl::Ignore);
+        state.held = [0; 4];
+    });
+}
+
+pub fn modifiers() -> Modifiers {
+    interrupts::without_interrupts(|| KEYBOARD.lock().modifiers())
+}
+
+/// Whether `code` is held down right now.
+pub fn is_pressed(code: KeyCode) -> bool {
+    interrupts::without_interrupts(|| KEYBOARD.lock().is_held(code))
+}
+
+/// Take the oldest event that hasn't been polled yet.
+pub fn poll_event() -> Option<KeyEvent> {
+    interrupts::without_interrupts(|| KEYBOARD.lock().pop())
+}
+
+/// Drop the events that haven't been polled.
+pub fn clear_events() {
+    interrupts::without_interrupts(|| while KEYBOARD.lock().pop().is_some() {});
+}
+
+/// Called by the keyboard interrupt handler.
+pub fn handle_interrupt() {
+    use x86_64::instructions::port::Port;
+
+    let byte = unsafe { Port::<u8>::new(0x60).read() };
+    handle_scancode(byte);
+}
+
+/// Process one byte from the keyboard.
+pub fn handle_scancode(byte: u8) {
+    let mut state = KEYBOARD.lock();
+    if byte == ps2::ACK {
+        if let Some(leds) = state.pending_leds.take() {
+            let _ = ps2::write_data(leds);
+        }
+        return;
+    }
+
+    let Ok(Some(key_event)) = state.keyboard.add_byte(byte) else {
+        return;
+    };
+    let code = key_event.code;
+    let pressed = key_event.state != KeyState::Up;
+    match key_event.state {
+        KeyState::Down => state.set_held(code, true),
+        KeyState::Up => state.set_held(code, false),
+        KeyState::SingleShot => {}
+    }
+    if pressed && code == KeyCode::ScrollLock {
+        state.scroll_lock = !state.scroll_lock;
+    }
+
+    let character = match state.keyboard.process_keyevent(key_event) {
+        Some(DecodedKey::Unicode(character)) => Some(character),
+        _ => None,
+    };
+    if pressed
+        && matches!(
+            code,
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock
+        )
+    {
+        state.update_leds();
+    }
+    let modifiers = state.modifiers();
+    state.push(KeyEvent {
+        code,
+        pressed,
+        character,
+        modifiers,
+    });
+    drop(state);
+
+    if let Some(character) = character {
+        push_input(character);
+    }
+}
@@ -5,8 +5,11 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 extern crate alloc;
+// lets `vga_buffer`, which the kernel binary includes too, name this crate
+extern crate self as mold_os;
 
 use alloc::boxed::Box;
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
 pub mod gdt;
 pub mod interrupts;
@@ -16,6 +19,39 @@ pub mod console;
 pub mod string;
 pub mod memory;
 pub mod allocator;
+pub mod stack;
+pub mod region;
+pub mod acpi;
+pub mod apic;
+pub mod pit;
+pub mod time;
+pub mod power;
+pub mod hpet;
+pub mod percpu;
+pub mod smp;
+pub mod syscall;
+pub mod usermode;
+pub mod address_space;
+pub mod elf;
+pub mod process;
+pub mod initramfs;
+pub mod vfs;
+pub mod block;
+pub mod ata;
+pub mod partition;
+pub mod pci;
+pub mod virtio;
+pub mod ahci;
+pub mod task;
+pub mod cache;
+pub mod ps2;
+pub mod mouse;
+pub mod keyboard;
+pub mod sound;
+pub mod graphics;
+pub mod psf;
+#[cfg(feature = "framebuffer")]
+pub mod framebuffer;
 
 pub trait Testable {
     fn run(&self) -> ();
@@ -27,8 +63,14 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
+        let start = time::now_ns();
         self();
-        serial_println!("[ok]");
+        let elapsed = time::now_ns().saturating_sub(start);
+        serial_println!(
+            "[ok] ({}.{:03} ms)",
+            elapsed / 1_000_000,
+            elapsed / 1_000 % 1_000
+        );
     }
 }
 
@@ -75,10 +117,63 @@ pub fn init() {
 
     log!("Initiating PICS");
     unsafe { interrupts::PICS.lock().initialize() };
+    pit::set_frequency(time::TICK_HZ);
+    time::calibrate_tsc();
+    ps2::init(ps2::ScancodeMode::Translated);
     log!("Enabling Interupts");
     x86_64::instructions::interrupts::enable();
 }
 
+/// Set up the global kernel memory, the heap and the guarded interrupt stacks.
+///
+/// Must be called after `init`.
+pub fn init_memory(boot_info: &'static BootInfo) {
+    log!("Initiating memory");
+    memory::init_pat();
+    unsafe { memory::init_global(boot_info) };
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        allocator::init_heap(mapper, frame_allocator)
+    })
+    .expect("heap initialization failed");
+    memory::reserve_kernel_areas().expect("failed to reserve the kernel areas");
+    gdt::init_stacks();
+}
+
+/// Discover the platform through ACPI, move interrupt handling to the APIC,
+/// start the other CPUs, enumerate PCI, probe the disks, set up the mouse
+/// and start the sound player. With the `framebuffer` feature the console
+/// moves to a linear framebuffer once PCI is enumerated.
+///
+/// The 8259 PIC and the PIT stay in use if no APIC is found. Must be called
+/// after `init_memory`.
+pub fn init_platform() {
+    acpi::init();
+    if !apic::init() {
+        warn!("No APIC found, using the 8259 PIC");
+    }
+    if hpet::init() {
+        time::calibrate_tsc_with_hpet();
+    }
+    smp::init();
+    pci::init();
+    #[cfg(feature = "framebuffer")]
+    framebuffer::init();
+    virtio::init();
+    ahci::init();
+    ata::init();
+    mouse::init();
+    sound::init();
+}
+
+/// Mount the root file system, the devices and the initramfs, and start
+/// writing cached disk sectors back periodically.
+///
+/// Must be called after `init_memory`.
+pub fn init_filesystems() {
+    vfs::init();
+    cache::init();
+}
+
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
@@ -88,6 +183,8 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// Halt forever. Runs no tasks, so it is safe after a panic and on the
+/// application processors; see `task::idle_loop` for the bootstrap processor.
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
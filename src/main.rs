@@ -18,9 +18,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Test or/and run
     #[cfg(test)]
     test_main();
-    application::run();
+    let quit = application::run();
 
     // Quit OS
     application::end();
-    mold_os::hlt_loop();
+    match quit {
+        application::QuitAction::Shutdown => mold_os::power::shutdown(),
+        application::QuitAction::Reboot => mold_os::power::reboot(),
+    }
 }
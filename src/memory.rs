@@ -1,16 +1,37 @@
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Frames below this address are never handed out by `BootInfoFrameAllocator`.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Start of the virtual address range that device memory is mapped into.
+pub const MMIO_AREA_START: u64 = 0x_7777_0000_0000;
+
+const IA32_PAT: u32 = 0x277;
+/// The power-on page attribute table with entry 1, which pages mapped with
+/// `WRITE_THROUGH` alone use, made write-combining instead of write-through.
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Deallocated frames are kept in a linked list that runs through the frames
+/// themselves and are handed out again before new ones.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_list: Option<PhysFrame>,
+    free_count: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -23,15 +44,54 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
+            free_count: 0,
         }
     }
+
+    /// Number of frames handed out and not deallocated yet.
+    pub fn frames_in_use(&self) -> usize {
+        self.next - self.free_count
+    }
+
+    /// Allocate `count` physically contiguous frames and return the first.
+    ///
+    /// Frames skipped while looking for a long enough run are put on the
+    /// free list, which requires `init_global` to have run.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut run: Option<(usize, PhysFrame)> = None;
+        let mut previous: Option<PhysFrame> = None;
+        let mut found = None;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            if previous.is_none_or(|previous| previous + 1 != frame) {
+                run = Some((index, frame));
+            }
+            previous = Some(frame);
+            let (start, first) = run.unwrap();
+            if index + 1 - start == count {
+                found = Some((start, first));
+                break;
+            }
+        }
+
+        let (start, first) = found?;
+        for index in self.next..start {
+            let frame = self.usable_frames().nth(index).unwrap();
+            unsafe { self.deallocate_frame(frame) };
+        }
+        self.next = start + count;
+        Some(first)
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        // map each region to its address range, keeping the first MiB free for
+        // real mode code like the SMP trampoline
+        let addr_ranges =
+            usable_regions.map(|r| r.range.start_addr().max(LOW_MEMORY_END)..r.range.end_addr());
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
@@ -41,12 +101,35 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            let next: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+            let next = unsafe { next.read() };
+            self.free_list =
+                (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.free_count -= 1;
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Requires `init_global` to have run, since the free list is written
+    /// through the physical memory mapping.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map_or(0, |next| next.start_address().as_u64());
+        let link: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        link.write(next);
+        self.free_list = Some(frame);
+        self.free_count += 1;
+    }
+}
+
 /// A FrameAllocator that always returns `None`.
 pub struct EmptyFrameAllocator;
 
@@ -56,6 +139,175 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// The kernel's page table together with the frame allocator that backs it.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+/// Global kernel memory state, set up once by `init_global`.
+pub static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static NEXT_MMIO_ADDR: AtomicU64 = AtomicU64::new(MMIO_AREA_START);
+
+/// Set up the global kernel page table and frame allocator from the boot info.
+///
+/// # Safety
+///
+/// This function is unsafe for the same reasons as `init` and
+/// `BootInfoFrameAllocator::init`. Code using `KERNEL_MEMORY` must not also
+/// create its own mapper through `init`.
+pub unsafe fn init_global(boot_info: &'static BootInfo) {
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+
+    let mapper = init(physical_memory_offset);
+    let frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_map);
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+}
+
+/// Returns the virtual address at which the bootloader mapped physical memory.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// Returns the virtual address through which the given physical address can be accessed.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Runs `f` with the kernel page table and frame allocator.
+///
+/// Interrupts are disabled while `f` runs so that interrupt handlers can't
+/// deadlock on `KERNEL_MEMORY`. Panics if `init_global` has not been called.
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.lock();
+        let memory = memory.as_mut().expect("kernel memory not initialized");
+        f(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+}
+
+/// Allocate `count` physically contiguous, zeroed frames that devices can
+/// access directly, and return the physical address of the first.
+///
+/// The frames are reached through `phys_to_virt` and are never freed.
+pub fn allocate_dma(count: usize) -> Option<PhysAddr> {
+    let frame =
+        with_kernel_memory(|_, frame_allocator| frame_allocator.allocate_contiguous(count))?;
+    let start = frame.start_address();
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(start).as_mut_ptr::<u8>(), 0, count * 4096);
+    }
+    Some(start)
+}
+
+/// Give the areas of the lower half that the kernel maps into while running,
+/// like the stacks and regions, their level 3 tables now.
+///
+/// Address spaces copy the kernel's level 4 entries when they are created,
+/// so this way they see mappings the kernel makes in these areas later, and
+/// user pages never go there.
+pub fn reserve_kernel_areas() -> Result<(), MapToError<Size4KiB>> {
+    let areas = [
+        crate::allocator::HEAP_START as u64,
+        crate::stack::STACK_AREA_START,
+        crate::region::REGION_AREA_START,
+        MMIO_AREA_START,
+    ];
+    with_kernel_memory(|mapper, frame_allocator| {
+        for area in areas {
+            let entry = &mut mapper.level_4_table_mut()[VirtAddr::new(area).p4_index()];
+            if !entry.is_unused() {
+                continue;
+            }
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                phys_to_virt(frame.start_address())
+                    .as_mut_ptr::<PageTable>()
+                    .write(PageTable::new())
+            };
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+        Ok(())
+    })
+}
+
+/// Program the page attribute table of the executing CPU so that
+/// `map_write_combining` works. Every CPU must do this before using such
+/// mappings.
+pub fn init_pat() {
+    unsafe { Msr::new(IA32_PAT).write(PAT_VALUE) };
+}
+
+/// Map `size` bytes of device memory at physical address `phys` as uncached.
+///
+/// Returns the virtual address corresponding to `phys`. Mappings are never
+/// removed, so this is meant for devices that stay around, like the APIC.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    map_device(
+        phys,
+        size,
+        PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+    )
+}
+
+/// Map `size` bytes of device memory at physical address `phys` like
+/// `map_mmio`, but write-combining: writes are buffered and sent in bursts,
+/// which suits framebuffers. Requires `init_pat`.
+pub fn map_write_combining(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    map_device(phys, size, PageTableFlags::WRITE_THROUGH)
+}
+
+fn map_device(
+    phys: PhysAddr,
+    size: u64,
+    cache_flags: PageTableFlags,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let frame_count = last_frame - first_frame + 1;
+
+    let virt_start = VirtAddr::new(NEXT_MMIO_ADDR.fetch_add(frame_count * 4096, Ordering::SeqCst));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cache_flags;
+
+    with_kernel_memory(|mapper, frame_allocator| {
+        let first_page = Page::<Size4KiB>::containing_address(virt_start);
+        for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+            let page = first_page + i as u64;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok(virt_start + (phys - first_frame.start_address()))
+    })
+}
+
+/// Read a `T` from a device register mapped with `map_mmio`.
+///
+/// # Safety
+///
+/// `address` must be a mapped register of that size.
+pub unsafe fn read_mmio<T>(address: VirtAddr) -> T {
+    core::ptr::read_volatile(address.as_ptr())
+}
+
+/// Write `value` to a device register mapped with `map_mmio`.
+///
+/// # Safety
+///
+/// `address` must be a mapped register of that size, and writing it must
+/// not break the device's invariants.
+pub unsafe fn write_mmio<T>(address: VirtAddr, value: T) {
+    core::ptr::write_volatile(address.as_mut_ptr(), value)
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -120,6 +372,39 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
+/// Returns whether `addr` is mapped in the active page table with user access
+/// at every level, and writable as well if `write` is set.
+pub fn is_user_accessible(addr: VirtAddr, write: bool) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let (mut frame, _) = Cr3::read();
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let table_ptr: *const PageTable = phys_to_virt(frame.start_address()).as_ptr();
+        let table = unsafe { &*table_ptr };
+        let entry = &table[index];
+        if !entry.flags().contains(required) {
+            return false;
+        }
+        // a huge page ends the walk early
+        if level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    true
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
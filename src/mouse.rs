@@ -0,0 +1,380 @@
+// PS/2 mouse on the auxiliary port of the 8042 controller
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::{enable_isa_irq, InterruptIndex};
+use crate::ps2::{self, Ps2Error};
+use crate::vga_buffer;
+use crate::{log, warn};
+
+const MOUSE_IRQ: u8 = 12;
+
+const SET_DEFAULTS: u8 = 0xF6;
+const ENABLE_REPORTING: u8 = 0xF4;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const GET_ID: u8 = 0xF2;
+/// Device ID of an IntelliMouse, which has a wheel.
+const ID_WHEEL: u8 = 3;
+/// Device ID of an IntelliMouse Explorer, which adds two side buttons.
+const ID_FIVE_BUTTONS: u8 = 4;
+
+const FLAG_LEFT: u8 = 1 << 0;
+const FLAG_RIGHT: u8 = 1 << 1;
+const FLAG_MIDDLE: u8 = 1 << 2;
+/// Set in the first byte of every packet, to find packet boundaries.
+const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+const FLAG_X_SIGN: u8 = 1 << 4;
+const FLAG_Y_SIGN: u8 = 1 << 5;
+const FLAG_X_OVERFLOW: u8 = 1 << 6;
+const FLAG_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Size of the text screen the position is clamped to.
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 25;
+/// Mouse movement counts it takes to move the position by one cell.
+pub const COUNTS_PER_COLUMN: i32 = 8;
+pub const COUNTS_PER_ROW: i32 = 16;
+
+/// Events kept until they are polled. Older ones are dropped.
+const QUEUE_SIZE: usize = 32;
+
+/// Packet format, chosen by how the mouse answered the detection sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Three byte packets, three buttons.
+    Standard,
+    /// Four byte packets with the wheel in the last byte.
+    Wheel,
+    /// Four byte packets with the wheel and two more buttons in the last byte.
+    FiveButtons,
+}
+
+impl Protocol {
+    pub fn packet_size(self) -> usize {
+        match self {
+            Protocol::Standard => 3,
+            Protocol::Wheel | Protocol::FiveButtons => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub back: bool,
+    pub forward: bool,
+}
+
+/// One decoded packet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Packet {
+    pub dx: i16,
+    /// Positive when the mouse moved away from the user, i.e. up the screen.
+    pub dy: i16,
+    /// Positive when the wheel was turned towards the user.
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+/// Assembles bytes from the mouse into packets.
+pub struct PacketDecoder {
+    protocol: Protocol,
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new(protocol: Protocol) -> Self {
+        PacketDecoder {
+            protocol,
+            bytes: [0; 4],
+            len: 0,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Add the next byte, returning the packet it completes.
+    pub fn add_byte(&mut self, byte: u8) -> Option<Packet> {
+        // a first byte without this bit means a byte got lost; wait for one
+        // that could start a packet
+        if self.len == 0 && byte & FLAG_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.protocol.packet_size() {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> Packet {
+        let [flags, x, y, extra] = self.bytes;
+        // movement is 9-bit two's complement with the sign in the first byte
+        let movement = |value: u8, sign: u8, overflow: u8| match flags & overflow {
+            0 if flags & sign != 0 => value as i16 - 0x100,
+            0 => value as i16,
+            _ => 0,
+        };
+        let mut packet = Packet {
+            dx: movement(x, FLAG_X_SIGN, FLAG_X_OVERFLOW),
+            dy: movement(y, FLAG_Y_SIGN, FLAG_Y_OVERFLOW),
+            wheel: 0,
+            buttons: Buttons {
+                left: flags & FLAG_LEFT != 0,
+                right: flags & FLAG_RIGHT != 0,
+                middle: flags & FLAG_MIDDLE != 0,
+                back: false,
+                forward: false,
+            },
+        };
+        match self.protocol {
+            Protocol::Standard => {}
+            Protocol::Wheel => packet.wheel = extra as i8,
+            Protocol::FiveButtons => {
+                // 4-bit wheel movement below the two side buttons
+                packet.wheel = ((extra << 4) as i8) >> 4;
+                packet.buttons.back = extra & 0x10 != 0;
+                packet.buttons.forward = extra & 0x20 != 0;
+            }
+        }
+        packet
+    }
+}
+
+/// What the mouse did, with the resulting position on the text screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub column: usize,
+    pub row: usize,
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+/// Follows the mouse position across the text screen.
+pub struct Tracker {
+    /// Position in movement counts, so slow movements add up.
+    x: i32,
+    y: i32,
+}
+
+impl Tracker {
+    /// A tracker starting in the middle of the screen.
+    pub const fn new() -> Self {
+        Tracker {
+            x: COLUMNS as i32 * COUNTS_PER_COLUMN / 2,
+            y: ROWS as i32 * COUNTS_PER_ROW / 2,
+        }
+    }
+
+    /// The column and row the mouse points at.
+    pub fn position(&self) -> (usize, usize) {
+        (
+            (self.x / COUNTS_PER_COLUMN) as usize,
+            (self.y / COUNTS_PER_ROW) as usize,
+        )
+    }
+
+    /// Move by `packet` and return the event describing it.
+    pub fn update(&mut self, packet: &Packet) -> MouseEvent {
+        let max_x = COLUMNS as i32 * COUNTS_PER_COLUMN - 1;
+        let max_y = ROWS as i32 * COUNTS_PER_ROW - 1;
+        self.x = (self.x + packet.dx as i32).clamp(0, max_x);
+        // rows count down the screen
+        self.y = (self.y - packet.dy as i32).clamp(0, max_y);
+        let (column, row) = self.position();
+        MouseEvent {
+            column,
+            row,
+            dx: packet.dx,
+            dy: packet.dy,
+            wheel: packet.wheel,
+            buttons: packet.buttons,
+        }
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Mouse {
+    decoder: PacketDecoder,
+    tracker: Tracker,
+    buttons: Buttons,
+    events: [MouseEvent; QUEUE_SIZE],
+    /// Index of the oldest event.
+    head: usize,
+    queued: usize,
+}
+
+impl Mouse {
+    fn push(&mut self, event: MouseEvent) {
+        if self.queued == QUEUE_SIZE {
+            self.head = (self.head + 1) % QUEUE_SIZE;
+            self.queued -= 1;
+        }
+        self.events[(self.head + self.queued) % QUEUE_SIZE] = event;
+        self.queued += 1;
+    }
+
+    fn pop(&mut self) -> Option<MouseEvent> {
+        if self.queued == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.queued -= 1;
+        Some(event)
+    }
+}
+
+const NO_BUTTONS: Buttons = Buttons {
+    left: false,
+    right: false,
+    middle: false,
+    back: false,
+    forward: false,
+};
+
+static MOUSE: Mutex<Mouse> = Mutex::new(Mouse {
+    decoder: PacketDecoder::new(Protocol::Standard),
+    tracker: Tracker::new(),
+    buttons: NO_BUTTONS,
+    events: [MouseEvent {
+        column: 0,
+        row: 0,
+        dx: 0,
+        dy: 0,
+        wheel: 0,
+        buttons: NO_BUTTONS,
+    }; QUEUE_SIZE],
+    head: 0,
+    queued: 0,
+});
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// The packet format of the mouse, if one was found.
+pub fn protocol() -> Option<Protocol> {
+    is_present().then(|| interrupts::without_interrupts(|| MOUSE.lock().decoder.protocol()))
+}
+
+/// Take the oldest event that hasn't been polled yet.
+pub fn poll_event() -> Option<MouseEvent> {
+    interrupts::without_interrupts(|| MOUSE.lock().pop())
+}
+
+/// The column and row the mouse points at.
+pub fn position() -> (usize, usize) {
+    interrupts::without_interrupts(|| MOUSE.lock().tracker.position())
+}
+
+pub fn buttons() -> Buttons {
+    interrupts::without_interrupts(|| MOUSE.lock().buttons)
+}
+
+/// Called by the mouse interrupt handler.
+pub fn handle_interrupt() {
+    use x86_64::instructions::port::Port;
+
+    let status = ps2::status();
+    if status & ps2::STATUS_AUX_DATA == 0 {
+        return;
+    }
+    let byte = unsafe { Port::<u8>::new(0x60).read() };
+
+    let mut mouse = MOUSE.lock();
+    let Some(packet) = mouse.decoder.add_byte(byte) else {
+        return;
+    };
+    let before = mouse.tracker.position();
+    let event = mouse.tracker.update(&packet);
+    mouse.buttons = packet.buttons;
+    mouse.push(event);
+    drop(mouse);
+
+    if (event.column, event.row) != before {
+        vga_buffer::show_mouse_cursor(event.row, event.column);
+    }
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    ps2::send_aux(SET_SAMPLE_RATE)?;
+    ps2::send_aux(rate)
+}
+
+fn device_id() -> Result<u8, Ps2Error> {
+    ps2::send_aux(GET_ID)?;
+    ps2::read_aux()
+}
+
+/// Unlock the wheel and side buttons with the sample rate sequences
+/// IntelliMouse compatible mice look out for.
+fn detect_protocol() -> Result<Protocol, Ps2Error> {
+    for rate in [200, 100, 80] {
+        set_sample_rate(rate)?;
+    }
+    let mut protocol = Protocol::Standard;
+    if device_id()? == ID_WHEEL {
+        protocol = Protocol::Wheel;
+        for rate in [200, 200, 80] {
+            set_sample_rate(rate)?;
+        }
+        if device_id()? == ID_FIVE_BUTTONS {
+            protocol = Protocol::FiveButtons;
+        }
+    }
+    set_sample_rate(100)?;
+    Ok(protocol)
+}
+
+fn configure() -> Result<Protocol, Ps2Error> {
+    let config = ps2::read_config()? & !ps2::CONFIG_AUX_CLOCK_DISABLED;
+    // the interrupt stays off until the mouse is set up, so its replies
+    // can be polled for
+    ps2::write_config(config & !ps2::CONFIG_AUX_INTERRUPT)?;
+
+    ps2::send_aux(SET_DEFAULTS)?;
+    let protocol = detect_protocol()?;
+    ps2::send_aux(ENABLE_REPORTING)?;
+
+    MOUSE.lock().decoder = PacketDecoder::new(protocol);
+    ps2::write_config(config | ps2::CONFIG_AUX_INTERRUPT)?;
+    Ok(protocol)
+}
+
+/// Set up the mouse, if `ps2::init` found one, and show its cursor.
+pub fn init() {
+    if !ps2::devices().is_some_and(|devices| devices.mouse) {
+        return;
+    }
+    match interrupts::without_interrupts(configure) {
+        Ok(protocol) => {
+            log!("PS/2 mouse found ({:?})", protocol);
+            PRESENT.store(true, Ordering::Relaxed);
+            enable_isa_irq(MOUSE_IRQ, InterruptIndex::Mouse);
+            let (column, row) = position();
+            vga_buffer::show_mouse_cursor(row, column);
+        }
+        Err(error) => {
+            // leave the port off so a half configured device stays quiet
+            let _ = ps2::write_command(ps2::COMMAND_DISABLE_AUX);
+            warn!("No PS/2 mouse: {:?}", error);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// MBR partition tables
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+
+// partition types that hold further tables instead of a file system
+const EXTENDED_CHS: u8 = 0x05;
+const EXTENDED_LBA: u8 = 0x0F;
+
+/// A primary partition, usable as a block device of its own.
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    sectors: u64,
+    kind: u8,
+}
+
+impl Partition {
+    /// The partition type byte from the table, e.g. 0x0C for FAT32.
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// First sector of the partition on the underlying device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        self.device.read_sectors(self.start + lba, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        self.device.write_sectors(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Read the MBR of `device` and return its primary partitions, named after
+/// the device with a `p<n>` suffix.
+///
+/// Returns no partitions if the first sector isn't a valid MBR, e.g. because
+/// the file system covers the whole device. Extended partitions are skipped.
+pub fn read_mbr(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let mut sector = [0; SECTOR_SIZE];
+    device.read_sectors(0, &mut sector)?;
+    if sector[510..] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    for index in 0..4 {
+        let entry = &sector[TABLE_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+        // anything but these status values means this isn't a partition table
+        if entry[0] != 0x00 && entry[0] != 0x80 {
+            return Ok(Vec::new());
+        }
+        let kind = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if kind == 0 || kind == EXTENDED_CHS || kind == EXTENDED_LBA {
+            continue;
+        }
+        if start == 0 || sectors == 0 || start + sectors > device.sector_count() {
+            return Ok(Vec::new());
+        }
+        partitions.push(Partition {
+            name: format!("{}p{}", device.name(), index + 1),
+            device: device.clone(),
+            start,
+            sectors,
+            kind,
+        });
+    }
+    Ok(partitions)
+}
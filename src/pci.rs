@@ -0,0 +1,427 @@
+// PCI bus enumeration through the legacy configuration ports
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{enable_isa_irq, InterruptIndex};
+use crate::log;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// configuration space registers
+const REG_VENDOR_ID: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_STATUS: u8 = 0x06;
+const REG_REVISION: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0E;
+const REG_BAR0: u8 = 0x10;
+const REG_CAPABILITIES: u8 = 0x34;
+const REG_INTERRUPT_LINE: u8 = 0x3C;
+const REG_INTERRUPT_PIN: u8 = 0x3D;
+
+pub const COMMAND_IO: u16 = 1;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// Serializes the address/data port pair.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Bus, device and function of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn config_address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u8(&self, offset: u8, value: u8) {
+        let shift = (offset & 3) * 8;
+        let old = self.read_u32(offset) & !(0xFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+}
+
+/// A base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64_bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+/// A function found on the bus.
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// The legacy IRQ the firmware routed the device to.
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, or 0 if the device doesn't interrupt.
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    /// IDs and configuration space offsets of the capabilities.
+    pub capabilities: Vec<(u8, u8)>,
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = address.read_u16(REG_VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class_register = address.read_u32(REG_REVISION);
+        let header_type = address.read_u8(REG_HEADER_TYPE);
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: address.read_u16(REG_VENDOR_ID + 2),
+            class: (class_register >> 24) as u8,
+            subclass: (class_register >> 16) as u8,
+            prog_if: (class_register >> 8) as u8,
+            revision: class_register as u8,
+            header_type,
+            interrupt_line: address.read_u8(REG_INTERRUPT_LINE),
+            interrupt_pin: address.read_u8(REG_INTERRUPT_PIN),
+            bars: [None; 6],
+            capabilities: Vec::new(),
+        };
+        // only general devices have six BARs, bridges have two
+        let bar_count = match header_type & 0x7F {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = read_bar(address, index);
+            device.bars[index] = bar;
+            index += slots;
+        }
+        device.capabilities = read_capabilities(address);
+        Some(device)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.address.read_u16(REG_COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.address.write_u16(REG_COMMAND, command);
+    }
+
+    /// Let the device decode its BARs and access memory on its own.
+    pub fn enable_bus_mastering(&self) {
+        self.set_command(self.command() | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    /// Configuration space offset of the first capability with `id`.
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        self.capabilities
+            .iter()
+            .find(|&&(capability, _)| capability == id)
+            .map(|&(_, offset)| offset)
+    }
+
+    pub fn msi(&self) -> Option<u8> {
+        self.capability(CAPABILITY_MSI)
+    }
+
+    pub fn msix(&self) -> Option<u8> {
+        self.capability(CAPABILITY_MSIX)
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & 0x80 != 0
+    }
+
+    /// A readable name for the class of the device.
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE interface",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, 0x00) => "SCSI storage controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA compatible controller",
+            (0x03, _) => "Display controller",
+            (0x04, 0x01) | (0x04, 0x03) => "Audio device",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus",
+            (0x0C, _) => "Serial bus controller",
+            _ => "Unclassified device",
+        }
+    }
+}
+
+/// Read BAR `index` and find its size by writing all ones to it. Returns
+/// the BAR and the number of slots it takes, two for 64-bit memory BARs.
+fn read_bar(address: PciAddress, index: usize) -> (Option<Bar>, usize) {
+    let offset = REG_BAR0 + index as u8 * 4;
+    let value = address.read_u32(offset);
+
+    // decoding must be off while the BAR holds the size mask
+    let command = address.read_u16(REG_COMMAND);
+    address.write_u16(REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let result = if value & 1 == 1 {
+        address.write_u32(offset, 0xFFFF_FFFF);
+        let mask = address.read_u32(offset) & 0xFFFF_FFFC;
+        address.write_u32(offset, value);
+        let size = (!mask).wrapping_add(1) & 0xFFFF;
+        let bar = (mask != 0).then_some(Bar::Io {
+            port: (value & 0xFFFC) as u16,
+            size,
+        });
+        (bar, 1)
+    } else {
+        let is_64_bit = (value >> 1) & 3 == 2;
+        address.write_u32(offset, 0xFFFF_FFFF);
+        let low_mask = address.read_u32(offset) & 0xFFFF_FFF0;
+        address.write_u32(offset, value);
+        let mut base = (value & 0xFFFF_FFF0) as u64;
+        let mut mask = low_mask as u64 | 0xFFFF_FFFF_0000_0000;
+        if is_64_bit {
+            let high = address.read_u32(offset + 4);
+            address.write_u32(offset + 4, 0xFFFF_FFFF);
+            let high_mask = address.read_u32(offset + 4);
+            address.write_u32(offset + 4, high);
+            base |= (high as u64) << 32;
+            mask = (high_mask as u64) << 32 | low_mask as u64;
+        }
+        let bar = (low_mask != 0).then_some(Bar::Memory {
+            address: base,
+            size: (!mask).wrapping_add(1),
+            prefetchable: value & 0x8 != 0,
+            is_64_bit,
+        });
+        (bar, if is_64_bit { 2 } else { 1 })
+    };
+
+    address.write_u16(REG_COMMAND, command);
+    result
+}
+
+fn read_capabilities(address: PciAddress) -> Vec<(u8, u8)> {
+    let mut capabilities = Vec::new();
+    if address.read_u16(REG_STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = address.read_u8(REG_CAPABILITIES) & 0xFC;
+    // there is room for at most 48 capabilities, more means a loop
+    while offset != 0 && capabilities.len() < 48 {
+        capabilities.push((address.read_u8(offset), offset));
+        offset = address.read_u8(offset + 1) & 0xFC;
+    }
+    capabilities
+}
+
+/// A driver that wants to be told about the devices it can handle.
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: fn(&PciDevice) -> bool,
+    /// Called once for every matching device, at registration or when the
+    /// device is found, whichever comes later.
+    pub probe: fn(&PciDevice),
+}
+
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// Register `driver` and probe it with the matching devices found so far.
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+    attach(driver);
+}
+
+fn attach(driver: &PciDriver) {
+    for device in devices().iter().filter(|device| (driver.matches)(device)) {
+        log!("PCI {}: using driver {}", device.address, driver.name);
+        (driver.probe)(device);
+    }
+}
+
+/// All devices found by `init`, ordered by address.
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+        .cloned()
+}
+
+pub fn find_class(class: u8, subclass: u8) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.class == class && device.subclass == subclass)
+        .cloned()
+        .collect()
+}
+
+type InterruptHandler = Box<dyn Fn() + Send + Sync>;
+
+/// Handlers of the legacy interrupt lines, with the ISA IRQ they are on.
+static INTERRUPT_HANDLERS: Mutex<Vec<(u8, InterruptHandler)>> = Mutex::new(Vec::new());
+
+/// Call `handler` whenever the legacy interrupt line of `device` fires.
+///
+/// Lines are level triggered and shared, so `handler` has to check and
+/// acknowledge its device. Returns `false` if the line can't be routed.
+pub fn register_interrupt_handler(
+    device: &PciDevice,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> bool {
+    let irq = device.interrupt_line;
+    let index = match InterruptIndex::for_pci_irq(irq) {
+        Some(index) if device.interrupt_pin != 0 => index,
+        _ => return false,
+    };
+    interrupts::without_interrupts(|| INTERRUPT_HANDLERS.lock().push((irq, Box::new(handler))));
+    device.set_command(device.command() & !COMMAND_INTERRUPT_DISABLE);
+    enable_isa_irq(irq, index);
+    true
+}
+
+/// Run the handlers registered for ISA IRQ `irq`.
+pub fn handle_interrupt(irq: u8) {
+    for (_, handler) in INTERRUPT_HANDLERS
+        .lock()
+        .iter()
+        .filter(|(line, _)| *line == irq)
+    {
+        handler();
+    }
+}
+
+/// Scan every bus for devices.
+pub fn scan() -> Vec<PciDevice> {
+    let mut found = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let Some(first) = PciDevice::probe(PciAddress::new(bus, device, 0)) else {
+                continue;
+            };
+            let functions = if first.is_multifunction() { 8 } else { 1 };
+            found.push(first);
+            for function in 1..functions {
+                if let Some(device) = PciDevice::probe(PciAddress::new(bus, device, function)) {
+                    found.push(device);
+                }
+            }
+        }
+    }
+    found
+}
+
+/// Enumerate the bus, log what is there and hand the devices to the drivers
+/// registered so far. Requires the heap.
+pub fn init() {
+    log!("Scanning PCI bus");
+    let found = scan();
+    for device in &found {
+        log!(
+            "{} {}: {:04x}:{:04x} (rev {:02x})",
+            device.address,
+            device.class_name(),
+            device.vendor_id,
+            device.device_id,
+            device.revision
+        );
+    }
+    *DEVICES.lock() = found;
+
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        attach(driver);
+    }
+}
@@ -0,0 +1,78 @@
+// Per-CPU data reached through the GS base
+use alloc::boxed::Box;
+use core::cell::Cell;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Data owned by a single CPU.
+///
+/// The GS base of each CPU points at its `PerCpu`, so the executing CPU can
+/// find its data without knowing its own ID.
+#[repr(C)]
+pub struct PerCpu {
+    /// Pointer to this structure, so that it can be read with a single `gs:` load.
+    self_ptr: *const PerCpu,
+    /// Index of this CPU; the bootstrap processor is CPU 0.
+    pub cpu_id: usize,
+    pub apic_id: u8,
+    /// The TSS loaded on this CPU.
+    pub tss: *mut TaskStateSegment,
+    /// Stack the `syscall` entry point switches to, 0 until user mode is first entered.
+    pub kernel_stack_top: Cell<u64>,
+    /// Scratch slot for the user stack pointer during `syscall` entry.
+    pub user_rsp: Cell<u64>,
+    /// Kernel context to return to when the running user program ends.
+    pub user_return: Cell<u64>,
+    /// PID of the process running on this CPU, 0 while only the kernel runs.
+    pub current_pid: Cell<u64>,
+}
+
+/// Offset of `kernel_stack_top`, for use in assembly.
+pub const KERNEL_STACK_TOP_OFFSET: usize = core::mem::offset_of!(PerCpu, kernel_stack_top);
+/// Offset of `user_rsp`, for use in assembly.
+pub const USER_RSP_OFFSET: usize = core::mem::offset_of!(PerCpu, user_rsp);
+
+/// Allocate the per-CPU data of the executing CPU and point its GS base at it.
+///
+/// Requires the heap to be initialized.
+pub fn init(cpu_id: usize, apic_id: u8, tss: *mut TaskStateSegment) {
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        cpu_id,
+        apic_id,
+        tss,
+        kernel_stack_top: Cell::new(0),
+        user_rsp: Cell::new(0),
+        user_return: Cell::new(0),
+        current_pid: Cell::new(0),
+    }));
+    per_cpu.self_ptr = per_cpu;
+    GsBase::write(VirtAddr::from_ptr(per_cpu));
+}
+
+/// Returns whether `init` has run on the executing CPU.
+pub fn is_initialized() -> bool {
+    GsBase::read().as_u64() != 0
+}
+
+/// Returns the per-CPU data of the executing CPU.
+///
+/// Panics if `init` hasn't run on this CPU.
+pub fn current() -> &'static PerCpu {
+    assert!(is_initialized(), "per-CPU data not initialized");
+    let ptr: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*ptr
+    }
+}
+
+/// Index of the executing CPU, 0 before per-CPU data is set up.
+pub fn cpu_id() -> usize {
+    if is_initialized() {
+        current().cpu_id
+    } else {
+        0
+    }
+}
@@ -0,0 +1,94 @@
+// Programmable Interval Timer (8253/8254)
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the PIT in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller, which gates channel 2.
+const PORT_B: u16 = 0x61;
+
+/// Serializes access to the PIT command register.
+static PIT_LOCK: Mutex<()> = Mutex::new(());
+
+fn divisor_for(frequency: u32) -> u16 {
+    (BASE_FREQUENCY / frequency.max(19)).min(u16::MAX as u32) as u16
+}
+
+/// Let channel 0 fire IRQ0 periodically at roughly `frequency` Hz.
+pub fn set_frequency(frequency: u32) {
+    let divisor = divisor_for(frequency);
+    let _lock = PIT_LOCK.lock();
+    unsafe {
+        // channel 0, lobyte/hibyte, mode 2 (rate generator)
+        Port::<u8>::new(COMMAND).write(0b0011_0100);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Busy-wait for `micros` microseconds using channel 2.
+///
+/// This doesn't depend on interrupts, so it can be used to calibrate other
+/// timers. The wait is limited to about 54 ms per call.
+pub fn wait_micros(micros: u32) {
+    let ticks = (BASE_FREQUENCY as u64 * micros as u64 / 1_000_000).clamp(1, 0xFFFF) as u16;
+    let _lock = PIT_LOCK.lock();
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        // enable the gate of channel 2 but keep the speaker disconnected
+        let value = port_b.read();
+        port_b.write((value & !0b10) | 0b1);
+
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        Port::<u8>::new(COMMAND).write(0b1011_0000);
+        let mut data = Port::<u8>::new(CHANNEL_2);
+        data.write(ticks as u8);
+        data.write((ticks >> 8) as u8);
+
+        // restart the count by toggling the gate
+        let value = port_b.read();
+        port_b.write(value & !0b1);
+        port_b.write(value | 0b1);
+
+        // bit 5 mirrors the output of channel 2, which goes high at terminal count
+        while port_b.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Drive the PC speaker with a square wave of roughly `frequency` Hz from
+/// channel 2 until `stop_tone`.
+///
+/// `wait_micros` also uses channel 2 and silences the speaker.
+pub fn start_tone(frequency: u32) {
+    let divisor = divisor_for(frequency);
+    let _lock = PIT_LOCK.lock();
+    unsafe {
+        // channel 2, lobyte/hibyte, mode 3 (square wave)
+        Port::<u8>::new(COMMAND).write(0b1011_0110);
+        let mut data = Port::<u8>::new(CHANNEL_2);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+
+        // open the gate and connect the speaker
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value | 0b11);
+    }
+}
+
+/// Disconnect the PC speaker from channel 2.
+pub fn stop_tone() {
+    let _lock = PIT_LOCK.lock();
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value & !0b11);
+    }
+}
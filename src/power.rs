@@ -0,0 +1,153 @@
+// Reboot and shutdown
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::acpi::{self, GenericAddress};
+use crate::{cache, log, memory, pit, warn};
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+/// Command that pulses the CPU reset line of the keyboard controller.
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+/// `SLP_EN` bit of the PM1 control registers.
+const SLP_EN: u16 = 1 << 13;
+/// `SCI_EN` bit of the PM1 control registers, set while ACPI mode is enabled.
+const SCI_EN: u16 = 1;
+
+/// Restart the machine after writing cached disk sectors back, trying the
+/// keyboard controller reset line, the ACPI reset register and finally a
+/// triple fault.
+pub fn reboot() -> ! {
+    log!("Rebooting");
+    cache::flush_all();
+    x86_64::instructions::interrupts::disable();
+
+    reset_via_keyboard_controller();
+    wait_ms(50);
+
+    reset_via_acpi();
+    wait_ms(50);
+
+    warn!("Reset failed, forcing a triple fault");
+    triple_fault()
+}
+
+/// Power off the machine by entering the ACPI S5 sleep state, after writing
+/// cached disk sectors back.
+pub fn shutdown() -> ! {
+    log!("Shutting down");
+    cache::flush_all();
+    x86_64::instructions::interrupts::disable();
+
+    shutdown_via_acpi();
+    wait_ms(50);
+
+    // QEMU and Bochs specific power off ports, used when ACPI is unavailable
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+    }
+    wait_ms(50);
+
+    warn!("Shutdown failed, it is now safe to turn off the computer");
+    crate::hlt_loop()
+}
+
+fn wait_ms(ms: u32) {
+    for _ in 0..ms {
+        pit::wait_micros(1000);
+    }
+}
+
+fn reset_via_keyboard_controller() {
+    unsafe {
+        let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+        // wait until the controller's input buffer is empty
+        for _ in 0..100_000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Port::<u8>::new(KEYBOARD_CONTROLLER_COMMAND).write(PULSE_RESET_LINE);
+    }
+}
+
+fn reset_via_acpi() {
+    let fadt = match acpi::info().and_then(|info| info.fadt.as_ref()) {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => unsafe {
+            Port::<u8>::new(register.address as u16).write(fadt.reset_value);
+        },
+        GenericAddress::SYSTEM_MEMORY => {
+            if let Ok(addr) = memory::map_mmio(PhysAddr::new(register.address), 1) {
+                unsafe { addr.as_mut_ptr::<u8>().write_volatile(fadt.reset_value) };
+            }
+        }
+        _ => warn!("Unsupported ACPI reset register address space"),
+    }
+}
+
+fn shutdown_via_acpi() {
+    let info = match acpi::info() {
+        Some(info) => info,
+        None => return,
+    };
+    let (fadt, pm1a_control) = match (info.fadt.as_ref(), info.pm1a_control_port()) {
+        (Some(fadt), Some(port)) => (fadt, port),
+        _ => return,
+    };
+    let (slp_typa, slp_typb) = match acpi::s5_sleep_types() {
+        Some(types) => types,
+        None => {
+            warn!("No \\_S5 object in the DSDT");
+            return;
+        }
+    };
+
+    unsafe {
+        let mut pm1a = Port::<u16>::new(pm1a_control);
+
+        // switch to ACPI mode if the firmware hasn't done so yet
+        if pm1a.read() & SCI_EN == 0 && fadt.smi_command_port != 0 && fadt.acpi_enable != 0 {
+            Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+            for _ in 0..300 {
+                if pm1a.read() & SCI_EN != 0 {
+                    break;
+                }
+                wait_ms(10);
+            }
+        }
+
+        pm1a.write((slp_typa << 10) | SLP_EN);
+        if let Some(pm1b_control) = info.pm1b_control_port() {
+            Port::<u16>::new(pm1b_control).write((slp_typb << 10) | SLP_EN);
+        }
+    }
+}
+
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    // with an empty IDT, any exception escalates to a triple fault
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop()
+}
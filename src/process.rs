@@ -0,0 +1,180 @@
+// Processes: user programs in their own address space
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::address_space;
+use crate::elf::{self, ElfError, Program};
+use crate::usermode::{self, ExitStatus};
+use crate::{log, percpu};
+
+/// Process ID. PIDs are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// A PID passed in by a program, which may not name any process.
+    pub(crate) fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    Elf(ElfError),
+    NoSuchProcess,
+    /// Only the parent of a process may wait for it.
+    NotAChild,
+    /// The process is running on another CPU.
+    NotExited,
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(err: ElfError) -> Self {
+        ProcessError::Elf(err)
+    }
+}
+
+enum State {
+    /// Loaded and waiting to run.
+    Ready(Program),
+    Running,
+    /// Finished; the address space is gone and only the status is kept
+    /// until the parent collects it with `wait`.
+    Exited(ExitStatus),
+}
+
+struct Process {
+    name: String,
+    /// `None` for processes started by the kernel.
+    parent: Option<Pid>,
+    state: State,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+/// The process running on the executing CPU, `None` in the kernel.
+pub fn current() -> Option<Pid> {
+    if !percpu::is_initialized() {
+        return None;
+    }
+    match percpu::current().current_pid.get() {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// Load an ELF executable as a new child of the current process.
+///
+/// The process doesn't run until its parent waits for it.
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError> {
+    let program = elf::load(image, argv, envp)?;
+    let pid = Pid(NEXT_PID.fetch_add(1, Ordering::SeqCst));
+    let process = Process {
+        name: String::from(name),
+        parent: current(),
+        state: State::Ready(program),
+    };
+    PROCESSES.lock().insert(pid, process);
+    Ok(pid)
+}
+
+/// Returns the parent of `pid`, `None` for processes of the kernel.
+pub fn parent(pid: Pid) -> Result<Option<Pid>, ProcessError> {
+    let processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    Ok(process.parent)
+}
+
+/// Returns the children of `pid` that haven't been waited for, or those of
+/// the kernel if `pid` is `None`.
+pub fn children(pid: Option<Pid>) -> Vec<Pid> {
+    let processes = PROCESSES.lock();
+    let children = processes
+        .iter()
+        .filter(|(_, process)| process.parent == pid);
+    children.map(|(&child, _)| child).collect()
+}
+
+/// Returns the name `pid` was spawned with.
+pub fn name(pid: Pid) -> Result<String, ProcessError> {
+    let processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    Ok(process.name.clone())
+}
+
+/// Wait for the child `pid` to exit and return its exit status.
+///
+/// Processes run on the waiting CPU, so a child that hasn't run yet runs to
+/// completion here, also when the caller is a process itself. A child that
+/// is already running elsewhere is reported as `NotExited`. The process is
+/// removed afterwards and its own children are handed to the kernel.
+pub fn wait(pid: Pid) -> Result<ExitStatus, ProcessError> {
+    let program = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        if process.parent != current() {
+            return Err(ProcessError::NotAChild);
+        }
+        match core::mem::replace(&mut process.state, State::Running) {
+            State::Ready(program) => Some(program),
+            State::Running => return Err(ProcessError::NotExited),
+            state => {
+                process.state = state;
+                None
+            }
+        }
+    };
+    if let Some(program) = program {
+        run(pid, program);
+    }
+
+    let mut processes = PROCESSES.lock();
+    let status = match processes.get(&pid).map(|process| &process.state) {
+        Some(State::Exited(status)) => *status,
+        _ => return Err(ProcessError::NoSuchProcess),
+    };
+    processes.remove(&pid);
+    for process in processes.values_mut() {
+        if process.parent == Some(pid) {
+            process.parent = None;
+        }
+    }
+    Ok(status)
+}
+
+fn run(pid: Pid, program: Program) {
+    let per_cpu = percpu::current();
+    let previous_pid = per_cpu.current_pid.replace(pid.as_u64());
+    let status = unsafe {
+        let previous = program.address_space.activate();
+        let status = usermode::run(program.entry, program.stack_top, 0);
+        address_space::restore(previous);
+        status
+    };
+    per_cpu.current_pid.set(previous_pid);
+    // frees the address space
+    drop(program);
+
+    match status {
+        ExitStatus::Exited(code) => log!("Process {} exited with code {}", pid, code),
+        ExitStatus::Killed(reason) => log!("Process {} was killed: {}", pid, reason),
+    }
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.state = State::Exited(status);
+    }
+}
@@ -0,0 +1,308 @@
+// 8042 PS/2 controller
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::{keyboard, log, time, warn};
+
+const DATA_PORT: u16 = 0x60;
+/// Read for the status register, written for controller commands.
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the auxiliary (mouse) port.
+pub const STATUS_AUX_DATA: u8 = 1 << 5;
+
+pub const COMMAND_READ_CONFIG: u8 = 0x20;
+pub const COMMAND_WRITE_CONFIG: u8 = 0x60;
+pub const COMMAND_DISABLE_AUX: u8 = 0xA7;
+pub const COMMAND_ENABLE_AUX: u8 = 0xA8;
+const COMMAND_TEST_AUX_PORT: u8 = 0xA9;
+const COMMAND_TEST_CONTROLLER: u8 = 0xAA;
+const COMMAND_TEST_KEYBOARD_PORT: u8 = 0xAB;
+const COMMAND_DISABLE_KEYBOARD: u8 = 0xAD;
+const COMMAND_ENABLE_KEYBOARD: u8 = 0xAE;
+/// Send the next data byte to the auxiliary device instead of the keyboard.
+const COMMAND_WRITE_AUX: u8 = 0xD4;
+
+/// Controller self-test result when it passed.
+const CONTROLLER_OK: u8 = 0x55;
+/// Port test result when it passed.
+const PORT_OK: u8 = 0x00;
+
+pub const CONFIG_KEYBOARD_INTERRUPT: u8 = 1 << 0;
+pub const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+pub const CONFIG_KEYBOARD_CLOCK_DISABLED: u8 = 1 << 4;
+pub const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+/// Translate scancode set 2 from the keyboard to set 1.
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const DEVICE_SET_SCANCODE_SET: u8 = 0xF0;
+const DEVICE_RESET: u8 = 0xFF;
+/// Devices send this after a reset when their self-test passed.
+const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Devices acknowledge every command byte with this.
+pub const ACK: u8 = 0xFA;
+/// Devices ask for the last byte again with this.
+pub const RESEND: u8 = 0xFE;
+
+const TIMEOUT_NS: u64 = 20_000_000;
+/// Devices may take a while to test themselves after a reset.
+const RESET_TIMEOUT_NS: u64 = 1_000_000_000;
+const RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or the device didn't respond in time.
+    Timeout,
+    /// The device answered a command with something other than `ACK`.
+    NotAcknowledged(u8),
+    /// A self-test of the controller, a port or a device failed with this result.
+    TestFailed(u8),
+}
+
+/// The scancodes keyboard input arrives in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeMode {
+    /// Set 2 from the keyboard translated to set 1 by the controller, as
+    /// most firmware leaves it.
+    Translated,
+    /// Set 2 as the keyboard sends it.
+    Set2,
+}
+
+/// What `init` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Devices {
+    /// Whether the controller has an auxiliary port.
+    pub dual_channel: bool,
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub scancodes: ScancodeMode,
+}
+
+static DEVICES: Mutex<Option<Devices>> = Mutex::new(None);
+
+pub fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_COMMAND_PORT).read() }
+}
+
+fn wait_for(timeout_ns: u64, condition: impl Fn(u8) -> bool) -> Result<(), Ps2Error> {
+    if time::wait_until(timeout_ns, || condition(status())) {
+        Ok(())
+    } else {
+        Err(Ps2Error::Timeout)
+    }
+}
+
+/// Read the next byte from the output buffer, from either port.
+///
+/// The functions here poll the data port, so callers must keep the keyboard
+/// and mouse interrupt handlers from reading it at the same time, e.g. by
+/// running with interrupts disabled.
+pub fn read_data() -> Result<u8, Ps2Error> {
+    wait_for(TIMEOUT_NS, |status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+/// Read the next byte from one port, dropping bytes from the other.
+fn read_from(aux: bool, timeout_ns: u64) -> Result<u8, Ps2Error> {
+    loop {
+        wait_for(timeout_ns, |status| status & STATUS_OUTPUT_FULL != 0)?;
+        let from_aux = status() & STATUS_AUX_DATA != 0;
+        let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+        if from_aux == aux {
+            return Ok(byte);
+        }
+    }
+}
+
+/// Read the next byte from the auxiliary port, dropping keyboard bytes.
+pub fn read_aux() -> Result<u8, Ps2Error> {
+    read_from(true, TIMEOUT_NS)
+}
+
+/// Read the next byte from the keyboard, dropping auxiliary bytes.
+pub fn read_keyboard() -> Result<u8, Ps2Error> {
+    read_from(false, TIMEOUT_NS)
+}
+
+pub fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_for(TIMEOUT_NS, |status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+pub fn write_command(command: u8) -> Result<(), Ps2Error> {
+    wait_for(TIMEOUT_NS, |status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(STATUS_COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+/// Discard whatever is waiting in the output buffer.
+pub fn flush() {
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        unsafe { Port::<u8>::new(DATA_PORT).read() };
+    }
+}
+
+pub fn read_config() -> Result<u8, Ps2Error> {
+    write_command(COMMAND_READ_CONFIG)?;
+    read_data()
+}
+
+pub fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Send `byte` to the device on one port and wait for its `ACK`.
+fn send(aux: bool, byte: u8) -> Result<(), Ps2Error> {
+    let mut response = RESEND;
+    for _ in 0..RETRIES {
+        if aux {
+            write_command(COMMAND_WRITE_AUX)?;
+        }
+        write_data(byte)?;
+        response = read_from(aux, TIMEOUT_NS)?;
+        if response != RESEND {
+            break;
+        }
+    }
+    match response {
+        ACK => Ok(()),
+        other => Err(Ps2Error::NotAcknowledged(other)),
+    }
+}
+
+/// Send `byte` to the device on the auxiliary port and wait for its `ACK`.
+pub fn send_aux(byte: u8) -> Result<(), Ps2Error> {
+    send(true, byte)
+}
+
+/// Send `byte` to the keyboard and wait for its `ACK`.
+pub fn send_keyboard(byte: u8) -> Result<(), Ps2Error> {
+    send(false, byte)
+}
+
+/// Reset the device on one port and wait for its self-test.
+fn reset_device(aux: bool) -> Result<(), Ps2Error> {
+    send(aux, DEVICE_RESET)?;
+    match read_from(aux, RESET_TIMEOUT_NS)? {
+        DEVICE_SELF_TEST_PASSED => {}
+        other => return Err(Ps2Error::TestFailed(other)),
+    }
+    if aux {
+        // mice follow up with their device ID
+        read_from(true, TIMEOUT_NS)?;
+    }
+    Ok(())
+}
+
+fn test_port(command: u8) -> Result<(), Ps2Error> {
+    write_command(command)?;
+    match read_data()? {
+        PORT_OK => Ok(()),
+        other => Err(Ps2Error::TestFailed(other)),
+    }
+}
+
+fn configure(scancodes: ScancodeMode) -> Result<Devices, Ps2Error> {
+    // keep the devices quiet while the controller is set up
+    write_command(COMMAND_DISABLE_KEYBOARD)?;
+    write_command(COMMAND_DISABLE_AUX)?;
+    flush();
+
+    let config =
+        read_config()? & !(CONFIG_KEYBOARD_INTERRUPT | CONFIG_AUX_INTERRUPT | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    write_command(COMMAND_TEST_CONTROLLER)?;
+    match read_data()? {
+        CONTROLLER_OK => {}
+        other => return Err(Ps2Error::TestFailed(other)),
+    }
+    // the self-test resets some controllers
+    write_config(config)?;
+
+    // enabling the auxiliary port only starts its clock if there is one
+    write_command(COMMAND_ENABLE_AUX)?;
+    let dual_channel = read_config()? & CONFIG_AUX_CLOCK_DISABLED == 0;
+    write_command(COMMAND_DISABLE_AUX)?;
+
+    let mut keyboard = true;
+    if let Err(error) = test_port(COMMAND_TEST_KEYBOARD_PORT) {
+        warn!("PS/2 keyboard port failed its test: {:?}", error);
+        keyboard = false;
+    }
+    let mut mouse = dual_channel;
+    if mouse {
+        if let Err(error) = test_port(COMMAND_TEST_AUX_PORT) {
+            warn!("PS/2 mouse port failed its test: {:?}", error);
+            mouse = false;
+        }
+    }
+
+    if keyboard {
+        write_command(COMMAND_ENABLE_KEYBOARD)?;
+        let setup = reset_device(false).and_then(|_| {
+            send_keyboard(DEVICE_SET_SCANCODE_SET)?;
+            send_keyboard(2)
+        });
+        if let Err(error) = setup {
+            warn!("No PS/2 keyboard: {:?}", error);
+            keyboard = false;
+        }
+    }
+    if mouse {
+        write_command(COMMAND_ENABLE_AUX)?;
+        if let Err(error) = reset_device(true) {
+            warn!("No PS/2 mouse: {:?}", error);
+            mouse = false;
+        }
+    }
+
+    // the mouse driver turns the mouse interrupt on once it has set it up
+    let mut config = read_config()? & !CONFIG_AUX_INTERRUPT;
+    if keyboard {
+        config |= CONFIG_KEYBOARD_INTERRUPT;
+    }
+    if scancodes == ScancodeMode::Translated {
+        config |= CONFIG_TRANSLATION;
+    }
+    write_config(config)?;
+
+    Ok(Devices {
+        dual_channel,
+        keyboard,
+        mouse,
+        scancodes,
+    })
+}
+
+/// Test the controller, reset the keyboard and mouse and set the keyboard up
+/// to send `scancodes`.
+pub fn init(scancodes: ScancodeMode) {
+    match interrupts::without_interrupts(|| configure(scancodes)) {
+        Ok(devices) => {
+            keyboard::set_scancode_mode(scancodes);
+            log!(
+                "PS/2 controller: keyboard {}, mouse {}",
+                if devices.keyboard { "found" } else { "missing" },
+                if devices.mouse { "found" } else { "missing" }
+            );
+            *DEVICES.lock() = Some(devices);
+        }
+        Err(error) => {
+            warn!("PS/2 controller failed: {:?}", error);
+            *DEVICES.lock() = None;
+        }
+    }
+}
+
+/// The devices found by `init`, if the controller works.
+pub fn devices() -> Option<Devices> {
+    *DEVICES.lock()
+}
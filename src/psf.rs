@@ -0,0 +1,205 @@
+// PC Screen Font (PSF1 and PSF2) bitmap fonts
+use alloc::vec::Vec;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE_512: u8 = 1 << 0;
+const PSF1_MODE_HAS_TABLE: u8 = 1 << 1;
+/// Ends the Unicode entries of a glyph in a PSF1 table.
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+/// Starts the sequences of combining characters, which are skipped.
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_TABLE: u32 = 1 << 0;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    /// Neither the PSF1 nor the PSF2 magic number.
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The header or the glyphs don't fit in the data.
+    Truncated,
+    /// Glyphs of zero size, or too small for their width and height.
+    BadGlyphSize,
+}
+
+/// A bitmap font. Each glyph is `height` rows of `bytes_per_row` bytes,
+/// with the leftmost pixel in the top bit.
+pub struct Font {
+    glyphs: &'static [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    /// Characters and the glyphs showing them, sorted by character.
+    unicode: Vec<(char, u32)>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl Font {
+    /// Parse a PSF1 or PSF2 font.
+    pub fn parse(data: &'static [u8]) -> Result<Font, PsfError> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Font::parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Font::parse_psf2(data)
+        } else {
+            Err(PsfError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &'static [u8]) -> Result<Font, PsfError> {
+        let (mode, height) = match data.get(2..PSF1_HEADER_SIZE) {
+            Some(&[mode, height]) => (mode, height as usize),
+            _ => return Err(PsfError::Truncated),
+        };
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let mut font =
+            Font::from_bitmaps(&data[PSF1_HEADER_SIZE..], glyph_count, 8, height, height)?;
+        if mode & PSF1_MODE_HAS_TABLE != 0 {
+            let table = &data[PSF1_HEADER_SIZE + glyph_count * height..];
+            let mut glyph = 0;
+            let mut in_sequence = false;
+            for entry in table.chunks_exact(2) {
+                match u16::from_le_bytes([entry[0], entry[1]]) {
+                    PSF1_SEPARATOR => {
+                        glyph += 1;
+                        in_sequence = false;
+                        // anything after the last glyph's entries is ignored
+                        if glyph == glyph_count as u32 {
+                            break;
+                        }
+                    }
+                    PSF1_START_SEQUENCE => in_sequence = true,
+                    code if !in_sequence => {
+                        if let Some(character) = char::from_u32(code as u32) {
+                            font.unicode.push((character, glyph));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            font.sort_unicode();
+        }
+        Ok(font)
+    }
+
+    fn parse_psf2(data: &'static [u8]) -> Result<Font, PsfError> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(PsfError::Truncated);
+        }
+        let field = |index: usize| read_u32(data, 4 + 4 * index) as usize;
+        let (version, header_size, flags) = (field(0), field(1), field(2));
+        let (glyph_count, bytes_per_glyph, height, width) =
+            (field(3), field(4), field(5), field(6));
+        if version != 0 {
+            return Err(PsfError::UnsupportedVersion(version as u32));
+        }
+        let glyphs = data.get(header_size..).ok_or(PsfError::Truncated)?;
+        let mut font = Font::from_bitmaps(glyphs, glyph_count, width, height, bytes_per_glyph)?;
+        if flags as u32 & PSF2_HAS_TABLE != 0 {
+            let table = &glyphs[glyph_count * bytes_per_glyph..];
+            let entries = table.split(|&byte| byte == PSF2_SEPARATOR);
+            for (glyph, entries) in entries.take(glyph_count).enumerate() {
+                // entries are UTF-8, followed by sequences after a 0xFE
+                let single = entries.split(|&byte| byte == PSF2_START_SEQUENCE).next();
+                let Some(Ok(characters)) = single.map(core::str::from_utf8) else {
+                    continue;
+                };
+                for character in characters.chars() {
+                    font.unicode.push((character, glyph as u32));
+                }
+            }
+            font.sort_unicode();
+        }
+        Ok(font)
+    }
+
+    /// A font of `glyph_count` glyphs stored one after the other, such as the
+    /// one of the VGA text mode, without a Unicode table.
+    pub fn from_bitmaps(
+        glyphs: &'static [u8],
+        glyph_count: usize,
+        width: usize,
+        height: usize,
+        bytes_per_glyph: usize,
+    ) -> Result<Font, PsfError> {
+        match width.div_ceil(8).checked_mul(height) {
+            Some(size) if size > 0 && size <= bytes_per_glyph => {}
+            _ => return Err(PsfError::BadGlyphSize),
+        }
+        match glyph_count.checked_mul(bytes_per_glyph) {
+            Some(size) if size <= glyphs.len() => {}
+            _ => return Err(PsfError::Truncated),
+        }
+        Ok(Font {
+            glyphs,
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode: Vec::new(),
+        })
+    }
+
+    fn sort_unicode(&mut self) {
+        self.unicode
+            .sort_unstable_by_key(|&(character, _)| character);
+        self.unicode.dedup_by_key(|&mut (character, _)| character);
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    /// Whether glyphs are looked up through a Unicode table.
+    pub fn has_unicode_table(&self) -> bool {
+        !self.unicode.is_empty()
+    }
+
+    pub fn bytes_per_row(&self) -> usize {
+        self.width.div_ceil(8)
+    }
+
+    /// The glyph at `index`.
+    pub fn glyph(&self, index: usize) -> Option<&'static [u8]> {
+        if index >= self.glyph_count {
+            return None;
+        }
+        let start = index * self.bytes_per_glyph;
+        Some(&self.glyphs[start..start + self.bytes_per_row() * self.height])
+    }
+
+    /// The glyph showing `character`, looked up in the Unicode table if the
+    /// font has one and by code point otherwise.
+    pub fn glyph_for(&self, character: char) -> Option<&'static [u8]> {
+        if self.unicode.is_empty() {
+            return self.glyph(character as usize);
+        }
+        let index = self
+            .unicode
+            .binary_search_by_key(&character, |&(character, _)| character)
+            .ok()?;
+        self.glyph(self.unicode[index].1 as usize)
+    }
+
+    /// Whether the pixel at `x`, `y` of `glyph` is set.
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        glyph[y * self.bytes_per_row() + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
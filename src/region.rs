@@ -0,0 +1,207 @@
+// Lazily backed virtual memory regions
+use spin::Mutex;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory;
+
+/// Start of the virtual address range that `reserve` hands out regions from.
+pub const REGION_AREA_START: u64 = 0x_6666_0000_0000;
+const MAX_REGIONS: usize = 64;
+const PAGE_SIZE: u64 = 4096;
+
+/// A range of virtual memory that is backed by zeroed frames on first access.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The region table is full.
+    TableFull,
+    /// The requested range overlaps an existing region.
+    Overlap,
+    /// Start or size are not page aligned, or the size is zero.
+    Unaligned,
+    /// The range runs past the end of the virtual address space.
+    OutOfSpace,
+}
+
+struct RegionTable {
+    regions: [Option<Region>; MAX_REGIONS],
+    next: VirtAddr,
+}
+
+static REGIONS: Mutex<RegionTable> = Mutex::new(RegionTable {
+    regions: [None; MAX_REGIONS],
+    next: VirtAddr::new_truncate(REGION_AREA_START),
+});
+
+/// Reserve `size` bytes of writable virtual memory without backing it yet.
+///
+/// The size is rounded up to whole pages. Frames are only allocated when a
+/// page is first touched.
+pub fn reserve(name: &'static str, size: u64) -> Result<VirtAddr, RegionError> {
+    if size == 0 {
+        return Err(RegionError::Unaligned);
+    }
+    let size = align_up(size).ok_or(RegionError::OutOfSpace)?;
+    let mut table = REGIONS.lock();
+    let start = table.next;
+    let region = new_region(name, start, size, PageTableFlags::WRITABLE)?;
+    // leave an unmapped page between regions to catch overruns
+    let next = end_plus(region.end, PAGE_SIZE)?;
+    insert(&mut table, region)?;
+    table.next = next;
+    Ok(start)
+}
+
+/// Reserve the range `start..start + size` with the given page flags.
+///
+/// This can be used for regions at fixed addresses, e.g. a heap that grows
+/// into its reserved range.
+pub fn reserve_at(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), RegionError> {
+    if size == 0 || !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(RegionError::Unaligned);
+    }
+    let region = new_region(name, start, size, flags)?;
+    insert(&mut REGIONS.lock(), region)
+}
+
+fn new_region(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Region, RegionError> {
+    Ok(Region {
+        name,
+        start,
+        end: end_plus(start, size)?,
+        flags: flags | PageTableFlags::PRESENT,
+    })
+}
+
+/// `addr + size`, if that is still a canonical address.
+fn end_plus(addr: VirtAddr, size: u64) -> Result<VirtAddr, RegionError> {
+    addr.as_u64()
+        .checked_add(size)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(RegionError::OutOfSpace)
+}
+
+fn insert(table: &mut RegionTable, region: Region) -> Result<(), RegionError> {
+    let overlaps = table
+        .regions
+        .iter()
+        .flatten()
+        .any(|other| region.start < other.end && other.start < region.end);
+    if overlaps {
+        return Err(RegionError::Overlap);
+    }
+    let slot = table
+        .regions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegionError::TableFull)?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/// Returns the region containing `addr`, if any.
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    REGIONS
+        .try_lock()?
+        .regions
+        .iter()
+        .flatten()
+        .find(|region| region.contains(addr))
+        .copied()
+}
+
+/// Try to resolve a page fault at `addr` by backing it with a zeroed frame.
+///
+/// Returns `true` if the faulting access can be retried. Protection
+/// violations and accesses outside of any region are never resolved.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let region = match find(addr) {
+        Some(region) => region,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return false;
+    }
+
+    // the fault may have happened while the kernel memory was locked
+    let mut memory = match memory::KERNEL_MEMORY.try_lock() {
+        Some(memory) => memory,
+        None => return false,
+    };
+    let memory = match memory.as_mut() {
+        Some(memory) => memory,
+        None => return false,
+    };
+
+    let frame = match memory.frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        let frame_ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        core::ptr::write_bytes(frame_ptr, 0, PAGE_SIZE as usize);
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe {
+        memory
+            .mapper
+            .map_to(page, frame, region.flags, &mut memory.frame_allocator)
+    } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+fn align_up(size: u64) -> Option<u64> {
+    Some(size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
@@ -0,0 +1,277 @@
+// Symmetric multiprocessing: starting the application processors
+use alloc::format;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{acpi, apic, gdt, interrupts, log, memory, percpu, pit, stack, syscall, warn};
+
+/// Physical address the trampoline is copied to. It must be page aligned and
+/// below 1 MiB, since the startup IPI only carries the page number.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+/// Size of each application processor's kernel stack in pages.
+const AP_STACK_PAGES: u64 = 16;
+
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// Real mode entry point of the application processors. It is copied to
+// `TRAMPOLINE_ADDR`, so all addresses in it are computed relative to that.
+// It switches to long mode through protected mode with the kernel's page
+// table and calls the entry point stored in the parameter block at its end.
+core::arch::global_asm!(
+    r#"
+.section .text.smp_trampoline, "ax"
+.global smp_trampoline_start
+.global smp_trampoline_params
+.global smp_trampoline_end
+
+.code16
+smp_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    lgdt [trampoline_gdt_ptr_addr]
+
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    # far jump to the 32-bit code segment
+    .byte 0x66, 0xea
+    .long smp_trampoline_32 - smp_trampoline_start + {base}
+    .word 0x08
+
+.code32
+smp_trampoline_32:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    # enable PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    # only 32 bits, so `init` checks that the page table is below 4 GiB
+    mov eax, [trampoline_params_addr]
+    mov cr3, eax
+
+    # enable long mode and no-execute pages
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    # enable paging and write protection
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    # far jump to the 64-bit code segment
+    .byte 0xea
+    .long smp_trampoline_64 - smp_trampoline_start + {base}
+    .word 0x18
+
+.code64
+smp_trampoline_64:
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, [trampoline_params_addr + 8]
+    mov rdi, [trampoline_params_addr + 24]
+    mov rax, [trampoline_params_addr + 16]
+    call rax
+2:
+    hlt
+    jmp 2b
+
+.align 16
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+smp_trampoline_gdt_ptr:
+    .word smp_trampoline_gdt_ptr - smp_trampoline_gdt - 1
+    .long smp_trampoline_gdt - smp_trampoline_start + {base}
+
+.align 8
+# filled in by `start_ap`: page table, stack top, entry point, CPU index
+smp_trampoline_params:
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad 0
+smp_trampoline_end:
+
+# absolute addresses of the data above once the trampoline is copied
+.set trampoline_gdt_ptr_addr, smp_trampoline_gdt_ptr - smp_trampoline_start + {base}
+.set trampoline_params_addr, smp_trampoline_params - smp_trampoline_start + {base}
+
+.text
+"#,
+    base = const TRAMPOLINE_ADDR,
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_params: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// Parameters read by the trampoline in long mode.
+#[repr(C)]
+struct TrampolineParams {
+    page_table: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_id: u64,
+}
+
+/// Number of CPUs that are up and running, including the bootstrap processor.
+pub fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::SeqCst)
+}
+
+/// Set up per-CPU data for the bootstrap processor and start all application
+/// processors listed in the MADT.
+///
+/// Requires `apic::init` to have run; without an APIC only the bootstrap
+/// processor is used.
+pub fn init() {
+    let bsp_apic_id = if apic::is_enabled() {
+        apic::local_apic_id()
+    } else {
+        0
+    };
+    percpu::init(0, bsp_apic_id, gdt::bsp_tss());
+    syscall::init();
+    log!("CPU 0 (APIC ID {}) online", bsp_apic_id);
+
+    let madt = match acpi::info().and_then(|info| info.madt.as_ref()) {
+        Some(madt) if apic::is_enabled() => madt,
+        _ => return,
+    };
+    let application_processors = madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != bsp_apic_id);
+    if application_processors.clone().count() == 0 {
+        return;
+    }
+
+    // the trampoline loads CR3 while still in 32-bit mode
+    let (page_table, _) = Cr3::read();
+    if page_table.start_address().as_u64() >= 1 << 32 {
+        warn!("Page table above 4 GiB, not starting application processors");
+        return;
+    }
+
+    log!("Starting application processors");
+    let trampoline = install_trampoline();
+    for (index, processor) in application_processors.enumerate() {
+        let cpu_id = index + 1;
+        if !start_ap(trampoline, cpu_id, processor.apic_id) {
+            warn!("CPU {} (APIC ID {}) did not start", cpu_id, processor.apic_id);
+        }
+    }
+    remove_trampoline();
+    log!("{} CPUs online", cpus_online());
+}
+
+/// Copy the trampoline to low memory and identity map it, so that it keeps
+/// running when the application processor enables paging.
+fn install_trampoline() -> VirtAddr {
+    let (start, params, end) = (
+        &raw const smp_trampoline_start as usize,
+        &raw const smp_trampoline_params as usize,
+        &raw const smp_trampoline_end as usize,
+    );
+    assert!(end - start <= 4096, "SMP trampoline doesn't fit in a page");
+
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => panic!("failed to identity map the SMP trampoline: {:?}", err),
+        }
+    });
+
+    let target = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR));
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, target.as_mut_ptr(), end - start);
+    }
+    target + (params - start) as u64
+}
+
+fn remove_trampoline() {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    memory::with_kernel_memory(|mapper, _| {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    });
+}
+
+/// Boot one application processor with INIT-SIPI-SIPI and wait for it to
+/// come online. Returns `false` on timeout.
+fn start_ap(params: VirtAddr, cpu_id: usize, apic_id: u8) -> bool {
+    let name = format!("CPU {} stack", cpu_id).leak();
+    let stack = stack::allocate(name, AP_STACK_PAGES).expect("AP stack allocation failed");
+
+    let (page_table, _) = Cr3::read();
+    unsafe {
+        params
+            .as_mut_ptr::<TrampolineParams>()
+            .write_volatile(TrampolineParams {
+                page_table: page_table.start_address().as_u64(),
+                stack_top: stack.top().as_u64(),
+                entry: ap_entry as *const () as u64,
+                cpu_id: cpu_id as u64,
+            });
+    }
+
+    let online_before = cpus_online();
+    apic::send_init(apic_id);
+    pit::wait_micros(10_000);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        pit::wait_micros(200);
+    }
+
+    // give the processor up to 100 ms to come up
+    for _ in 0..100 {
+        if cpus_online() > online_before {
+            return true;
+        }
+        pit::wait_micros(1000);
+    }
+    false
+}
+
+/// Long mode entry point of the application processors, called by the trampoline.
+extern "C" fn ap_entry(cpu_id: u64) -> ! {
+    let cpu_id = cpu_id as usize;
+    memory::init_pat();
+    let tss = gdt::init_ap(cpu_id);
+    interrupts::init_idt();
+    apic::enable_local_apic();
+    let apic_id = apic::local_apic_id();
+    percpu::init(cpu_id, apic_id, tss);
+    syscall::init();
+
+    log!("CPU {} (APIC ID {}) online", cpu_id, apic_id);
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    // external interrupts are routed to the bootstrap processor only, so the
+    // application processors just idle for now
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop()
+}
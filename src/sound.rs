@@ -0,0 +1,189 @@
+// PC speaker sound effects and melodies
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::{pit, task, time};
+
+/// How often the player checks whether the current note is over.
+pub const UPDATE_INTERVAL_MS: u64 = 10;
+
+/// Frequencies of the notes C4 to B4 in hundredths of a Hz.
+const OCTAVE_4: [u32; 12] = [
+    26163, 27718, 29366, 31113, 32963, 34923, 36999, 39200, 41530, 44000, 46616, 49388,
+];
+
+/// A tone, or a rest if `frequency` is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub frequency: u32,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundError {
+    /// The token at this index of the notation isn't a note or rest.
+    InvalidToken(usize),
+}
+
+/// Frequency in Hz of the note `name` ('a' to 'g') in `octave`, raised by
+/// `semitones`.
+pub fn note_frequency(name: char, semitones: i32, octave: u32) -> Option<u32> {
+    let base = match name.to_ascii_lowercase() {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    if octave > 8 {
+        return None;
+    }
+    let semitone = base + semitones;
+    let octave = octave as i32 + semitone.div_euclid(12);
+    let centi_hz = OCTAVE_4[semitone.rem_euclid(12) as usize] as u64;
+    let centi_hz = match octave {
+        0..=3 => centi_hz >> (4 - octave),
+        4..=9 => centi_hz << (octave - 4),
+        _ => return None,
+    };
+    Some(((centi_hz + 50) / 100) as u32)
+}
+
+/// Parse one token of `parse`.
+fn parse_note(token: &str, tempo: u32) -> Option<Note> {
+    let (pitch, length) = token.split_once('/').unwrap_or((token, "4"));
+    let (length, dotted) = match length.strip_suffix('.') {
+        Some(length) => (length, true),
+        None => (length, false),
+    };
+    let length: u64 = length
+        .parse()
+        .ok()
+        .filter(|length| [1, 2, 4, 8, 16, 32].contains(length))?;
+    // a whole note lasts four beats
+    let mut duration_ms = 4 * 60_000 / (tempo.max(1) as u64 * length);
+    if dotted {
+        duration_ms += duration_ms / 2;
+    }
+
+    let mut chars = pitch.chars();
+    let name = chars.next()?;
+    if name == 'r' {
+        return chars.next().is_none().then_some(Note {
+            frequency: 0,
+            duration_ms,
+        });
+    }
+    let rest = chars.as_str();
+    let (semitones, octave) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave = if octave.is_empty() {
+        4
+    } else {
+        octave.parse().ok()?
+    };
+    Some(Note {
+        frequency: note_frequency(name, semitones, octave)?,
+        duration_ms,
+    })
+}
+
+/// Parse a melody written as space separated notes.
+///
+/// A note is a letter from `a` to `g`, an optional `#` or `b`, an optional
+/// octave (4 if left out) and an optional length after a `/`: 1 for a whole
+/// note, 2 for a half note and so on down to 32, with a `.` to make it half
+/// as long again. Quarter notes are the default. `r` is a rest, so
+/// `"c4/8 e4/8 g4/4. r/8 c5/2"` is a rising arpeggio. `tempo` is in quarter
+/// notes per minute.
+pub fn parse(notation: &str, tempo: u32) -> Result<Vec<Note>, SoundError> {
+    notation
+        .split_whitespace()
+        .enumerate()
+        .map(|(index, token)| {
+            parse_note(&token.to_ascii_lowercase(), tempo).ok_or(SoundError::InvalidToken(index))
+        })
+        .collect()
+}
+
+struct Player {
+    notes: VecDeque<Note>,
+    /// Uptime at which the current note is over.
+    note_end_ms: u64,
+    playing: bool,
+}
+
+static PLAYER: Mutex<Player> = Mutex::new(Player {
+    notes: VecDeque::new(),
+    note_end_ms: 0,
+    playing: false,
+});
+
+/// Start the next note once the current one is over.
+fn update() {
+    let mut player = PLAYER.lock();
+    let now = time::uptime_ms();
+    if !player.playing || now < player.note_end_ms {
+        return;
+    }
+    match player.notes.pop_front() {
+        Some(note) => {
+            if note.frequency == 0 {
+                pit::stop_tone();
+            } else {
+                pit::start_tone(note.frequency);
+            }
+            player.note_end_ms = now + note.duration_ms;
+        }
+        None => {
+            pit::stop_tone();
+            player.playing = false;
+        }
+    }
+}
+
+/// Play `notes` in the background, replacing whatever is playing.
+pub fn play(notes: Vec<Note>) {
+    {
+        let mut player = PLAYER.lock();
+        player.notes = notes.into();
+        player.note_end_ms = 0;
+        player.playing = true;
+    }
+    update();
+}
+
+/// Parse `notation` like `parse` and play it in the background.
+pub fn play_notation(notation: &str, tempo: u32) -> Result<(), SoundError> {
+    play(parse(notation, tempo)?);
+    Ok(())
+}
+
+/// Sound `frequency` Hz for `duration_ms` without waiting for it to end.
+pub fn beep(frequency: u32, duration_ms: u64) {
+    play(alloc::vec![Note {
+        frequency,
+        duration_ms,
+    }]);
+}
+
+/// Silence the speaker and drop the rest of the melody.
+pub fn stop() {
+    play(Vec::new());
+}
+
+pub fn is_playing() -> bool {
+    PLAYER.lock().playing
+}
+
+/// Start the task that moves melodies along.
+pub fn init() {
+    task::spawn_periodic("sound", UPDATE_INTERVAL_MS, update);
+}
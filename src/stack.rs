@@ -0,0 +1,157 @@
+// Kernel stacks with guard pages
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRange, FrameAllocator, FrameDeallocator, Mapper, Page,
+        PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory;
+
+/// Start of the virtual address range that kernel stacks are allocated from.
+pub const STACK_AREA_START: u64 = 0x_5555_5555_0000;
+const MAX_STACKS: usize = 64;
+
+/// A kernel stack mapped in virtual memory with an unmapped guard page below it.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    name: &'static str,
+    guard: Page,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// The name reported when this stack overflows.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The initial stack pointer, i.e. the first address above the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        (self.guard + 1).start_address()
+    }
+
+    /// The unmapped page right below the stack.
+    pub fn guard_page(&self) -> Page {
+        self.guard
+    }
+}
+
+struct StackTable {
+    stacks: [Option<KernelStack>; MAX_STACKS],
+    len: usize,
+    next: VirtAddr,
+}
+
+static STACKS: Mutex<StackTable> = Mutex::new(StackTable {
+    stacks: [None; MAX_STACKS],
+    len: 0,
+    next: VirtAddr::new_truncate(STACK_AREA_START),
+});
+
+/// Allocate a stack of `pages` pages through the global kernel memory.
+pub fn allocate(name: &'static str, pages: u64) -> Result<KernelStack, MapToError<Size4KiB>> {
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        allocate_with(name, pages, mapper, frame_allocator)
+    })
+}
+
+/// Allocate a stack of `pages` pages using the given mapper and frame allocator.
+///
+/// The page below the stack is left unmapped, so running off the end of the
+/// stack causes a page fault that `guard_hit` can attribute to this stack.
+/// If mapping fails, the pages mapped so far are unmapped and their frames
+/// freed again.
+pub fn allocate_with(
+    name: &'static str,
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<KernelStack, MapToError<Size4KiB>> {
+    let mut table = STACKS.lock();
+    if table.len >= MAX_STACKS {
+        panic!("too many kernel stacks");
+    }
+
+    let guard = Page::containing_address(table.next);
+    let bottom = guard + 1;
+    let end = bottom + pages;
+
+    for page in Page::range(bottom, end) {
+        if let Err(err) = map_page(page, mapper, frame_allocator) {
+            unmap_pages(Page::range(bottom, page), mapper, frame_allocator);
+            return Err(err);
+        }
+    }
+
+    // The first page after this stack stays unmapped and becomes the guard
+    // page of the next one.
+    table.next = end.start_address();
+
+    let stack = KernelStack {
+        name,
+        guard,
+        top: end.start_address(),
+    };
+    let index = table.len;
+    table.stacks[index] = Some(stack);
+    table.len += 1;
+
+    Ok(stack)
+}
+
+fn map_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/// Undo `map_page` for every page in `pages`.
+fn unmap_pages(
+    pages: PageRange,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Returns the name of the stack whose guard page contains `addr`, if any.
+///
+/// This is called from fault handlers, so it gives up instead of spinning if
+/// the stack table is currently locked.
+pub fn guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let table = STACKS.try_lock()?;
+    table.stacks[..table.len]
+        .iter()
+        .flatten()
+        .find(|stack| stack.guard == page)
+        .map(|stack| stack.name)
+}
@@ -0,0 +1,241 @@
+// System calls through the `syscall` instruction
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::process::{self, Pid, ProcessError};
+use crate::vfs::{self, VfsError};
+use crate::{console, gdt, memory, percpu, print, serial, time, usermode};
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_YIELD: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_GETPID: u64 = 5;
+pub const SYS_GETPPID: u64 = 6;
+pub const SYS_SPAWN: u64 = 7;
+pub const SYS_WAIT: u64 = 8;
+
+// Errors are returned as negative values.
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EIO: i64 = 5;
+pub const ENOEXEC: i64 = 8;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENOSYS: i64 = 38;
+
+/// Longest pause `sleep` accepts, about 49 days.
+pub const MAX_SLEEP_MS: u64 = u32::MAX as u64;
+
+/// A system call takes up to three arguments from `rdi`, `rsi` and `rdx`.
+type Handler = fn(u64, u64, u64) -> i64;
+
+static SYSCALL_TABLE: [Handler; 9] = [
+    sys_read,
+    sys_write,
+    sys_exit,
+    sys_yield,
+    sys_sleep,
+    sys_getpid,
+    sys_getppid,
+    sys_spawn,
+    sys_wait,
+];
+
+// Entry point of the `syscall` instruction. The CPU leaves the user return
+// address in `rcx` and the user flags in `r11` but doesn't switch stacks, so
+// the kernel stack is taken from the per-CPU data.
+core::arch::global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_stack_top}]
+    push qword ptr gs:[{user_rsp}]
+    push rcx
+    push r11
+    push rdi
+    push rsi
+    push rdx
+    push r8
+    push r9
+    push r10
+    sub rsp, 8
+    mov rcx, rax
+    call {dispatch}
+    add rsp, 8
+    pop r10
+    pop r9
+    pop r8
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r11
+    pop rcx
+    cli
+    pop rsp
+    swapgs
+    sysretq
+"#,
+    user_rsp = const percpu::USER_RSP_OFFSET,
+    kernel_stack_top = const percpu::KERNEL_STACK_TOP_OFFSET,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enable the `syscall` instruction on the executing CPU.
+///
+/// Requires the GDT and the per-CPU data of this CPU.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout doesn't match syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // enter the kernel with interrupts disabled until the stack is switched
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn dispatch(arg0: u64, arg1: u64, arg2: u64, number: u64) -> i64 {
+    // the kernel stack is in place, so interrupts are safe again
+    x86_64::instructions::interrupts::enable();
+    match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => -ENOSYS,
+    }
+}
+
+/// Returns the user buffer at `ptr` if all of it is accessible to the program.
+fn user_buffer(ptr: u64, len: u64, write: bool) -> Result<&'static mut [u8], i64> {
+    let end = ptr.checked_add(len).ok_or(EFAULT)?;
+    if end > usermode::USER_SPACE_END {
+        return Err(EFAULT);
+    }
+    if len > 0 {
+        let mut page = ptr & !0xfff;
+        while page < end {
+            if !memory::is_user_accessible(VirtAddr::new(page), write) {
+                return Err(EFAULT);
+            }
+            page += 0x1000;
+        }
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+/// `read(fd, buf, len)`: read one typed character from the console (fd 0).
+/// Blocks until a character is available and returns the number of bytes read.
+fn sys_read(fd: u64, buf: u64, len: u64) -> i64 {
+    if fd != 0 {
+        return -EBADF;
+    }
+    let buf = match user_buffer(buf, len, true) {
+        Ok(buf) => buf,
+        Err(err) => return -err,
+    };
+    let character = console::get_char();
+    let mut bytes = [0; 4];
+    let encoded = character.encode_utf8(&mut bytes).as_bytes();
+    if encoded.len() > buf.len() {
+        return -EINVAL;
+    }
+    buf[..encoded.len()].copy_from_slice(encoded);
+    encoded.len() as i64
+}
+
+/// `write(fd, buf, len)`: write to the screen (fd 1) or the serial port (fd 2).
+fn sys_write(fd: u64, buf: u64, len: u64) -> i64 {
+    let buf = match user_buffer(buf, len, false) {
+        Ok(buf) => buf,
+        Err(err) => return -err,
+    };
+    let text = match core::str::from_utf8(buf) {
+        Ok(text) => text,
+        Err(_) => return -EINVAL,
+    };
+    match fd {
+        1 => print!("{}", text),
+        2 => serial::_print(format_args!("{}", text)),
+        _ => return -EBADF,
+    }
+    len as i64
+}
+
+/// `exit(code)`: end the program and return `code` to the kernel.
+fn sys_exit(code: u64, _: u64, _: u64) -> i64 {
+    usermode::exit(code as i64)
+}
+
+/// `yield()`: give up the CPU until the next interrupt.
+fn sys_yield(_: u64, _: u64, _: u64) -> i64 {
+    x86_64::instructions::hlt();
+    0
+}
+
+/// `sleep(ms)`: pause the program for `ms` milliseconds, at most `MAX_SLEEP_MS`.
+fn sys_sleep(ms: u64, _: u64, _: u64) -> i64 {
+    if ms > MAX_SLEEP_MS {
+        return -EINVAL;
+    }
+    time::sleep_ms(ms);
+    0
+}
+
+/// `getpid()`: the PID of the calling process, 0 outside of a process.
+fn sys_getpid(_: u64, _: u64, _: u64) -> i64 {
+    process::current().map_or(0, |pid| pid.as_u64() as i64)
+}
+
+/// `getppid()`: the PID of the parent process, 0 if the kernel started it.
+fn sys_getppid(_: u64, _: u64, _: u64) -> i64 {
+    let parent = process::current().and_then(|pid| process::parent(pid).ok().flatten());
+    parent.map_or(0, |pid| pid.as_u64() as i64)
+}
+
+/// `spawn(path, len)`: load the executable at `path` as a child of the
+/// caller and return its PID. The child runs once the caller waits for it.
+fn sys_spawn(path: u64, len: u64, _: u64) -> i64 {
+    let path = match user_buffer(path, len, false) {
+        Ok(path) => path,
+        Err(err) => return -err,
+    };
+    let Ok(path) = core::str::from_utf8(path) else {
+        return -EINVAL;
+    };
+    let image = match vfs::read_to_vec(path) {
+        Ok(image) => image,
+        Err(VfsError::NotFound) => return -ENOENT,
+        Err(_) => return -EIO,
+    };
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match process::spawn(name, &image, &[path], &[]) {
+        Ok(pid) => pid.as_u64() as i64,
+        Err(_) => -ENOEXEC,
+    }
+}
+
+/// `wait(pid)`: run the child `pid` until it exits and return its exit code,
+/// -1 if it was killed.
+fn sys_wait(pid: u64, _: u64, _: u64) -> i64 {
+    match process::wait(Pid::from_u64(pid)) {
+        Ok(usermode::ExitStatus::Exited(code)) => code,
+        Ok(usermode::ExitStatus::Killed(_)) => -1,
+        Err(ProcessError::NotAChild) => -ECHILD,
+        Err(ProcessError::NotExited) => -EAGAIN,
+        Err(_) => -ESRCH,
+    }
+}
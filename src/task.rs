@@ -0,0 +1,102 @@
+// Periodic kernel tasks driven by the timer
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time;
+
+struct Task {
+    name: &'static str,
+    period_ms: u64,
+    next_run_ms: u64,
+    run: Arc<dyn Fn() + Send + Sync>,
+}
+
+static TASKS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+/// Uptime at which the next task is due.
+static NEXT_DUE_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Set by the timer once a task is due.
+static PENDING: AtomicBool = AtomicBool::new(false);
+/// Keeps `run_pending` from running tasks inside tasks.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Run `run` every `period_ms` milliseconds.
+///
+/// Tasks don't run in the timer interrupt but the next time the kernel is
+/// idle, so they may take locks and wait for devices.
+pub fn spawn_periodic(name: &'static str, period_ms: u64, run: impl Fn() + Send + Sync + 'static) {
+    let next_run_ms = time::uptime_ms() + period_ms;
+    interrupts::without_interrupts(|| {
+        TASKS.lock().push(Task {
+            name,
+            period_ms,
+            next_run_ms,
+            run: Arc::new(run),
+        })
+    });
+    NEXT_DUE_MS.fetch_min(next_run_ms, Ordering::SeqCst);
+}
+
+/// Names of the registered tasks.
+pub fn names() -> Vec<&'static str> {
+    interrupts::without_interrupts(|| TASKS.lock().iter().map(|task| task.name).collect())
+}
+
+/// Called by the timer interrupt handler on every tick.
+pub fn tick() {
+    if time::uptime_ms() >= NEXT_DUE_MS.load(Ordering::Relaxed) {
+        PENDING.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Run the tasks that are due. Called wherever the kernel waits for
+/// something, outside of interrupt handlers and with no locks held.
+pub fn run_pending() {
+    if !PENDING.load(Ordering::Relaxed) || RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    PENDING.store(false, Ordering::Relaxed);
+
+    let now = time::uptime_ms();
+    let mut index = 0;
+    loop {
+        // tasks run without the lock so they can spawn more tasks
+        let due = interrupts::without_interrupts(|| {
+            let mut tasks = TASKS.lock();
+            let task = tasks.get_mut(index)?;
+            if task.next_run_ms > now {
+                return Some(None);
+            }
+            task.next_run_ms = now + task.period_ms;
+            Some(Some(task.run.clone()))
+        });
+        match due {
+            None => break,
+            Some(Some(run)) => run(),
+            Some(None) => {}
+        }
+        index += 1;
+    }
+
+    let next = interrupts::without_interrupts(|| {
+        TASKS
+            .lock()
+            .iter()
+            .map(|task| task.next_run_ms)
+            .min()
+            .unwrap_or(u64::MAX)
+    });
+    NEXT_DUE_MS.store(next, Ordering::SeqCst);
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Halt forever, running tasks as they become due. Only for the bootstrap
+/// processor: tasks wait for the timer, which the other CPUs don't get.
+pub fn idle_loop() -> ! {
+    loop {
+        run_pending();
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,199 @@
+// Kernel time keeping
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::{hpet, log, pit, task, warn};
+
+/// Frequency of the timer interrupt in Hz.
+pub const TICK_HZ: u32 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// TSC frequency in kHz, 0 until calibrated.
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value that `now_ns` counts from.
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds at `TSC_BASE`, so recalibrating doesn't make time jump.
+static NS_BASE: AtomicU64 = AtomicU64::new(0);
+/// Odd while the TSC calibration is being changed.
+static TSC_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Called by the timer interrupt handler on every tick.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer ticks since the timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer was started, with tick resolution.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ as u64
+}
+
+/// Halt until at least `ms` milliseconds have passed.
+///
+/// Requires interrupts to be enabled.
+pub fn sleep_ms(ms: u64) {
+    let end = uptime_ms().saturating_add(ms);
+    while uptime_ms() < end {
+        task::run_pending();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Wait up to `timeout_ns` for `condition` to hold and return whether it did.
+///
+/// Halts between checks if interrupts are enabled, so an interrupt that makes
+/// `condition` true, or at the latest the next timer tick, ends the wait.
+/// Spins otherwise.
+pub fn wait_until(timeout_ns: u64, condition: impl Fn() -> bool) -> bool {
+    use x86_64::instructions::interrupts;
+
+    let deadline = now_ns() + timeout_ns;
+    let halt = interrupts::are_enabled();
+    loop {
+        if halt {
+            interrupts::disable();
+        }
+        // checked with interrupts off so a wakeup can't slip in before `hlt`
+        let done = condition();
+        if done || now_ns() > deadline {
+            if halt {
+                interrupts::enable();
+            }
+            return done;
+        }
+        if halt {
+            interrupts::enable_and_hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns whether the TSC runs at a constant rate regardless of power states.
+fn tsc_is_invariant() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Measure the TSC frequency against the PIT.
+///
+/// This works without interrupts or memory management, so it is done early
+/// in `init` and refined later with `calibrate_tsc_with_hpet`.
+pub fn calibrate_tsc() {
+    const CALIBRATION_US: u64 = 10_000;
+
+    let start = rdtsc();
+    pit::wait_micros(CALIBRATION_US as u32);
+    let elapsed = rdtsc() - start;
+    set_tsc_khz(elapsed * 1000 / CALIBRATION_US);
+
+    if !tsc_is_invariant() {
+        warn!("TSC is not invariant, timing may drift");
+    }
+}
+
+/// Measure the TSC frequency against the HPET, which is more precise than the PIT.
+pub fn calibrate_tsc_with_hpet() {
+    const CALIBRATION_US: u64 = 20_000;
+
+    if !hpet::is_available() {
+        return;
+    }
+    let (tsc_start, hpet_start) = (rdtsc(), hpet::now_ns());
+    hpet::wait_micros(CALIBRATION_US);
+    let (tsc_end, hpet_end) = (rdtsc(), hpet::now_ns());
+
+    let elapsed_ns = hpet_end - hpet_start;
+    let khz = (tsc_end - tsc_start) as u128 * 1_000_000 / elapsed_ns.max(1) as u128;
+    set_tsc_khz(khz as u64);
+    log!("TSC calibrated to {} kHz", TSC_KHZ.load(Ordering::Relaxed));
+}
+
+/// Switch to a new TSC frequency, rebasing so that `now_ns` continues from
+/// the current time instead of jumping.
+fn set_tsc_khz(khz: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let tsc = rdtsc();
+        let ns = match read_calibration() {
+            (0, _, _) => 0,
+            calibration => ns_at(tsc, calibration),
+        };
+        TSC_SEQ.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        TSC_BASE.store(tsc, Ordering::Relaxed);
+        NS_BASE.store(ns, Ordering::Relaxed);
+        TSC_KHZ.store(khz.max(1), Ordering::Relaxed);
+        TSC_SEQ.fetch_add(1, Ordering::Release);
+    });
+}
+
+/// A consistent snapshot of `TSC_KHZ`, `TSC_BASE` and `NS_BASE`.
+fn read_calibration() -> (u64, u64, u64) {
+    loop {
+        let seq = TSC_SEQ.load(Ordering::Acquire);
+        if seq % 2 == 1 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let khz = TSC_KHZ.load(Ordering::Relaxed);
+        let tsc_base = TSC_BASE.load(Ordering::Relaxed);
+        let ns_base = NS_BASE.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        if TSC_SEQ.load(Ordering::Relaxed) == seq {
+            return (khz, tsc_base, ns_base);
+        }
+    }
+}
+
+fn ns_at(tsc: u64, (khz, tsc_base, ns_base): (u64, u64, u64)) -> u64 {
+    let elapsed = tsc.wrapping_sub(tsc_base);
+    ns_base + (elapsed as u128 * 1_000_000 / khz as u128) as u64
+}
+
+/// Nanoseconds since the TSC was first calibrated.
+///
+/// Calibrates the TSC against the PIT on first use if that hasn't happened yet.
+pub fn now_ns() -> u64 {
+    if TSC_KHZ.load(Ordering::Relaxed) == 0 {
+        calibrate_tsc();
+    }
+    ns_at(rdtsc(), read_calibration())
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let start = ticks();
+    while ticks() == start {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_now_ns_is_monotonic() {
+    let start = now_ns();
+    sleep_ms(5);
+    let end = now_ns();
+    assert!(end > start);
+    assert!(end - start >= 4_000_000);
+}
+
+#[test_case]
+fn test_recalibration_does_not_go_back() {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    let before = now_ns();
+    // a higher frequency would halve the time counted since the base
+    set_tsc_khz(khz * 2);
+    let after = now_ns();
+    set_tsc_khz(khz);
+    assert!(after >= before);
+    assert!(now_ns() >= after);
+}
@@ -0,0 +1,202 @@
+// Running programs in ring 3
+use alloc::format;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::{gdt, memory, percpu, stack, warn};
+
+/// End of the address range that belongs to user programs. The last page of
+/// the lower half is left out: a `syscall` at its very end would make
+/// `sysretq` return to a non-canonical address and fault in ring 0.
+pub const USER_SPACE_END: u64 = 0x0000_7fff_ffff_f000;
+/// Size of the kernel stack each CPU uses for system calls and interrupts from user mode.
+const KERNEL_STACK_PAGES: u64 = 16;
+/// Kernel stack a program started from a system call needs at least.
+const MIN_NESTED_STACK: u64 = 4 * 4096;
+
+/// How a user program ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program called `exit` with the given code.
+    Exited(i64),
+    /// The program caused the given exception and was stopped.
+    Killed(&'static str),
+}
+
+/// Kernel registers saved by `usermode_enter`: `rbx`, `rbp`, `r12`-`r15`,
+/// `rsp` and `rflags`.
+#[repr(C)]
+struct SavedRegisters([u64; 8]);
+
+/// Kernel state to return to when the running program ends.
+struct KernelContext {
+    registers: SavedRegisters,
+    killed: Option<&'static str>,
+}
+
+// `usermode_enter(entry, stack_top, arg, context)` saves the callee-saved
+// registers in `context` and drops to ring 3 through `sysret`.
+// `usermode_resume(context, result)` restores them, which makes
+// `usermode_enter` return `result`.
+core::arch::global_asm!(
+    r#"
+.global usermode_enter
+usermode_enter:
+    mov [rcx], rbx
+    mov [rcx + 8], rbp
+    mov [rcx + 16], r12
+    mov [rcx + 24], r13
+    mov [rcx + 32], r14
+    mov [rcx + 40], r15
+    mov [rcx + 48], rsp
+    pushfq
+    pop qword ptr [rcx + 56]
+
+    cli
+    mov rcx, rdi
+    mov r11, 0x202
+    mov rsp, rsi
+    mov rdi, rdx
+    # don't leak kernel values to the program
+    xor eax, eax
+    xor ebx, ebx
+    xor edx, edx
+    xor esi, esi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    swapgs
+    sysretq
+
+.global usermode_resume
+usermode_resume:
+    mov rbx, [rdi]
+    mov rbp, [rdi + 8]
+    mov r12, [rdi + 16]
+    mov r13, [rdi + 24]
+    mov r14, [rdi + 32]
+    mov r15, [rdi + 40]
+    mov rsp, [rdi + 48]
+    mov rax, rsi
+    push qword ptr [rdi + 56]
+    popfq
+    ret
+"#
+);
+
+extern "C" {
+    fn usermode_enter(entry: u64, stack_top: u64, arg: u64, saved: *mut SavedRegisters) -> i64;
+    fn usermode_resume(saved: *const SavedRegisters, result: i64) -> !;
+}
+
+/// Map `pages` zeroed pages at `start` that are accessible from user mode.
+///
+/// `flags` are added to `PRESENT | USER_ACCESSIBLE`. The pages are mapped in
+/// the kernel page table, so they are visible on every CPU.
+pub fn map_user_pages(
+    start: VirtAddr,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(
+        start.as_u64() + pages * 4096 <= USER_SPACE_END,
+        "user pages must be in the lower half"
+    );
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let first = Page::<Size4KiB>::containing_address(start);
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        for page in Page::range(first, first + pages) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                let frame_ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+                core::ptr::write_bytes(frame_ptr, 0, 4096);
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Run user code at `entry` in ring 3 on the stack ending at `stack_top`,
+/// with `arg` in `rdi`, until it exits or faults.
+///
+/// Called from a system call, the running program is suspended and the new
+/// one uses the rest of the kernel stack below the current frame.
+///
+/// Requires `syscall::init` to have run on the executing CPU.
+pub fn run(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> ExitStatus {
+    assert!(
+        entry.as_u64() < USER_SPACE_END && stack_top.as_u64() <= USER_SPACE_END,
+        "user programs must run in the lower half"
+    );
+    let per_cpu = percpu::current();
+    if per_cpu.kernel_stack_top.get() == 0 {
+        let name = format!("CPU {} user mode kernel stack", per_cpu.cpu_id).leak();
+        let stack =
+            stack::allocate(name, KERNEL_STACK_PAGES).expect("kernel stack allocation failed");
+        per_cpu.kernel_stack_top.set(stack.top().as_u64());
+    }
+
+    let outer_return = per_cpu.user_return.get();
+    let outer_stack_top = per_cpu.kernel_stack_top.get();
+    if outer_return != 0 {
+        let rsp: u64;
+        unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+        // leave room for the return address `usermode_enter` is called with
+        let nested_top = (rsp - 128) & !0xf;
+        if nested_top - (outer_stack_top - KERNEL_STACK_PAGES * 4096) < MIN_NESTED_STACK {
+            warn!("User program not started: kernel stack exhausted");
+            return ExitStatus::Killed("kernel stack exhausted");
+        }
+        per_cpu.kernel_stack_top.set(nested_top);
+    }
+    gdt::set_kernel_stack(VirtAddr::new(per_cpu.kernel_stack_top.get()));
+
+    let mut context = KernelContext {
+        registers: SavedRegisters([0; 8]),
+        killed: None,
+    };
+    let context: *mut KernelContext = &mut context;
+    per_cpu.user_return.set(context as u64);
+    let result = unsafe {
+        let saved = &raw mut (*context).registers;
+        usermode_enter(entry.as_u64(), stack_top.as_u64(), arg, saved)
+    };
+    per_cpu.user_return.set(outer_return);
+    per_cpu.kernel_stack_top.set(outer_stack_top);
+    gdt::set_kernel_stack(VirtAddr::new(outer_stack_top));
+
+    match unsafe { (*context).killed } {
+        Some(reason) => ExitStatus::Killed(reason),
+        None => ExitStatus::Exited(result),
+    }
+}
+
+/// End the running user program from a system call.
+pub(crate) fn exit(code: i64) -> ! {
+    let context = percpu::current().user_return.get() as *const KernelContext;
+    assert!(!context.is_null(), "no user program is running");
+    unsafe { usermode_resume(&raw const (*context).registers, code) }
+}
+
+/// End the running user program after it caused an exception.
+///
+/// # Safety
+///
+/// Must only be called from an exception handler that interrupted user mode,
+/// since it swaps the GS base back to the kernel's.
+pub(crate) unsafe fn kill(reason: &'static str) -> ! {
+    core::arch::asm!("swapgs", options(nomem, nostack, preserves_flags));
+    warn!("User program killed: {}", reason);
+    let context = percpu::current().user_return.get() as *mut KernelContext;
+    (*context).killed = Some(reason);
+    usermode_resume(&raw const (*context).registers, -1)
+}
@@ -0,0 +1,144 @@
+// Device file system exposing the console and the serial port
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+
+use super::{DirEntry, FileSystem, Inode, InodeKind, Metadata, Result, VfsError};
+use crate::{console, serial, vga_buffer};
+
+fn device_metadata() -> Metadata {
+    Metadata {
+        kind: InodeKind::Device,
+        size: 0,
+    }
+}
+
+/// The VGA text console. Reads wait for a key press and return one character.
+pub struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        device_metadata()
+    }
+
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let character = console::get_char();
+        let mut bytes = [0; 4];
+        let encoded = character.encode_utf8(&mut bytes).as_bytes();
+        let len = encoded.len().min(buf.len());
+        buf[..len].copy_from_slice(&encoded[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        vga_buffer::write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+/// The first serial port. Reads return what has been received so far, which
+/// may be nothing.
+pub struct Serial;
+
+impl Inode for Serial {
+    fn metadata(&self) -> Metadata {
+        device_metadata()
+    }
+
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut serial = serial::SERIAL1.lock();
+        let mut read = 0;
+        while read < buf.len() {
+            match serial.try_receive() {
+                Ok(byte) => buf[read] = byte,
+                Err(_) => break,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        interrupts::without_interrupts(|| {
+            let mut serial = serial::SERIAL1.lock();
+            for &byte in buf {
+                serial.send(byte);
+            }
+        });
+        Ok(buf.len())
+    }
+}
+
+struct Root {
+    devices: Vec<(&'static str, Arc<dyn Inode>)>,
+}
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: InodeKind::Directory,
+            size: self.devices.len(),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.devices
+            .iter()
+            .find(|(device, _)| *device == name)
+            .map(|(_, inode)| inode.clone())
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _kind: InodeKind) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|(name, _)| DirEntry {
+                name: (*name).into(),
+                kind: InodeKind::Device,
+            })
+            .collect())
+    }
+}
+
+/// The devices, usually mounted at `/dev`.
+pub struct DevFs {
+    root: Arc<Root>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        let devices: Vec<(&'static str, Arc<dyn Inode>)> = vec![
+            ("console", Arc::new(Console)),
+            ("serial0", Arc::new(Serial)),
+        ];
+        DevFs {
+            root: Arc::new(Root { devices }),
+        }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,1131 @@
+// FAT12, FAT16 and FAT32 file systems on block devices
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, Inode, InodeKind, Metadata, Result, VfsError};
+use crate::block::{BlockDevice, BlockError, SECTOR_SIZE};
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of a deleted entry.
+const DELETED: u8 = 0xE5;
+/// Set in the sequence number of the last long name entry.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// UTF-16 characters stored in one long name entry.
+const LONG_NAME_CHARS: usize = 13;
+/// Offsets of the characters in a long name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255;
+
+// the reserved bits 0x08 and 0x10 mark lower case short names
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// 1980-01-01, the FAT epoch. There is no clock to take dates from.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+impl From<BlockError> for VfsError {
+    fn from(_: BlockError) -> Self {
+        VfsError::Io
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Smallest FAT entry value that marks the end of a chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+/// Where a directory keeps its entries.
+#[derive(Clone, Copy)]
+enum Directory {
+    /// The fixed size root directory of FAT12 and FAT16.
+    FixedRoot,
+    /// A cluster chain, as for every other directory.
+    Chain(u32),
+}
+
+/// Position of a 32 byte entry on the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    lba: u64,
+    offset: usize,
+}
+
+/// A file or directory entry with its long name, if any.
+struct RawEntry {
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    location: Location,
+    /// Locations of the long name entries in front of it.
+    long_name_locations: Vec<Location>,
+}
+
+impl RawEntry {
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.short_name[0] == b'.'
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Checksum of a short name that long name entries refer to.
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// The readable form of a short name, e.g. `README.TXT`.
+fn display_short_name(short_name: &[u8; 11], case_flags: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let text: String = bytes
+            .iter()
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .into();
+        if lower {
+            text.to_lowercase()
+        } else {
+            text
+        }
+    };
+    let mut name = part(&short_name[..8], case_flags & LOWER_CASE_BASE != 0);
+    let extension = part(&short_name[8..], case_flags & LOWER_CASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+fn is_short_name_char(character: char) -> bool {
+    character.is_ascii_uppercase()
+        || character.is_ascii_digit()
+        || "!#$%&'()-@^_`{}~".contains(character)
+}
+
+/// The short name for `name` if it is a valid upper case 8.3 name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max: usize| part.len() <= max && part.chars().all(is_short_name_char);
+    if base.is_empty() || !valid(base, 8) || !valid(extension, 3) {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// A short name like `LONGFI~1.TXT` for a name that needs a long name entry.
+fn numbered_short_name(name: &str, number: usize) -> [u8; 11] {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| if is_short_name_char(c) { c as u8 } else { b'_' })
+            .collect()
+    };
+    let tail = alloc::format!("~{}", number);
+    let mut base = clean(base);
+    base.truncate(8 - tail.len());
+    base.extend_from_slice(tail.as_bytes());
+    let mut extension = clean(extension);
+    extension.truncate(3);
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(&base);
+    short_name[8..8 + extension.len()].copy_from_slice(&extension);
+    short_name
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let invalid = "\"*/:<>?\\|";
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME_LEN
+        || name.chars().any(|c| c < ' ' || invalid.contains(c))
+    {
+        return Err(VfsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// The on-disk layout of a FAT volume and access to it.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_count: u64,
+    sectors_per_fat: u64,
+    root_start: u64,
+    root_sectors: u64,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    /// Where the search for a free cluster starts.
+    next_free: u32,
+    /// The cluster `cluster_at` found last, where the next lookup in the
+    /// same chain can continue.
+    cursor: Cell<Option<ChainCursor>>,
+}
+
+#[derive(Clone, Copy)]
+struct ChainCursor {
+    first: u32,
+    index: usize,
+    cluster: u32,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut boot = [0; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot)?;
+        let bytes_per_sector = u16_at(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17) as u64;
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let sectors_per_fat = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if boot[510..] != [0x55, 0xAA]
+            || (boot[0] != 0xEB && boot[0] != 0xE9)
+            || bytes_per_sector != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || sectors_per_fat == 0
+            || total_sectors > device.sector_count()
+        {
+            return Err(VfsError::Unsupported);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(SECTOR_SIZE as u64);
+        let fat_start = reserved_sectors;
+        let root_start = fat_start + fat_count * sectors_per_fat;
+        let data_start = root_start + root_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_start)
+            .ok_or(VfsError::Unsupported)?
+            / sectors_per_cluster;
+        // the cluster count alone decides the FAT type
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let root_cluster = if fat_type == FatType::Fat32 {
+            u32_at(&boot, 44)
+        } else {
+            0
+        };
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return Err(VfsError::Unsupported);
+        }
+        if fat_type == FatType::Fat32 && !(2..cluster_count as u32 + 2).contains(&root_cluster) {
+            return Err(VfsError::Unsupported);
+        }
+
+        Ok(Volume {
+            device,
+            fat_type,
+            sectors_per_cluster,
+            fat_start,
+            fat_count,
+            sectors_per_fat,
+            root_start,
+            root_sectors,
+            data_start,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            next_free: 2,
+            cursor: Cell::new(None),
+        })
+    }
+
+    fn read_sector(&self, lba: u64) -> Result<[u8; SECTOR_SIZE]> {
+        let mut sector = [0; SECTOR_SIZE];
+        self.device.read_sectors(lba, &mut sector)?;
+        Ok(sector)
+    }
+
+    fn write_sector(&self, lba: u64, sector: &[u8; SECTOR_SIZE]) -> Result<()> {
+        Ok(self.device.write_sectors(lba, sector)?)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_start(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// Byte offset of the FAT entry of `cluster` in the FAT.
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// Read `buf.len()` bytes of the first FAT at `offset`. An entry spans at
+    /// most two sectors.
+    fn read_fat_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let lba = self.fat_start + offset / SECTOR_SIZE as u64;
+        let start = offset as usize % SECTOR_SIZE;
+        let len = buf.len().min(SECTOR_SIZE - start);
+        buf[..len].copy_from_slice(&self.read_sector(lba)?[start..start + len]);
+        if len < buf.len() {
+            let rest = buf.len() - len;
+            buf[len..].copy_from_slice(&self.read_sector(lba + 1)?[..rest]);
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset` into every copy of the FAT.
+    fn write_fat_bytes(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let start = offset as usize % SECTOR_SIZE;
+        let len = data.len().min(SECTOR_SIZE - start);
+        for copy in 0..self.fat_count {
+            let lba = self.fat_start + copy * self.sectors_per_fat + offset / SECTOR_SIZE as u64;
+            let mut sector = self.read_sector(lba)?;
+            sector[start..start + len].copy_from_slice(&data[..len]);
+            self.write_sector(lba, &sector)?;
+            if len < data.len() {
+                let mut sector = self.read_sector(lba + 1)?;
+                sector[..data.len() - len].copy_from_slice(&data[len..]);
+                self.write_sector(lba + 1, &sector)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let offset = self.fat_offset(cluster);
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes) as u32;
+                if cluster.is_multiple_of(2) {
+                    value & 0xFFF
+                } else {
+                    value >> 4
+                }
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        // appending keeps the links in front of the cursor, freeing may not
+        if value == 0 {
+            self.cursor.set(None);
+        }
+        let offset = self.fat_offset(cluster);
+        match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                let old = u16::from_le_bytes(bytes);
+                let value = value as u16 & 0xFFF;
+                let new = if cluster.is_multiple_of(2) {
+                    (old & 0xF000) | value
+                } else {
+                    (old & 0x000F) | value << 4
+                };
+                self.write_fat_bytes(offset, &new.to_le_bytes())
+            }
+            FatType::Fat16 => self.write_fat_bytes(offset, &(value as u16).to_le_bytes()),
+            FatType::Fat32 => {
+                // the top four bits are reserved and must be kept
+                let mut bytes = [0; 4];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                let old = u32::from_le_bytes(bytes);
+                let new = (old & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                self.write_fat_bytes(offset, &new.to_le_bytes())
+            }
+        }
+    }
+
+    /// The clusters of the chain starting at `first`.
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while self.is_valid_cluster(cluster) {
+            // a longer chain than the volume has clusters must be a loop
+            if clusters.len() > self.cluster_count as usize {
+                return Err(VfsError::Io);
+            }
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+        if cluster != 0 && cluster < self.fat_type.end_of_chain() && !clusters.is_empty() {
+            return Err(VfsError::Io);
+        }
+        Ok(clusters)
+    }
+
+    /// The cluster at `index` in the chain starting at `first`.
+    ///
+    /// The lookup continues from the previous one if that was in the same
+    /// chain and not further along, so going through a file in order
+    /// follows each link once.
+    fn cluster_at(&self, first: u32, index: usize) -> Result<u32> {
+        let (mut position, mut cluster) = match self.cursor.get() {
+            Some(cursor) if cursor.first == first && cursor.index <= index => {
+                (cursor.index, cursor.cluster)
+            }
+            _ => (0, first),
+        };
+        while position < index && self.is_valid_cluster(cluster) {
+            cluster = self.fat_entry(cluster)?;
+            position += 1;
+        }
+        if !self.is_valid_cluster(cluster) {
+            return Err(VfsError::Io);
+        }
+        self.cursor.set(Some(ChainCursor {
+            first,
+            index,
+            cluster,
+        }));
+        Ok(cluster)
+    }
+
+    /// The length and the last cluster of the chain starting at `first`, or
+    /// `None` if it's empty.
+    fn chain_end(&self, first: u32) -> Result<Option<(usize, u32)>> {
+        let (mut index, mut cluster) = match self.cursor.get() {
+            Some(cursor) if cursor.first == first => (cursor.index, cursor.cluster),
+            _ if self.is_valid_cluster(first) => (0, first),
+            _ => return Ok(None),
+        };
+        loop {
+            let next = self.fat_entry(cluster)?;
+            if !self.is_valid_cluster(next) {
+                break;
+            }
+            // a longer chain than the volume has clusters must be a loop
+            if index > self.cluster_count as usize {
+                return Err(VfsError::Io);
+            }
+            (index, cluster) = (index + 1, next);
+        }
+        self.cursor.set(Some(ChainCursor {
+            first,
+            index,
+            cluster,
+        }));
+        Ok(Some((index + 1, cluster)))
+    }
+
+    /// Allocate a zeroed cluster and append it to the chain ending in `last`.
+    fn allocate_cluster(&mut self, last: Option<u32>) -> Result<u32> {
+        let count = self.cluster_count;
+        let cluster = (0..count)
+            .map(|i| 2 + (self.next_free - 2 + i) % count)
+            .find(|&cluster| matches!(self.fat_entry(cluster), Ok(0)))
+            .ok_or(VfsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, 0x0FFF_FFFF)?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?;
+        }
+        let start = self.cluster_start(cluster);
+        for lba in start..start + self.sectors_per_cluster {
+            self.write_sector(lba, &[0; SECTOR_SIZE])?;
+        }
+        self.next_free = cluster;
+        Ok(cluster)
+    }
+
+    /// Mark the clusters of a chain as free.
+    fn free_chain(&mut self, first: u32) -> Result<()> {
+        // a loop ends at the first cluster freed already
+        let mut cluster = first;
+        while self.is_valid_cluster(cluster) {
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    fn free_clusters(&self) -> Result<u32> {
+        let width = match self.fat_type {
+            FatType::Fat12 => {
+                let clusters = 2..self.cluster_count + 2;
+                let mut free = 0;
+                for cluster in clusters {
+                    if self.fat_entry(cluster)? == 0 {
+                        free += 1;
+                    }
+                }
+                return Ok(free);
+            }
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        // FAT16 and FAT32 entries never cross sectors, so read whole sectors
+        let mut free = 0;
+        let mut sector = [0; SECTOR_SIZE];
+        let mut loaded = None;
+        for cluster in 2..self.cluster_count + 2 {
+            let offset = self.fat_offset(cluster);
+            let lba = self.fat_start + offset / SECTOR_SIZE as u64;
+            if loaded != Some(lba) {
+                sector = self.read_sector(lba)?;
+                loaded = Some(lba);
+            }
+            let start = offset as usize % SECTOR_SIZE;
+            if sector[start..start + width].iter().all(|&byte| byte == 0) {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    fn root(&self) -> Directory {
+        match self.fat_type {
+            FatType::Fat32 => Directory::Chain(self.root_cluster),
+            _ => Directory::FixedRoot,
+        }
+    }
+
+    /// Sectors holding the entries of `directory`, in order.
+    fn directory_sectors(&self, directory: Directory) -> Result<Vec<u64>> {
+        match directory {
+            Directory::FixedRoot => {
+                Ok((self.root_start..self.root_start + self.root_sectors).collect())
+            }
+            Directory::Chain(first) => Ok(self
+                .chain(first)?
+                .into_iter()
+                .flat_map(|cluster| {
+                    let start = self.cluster_start(cluster);
+                    start..start + self.sectors_per_cluster
+                })
+                .collect()),
+        }
+    }
+
+    fn read_entry(&self, location: Location) -> Result<[u8; ENTRY_SIZE]> {
+        let sector = self.read_sector(location.lba)?;
+        Ok(sector[location.offset..location.offset + ENTRY_SIZE]
+            .try_into()
+            .unwrap())
+    }
+
+    fn write_entry(&self, location: Location, entry: &[u8; ENTRY_SIZE]) -> Result<()> {
+        let mut sector = self.read_sector(location.lba)?;
+        sector[location.offset..location.offset + ENTRY_SIZE].copy_from_slice(entry);
+        self.write_sector(location.lba, &sector)
+    }
+
+    /// All entries of `directory` except deleted ones and volume labels.
+    fn entries(&self, directory: Directory) -> Result<Vec<RawEntry>> {
+        let mut entries = Vec::new();
+        let mut long_name: Vec<u16> = Vec::new();
+        let mut long_name_locations = Vec::new();
+        let mut long_name_checksum = 0;
+
+        for lba in self.directory_sectors(directory)? {
+            let sector = self.read_sector(lba)?;
+            for (index, raw) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                let location = Location {
+                    lba,
+                    offset: index * ENTRY_SIZE,
+                };
+                match raw[0] {
+                    0 => return Ok(entries),
+                    DELETED => {
+                        long_name.clear();
+                        long_name_locations.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                let attributes = raw[11];
+                if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                    // long name entries come last part first
+                    if raw[0] & LAST_LONG_ENTRY != 0 {
+                        long_name.clear();
+                        long_name_locations.clear();
+                    }
+                    let part = LONG_NAME_OFFSETS.iter().map(|&offset| u16_at(raw, offset));
+                    let mut part: Vec<u16> = part.take_while(|&c| c != 0).collect();
+                    part.extend_from_slice(&long_name);
+                    long_name = part;
+                    long_name_locations.push(location);
+                    long_name_checksum = raw[13];
+                    continue;
+                }
+                if attributes & ATTR_VOLUME_ID != 0 {
+                    long_name.clear();
+                    long_name_locations.clear();
+                    continue;
+                }
+
+                let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+                let has_long_name =
+                    !long_name.is_empty() && long_name_checksum == short_name_checksum(&short_name);
+                let name = if has_long_name {
+                    String::from_utf16_lossy(&long_name)
+                } else {
+                    display_short_name(&short_name, raw[12])
+                };
+                let high = if self.fat_type == FatType::Fat32 {
+                    u16_at(raw, 20) as u32
+                } else {
+                    0
+                };
+                entries.push(RawEntry {
+                    name,
+                    short_name,
+                    attributes,
+                    first_cluster: high << 16 | u16_at(raw, 26) as u32,
+                    location,
+                    long_name_locations: if has_long_name {
+                        core::mem::take(&mut long_name_locations)
+                    } else {
+                        Vec::new()
+                    },
+                });
+                long_name.clear();
+                long_name_locations.clear();
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Find `name` in `directory`. Names are compared without regard to case.
+    fn find(&self, directory: Directory, name: &str) -> Result<RawEntry> {
+        self.entries(directory)?
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .find(|entry| {
+                entry.name.eq_ignore_ascii_case(name)
+                    || display_short_name(&entry.short_name, 0).eq_ignore_ascii_case(name)
+            })
+            .ok_or(VfsError::NotFound)
+    }
+
+    /// Find `count` consecutive free entries in `directory`, growing it if needed.
+    fn free_slots(&mut self, directory: Directory, count: usize) -> Result<Vec<Location>> {
+        loop {
+            let sectors = self.directory_sectors(directory)?;
+            let mut run = Vec::new();
+            for &lba in &sectors {
+                let sector = self.read_sector(lba)?;
+                for index in 0..ENTRIES_PER_SECTOR {
+                    let offset = index * ENTRY_SIZE;
+                    if sector[offset] == 0 || sector[offset] == DELETED {
+                        run.push(Location { lba, offset });
+                        if run.len() == count {
+                            return Ok(run);
+                        }
+                    } else {
+                        run.clear();
+                    }
+                }
+            }
+            match directory {
+                Directory::FixedRoot => return Err(VfsError::NoSpace),
+                Directory::Chain(first) => {
+                    let last = *self.chain(first)?.last().ok_or(VfsError::Io)?;
+                    self.allocate_cluster(Some(last))?;
+                }
+            }
+        }
+    }
+
+    /// Add an entry called `name` to `directory`, with long name entries if
+    /// the name isn't a plain 8.3 name.
+    fn add_entry(
+        &mut self,
+        directory: Directory,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+    ) -> Result<Location> {
+        let existing = self.entries(directory)?;
+        let (short_name, long_name) = match exact_short_name(name) {
+            Some(short_name) => (short_name, None),
+            None => {
+                let short_name = (1..)
+                    .map(|number| numbered_short_name(name, number))
+                    .find(|candidate| existing.iter().all(|entry| entry.short_name != *candidate))
+                    .unwrap();
+                (short_name, Some(name.encode_utf16().collect::<Vec<u16>>()))
+            }
+        };
+        let long_entries = long_name
+            .as_ref()
+            .map_or(0, |name| name.len().div_ceil(LONG_NAME_CHARS));
+        let slots = self.free_slots(directory, long_entries + 1)?;
+
+        if let Some(long_name) = &long_name {
+            let checksum = short_name_checksum(&short_name);
+            for (slot, &location) in slots[..long_entries].iter().enumerate() {
+                let sequence = long_entries - slot;
+                let mut entry = [0; ENTRY_SIZE];
+                entry[0] = sequence as u8;
+                if slot == 0 {
+                    entry[0] |= LAST_LONG_ENTRY;
+                }
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = checksum;
+                // the name ends with a NUL and is padded with 0xFFFF
+                let start = (sequence - 1) * LONG_NAME_CHARS;
+                for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    let character = match long_name.get(start + i) {
+                        Some(&character) => character,
+                        None if start + i == long_name.len() => 0,
+                        None => 0xFFFF,
+                    };
+                    entry[offset..offset + 2].copy_from_slice(&character.to_le_bytes());
+                }
+                self.write_entry(location, &entry)?;
+            }
+        }
+
+        let location = slots[long_entries];
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..11].copy_from_slice(&short_name);
+        entry[11] = attributes;
+        for offset in [16, 18, 24] {
+            entry[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        }
+        set_first_cluster(&mut entry, first_cluster);
+        self.write_entry(location, &entry)?;
+        Ok(location)
+    }
+
+    /// Create a directory cluster with its `.` and `..` entries.
+    fn new_directory(&mut self, parent: Directory) -> Result<u32> {
+        let cluster = self.allocate_cluster(None)?;
+        let parent_cluster = match parent {
+            Directory::Chain(cluster) if cluster != self.root_cluster => cluster,
+            // `..` of a directory in the root points at cluster 0
+            _ => 0,
+        };
+        let mut sector = [0; SECTOR_SIZE];
+        for (index, (name, target)) in [(".", cluster), ("..", parent_cluster)].iter().enumerate() {
+            let entry = &mut sector[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            entry[..11].fill(b' ');
+            entry[..name.len()].copy_from_slice(name.as_bytes());
+            entry[11] = ATTR_DIRECTORY;
+            let entry: &mut [u8; ENTRY_SIZE] = entry.try_into().unwrap();
+            set_first_cluster(entry, *target);
+        }
+        self.write_sector(self.cluster_start(cluster), &sector)?;
+        Ok(cluster)
+    }
+}
+
+fn set_first_cluster(entry: &mut [u8; ENTRY_SIZE], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// A file or directory. Its state is read from its directory entry on every
+/// access, so several inodes for the same file stay consistent.
+struct FatInode {
+    volume: Arc<Mutex<Volume>>,
+    /// The directory entry, `None` for the root directory.
+    location: Option<Location>,
+    directory: bool,
+}
+
+/// The parts of a directory entry an inode needs.
+struct EntryState {
+    first_cluster: u32,
+    size: u32,
+}
+
+impl FatInode {
+    fn state(&self, volume: &Volume) -> Result<EntryState> {
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Ok(EntryState {
+                    first_cluster: volume.root_cluster,
+                    size: 0,
+                })
+            }
+        };
+        let entry = volume.read_entry(location)?;
+        // the file was deleted through another inode
+        if entry[0] == DELETED || entry[0] == 0 {
+            return Err(VfsError::NotFound);
+        }
+        let high = if volume.fat_type == FatType::Fat32 {
+            u16_at(&entry, 20) as u32
+        } else {
+            0
+        };
+        Ok(EntryState {
+            first_cluster: high << 16 | u16_at(&entry, 26) as u32,
+            size: u32_at(&entry, 28),
+        })
+    }
+
+    fn set_state(&self, volume: &Volume, state: &EntryState) -> Result<()> {
+        let location = self.location.ok_or(VfsError::IsADirectory)?;
+        let mut entry = volume.read_entry(location)?;
+        set_first_cluster(&mut entry, state.first_cluster);
+        entry[28..32].copy_from_slice(&state.size.to_le_bytes());
+        entry[11] |= ATTR_ARCHIVE;
+        volume.write_entry(location, &entry)
+    }
+
+    fn as_directory(&self, volume: &Volume) -> Result<Directory> {
+        if !self.directory {
+            return Err(VfsError::NotADirectory);
+        }
+        match self.location {
+            None => Ok(volume.root()),
+            Some(_) => Ok(Directory::Chain(self.state(volume)?.first_cluster)),
+        }
+    }
+
+    fn child(&self, entry: &RawEntry) -> Arc<FatInode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            location: Some(entry.location),
+            directory: entry.is_directory(),
+        })
+    }
+
+    /// Make the cluster chain of a file at least `clusters` long.
+    fn grow_chain(
+        &self,
+        volume: &mut Volume,
+        state: &mut EntryState,
+        clusters: usize,
+    ) -> Result<()> {
+        let (mut count, mut last) = match volume.chain_end(state.first_cluster)? {
+            Some((count, last)) => (count, Some(last)),
+            None => (0, None),
+        };
+        while count < clusters {
+            let cluster = volume.allocate_cluster(last)?;
+            if last.is_none() {
+                state.first_cluster = cluster;
+            }
+            (count, last) = (count + 1, Some(cluster));
+        }
+        Ok(())
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let volume = self.volume.lock();
+        let size = match self.directory {
+            true => 0,
+            false => self.state(&volume).map_or(0, |state| state.size as usize),
+        };
+        Metadata {
+            kind: if self.directory {
+                InodeKind::Directory
+            } else {
+                InodeKind::File
+            },
+            size,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.directory {
+            return Err(VfsError::IsADirectory);
+        }
+        let volume = self.volume.lock();
+        let state = self.state(&volume)?;
+        let size = state.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let cluster_size = volume.cluster_size();
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let cluster = volume.cluster_at(state.first_cluster, position / cluster_size)?;
+            let within = position % cluster_size;
+            let lba = volume.cluster_start(cluster) + (within / SECTOR_SIZE) as u64;
+            let sector = volume.read_sector(lba)?;
+            let start = within % SECTOR_SIZE;
+            let count = (SECTOR_SIZE - start).min(len - done);
+            buf[done..done + count].copy_from_slice(&sector[start..start + count]);
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.directory {
+            return Err(VfsError::IsADirectory);
+        }
+        let end = offset.checked_add(buf.len()).ok_or(VfsError::NoSpace)?;
+        if end > u32::MAX as usize {
+            return Err(VfsError::NoSpace);
+        }
+        let mut volume = self.volume.lock();
+        let mut state = self.state(&volume)?;
+        let cluster_size = volume.cluster_size();
+        let old_size = state.size as usize;
+
+        let clusters = end.div_ceil(cluster_size);
+        if let Err(err) = self.grow_chain(&mut volume, &mut state, clusters) {
+            // keep the clusters that were allocated attached to the file
+            self.set_state(&volume, &state)?;
+            return Err(err);
+        }
+        // a gap between the old end and `offset` reads as zeros
+        let zeros = [0; SECTOR_SIZE];
+        let mut position = old_size.min(offset);
+        while position < end {
+            let cluster = volume.cluster_at(state.first_cluster, position / cluster_size)?;
+            let within = position % cluster_size;
+            let lba = volume.cluster_start(cluster) + (within / SECTOR_SIZE) as u64;
+            let start = within % SECTOR_SIZE;
+            let data = match position.checked_sub(offset) {
+                Some(done) => &buf[done..],
+                None => &zeros[..(offset - position).min(SECTOR_SIZE)],
+            };
+            let count = (SECTOR_SIZE - start).min(data.len());
+            let mut sector = if count == SECTOR_SIZE {
+                [0; SECTOR_SIZE]
+            } else {
+                volume.read_sector(lba)?
+            };
+            sector[start..start + count].copy_from_slice(&data[..count]);
+            volume.write_sector(lba, &sector)?;
+            position += count;
+        }
+
+        state.size = state.size.max(end as u32);
+        self.set_state(&volume, &state)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: usize) -> Result<()> {
+        if self.directory {
+            return Err(VfsError::IsADirectory);
+        }
+        let current = self.metadata().size;
+        if size > current {
+            // writing nothing at `size` fills the gap up to it with zeros
+            return self.write_at(size, &[]).map(|_| ());
+        }
+
+        let mut volume = self.volume.lock();
+        let mut state = self.state(&volume)?;
+        let keep = size.div_ceil(volume.cluster_size());
+        if keep == 0 {
+            volume.free_chain(state.first_cluster)?;
+            state.first_cluster = 0;
+        } else if let Some((count, _)) = volume.chain_end(state.first_cluster)? {
+            if keep < count {
+                let last = volume.cluster_at(state.first_cluster, keep - 1)?;
+                let rest = volume.fat_entry(last)?;
+                volume.set_fat_entry(last, 0x0FFF_FFFF)?;
+                volume.free_chain(rest)?;
+            }
+        }
+        state.size = size as u32;
+        self.set_state(&volume, &state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let volume = self.volume.lock();
+        let directory = self.as_directory(&volume)?;
+        let entry = volume.find(directory, name)?;
+        Ok(self.child(&entry))
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> Result<Arc<dyn Inode>> {
+        check_name(name)?;
+        let mut volume = self.volume.lock();
+        let directory = self.as_directory(&volume)?;
+        match volume.find(directory, name) {
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let location = match kind {
+            InodeKind::File => volume.add_entry(directory, name, ATTR_ARCHIVE, 0)?,
+            InodeKind::Directory => {
+                let cluster = volume.new_directory(directory)?;
+                match volume.add_entry(directory, name, ATTR_DIRECTORY, cluster) {
+                    Ok(location) => location,
+                    Err(err) => {
+                        volume.free_chain(cluster)?;
+                        return Err(err);
+                    }
+                }
+            }
+            InodeKind::Device => return Err(VfsError::Unsupported),
+        };
+        Ok(Arc::new(FatInode {
+            volume: self.volume.clone(),
+            location: Some(location),
+            directory: kind == InodeKind::Directory,
+        }))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let mut volume = self.volume.lock();
+        let directory = self.as_directory(&volume)?;
+        let entry = volume.find(directory, name)?;
+        if entry.attributes & ATTR_READ_ONLY != 0 {
+            return Err(VfsError::ReadOnly);
+        }
+        if entry.is_directory() {
+            let children = volume.entries(Directory::Chain(entry.first_cluster))?;
+            if children.iter().any(|child| !child.is_dot()) {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+        }
+
+        for &location in entry.long_name_locations.iter().chain([&entry.location]) {
+            let mut raw = volume.read_entry(location)?;
+            raw[0] = DELETED;
+            volume.write_entry(location, &raw)?;
+        }
+        if volume.is_valid_cluster(entry.first_cluster) {
+            volume.free_chain(entry.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>> {
+        let volume = self.volume.lock();
+        let directory = self.as_directory(&volume)?;
+        Ok(volume
+            .entries(directory)?
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| DirEntry {
+                kind: if entry.is_directory() {
+                    InodeKind::Directory
+                } else {
+                    InodeKind::File
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+}
+
+/// A FAT file system on a block device or partition.
+pub struct FatFs {
+    volume: Arc<Mutex<Volume>>,
+}
+
+impl FatFs {
+    /// Read the boot sector of `device`. Fails with `Unsupported` if it
+    /// doesn't hold a FAT file system.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        Ok(FatFs {
+            volume: Arc::new(Mutex::new(Volume::new(device)?)),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.lock().fat_type
+    }
+
+    /// Bytes per cluster, the unit files grow in.
+    pub fn cluster_size(&self) -> usize {
+        self.volume.lock().cluster_size()
+    }
+
+    /// Number of clusters not used by any file or directory.
+    pub fn free_clusters(&self) -> Result<u32> {
+        self.volume.lock().free_clusters()
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            location: None,
+            directory: true,
+        })
+    }
+}
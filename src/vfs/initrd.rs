@@ -0,0 +1,106 @@
+// Read-only view of the embedded initramfs
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, Inode, InodeKind, Metadata, Result, VfsError};
+use crate::initramfs::{self, FileKind};
+
+impl From<initramfs::Error> for VfsError {
+    fn from(err: initramfs::Error) -> Self {
+        match err {
+            initramfs::Error::NotFound => VfsError::NotFound,
+            initramfs::Error::NotADirectory => VfsError::NotADirectory,
+            initramfs::Error::IsADirectory => VfsError::IsADirectory,
+            initramfs::Error::Corrupt => VfsError::Io,
+        }
+    }
+}
+
+fn kind(kind: FileKind) -> InodeKind {
+    match kind {
+        FileKind::File => InodeKind::File,
+        FileKind::Directory => InodeKind::Directory,
+    }
+}
+
+/// A path in the archive.
+struct InitrdInode {
+    path: String,
+    metadata: Metadata,
+}
+
+impl InitrdInode {
+    fn new(path: String) -> Result<Arc<Self>> {
+        let stat = initramfs::stat(&path)?;
+        Ok(Arc::new(InitrdInode {
+            path,
+            metadata: Metadata {
+                kind: kind(stat.kind),
+                size: stat.size,
+            },
+        }))
+    }
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let data = initramfs::open(&self.path)?.contents();
+        let remaining = data.get(offset..).unwrap_or(&[]);
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<()> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        if self.metadata.kind != InodeKind::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(InitrdInode::new(format!("{}/{}", self.path, name))?)
+    }
+
+    fn create(&self, _name: &str, _kind: InodeKind) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>> {
+        let entries = initramfs::readdir(&self.path)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| DirEntry {
+                name: entry.name,
+                kind: kind(entry.kind),
+            })
+            .collect())
+    }
+}
+
+/// The initramfs as a file system, usually mounted at `/initrd`.
+pub struct InitrdFs;
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        InitrdInode::new(String::new()).expect("initramfs has no root")
+    }
+}
@@ -0,0 +1,495 @@
+// Virtual file system: mount table, path resolution and file descriptors
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use spin::Mutex;
+
+use crate::block::{self, BlockDevice};
+use crate::cache::{self, BlockCache};
+use crate::partition;
+use crate::{log, warn};
+
+pub mod devfs;
+pub mod fat;
+pub mod initrd;
+pub mod tmpfs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    /// The file system or file can't be modified.
+    ReadOnly,
+    /// The file descriptor isn't open, or not for this kind of access.
+    BadDescriptor,
+    InvalidPath,
+    /// The inode doesn't support the operation.
+    Unsupported,
+    /// The file system is full.
+    NoSpace,
+    /// Renaming between different file systems.
+    CrossDevice,
+    /// The underlying device failed.
+    Io,
+}
+
+pub type Result<T> = core::result::Result<T, VfsError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    File,
+    Directory,
+    /// A character device like the console.
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: InodeKind,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: InodeKind,
+}
+
+/// A file, directory or device of some file system.
+///
+/// Operations that don't apply to an inode default to an error.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Read from `offset` into `buf`, returning the number of bytes read.
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// Write `buf` at `offset`, returning the number of bytes written.
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// Change the size of a file, filling with zeros when it grows.
+    fn truncate(&self, _size: usize) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Create a file or directory called `name` in this directory.
+    fn create(&self, _name: &str, _kind: InodeKind) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Remove the file or empty directory called `name` from this directory.
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Move `old_name` in this directory to `new_name` in `new_parent`,
+    /// replacing a file or empty directory of the same kind.
+    fn rename(&self, _old_name: &str, _new_parent: &Arc<dyn Inode>, _new_name: &str) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// The concrete inode, for file systems that need to recognize their own
+    /// inodes, e.g. when renaming.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    /// Components of the absolute mount point path.
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Split `path` into its components, resolving `.` and `..`.
+///
+/// All paths are absolute; `..` at the root stays at the root.
+pub fn normalize(path: &str) -> Result<Vec<String>> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components: Vec<String> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name.to_string()),
+        }
+    }
+    Ok(components)
+}
+
+/// Mount `fs` at `path`. Except for the root, the mount point must be an
+/// existing directory.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let components = normalize(path)?;
+    if !components.is_empty() && lookup(path)?.metadata().kind != InodeKind::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == components) {
+        return Err(VfsError::AlreadyExists);
+    }
+    log!("Mounting {} at {}", fs.name(), path);
+    mounts.push(Mount {
+        path: components,
+        fs,
+    });
+    Ok(())
+}
+
+/// Remove the file system mounted at `path`.
+pub fn unmount(path: &str) -> Result<()> {
+    let components = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == components)
+        .ok_or(VfsError::NotFound)?;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Find the inode at `path`, crossing mount points on the way.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>> {
+    let components = normalize(path)?;
+    let (mut inode, depth) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|mount| components.starts_with(&mount.path))
+            .max_by_key(|mount| mount.path.len())
+            .ok_or(VfsError::NotFound)?;
+        (mount.fs.root(), mount.path.len())
+    };
+    for component in &components[depth..] {
+        inode = inode.lookup(component)?;
+    }
+    Ok(inode)
+}
+
+/// Split `path` into its parent directory and final component.
+fn split_parent(path: &str) -> Result<(Arc<dyn Inode>, String)> {
+    let mut components = normalize(path)?;
+    let name = components.pop().ok_or(VfsError::InvalidPath)?;
+    let parent = alloc::format!("/{}", components.join("/"));
+    Ok((lookup(&parent)?, name))
+}
+
+pub fn stat(path: &str) -> Result<Metadata> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// List the directory at `path`, including directories mounted in it.
+pub fn readdir(path: &str) -> Result<Vec<DirEntry>> {
+    let mut entries = lookup(path)?.readdir()?;
+    let components = normalize(path)?;
+    for mount in MOUNTS.lock().iter() {
+        if mount.path.len() == components.len() + 1 && mount.path.starts_with(&components) {
+            let name = &mount.path[components.len()];
+            if !entries.iter().any(|entry| &entry.name == name) {
+                entries.push(DirEntry {
+                    name: name.clone(),
+                    kind: InodeKind::Directory,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn mkdir(path: &str) -> Result<()> {
+    let (parent, name) = split_parent(path)?;
+    parent.create(&name, InodeKind::Directory).map(|_| ())
+}
+
+fn is_mount_point(components: &[String]) -> bool {
+    MOUNTS.lock().iter().any(|mount| mount.path == components)
+}
+
+/// Remove the file at `path`.
+pub fn unlink(path: &str) -> Result<()> {
+    if stat(path)?.kind == InodeKind::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+    let (parent, name) = split_parent(path)?;
+    parent.remove(&name)
+}
+
+/// Remove the empty directory at `path`.
+pub fn rmdir(path: &str) -> Result<()> {
+    if stat(path)?.kind != InodeKind::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    if is_mount_point(&normalize(path)?) {
+        return Err(VfsError::DirectoryNotEmpty);
+    }
+    let (parent, name) = split_parent(path)?;
+    parent.remove(&name)
+}
+
+/// Move the file or directory at `old_path` to `new_path` on the same file
+/// system.
+pub fn rename(old_path: &str, new_path: &str) -> Result<()> {
+    let old = normalize(old_path)?;
+    let new = normalize(new_path)?;
+    if old.is_empty() || is_mount_point(&old) || is_mount_point(&new) {
+        return Err(VfsError::InvalidPath);
+    }
+    // a directory can't move below itself
+    if new.len() > old.len() && new.starts_with(&old) {
+        return Err(VfsError::InvalidPath);
+    }
+    let (old_parent, old_name) = split_parent(old_path)?;
+    let (new_parent, new_name) = split_parent(new_path)?;
+    old_parent.rename(&old_name, &new_parent, &new_name)
+}
+
+/// Change the size of the file at `path`.
+pub fn truncate(path: &str, size: usize) -> Result<()> {
+    lookup(path)?.truncate(size)
+}
+
+/// Flags for `open`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    pub const READ_WRITE: OpenFlags = OpenFlags(1 | 1 << 1);
+    /// Create the file if it doesn't exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Cut the file to zero length.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Start every write at the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn empty() -> OpenFlags {
+        OpenFlags(0)
+    }
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// A file descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fd(usize);
+
+struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    offset: usize,
+}
+
+static FILES: Mutex<Vec<Option<OpenFile>>> = Mutex::new(Vec::new());
+
+/// Open the file at `path`.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Fd> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(VfsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = split_parent(path)?;
+            parent.create(&name, InodeKind::File)?
+        }
+        Err(err) => return Err(err),
+    };
+    let kind = inode.metadata().kind;
+    if kind == InodeKind::Directory && flags.contains(OpenFlags::WRITE) {
+        return Err(VfsError::IsADirectory);
+    }
+    if kind == InodeKind::File && flags.contains(OpenFlags::TRUNCATE) {
+        inode.truncate(0)?;
+    }
+
+    let file = OpenFile {
+        inode,
+        flags,
+        offset: 0,
+    };
+    let mut files = FILES.lock();
+    let index = match files.iter().position(Option::is_none) {
+        Some(index) => {
+            files[index] = Some(file);
+            index
+        }
+        None => {
+            files.push(Some(file));
+            files.len() - 1
+        }
+    };
+    Ok(Fd(index))
+}
+
+fn with_file<R>(fd: Fd, f: impl FnOnce(&mut OpenFile) -> Result<R>) -> Result<R> {
+    let mut files = FILES.lock();
+    let file = files
+        .get_mut(fd.0)
+        .and_then(Option::as_mut)
+        .ok_or(VfsError::BadDescriptor)?;
+    f(file)
+}
+
+/// Read from the current offset of `fd` and advance it.
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize> {
+    // devices may block, so don't hold the table lock while reading
+    let (inode, offset) = with_file(fd, |file| {
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::BadDescriptor);
+        }
+        Ok((file.inode.clone(), file.offset))
+    })?;
+    let read = inode.read_at(offset, buf)?;
+    with_file(fd, |file| {
+        file.offset = offset + read;
+        Ok(read)
+    })
+}
+
+/// Write at the current offset of `fd`, or at the end in append mode.
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize> {
+    let (inode, offset) = with_file(fd, |file| {
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadDescriptor);
+        }
+        let offset = if file.flags.contains(OpenFlags::APPEND) {
+            file.inode.metadata().size
+        } else {
+            file.offset
+        };
+        Ok((file.inode.clone(), offset))
+    })?;
+    let written = inode.write_at(offset, buf)?;
+    with_file(fd, |file| {
+        file.offset = offset + written;
+        Ok(written)
+    })
+}
+
+/// Move the offset of `fd` to `offset` bytes from the start.
+pub fn seek(fd: Fd, offset: usize) -> Result<()> {
+    with_file(fd, |file| {
+        file.offset = offset;
+        Ok(())
+    })
+}
+
+pub fn fstat(fd: Fd) -> Result<Metadata> {
+    with_file(fd, |file| Ok(file.inode.metadata()))
+}
+
+/// Change the size of the file open as `fd`, which must be writable.
+pub fn ftruncate(fd: Fd, size: usize) -> Result<()> {
+    let inode = with_file(fd, |file| {
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadDescriptor);
+        }
+        Ok(file.inode.clone())
+    })?;
+    inode.truncate(size)
+}
+
+pub fn close(fd: Fd) -> Result<()> {
+    let mut files = FILES.lock();
+    match files.get_mut(fd.0) {
+        Some(slot @ Some(_)) => {
+            *slot = None;
+            Ok(())
+        }
+        _ => Err(VfsError::BadDescriptor),
+    }
+}
+
+/// Read the whole file at `path`.
+pub fn read_to_vec(path: &str) -> Result<Vec<u8>> {
+    let inode = lookup(path)?;
+    let mut data = alloc::vec![0; inode.metadata().size];
+    let read = inode.read_at(0, &mut data)?;
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Set up the root file system: a tmpfs with another tmpfs for scratch files
+/// in `/tmp`, the devices in `/dev`, the initramfs in `/initrd` and the FAT
+/// file systems on the block devices in `/mnt`. Requires the heap.
+pub fn init() {
+    log!("Initiating VFS");
+    mount("/", Arc::new(tmpfs::TmpFs::new())).expect("failed to mount the root file system");
+    for (path, fs) in [
+        ("/tmp", Arc::new(tmpfs::TmpFs::new()) as Arc<dyn FileSystem>),
+        ("/dev", Arc::new(devfs::DevFs::new())),
+        ("/initrd", Arc::new(initrd::InitrdFs)),
+    ] {
+        mkdir(path).expect("failed to create mount point");
+        mount(path, fs).expect("failed to mount file system");
+    }
+    mkdir("/mnt").expect("failed to create mount point");
+    mount_disks();
+}
+
+/// Mount the FAT file systems on the block devices at `/mnt/<device>`.
+///
+/// Devices with an MBR are searched partition by partition, and the
+/// partitions are registered as block devices of their own. Mounted volumes
+/// are accessed through a `BlockCache`.
+fn mount_disks() {
+    for device in block::devices() {
+        let volumes: Vec<Arc<dyn BlockDevice>> = match partition::read_mbr(&device) {
+            Ok(partitions) if !partitions.is_empty() => partitions
+                .into_iter()
+                .map(|partition| {
+                    let partition: Arc<dyn BlockDevice> = Arc::new(partition);
+                    block::register(partition.clone());
+                    partition
+                })
+                .collect(),
+            _ => alloc::vec![device],
+        };
+        for volume in volumes {
+            let cache = Arc::new(BlockCache::new(volume.clone(), cache::DEFAULT_CAPACITY));
+            let Ok(fs) = fat::FatFs::new(cache.clone()) else {
+                continue;
+            };
+            let path = alloc::format!("/mnt/{}", volume.name());
+            match mkdir(&path).and_then(|_| mount(&path, Arc::new(fs))) {
+                Ok(()) => cache::register(cache),
+                Err(err) => warn!("Failed to mount {}: {:?}", volume.name(), err),
+            }
+        }
+    }
+}
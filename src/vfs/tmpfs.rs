@@ -0,0 +1,311 @@
+// Writable file system on the kernel heap
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, Inode, InodeKind, Metadata, Result, VfsError};
+use crate::allocator;
+
+/// Bytes charged for every inode on top of its name and contents.
+const INODE_OVERHEAD: usize = core::mem::size_of::<TmpInode>() + 64;
+
+/// Heap usage of one file system.
+struct Usage {
+    used: AtomicUsize,
+    limit: usize,
+}
+
+impl Usage {
+    /// Account for `bytes` more, failing if that goes over the limit.
+    fn charge(&self, bytes: usize) -> Result<()> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| VfsError::NoSpace)
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+}
+
+/// An inode of a tmpfs. Its overhead and contents stay charged until the
+/// last reference goes away, so unlinked files that are still open count
+/// against the limit. Names are charged to the directory holding them.
+pub struct TmpInode {
+    contents: Mutex<Contents>,
+    usage: Arc<Usage>,
+    /// `INODE_OVERHEAD`, or 0 for the root, which isn't charged.
+    overhead: usize,
+}
+
+impl TmpInode {
+    fn new(kind: InodeKind, usage: Arc<Usage>, overhead: usize) -> Result<Arc<Self>> {
+        let contents = match kind {
+            InodeKind::File => Contents::File(Vec::new()),
+            InodeKind::Directory => Contents::Directory(BTreeMap::new()),
+            InodeKind::Device => return Err(VfsError::Unsupported),
+        };
+        usage.charge(overhead)?;
+        Ok(Arc::new(TmpInode {
+            contents: Mutex::new(contents),
+            usage,
+            overhead,
+        }))
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&*self.contents.lock(), Contents::Directory(children) if children.is_empty())
+    }
+
+    /// Resize a file, keeping the accounting up to date.
+    fn resize(&self, data: &mut Vec<u8>, size: usize) -> Result<()> {
+        if size > data.len() {
+            self.usage.charge(size - data.len())?;
+        } else {
+            self.usage.release(data.len() - size);
+        }
+        data.resize(size, 0);
+        data.shrink_to_fit();
+        Ok(())
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        let contents = match self.contents.get_mut() {
+            Contents::File(data) => data.len(),
+            Contents::Directory(_) => 0,
+        };
+        self.usage.release(self.overhead + contents);
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        match &*self.contents.lock() {
+            Contents::File(data) => Metadata {
+                kind: InodeKind::File,
+                size: data.len(),
+            },
+            Contents::Directory(children) => Metadata {
+                kind: InodeKind::Directory,
+                size: children.len(),
+            },
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        match &*self.contents.lock() {
+            Contents::File(data) => {
+                let remaining = data.get(offset..).unwrap_or(&[]);
+                let len = remaining.len().min(buf.len());
+                buf[..len].copy_from_slice(&remaining[..len]);
+                Ok(len)
+            }
+            Contents::Directory(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        match &mut *self.contents.lock() {
+            Contents::File(data) => {
+                let end = offset.checked_add(buf.len()).ok_or(VfsError::NoSpace)?;
+                if data.len() < end {
+                    self.resize(data, end)?;
+                }
+                data[offset..end].copy_from_slice(buf);
+                Ok(buf.len())
+            }
+            Contents::Directory(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn truncate(&self, size: usize) -> Result<()> {
+        match &mut *self.contents.lock() {
+            Contents::File(data) => self.resize(data, size),
+            Contents::Directory(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        match &*self.contents.lock() {
+            Contents::Directory(children) => match children.get(name) {
+                Some(child) => Ok(child.clone()),
+                None => Err(VfsError::NotFound),
+            },
+            Contents::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> Result<Arc<dyn Inode>> {
+        match &mut *self.contents.lock() {
+            Contents::Directory(children) => {
+                if children.contains_key(name) {
+                    return Err(VfsError::AlreadyExists);
+                }
+                self.usage.charge(name.len())?;
+                let child = TmpInode::new(kind, self.usage.clone(), INODE_OVERHEAD)
+                    .inspect_err(|_| self.usage.release(name.len()))?;
+                children.insert(name.to_string(), child.clone());
+                Ok(child)
+            }
+            Contents::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        match &mut *self.contents.lock() {
+            Contents::Directory(children) => {
+                let child = children.get(name).ok_or(VfsError::NotFound)?;
+                if let Contents::Directory(grandchildren) = &*child.contents.lock() {
+                    if !grandchildren.is_empty() {
+                        return Err(VfsError::DirectoryNotEmpty);
+                    }
+                }
+                // the inode itself is released once the last handle is gone
+                children.remove(name);
+                self.usage.release(name.len());
+                Ok(())
+            }
+            Contents::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        let target = new_parent
+            .as_any()
+            .and_then(|any| any.downcast_ref::<TmpInode>())
+            .filter(|target| Arc::ptr_eq(&target.usage, &self.usage))
+            .ok_or(VfsError::CrossDevice)?;
+
+        let child = match &*self.contents.lock() {
+            Contents::Directory(children) => children.get(old_name).cloned(),
+            Contents::File(_) => return Err(VfsError::NotADirectory),
+        }
+        .ok_or(VfsError::NotFound)?;
+        if core::ptr::eq(target, &*child) {
+            return Err(VfsError::InvalidPath);
+        }
+        if core::ptr::eq(target, self) && old_name == new_name {
+            return Ok(());
+        }
+
+        // check the target and replace it before the source goes away
+        let freed;
+        match &mut *target.contents.lock() {
+            Contents::Directory(children) => {
+                if let Some(existing) = children.get(new_name) {
+                    let child_kind = child.metadata().kind;
+                    match (child_kind, existing.metadata().kind) {
+                        (InodeKind::File, InodeKind::Directory) => {
+                            return Err(VfsError::IsADirectory)
+                        }
+                        (InodeKind::Directory, InodeKind::File) => {
+                            return Err(VfsError::NotADirectory)
+                        }
+                        (InodeKind::Directory, _) if !existing.is_empty_directory() => {
+                            return Err(VfsError::DirectoryNotEmpty)
+                        }
+                        _ => {}
+                    }
+                }
+                // charge a longer name before anything is replaced
+                let replaced = children.contains_key(new_name);
+                freed = old_name.len() + if replaced { new_name.len() } else { 0 };
+                if new_name.len() > freed {
+                    self.usage.charge(new_name.len() - freed)?;
+                }
+                children.insert(new_name.to_string(), child);
+            }
+            Contents::File(_) => return Err(VfsError::NotADirectory),
+        }
+
+        if let Contents::Directory(children) = &mut *self.contents.lock() {
+            children.remove(old_name);
+        }
+        self.usage.release(freed.saturating_sub(new_name.len()));
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>> {
+        match &*self.contents.lock() {
+            Contents::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, child)| DirEntry {
+                    name: name.clone(),
+                    kind: child.metadata().kind,
+                })
+                .collect()),
+            Contents::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// A file system that lives on the heap and is lost on reboot.
+///
+/// Names, contents and a fixed overhead per inode are counted against a
+/// limit, so a full tmpfs fails with `NoSpace` before the heap runs out.
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+    usage: Arc<Usage>,
+}
+
+impl TmpFs {
+    /// A tmpfs that may use up to `limit` bytes of the heap.
+    pub fn with_limit(limit: usize) -> Self {
+        let usage = Arc::new(Usage {
+            used: AtomicUsize::new(0),
+            limit,
+        });
+        TmpFs {
+            root: TmpInode::new(InodeKind::Directory, usage.clone(), 0).unwrap(),
+            usage,
+        }
+    }
+
+    /// A tmpfs that may use up to a quarter of the heap.
+    pub fn new() -> Self {
+        Self::with_limit(allocator::HEAP_SIZE / 4)
+    }
+
+    /// Bytes currently counted against the limit.
+    pub fn used(&self) -> usize {
+        self.usage.used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.usage.limit
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -61,6 +61,17 @@ macro_rules! resetcolor {
     };
 }
 
+/// Hand the call to the framebuffer console instead and return, while the
+/// console is on the framebuffer.
+macro_rules! on_framebuffer {
+    ($console:ident => $body:expr $(, $ret:expr)?) => {
+        #[cfg(feature = "framebuffer")]
+        if mold_os::framebuffer::with_console(|$console| $body).is_some() {
+            return $($ret)?;
+        }
+    };
+}
+
 /// Prints the given formatted string to the VGA text buffer
 /// through the global `WRITER` instance.
 #[doc(hidden)]
@@ -68,6 +79,7 @@ pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    on_framebuffer!(console => console.write_fmt(args).unwrap());
     interrupts::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
     });
@@ -75,6 +87,7 @@ pub fn _print(args: fmt::Arguments) {
 
 #[doc(hidden)]
 pub fn _clrscr() {
+    on_framebuffer!(console => console.clear());
     // Lock the writer and call the clear method
     let mut writer = WRITER.lock();
     writer.clear();
@@ -82,25 +95,41 @@ pub fn _clrscr() {
 
 pub fn _setcolor(fg: Color, bg: Color) {
     let color_code: ColorCode = ColorCode::new(fg, bg);
+    on_framebuffer!(console => console.set_attribute(color_code.0));
     WRITER.lock().color_code = color_code; // Set the color code in the locked writer
 }
 
 pub fn _reset_color() {
     let color_code: ColorCode = ColorCode::new(Color::White, Color::Black);
+    on_framebuffer!(console => console.set_attribute(color_code.0));
     WRITER.lock().color_code = color_code; // Reset to white on black
 }
 
 // Drawing functions that will be exposed for use
 pub fn write_text_at(row: usize, col: usize, text: &str) {
     use x86_64::instructions::interrupts;
+    on_framebuffer!(console => console.write_string_at(row, col, text));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.write_string_at(row, col, text);
     });
 }
 
+/// Write raw bytes, as `/dev/console` does.
+pub fn write_bytes(bytes: &[u8]) {
+    use x86_64::instructions::interrupts;
+    on_framebuffer!(console => bytes.iter().for_each(|&byte| console.write_byte(byte)));
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for &byte in bytes {
+            writer.write_byte(byte);
+        }
+    });
+}
+
 pub fn draw_horizontal_line(row: usize, start_col: usize, end_col: usize) {
     use x86_64::instructions::interrupts;
+    on_framebuffer!(console => console.draw_horizontal_line(row, start_col, end_col));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.draw_horizontal_line(row, start_col, end_col);
@@ -109,6 +138,7 @@ pub fn draw_horizontal_line(row: usize, start_col: usize, end_col: usize) {
 
 pub fn draw_vertical_line(col: usize, start_row: usize, end_row: usize) {
     use x86_64::instructions::interrupts;
+    on_framebuffer!(console => console.draw_vertical_line(col, start_row, end_row));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.draw_vertical_line(col, start_row, end_row);
@@ -117,6 +147,7 @@ pub fn draw_vertical_line(col: usize, start_row: usize, end_row: usize) {
 
 pub fn draw_box(start_row: usize, start_col: usize, end_row: usize, end_col: usize) {
     use x86_64::instructions::interrupts;
+    on_framebuffer!(console => console.draw_box(start_row, start_col, end_row, end_col));
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.draw_box(start_row, start_col, end_row, end_col);
@@ -125,12 +156,73 @@ pub fn draw_box(start_row: usize, start_col: usize, end_row: usize, end_col: usi
 
 pub fn clear_screen() {
     use x86_64::instructions::interrupts;
+    on_framebuffer!(console => console.clear());
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writer.clear();
     });
 }
 
+/// The cell the mouse cursor is drawn on and the colour it had before.
+struct MouseCursor {
+    row: usize,
+    col: usize,
+    color_code: ColorCode,
+}
+
+static MOUSE_CURSOR: Mutex<Option<MouseCursor>> = Mutex::new(None);
+
+/// The text buffer, for the mouse cursor which mustn't wait for `WRITER`.
+fn screen() -> &'static mut Buffer {
+    unsafe { &mut *(0xb8000 as *mut Buffer) }
+}
+
+/// Put the colour back on the cell under the cursor, unless something
+/// was written there since the cursor was drawn.
+fn restore_mouse_cursor(cursor: &mut Option<MouseCursor>) -> Option<(usize, usize)> {
+    let cursor = cursor.take()?;
+    let cell = &mut screen().chars[cursor.row][cursor.col];
+    let mut character = cell.read();
+    if character.color_code == cursor.color_code.inverted() {
+        character.color_code = cursor.color_code;
+        cell.write(character);
+    }
+    Some((cursor.row, cursor.col))
+}
+
+/// Draw the mouse cursor on the cell at `row`, `col` by swapping its
+/// foreground and background colours, removing it from where it was.
+///
+/// Doesn't take the `WRITER` lock, so it may be called from interrupt handlers.
+pub fn show_mouse_cursor(row: usize, col: usize) {
+    use x86_64::instructions::interrupts;
+    // the cursor is only drawn in text mode
+    on_framebuffer!(_console => ());
+    if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut cursor = MOUSE_CURSOR.lock();
+        restore_mouse_cursor(&mut cursor);
+        let cell = &mut screen().chars[row][col];
+        let mut character = cell.read();
+        *cursor = Some(MouseCursor {
+            row,
+            col,
+            color_code: character.color_code,
+        });
+        character.color_code = character.color_code.inverted();
+        cell.write(character);
+    });
+}
+
+/// Remove the mouse cursor from the screen and return where it was.
+pub fn hide_mouse_cursor() -> Option<(usize, usize)> {
+    use x86_64::instructions::interrupts;
+    on_framebuffer!(_console => (), None);
+    interrupts::without_interrupts(|| restore_mouse_cursor(&mut MOUSE_CURSOR.lock()))
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -162,6 +254,17 @@ impl ColorCode {
         let code = (background as u8) << 4 | (foreground as u8);
         ColorCode(code)
     }
+
+    /// The same colours with foreground and background swapped.
+    fn inverted(self) -> ColorCode {
+        let inverted = ColorCode(self.0.rotate_left(4));
+        if inverted == self {
+            // swapping equal colours would leave the cursor invisible
+            ColorCode::new(Color::Black, Color::LightGray)
+        } else {
+            inverted
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -233,6 +336,8 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
+        // the mouse cursor stays where it is instead of scrolling with the text
+        let mouse = hide_mouse_cursor();
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -241,6 +346,9 @@ impl Writer {
         }
         self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
+        if let Some((row, col)) = mouse {
+            show_mouse_cursor(row, col);
+        }
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -254,10 +362,14 @@ impl Writer {
     }
 
     pub fn clear(&mut self) {
+        let mouse = hide_mouse_cursor();
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row); // Clear each row
         }
         self.column_position = 0; // Reset column position to 0 after clearing
+        if let Some((row, col)) = mouse {
+            show_mouse_cursor(row, col);
+        }
     }
 
     pub fn write_string(&mut self, s: &str) {
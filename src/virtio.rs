@@ -0,0 +1,612 @@
+// virtio-blk driver for the legacy and modern PCI transports
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::pci::{self, Bar, PciDevice, PciDriver};
+use crate::{log, memory, time, warn};
+
+const VENDOR_ID: u16 = 0x1AF4;
+const DEVICE_BLOCK_TRANSITIONAL: u16 = 0x1001;
+const DEVICE_BLOCK_MODERN: u16 = 0x1042;
+
+// device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+const FEATURE_BLOCK_READ_ONLY: u64 = 1 << 5;
+const FEATURE_BLOCK_FLUSH: u64 = 1 << 9;
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// legacy registers, offsets from the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;
+
+// modern common configuration, offsets from its capability
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+// vendor capability types of the modern transport
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+/// Largest queue we set up; a request only needs three descriptors.
+const MAX_QUEUE_SIZE: u16 = 256;
+const TIMEOUT_NS: u64 = 5_000_000_000;
+
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+static DISKS: Mutex<Vec<Arc<VirtioBlock>>> = Mutex::new(Vec::new());
+
+/// How the registers of a device are reached.
+#[derive(Clone, Copy)]
+enum Transport {
+    /// Virtio 0.9.5 registers in I/O space.
+    Legacy { base: u16 },
+    /// Virtio 1.0 structures in memory BARs.
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        notify_multiplier: u32,
+        isr: VirtAddr,
+        device: VirtAddr,
+    },
+}
+
+impl Transport {
+    /// Find the modern configuration structures of `device` and map them.
+    fn modern(device: &PciDevice) -> Option<Transport> {
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for &(id, offset) in &device.capabilities {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let address = device.address;
+            let kind = address.read_u8(offset + 3);
+            let bar = address.read_u8(offset + 4) as usize;
+            let start = address.read_u32(offset + 8) as u64;
+            let length = address.read_u32(offset + 12) as u64;
+            let Some(Some(Bar::Memory { address: base, .. })) = device.bars.get(bar) else {
+                continue;
+            };
+            let map = || memory::map_mmio(PhysAddr::new(base + start), length).ok();
+            match kind {
+                CFG_COMMON if common.is_none() => common = map(),
+                CFG_NOTIFY if notify.is_none() => {
+                    notify = map();
+                    notify_multiplier = address.read_u32(offset + 16);
+                }
+                CFG_ISR if isr.is_none() => isr = map(),
+                CFG_DEVICE if config.is_none() => config = map(),
+                _ => {}
+            }
+        }
+        Some(Transport::Modern {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            isr: isr?,
+            device: config?,
+        })
+    }
+
+    fn legacy(device: &PciDevice) -> Option<Transport> {
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Some(Transport::Legacy { base: port }),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { base } => unsafe { Port::new(base + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => unsafe {
+                memory::read_mmio(common + COMMON_STATUS)
+            },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { base } => unsafe { Port::new(base + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => unsafe {
+                memory::write_mmio(common + COMMON_STATUS, status)
+            },
+        }
+    }
+
+    fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    fn reset(&self) {
+        self.set_status(0);
+        // modern devices may take a moment to finish the reset
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { base } => unsafe {
+                Port::<u32>::new(base + LEGACY_DEVICE_FEATURES).read() as u64
+            },
+            Transport::Modern { common, .. } => unsafe {
+                memory::write_mmio(common + COMMON_DEVICE_FEATURE_SELECT, 0u32);
+                let low: u32 = memory::read_mmio(common + COMMON_DEVICE_FEATURE);
+                memory::write_mmio(common + COMMON_DEVICE_FEATURE_SELECT, 1u32);
+                let high: u32 = memory::read_mmio(common + COMMON_DEVICE_FEATURE);
+                (high as u64) << 32 | low as u64
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Transport::Modern { common, .. } => unsafe {
+                memory::write_mmio(common + COMMON_DRIVER_FEATURE_SELECT, 0u32);
+                memory::write_mmio(common + COMMON_DRIVER_FEATURE, features as u32);
+                memory::write_mmio(common + COMMON_DRIVER_FEATURE_SELECT, 1u32);
+                memory::write_mmio(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            },
+        }
+    }
+
+    /// Read a 32-bit field of the device specific configuration.
+    fn config_u32(&self, offset: u16) -> u32 {
+        match *self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_CONFIG + offset).read()
+            },
+            Transport::Modern { device, .. } => unsafe {
+                memory::read_mmio(device + offset as u64)
+            },
+        }
+    }
+
+    /// Allocate queue 0 and tell the device where it is.
+    fn setup_queue(&self) -> Result<Virtqueue, BlockError> {
+        let size = match *self {
+            // legacy devices dictate the size of the queue
+            Transport::Legacy { base } => unsafe {
+                Port::<u16>::new(base + LEGACY_QUEUE_SELECT).write(0);
+                Port::<u16>::new(base + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => unsafe {
+                memory::write_mmio(common + COMMON_QUEUE_SELECT, 0u16);
+                let size: u16 = memory::read_mmio(common + COMMON_QUEUE_SIZE);
+                size.min(MAX_QUEUE_SIZE)
+            },
+        };
+        let queue = Virtqueue::new(size)?;
+        self.attach_queue(&queue);
+        Ok(queue)
+    }
+
+    /// Tell the device where queue 0 is.
+    fn attach_queue(&self, queue: &Virtqueue) {
+        match *self {
+            Transport::Legacy { base } => unsafe {
+                Port::<u16>::new(base + LEGACY_QUEUE_SELECT).write(0);
+                Port::new(base + LEGACY_QUEUE_ADDRESS).write((queue.phys.as_u64() / 4096) as u32)
+            },
+            Transport::Modern { common, .. } => unsafe {
+                memory::write_mmio(common + COMMON_QUEUE_SELECT, 0u16);
+                memory::write_mmio(common + COMMON_QUEUE_SIZE, queue.size);
+                memory::write_mmio(common + COMMON_QUEUE_DESC, queue.phys.as_u64());
+                memory::write_mmio(
+                    common + COMMON_QUEUE_DRIVER,
+                    queue.phys.as_u64() + queue.avail_offset() as u64,
+                );
+                memory::write_mmio(
+                    common + COMMON_QUEUE_DEVICE,
+                    queue.phys.as_u64() + queue.used_offset() as u64,
+                );
+                memory::write_mmio(common + COMMON_QUEUE_ENABLE, 1u16);
+            },
+        }
+    }
+
+    /// Tell the device that queue 0 has new buffers.
+    fn notify(&self) {
+        match *self {
+            Transport::Legacy { base } => unsafe {
+                Port::new(base + LEGACY_QUEUE_NOTIFY).write(0u16)
+            },
+            Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                ..
+            } => unsafe {
+                memory::write_mmio(common + COMMON_QUEUE_SELECT, 0u16);
+                let offset: u16 = memory::read_mmio(common + COMMON_QUEUE_NOTIFY_OFF);
+                memory::write_mmio(notify + offset as u64 * notify_multiplier as u64, 0u16);
+            },
+        }
+    }
+
+    /// Read and thereby acknowledge the interrupt status.
+    fn acknowledge_interrupt(&self) -> u8 {
+        match *self {
+            Transport::Legacy { base } => unsafe { Port::new(base + LEGACY_ISR).read() },
+            Transport::Modern { isr, .. } => unsafe { memory::read_mmio(isr) },
+        }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue in physically contiguous frames, laid out the way the
+/// legacy interface requires: descriptors, the available ring and, on the
+/// next page, the used ring.
+struct Virtqueue {
+    size: u16,
+    phys: PhysAddr,
+    /// Value of the used index when the last request completed.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(size: u16) -> Result<Virtqueue, BlockError> {
+        if size < 3 {
+            return Err(BlockError::Io);
+        }
+        let mut queue = Virtqueue {
+            size,
+            phys: PhysAddr::zero(),
+            last_used: 0,
+        };
+        let frames = (queue.used_offset() + 6 + 8 * size as usize).div_ceil(4096);
+        queue.phys = memory::allocate_dma(frames).ok_or(BlockError::Io)?;
+        Ok(queue)
+    }
+
+    /// Empty the rings, for a device that was reset.
+    fn clear(&mut self) {
+        let bytes = self.used_offset() + 6 + 8 * self.size as usize;
+        unsafe { ptr::write_bytes(self.base().as_mut_ptr::<u8>(), 0, bytes) };
+        self.last_used = 0;
+    }
+
+    fn avail_offset(&self) -> usize {
+        16 * self.size as usize
+    }
+
+    fn used_offset(&self) -> usize {
+        (self.avail_offset() + 6 + 2 * self.size as usize).next_multiple_of(4096)
+    }
+
+    fn base(&self) -> VirtAddr {
+        memory::phys_to_virt(self.phys)
+    }
+
+    /// Make the chain of `buffers` available as a request.
+    fn submit(&mut self, buffers: &[(PhysAddr, u32, u16)]) {
+        let base = self.base();
+        let descriptors: *mut Descriptor = base.as_mut_ptr();
+        for (i, &(address, length, flags)) in buffers.iter().enumerate() {
+            let next = if i + 1 < buffers.len() { DESC_NEXT } else { 0 };
+            unsafe {
+                descriptors.add(i).write_volatile(Descriptor {
+                    address: address.as_u64(),
+                    length,
+                    flags: flags | next,
+                    next: i as u16 + 1,
+                });
+            }
+        }
+        let avail = base + self.avail_offset() as u64;
+        unsafe {
+            let index: u16 = memory::read_mmio(avail + 2u64);
+            memory::write_mmio(avail + 4 + 2 * (index % self.size) as u64, 0u16);
+            fence(Ordering::SeqCst);
+            memory::write_mmio(avail + 2u64, index.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    fn used_index(&self) -> u16 {
+        let used = self.base() + self.used_offset() as u64;
+        unsafe { memory::read_mmio(used + 2u64) }
+    }
+
+    fn completed(&self) -> bool {
+        self.used_index() != self.last_used
+    }
+}
+
+/// Request header and status byte in front of the data bounce buffer.
+struct Request {
+    queue: Virtqueue,
+    /// Header at offset 0, status at 16 and data from the next frame on.
+    buffer: PhysAddr,
+}
+
+/// A virtio block device.
+pub struct VirtioBlock {
+    name: String,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    transport: Transport,
+    request: Mutex<Request>,
+}
+
+impl VirtioBlock {
+    fn new(device: &PciDevice) -> Result<VirtioBlock, BlockError> {
+        device.enable_bus_mastering();
+        let transport = Transport::modern(device)
+            .or_else(|| Transport::legacy(device))
+            .ok_or(BlockError::Io)?;
+        let features = negotiate(transport)?;
+        let queue = transport.setup_queue()?;
+        let buffer = memory::allocate_dma(1 + block::BOUNCE_FRAMES).ok_or(BlockError::Io)?;
+        let sectors = transport.config_u32(0) as u64 | (transport.config_u32(4) as u64) << 32;
+        transport.add_status(STATUS_DRIVER_OK);
+
+        let index = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+        Ok(VirtioBlock {
+            name: format!("virtio{}", index),
+            sectors,
+            read_only: features & FEATURE_BLOCK_READ_ONLY != 0,
+            can_flush: features & FEATURE_BLOCK_FLUSH != 0,
+            transport,
+            request: Mutex::new(Request { queue, buffer }),
+        })
+    }
+
+    /// Reset the device after a request timed out, so that it lets go of
+    /// the queue and the buffers, and set it up again.
+    fn restart(&self, request: &mut Request) -> Result<(), BlockError> {
+        negotiate(self.transport)?;
+        request.queue.clear();
+        self.transport.attach_queue(&request.queue);
+        self.transport.add_status(STATUS_DRIVER_OK);
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether the device is driven through the legacy I/O port interface.
+    pub fn is_legacy(&self) -> bool {
+        matches!(self.transport, Transport::Legacy { .. })
+    }
+
+    /// Send one request and wait for it to complete. `fill` prepares the
+    /// data buffer and `drain` reads it back afterwards.
+    fn transfer(
+        &self,
+        kind: u32,
+        lba: u64,
+        sectors: usize,
+        fill: impl FnOnce(&mut [u8]),
+        drain: impl FnOnce(&[u8]),
+    ) -> Result<(), BlockError> {
+        let mut request = self.request.lock();
+        let header = request.buffer;
+        let status = header + 16u64;
+        let data = header + 4096u64;
+        let length = sectors * SECTOR_SIZE;
+        let data_slice = unsafe {
+            core::slice::from_raw_parts_mut(memory::phys_to_virt(data).as_mut_ptr::<u8>(), length)
+        };
+        fill(data_slice);
+        unsafe {
+            let header = memory::phys_to_virt(header);
+            memory::write_mmio(header, kind);
+            memory::write_mmio(header + 4u64, 0u32);
+            memory::write_mmio(header + 8u64, lba);
+            memory::write_mmio(memory::phys_to_virt(status), 0xFFu8);
+        }
+
+        let data_flags = if kind == REQUEST_IN { DESC_WRITE } else { 0 };
+        if length == 0 {
+            request
+                .queue
+                .submit(&[(header, 16, 0), (status, 1, DESC_WRITE)]);
+        } else {
+            request.queue.submit(&[
+                (header, 16, 0),
+                (data, length as u32, data_flags),
+                (status, 1, DESC_WRITE),
+            ]);
+        }
+        self.transport.notify();
+        // the interrupt wakes us early; without it the timer does
+        if !time::wait_until(TIMEOUT_NS, || request.queue.completed()) {
+            // the device may still use the buffers the next request needs
+            if let Err(error) = self.restart(&mut request) {
+                warn!("{}: restart failed: {:?}", self.name, error);
+            }
+            return Err(BlockError::Timeout);
+        }
+        request.queue.last_used = request.queue.used_index();
+
+        match unsafe { memory::read_mmio::<u8>(memory::phys_to_virt(status)) } {
+            0 => {
+                drain(data_slice);
+                Ok(())
+            }
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+/// Reset the device and agree on the features to use, which are returned.
+fn negotiate(transport: Transport) -> Result<u64, BlockError> {
+    let modern = matches!(transport, Transport::Modern { .. });
+    transport.reset();
+    transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    let offered = transport.device_features();
+    let mut features = offered & (FEATURE_BLOCK_READ_ONLY | FEATURE_BLOCK_FLUSH);
+    if modern {
+        if offered & FEATURE_VERSION_1 == 0 {
+            transport.set_status(STATUS_FAILED);
+            return Err(BlockError::Io);
+        }
+        features |= FEATURE_VERSION_1;
+    }
+    transport.set_driver_features(features);
+    if modern {
+        transport.add_status(STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.set_status(STATUS_FAILED);
+            return Err(BlockError::Io);
+        }
+    }
+    Ok(features)
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        for (i, chunk) in buf
+            .chunks_mut(block::BOUNCE_SECTORS * SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = lba + (i * block::BOUNCE_SECTORS) as u64;
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.transfer(
+                REQUEST_IN,
+                lba,
+                sectors,
+                |_| {},
+                |data| chunk.copy_from_slice(data),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        for (i, chunk) in buf.chunks(block::BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * block::BOUNCE_SECTORS) as u64;
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.transfer(
+                REQUEST_OUT,
+                lba,
+                sectors,
+                |data| data.copy_from_slice(chunk),
+                |_| {},
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        self.transfer(REQUEST_FLUSH, 0, 0, |_| {}, |_| {})
+    }
+}
+
+fn is_virtio_block(device: &PciDevice) -> bool {
+    device.vendor_id == VENDOR_ID
+        && matches!(
+            device.device_id,
+            DEVICE_BLOCK_TRANSITIONAL | DEVICE_BLOCK_MODERN
+        )
+}
+
+fn probe(device: &PciDevice) {
+    let disk = match VirtioBlock::new(device) {
+        Ok(disk) => Arc::new(disk),
+        Err(error) => {
+            warn!("{}: virtio-blk setup failed: {:?}", device.address, error);
+            return;
+        }
+    };
+    let transport = disk.transport;
+    // completions are noticed on the next timer tick if this fails
+    pci::register_interrupt_handler(device, move || {
+        transport.acknowledge_interrupt();
+    });
+    log!(
+        "{}: virtio-blk ({} sectors, {} transport{})",
+        disk.name,
+        disk.sectors,
+        if disk.is_legacy() { "legacy" } else { "modern" },
+        if disk.read_only { ", read-only" } else { "" }
+    );
+    DISKS.lock().push(disk.clone());
+    block::register(disk);
+}
+
+/// The virtio disks that were set up, in the order they were found.
+pub fn disks() -> Vec<Arc<VirtioBlock>> {
+    DISKS.lock().clone()
+}
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: is_virtio_block,
+    probe,
+};
+
+/// Register the driver with the PCI bus. Requires `pci::init` to have run
+/// for devices to be found right away.
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::acpi;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    acpi::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn rsdp_is_found() {
+    assert!(acpi::find_rsdp().is_some());
+}
+
+#[test_case]
+fn tables_have_valid_checksums() {
+    for signature in [b"APIC", b"FACP", b"HPET"] {
+        assert!(acpi::find_table(signature).is_some());
+    }
+}
+
+#[test_case]
+fn platform_info() {
+    let info = acpi::info().expect("ACPI not initialized");
+    assert!(info.cpu_count() >= 1);
+    assert!(info.local_apic_address().is_some());
+    assert!(info.io_apic_addresses().count() >= 1);
+    assert!(info.pm1a_control_port().is_some());
+    assert!(info.hpet.is_some());
+}
+
+#[test_case]
+fn dsdt_has_s5_object() {
+    assert!(acpi::dsdt().is_some());
+    assert!(acpi::s5_sleep_types().is_some());
+}
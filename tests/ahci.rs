@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod block_device;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::block::{self, BlockDevice};
+use mold_os::pci;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// The disk `test-args` attaches to the AHCI controller.
+fn sata_disk() -> Arc<dyn BlockDevice> {
+    block::find("sata0").expect("SATA disk not found")
+}
+
+#[test_case]
+fn disk_is_found_on_pci() {
+    assert!(!pci::find_class(0x01, 0x06).is_empty());
+    // IDENTIFY reports the 4 MiB image made by the build script
+    assert_eq!(sata_disk().sector_count(), 8192);
+}
+
+#[test_case]
+fn sata_disk_conforms() {
+    block_device::check(&*sata_disk());
+}
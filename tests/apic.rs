@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::{acpi, apic, hpet, time};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_processors_and_io_apic() {
+    let madt = acpi::info()
+        .and_then(|info| info.madt.as_ref())
+        .expect("no MADT found");
+    assert!(madt.processors.iter().any(|p| p.enabled));
+    assert!(!madt.io_apics.is_empty());
+}
+
+#[test_case]
+fn apic_replaces_pic() {
+    assert!(apic::is_enabled());
+}
+
+#[test_case]
+fn lapic_timer_ticks() {
+    let start = time::ticks();
+    time::sleep_ms(20);
+    assert!(time::ticks() >= start + 20);
+}
+
+#[test_case]
+fn hpet_counts_up() {
+    assert!(hpet::is_available());
+    let start = hpet::now_ns();
+    hpet::wait_micros(1000);
+    assert!(hpet::now_ns() - start >= 1_000_000);
+}
+
+#[test_case]
+fn tsc_agrees_with_hpet() {
+    let (tsc_start, hpet_start) = (time::now_ns(), hpet::now_ns());
+    hpet::wait_micros(10_000);
+    let tsc_elapsed = time::now_ns() - tsc_start;
+    let hpet_elapsed = hpet::now_ns() - hpet_start;
+    // allow 10% deviation, emulated TSCs are not very precise
+    assert!(tsc_elapsed.abs_diff(hpet_elapsed) < hpet_elapsed / 10);
+}
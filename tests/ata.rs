@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod block_device;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::block::{self, BlockDevice, SECTOR_SIZE};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// The scratch disk `test-args` attaches as the primary slave.
+fn scratch_disk() -> Arc<dyn BlockDevice> {
+    block::find("ata1").expect("scratch disk not found")
+}
+
+#[test_case]
+fn boot_disk_is_found() {
+    // the boot image itself is attached as the primary master
+    let disk = block::find("ata0").expect("boot disk not found");
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xAA]);
+}
+
+#[test_case]
+fn scratch_disk_conforms() {
+    block_device::check(&*scratch_disk());
+}
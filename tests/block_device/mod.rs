@@ -0,0 +1,63 @@
+// Checks every block device driver has to pass, shared by the driver tests
+use alloc::vec;
+use alloc::vec::Vec;
+use mold_os::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use spin::Mutex;
+
+/// More sectors than one LBA28 command or a driver's bounce buffer carries;
+/// too big for the heap.
+static LARGE: Mutex<[u8; 300 * SECTOR_SIZE]> = Mutex::new([0; 300 * SECTOR_SIZE]);
+
+/// Run the reads and writes every `BlockDevice` must handle on `disk`, a
+/// scratch disk whose contents may be overwritten.
+pub fn check(disk: &dyn BlockDevice) {
+    write_and_read_back(disk);
+    large_transfer(disk);
+    invalid_requests(disk);
+}
+
+fn write_and_read_back(disk: &dyn BlockDevice) {
+    let data: Vec<u8> = (0..3 * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+    disk.write_sectors(5, &data).unwrap();
+    disk.flush().unwrap();
+
+    let mut read = vec![0; data.len()];
+    disk.read_sectors(5, &mut read).unwrap();
+    assert_eq!(read, data);
+
+    let mut middle = [0; SECTOR_SIZE];
+    disk.read_sectors(6, &mut middle).unwrap();
+    assert_eq!(&middle[..], &data[SECTOR_SIZE..2 * SECTOR_SIZE]);
+}
+
+fn large_transfer(disk: &dyn BlockDevice) {
+    let mut buf = LARGE.lock();
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (i / SECTOR_SIZE) as u8;
+    }
+    disk.write_sectors(100, &*buf).unwrap();
+    buf.fill(0);
+    disk.read_sectors(100, &mut *buf).unwrap();
+    assert!(buf
+        .iter()
+        .enumerate()
+        .all(|(i, &byte)| byte == (i / SECTOR_SIZE) as u8));
+}
+
+fn invalid_requests(disk: &dyn BlockDevice) {
+    let mut sector = [0; SECTOR_SIZE];
+    let end = disk.sector_count();
+    assert_eq!(
+        disk.read_sectors(end, &mut sector),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.write_sectors(end - 1, &[0; 2 * SECTOR_SIZE]),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.read_sectors(0, &mut sector[..100]),
+        Err(BlockError::BadBuffer)
+    );
+    disk.read_sectors(end - 1, &mut sector).unwrap();
+}
@@ -0,0 +1,199 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mold_os::block::{BlockDevice, BlockError, RamDisk, SECTOR_SIZE};
+use mold_os::cache::{self, BlockCache, FLUSH_INTERVAL_MS, READ_AHEAD};
+use mold_os::time;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_filesystems();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// A RAM disk that counts the sectors going through it.
+struct CountingDisk {
+    disk: RamDisk,
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl CountingDisk {
+    fn new(sectors: u64) -> Arc<Self> {
+        Arc::new(CountingDisk {
+            disk: RamDisk::new("counted", sectors),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    fn reads(&self) -> u64 {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+}
+
+impl BlockDevice for CountingDisk {
+    fn name(&self) -> &str {
+        self.disk.name()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.disk.sector_count()
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads
+            .fetch_add((buf.len() / SECTOR_SIZE) as u64, Ordering::SeqCst);
+        self.disk.read_sectors(lba, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.writes
+            .fetch_add((buf.len() / SECTOR_SIZE) as u64, Ordering::SeqCst);
+        self.disk.write_sectors(lba, buf)
+    }
+}
+
+#[test_case]
+fn repeated_reads_hit() {
+    let disk = CountingDisk::new(64);
+    let cache = BlockCache::new(disk.clone(), 16);
+    let mut sector = [0; SECTOR_SIZE];
+    cache.read_sectors(10, &mut sector).unwrap();
+    cache.read_sectors(10, &mut sector).unwrap();
+    cache.read_sectors(10, &mut sector).unwrap();
+    assert_eq!(disk.reads(), 1);
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert_eq!(stats.hit_rate(), 66);
+}
+
+#[test_case]
+fn writes_are_deferred() {
+    let disk = CountingDisk::new(64);
+    let cache = BlockCache::new(disk.clone(), 16);
+    cache.write_sectors(3, &[7; 2 * SECTOR_SIZE]).unwrap();
+    assert_eq!(disk.writes(), 0);
+    assert_eq!(cache.dirty_sectors(), 2);
+
+    // reads see the cached data before it is written back
+    let mut sector = [0; SECTOR_SIZE];
+    cache.read_sectors(4, &mut sector).unwrap();
+    assert_eq!(sector, [7; SECTOR_SIZE]);
+    disk.read_sectors(4, &mut sector).unwrap();
+    assert_eq!(sector, [0; SECTOR_SIZE]);
+
+    cache.flush().unwrap();
+    assert_eq!(disk.writes(), 2);
+    assert_eq!(cache.dirty_sectors(), 0);
+    disk.read_sectors(4, &mut sector).unwrap();
+    assert_eq!(sector, [7; SECTOR_SIZE]);
+    assert_eq!(cache.stats().write_backs, 2);
+}
+
+#[test_case]
+fn least_recently_used_is_evicted() {
+    let disk = CountingDisk::new(64);
+    let cache = BlockCache::new(disk.clone(), 4);
+    let mut sector = [0; SECTOR_SIZE];
+    for lba in [0, 10, 20, 30] {
+        cache.read_sectors(lba, &mut sector).unwrap();
+    }
+    // touch sector 0 so that 10 is the oldest
+    cache.read_sectors(0, &mut sector).unwrap();
+    cache.write_sectors(40, &[1; SECTOR_SIZE]).unwrap();
+    assert_eq!(cache.len(), 4);
+    assert_eq!(cache.stats().evictions, 1);
+
+    let reads = disk.reads();
+    cache.read_sectors(0, &mut sector).unwrap();
+    assert_eq!(disk.reads(), reads);
+    cache.read_sectors(10, &mut sector).unwrap();
+    assert_eq!(disk.reads(), reads + 1);
+
+    // evicting a dirty sector writes it back
+    for lba in [50, 51, 52, 53] {
+        cache.read_sectors(lba, &mut sector).unwrap();
+    }
+    assert_eq!(disk.writes(), 1);
+    disk.read_sectors(40, &mut sector).unwrap();
+    assert_eq!(sector, [1; SECTOR_SIZE]);
+}
+
+#[test_case]
+fn sequential_reads_read_ahead() {
+    let disk = CountingDisk::new(64);
+    let cache = BlockCache::new(disk.clone(), 32);
+    let mut sector = [0; SECTOR_SIZE];
+    cache.read_sectors(0, &mut sector).unwrap();
+    cache.read_sectors(1, &mut sector).unwrap();
+    assert_eq!(disk.reads(), 2 + READ_AHEAD);
+    assert_eq!(cache.stats().read_ahead, READ_AHEAD);
+
+    for lba in 2..2 + READ_AHEAD {
+        cache.read_sectors(lba, &mut sector).unwrap();
+    }
+    assert_eq!(cache.stats().misses, 2);
+
+    cache.read_sectors(2 + READ_AHEAD, &mut sector).unwrap();
+    assert_eq!(cache.stats().read_ahead, READ_AHEAD * 2);
+
+    // random reads don't read ahead
+    cache.read_sectors(40, &mut sector).unwrap();
+    cache.read_sectors(30, &mut sector).unwrap();
+    assert_eq!(cache.stats().read_ahead, READ_AHEAD * 2);
+}
+
+#[test_case]
+fn large_requests_bypass_the_cache() {
+    let disk = CountingDisk::new(64);
+    let cache = BlockCache::new(disk.clone(), 4);
+    cache.write_sectors(2, &[5; SECTOR_SIZE]).unwrap();
+
+    let mut buf = vec![0; 8 * SECTOR_SIZE];
+    cache.read_sectors(0, &mut buf).unwrap();
+    assert_eq!(&buf[2 * SECTOR_SIZE..3 * SECTOR_SIZE], &[5; SECTOR_SIZE]);
+    assert_eq!(cache.len(), 1);
+
+    cache.write_sectors(0, &[9; 8 * SECTOR_SIZE]).unwrap();
+    assert_eq!(cache.dirty_sectors(), 0);
+    let mut sector = [0; SECTOR_SIZE];
+    cache.read_sectors(2, &mut sector).unwrap();
+    assert_eq!(sector, [9; SECTOR_SIZE]);
+}
+
+#[test_case]
+fn dirty_sectors_are_flushed_periodically() {
+    let disk = CountingDisk::new(64);
+    let cache = Arc::new(BlockCache::new(disk.clone(), 8));
+    cache::register(cache.clone());
+    cache.write_sectors(1, &[3; SECTOR_SIZE]).unwrap();
+
+    time::sleep_ms(2 * FLUSH_INTERVAL_MS + 100);
+    assert_eq!(cache.dirty_sectors(), 0);
+    assert_eq!(disk.writes(), 1);
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::memory;
+use mold_os::region;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+fn is_mapped(addr: x86_64::VirtAddr) -> bool {
+    unsafe { memory::translate_addr(addr, memory::physical_memory_offset()).is_some() }
+}
+
+#[test_case]
+fn reserved_region_is_not_backed() {
+    let start = region::reserve("unbacked test region", 64 * 4096).unwrap();
+    assert!(!is_mapped(start));
+    assert!(!is_mapped(start + 63 * 4096u64));
+}
+
+#[test_case]
+fn touched_pages_are_zeroed() {
+    let start = region::reserve("zeroed test region", 16 * 4096).unwrap();
+    let ptr: *const u64 = (start + 5 * 4096u64).as_ptr();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    assert!(is_mapped(start + 5 * 4096u64));
+    assert!(!is_mapped(start + 6 * 4096u64));
+}
+
+#[test_case]
+fn large_sparse_buffer() {
+    // 64 MiB would never fit into the heap, but only touched pages cost a frame
+    let size = 64 * 1024 * 1024;
+    let start = region::reserve("sparse test region", size).unwrap();
+    for offset in (0..size).step_by(8 * 1024 * 1024) {
+        let ptr: *mut u64 = (start + offset).as_mut_ptr();
+        unsafe { ptr.write_volatile(offset) };
+        assert_eq!(unsafe { ptr.read_volatile() }, offset);
+    }
+}
+
+#[test_case]
+fn overlapping_regions_are_rejected() {
+    use x86_64::structures::paging::PageTableFlags;
+
+    let start = region::reserve("overlap test region", 4 * 4096).unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    assert_eq!(
+        region::reserve_at("overlap", start + 4096u64, 4096, flags),
+        Err(region::RegionError::Overlap)
+    );
+}
+
+#[test_case]
+fn oversized_regions_are_rejected() {
+    use x86_64::structures::paging::PageTableFlags;
+
+    let before = region::reserve("before oversized", 4096).unwrap();
+    assert_eq!(
+        region::reserve("oversized", u64::MAX),
+        Err(region::RegionError::OutOfSpace)
+    );
+    assert_eq!(
+        region::reserve("oversized", 1 << 47),
+        Err(region::RegionError::OutOfSpace)
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    assert_eq!(
+        region::reserve_at("oversized", before, u64::MAX - 4095, flags),
+        Err(region::RegionError::OutOfSpace)
+    );
+    // failed reservations leave no hole behind
+    let after = region::reserve("after oversized", 4096).unwrap();
+    assert_eq!(after, before + 2 * 4096u64);
+}
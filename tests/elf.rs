@@ -0,0 +1,312 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::address_space::{self, AddressSpace};
+use mold_os::elf::{self, ElfError, PIE_BASE};
+use mold_os::memory::MMIO_AREA_START;
+use mold_os::region::{self, REGION_AREA_START};
+use mold_os::stack::STACK_AREA_START;
+use mold_os::usermode::ExitStatus;
+use x86_64::VirtAddr;
+
+const TEXT_ADDR: u64 = 0x_2000_0000_0000;
+const DATA_ADDR: u64 = TEXT_ADDR + 0x10000;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+core::arch::global_asm!(
+    r#"
+.global args_program, args_program_end
+args_program:
+    # write(2, argv[1], 5)
+    mov edi, 2
+    mov rsi, [rsp + 16]
+    mov edx, 5
+    mov eax, 1
+    syscall
+    # exit(argc + 10 * written)
+    imul rdi, rax, 10
+    add rdi, [rsp]
+    mov eax, 2
+    syscall
+args_program_end:
+
+.global bss_program, bss_program_end
+bss_program:
+    mov rax, {data}
+    mov rdi, [rax + 0x1000]
+    mov qword ptr [rax], 5
+    add rdi, [rax]
+    mov eax, 2
+    syscall
+bss_program_end:
+
+.global text_write_program, text_write_program_end
+text_write_program:
+    lea rax, [rip]
+    mov byte ptr [rax], 0
+    xor edi, edi
+    mov eax, 2
+    syscall
+text_write_program_end:
+
+.global data_jump_program, data_jump_program_end
+data_jump_program:
+    mov rax, {data}
+    jmp rax
+data_jump_program_end:
+
+.global pie_program, pie_program_end
+pie_program:
+    lea rdi, [rip + 2f]
+    mov rdi, [rdi]
+    mov eax, 2
+    syscall
+2:
+    .quad 7
+pie_program_end:
+"#,
+    data = const DATA_ADDR,
+);
+
+macro_rules! program {
+    ($start:ident, $end:ident) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        let start = &raw const $start;
+        let len = &raw const $end as usize - start as usize;
+        unsafe { core::slice::from_raw_parts(start, len) }
+    }};
+}
+
+/// A loadable segment for `build_elf`: address, flags, contents and size in memory.
+struct Segment<'a>(u64, u32, &'a [u8], u64);
+
+/// Build an ELF64 image with one page aligned segment per entry, entering
+/// at the start of the first one.
+fn build_elf(kind: u16, segments: &[Segment]) -> Vec<u8> {
+    let mut image = vec![0u8; 64 + 56 * segments.len()];
+    image[0..4].copy_from_slice(b"\x7fELF");
+    image[4] = 2; // 64-bit
+    image[5] = 1; // little endian
+    image[6] = 1; // version
+    image[16..18].copy_from_slice(&kind.to_le_bytes());
+    image[18..20].copy_from_slice(&0x3Eu16.to_le_bytes());
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[24..32].copy_from_slice(&segments[0].0.to_le_bytes());
+    image[32..40].copy_from_slice(&64u64.to_le_bytes());
+    image[52..54].copy_from_slice(&64u16.to_le_bytes());
+    image[54..56].copy_from_slice(&56u16.to_le_bytes());
+    image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+    for (index, Segment(vaddr, flags, data, memory_size)) in segments.iter().enumerate() {
+        let offset = 0x1000 * (index as u64 + 1);
+        let header = 64 + 56 * index;
+        image[header..header + 4].copy_from_slice(&1u32.to_le_bytes());
+        image[header + 4..header + 8].copy_from_slice(&flags.to_le_bytes());
+        image[header + 8..header + 16].copy_from_slice(&offset.to_le_bytes());
+        image[header + 16..header + 24].copy_from_slice(&vaddr.to_le_bytes());
+        image[header + 24..header + 32].copy_from_slice(&vaddr.to_le_bytes());
+        image[header + 32..header + 40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        image[header + 40..header + 48].copy_from_slice(&memory_size.to_le_bytes());
+        image[header + 48..header + 56].copy_from_slice(&0x1000u64.to_le_bytes());
+
+        image.resize(offset as usize, 0);
+        image.extend_from_slice(data);
+    }
+    image
+}
+
+fn text(code: &[u8]) -> Segment<'_> {
+    Segment(TEXT_ADDR, PF_R | PF_X, code, code.len() as u64)
+}
+
+#[test_case]
+fn arguments_reach_the_program() {
+    let image = build_elf(ET_EXEC, &[text(program!(args_program, args_program_end))]);
+    let status = elf::exec(&image, &["args", "hello"], &[]).expect("exec failed");
+    assert_eq!(status, ExitStatus::Exited(2 + 10 * 5));
+}
+
+#[test_case]
+fn initial_stack_layout() {
+    let image = build_elf(ET_EXEC, &[text(program!(args_program, args_program_end))]);
+    let program = elf::load(&image, &["a", "bc"], &["X=1"]).expect("load failed");
+    assert_eq!(program.stack_top.as_u64() % 16, 0);
+
+    unsafe {
+        let previous = program.address_space.activate();
+        let stack = program.stack_top.as_ptr::<u64>();
+        let string = |ptr: u64| {
+            let ptr = ptr as *const u8;
+            let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+            core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).unwrap()
+        };
+        let argc = *stack;
+        let argv = [string(*stack.add(1)), string(*stack.add(2))];
+        let argv_end = *stack.add(3);
+        let env = string(*stack.add(4));
+        let envp_end = *stack.add(5);
+        let mut auxv = Vec::new();
+        let mut entry = stack.add(6);
+        while *entry != 0 {
+            auxv.push((*entry, *entry.add(1)));
+            entry = entry.add(2);
+        }
+        address_space::restore(previous);
+
+        assert_eq!(argc, 2);
+        assert_eq!(argv, ["a", "bc"]);
+        assert_eq!(argv_end, 0);
+        assert_eq!(env, "X=1");
+        assert_eq!(envp_end, 0);
+        assert!(auxv.contains(&(6, 4096))); // AT_PAGESZ
+        assert!(auxv.contains(&(9, TEXT_ADDR))); // AT_ENTRY
+    }
+}
+
+#[test_case]
+fn bss_is_zeroed_and_writable() {
+    let data = [0xAAu8; 16];
+    let image = build_elf(
+        ET_EXEC,
+        &[
+            text(program!(bss_program, bss_program_end)),
+            Segment(DATA_ADDR, PF_R | PF_W, &data, 0x2000),
+        ],
+    );
+    let status = elf::exec(&image, &[], &[]).expect("exec failed");
+    assert_eq!(status, ExitStatus::Exited(5));
+}
+
+#[test_case]
+fn text_is_read_only() {
+    let image = build_elf(
+        ET_EXEC,
+        &[text(program!(text_write_program, text_write_program_end))],
+    );
+    let status = elf::exec(&image, &[], &[]).expect("exec failed");
+    assert_eq!(status, ExitStatus::Killed("page fault"));
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    let data = [0x90u8; 16];
+    let image = build_elf(
+        ET_EXEC,
+        &[
+            text(program!(data_jump_program, data_jump_program_end)),
+            Segment(DATA_ADDR, PF_R | PF_W, &data, data.len() as u64),
+        ],
+    );
+    let status = elf::exec(&image, &[], &[]).expect("exec failed");
+    assert_eq!(status, ExitStatus::Killed("page fault"));
+}
+
+#[test_case]
+fn position_independent_executable() {
+    let code = program!(pie_program, pie_program_end);
+    let image = build_elf(ET_DYN, &[Segment(0, PF_R | PF_X, code, code.len() as u64)]);
+    let program = elf::load(&image, &[], &[]).expect("load failed");
+    assert_eq!(program.entry.as_u64(), PIE_BASE);
+    let status = elf::exec(&image, &[], &[]).expect("exec failed");
+    assert_eq!(status, ExitStatus::Exited(7));
+}
+
+#[test_case]
+fn program_pages_stay_out_of_the_kernel_page_table() {
+    let image = build_elf(ET_EXEC, &[text(program!(args_program, args_program_end))]);
+    elf::exec(&image, &["args", "hello"], &[]).expect("exec failed");
+    assert!(!mold_os::memory::is_user_accessible(
+        VirtAddr::new(TEXT_ADDR),
+        false
+    ));
+}
+
+#[test_case]
+fn kernel_areas_are_not_user_space() {
+    let space = AddressSpace::new().unwrap();
+    for area in [STACK_AREA_START, REGION_AREA_START, MMIO_AREA_START] {
+        // even where the kernel hasn't mapped anything yet
+        let end_of_slot = (area | 0x7F_FFFF_FFFF) + 1 - 4096;
+        assert!(!space.is_user_range(VirtAddr::new(end_of_slot), 4096));
+    }
+
+    // a region reserved after the address space was made is reachable in it
+    let start = region::reserve("late region", 4096).unwrap();
+    unsafe {
+        let previous = space.activate();
+        start.as_mut_ptr::<u64>().write_volatile(7);
+        address_space::restore(previous);
+        assert_eq!(start.as_ptr::<u64>().read_volatile(), 7);
+    }
+}
+
+#[test_case]
+fn invalid_images_are_rejected() {
+    let code = program!(args_program, args_program_end);
+
+    let mut image = build_elf(ET_EXEC, &[text(code)]);
+    image[0] = 0;
+    assert!(matches!(elf::load(&image, &[], &[]), Err(ElfError::NotElf)));
+
+    let mut image = build_elf(ET_EXEC, &[text(code)]);
+    image[18] = 0x28; // ARM
+    assert!(matches!(
+        elf::load(&image, &[], &[]),
+        Err(ElfError::Unsupported)
+    ));
+
+    let image = build_elf(ET_EXEC, &[Segment(TEXT_ADDR, PF_R, code, 1)]);
+    assert!(matches!(
+        elf::load(&image, &[], &[]),
+        Err(ElfError::Malformed)
+    ));
+
+    // addresses the kernel uses are off limits
+    let kernel_addr = VirtAddr::from_ptr(code).as_u64();
+    let image = build_elf(
+        ET_EXEC,
+        &[Segment(kernel_addr, PF_R | PF_X, code, code.len() as u64)],
+    );
+    assert!(matches!(
+        elf::load(&image, &[], &[]),
+        Err(ElfError::BadAddress)
+    ));
+
+    let image = build_elf(ET_EXEC, &[Segment(0xffff_8000_0000_0000, PF_R, code, 16)]);
+    assert!(matches!(
+        elf::load(&image, &[], &[]),
+        Err(ElfError::BadAddress)
+    ));
+}
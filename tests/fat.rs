@@ -0,0 +1,289 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::block::{BlockDevice, RamDisk, SECTOR_SIZE};
+use mold_os::partition;
+use mold_os::vfs::fat::{FatFs, FatType};
+use mold_os::vfs::{self, FileSystem, InodeKind, OpenFlags, VfsError};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_filesystems();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// Write an empty FAT file system with one sector per cluster to `device`,
+/// the way `mkfs.fat` lays it out.
+fn format(device: &dyn BlockDevice, fat_type: FatType) {
+    let total = device.sector_count();
+    let (reserved, root_entries, entry_bits) = match fat_type {
+        FatType::Fat12 => (1u64, 64u64, 12),
+        FatType::Fat16 => (1, 512, 16),
+        FatType::Fat32 => (32, 0, 32),
+    };
+    let root_sectors = root_entries * 32 / SECTOR_SIZE as u64;
+    let fat_sectors = ((total + 2) * entry_bits / 8).div_ceil(SECTOR_SIZE as u64);
+
+    let mut boot = [0u8; SECTOR_SIZE];
+    boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"mkfs.fat");
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    boot[21] = 0xF8;
+    if fat_type == FatType::Fat32 {
+        boot[32..36].copy_from_slice(&(total as u32).to_le_bytes());
+        boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    } else {
+        boot[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+        boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    }
+    boot[510..].copy_from_slice(&[0x55, 0xAA]);
+    device.write_sectors(0, &boot).unwrap();
+
+    // the first two entries hold the media type; FAT32 also has the root
+    // directory in cluster 2
+    let mut fat = [0u8; SECTOR_SIZE];
+    match fat_type {
+        FatType::Fat12 => fat[..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]),
+        FatType::Fat16 => fat[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]),
+        FatType::Fat32 => {
+            fat[..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+            fat[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+            fat[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        }
+    }
+    for copy in 0..2 {
+        device
+            .write_sectors(reserved + copy * fat_sectors, &fat)
+            .unwrap();
+    }
+    let data_start = reserved + 2 * fat_sectors + root_sectors;
+    assert!(data_start < total);
+}
+
+/// Sectors that give each FAT type a cluster count in its range.
+fn disk_sectors(fat_type: FatType) -> u64 {
+    match fat_type {
+        FatType::Fat12 => 128,
+        FatType::Fat16 => 8192,
+        FatType::Fat32 => 70000,
+    }
+}
+
+fn formatted(fat_type: FatType) -> Arc<FatFs> {
+    let disk = Arc::new(RamDisk::new("ram", disk_sectors(fat_type)));
+    format(&*disk, fat_type);
+    let fs = FatFs::new(disk).expect("not recognized as FAT");
+    assert_eq!(fs.fat_type(), fat_type);
+    Arc::new(fs)
+}
+
+fn write_file(path: &str, data: &[u8], flags: OpenFlags) -> Result<(), VfsError> {
+    let fd = vfs::open(path, OpenFlags::WRITE | OpenFlags::CREATE | flags)?;
+    let result = vfs::write(fd, data);
+    vfs::close(fd)?;
+    result.map(|_| ())
+}
+
+fn names(path: &str) -> Vec<String> {
+    let entries = vfs::readdir(path).unwrap();
+    entries.into_iter().map(|entry| entry.name).collect()
+}
+
+/// Create, append to, list and delete files on a freshly formatted volume.
+fn exercise(fat_type: FatType) {
+    let fs = formatted(fat_type);
+    let free = fs.free_clusters().unwrap();
+    let mount_point = format!("/{:?}", fat_type);
+    vfs::mkdir(&mount_point).unwrap();
+    vfs::mount(&mount_point, fs.clone()).unwrap();
+    let path = |name: &str| format!("{}/{}", mount_point, name);
+
+    // a plain 8.3 name and a long one spanning several clusters
+    write_file(&path("SCORES.TXT"), b"100", OpenFlags::empty()).unwrap();
+    let level: Vec<u8> = (0..3000).map(|i| (i % 97) as u8).collect();
+    write_file(&path("Saved maze level.txt"), &level, OpenFlags::empty()).unwrap();
+    write_file(&path("SCORES.TXT"), b" 250", OpenFlags::APPEND).unwrap();
+
+    assert_eq!(vfs::read_to_vec(&path("scores.txt")).unwrap(), b"100 250");
+    assert_eq!(
+        vfs::read_to_vec(&path("Saved maze level.txt")).unwrap(),
+        level
+    );
+    assert_eq!(names(&mount_point), ["SCORES.TXT", "Saved maze level.txt"]);
+    assert!(fs.free_clusters().unwrap() < free - 5);
+
+    vfs::mkdir(&path("saves")).unwrap();
+    vfs::mkdir(&path("saves/slot 1")).unwrap();
+    write_file(
+        &path("saves/slot 1/game.sav"),
+        b"level 3",
+        OpenFlags::empty(),
+    )
+    .unwrap();
+    assert_eq!(
+        vfs::stat(&path("saves/./slot 1/../slot 1")).unwrap().kind,
+        InodeKind::Directory
+    );
+    assert_eq!(vfs::rmdir(&path("saves")), Err(VfsError::DirectoryNotEmpty));
+
+    vfs::unlink(&path("saves/slot 1/game.sav")).unwrap();
+    vfs::rmdir(&path("saves/slot 1")).unwrap();
+    vfs::rmdir(&path("saves")).unwrap();
+    vfs::unlink(&path("Saved maze level.txt")).unwrap();
+    vfs::unlink(&path("SCORES.TXT")).unwrap();
+    assert!(names(&mount_point).is_empty());
+    assert_eq!(fs.free_clusters().unwrap(), free);
+
+    vfs::unmount(&mount_point).unwrap();
+    vfs::rmdir(&mount_point).unwrap();
+}
+
+#[test_case]
+fn fat12() {
+    exercise(FatType::Fat12);
+}
+
+#[test_case]
+fn fat16() {
+    exercise(FatType::Fat16);
+}
+
+#[test_case]
+fn fat32() {
+    exercise(FatType::Fat32);
+}
+
+#[test_case]
+fn many_entries_grow_a_directory() {
+    let fs = formatted(FatType::Fat16);
+    let root = fs.root();
+    let dir = root.create("dir", InodeKind::Directory).unwrap();
+    // one sector of a cluster holds 16 entries, long names take two each
+    for i in 0..20 {
+        dir.create(&format!("file number {}", i), InodeKind::File)
+            .unwrap();
+    }
+    assert_eq!(dir.readdir().unwrap().len(), 20);
+    assert!(dir.lookup("file number 19").is_ok());
+    assert_eq!(
+        dir.create("FILE NUMBER 3", InodeKind::File).err(),
+        Some(VfsError::AlreadyExists)
+    );
+}
+
+#[test_case]
+fn truncate_frees_clusters() {
+    let fs = formatted(FatType::Fat12);
+    let free = fs.free_clusters().unwrap();
+    let file = fs.root().create("data.bin", InodeKind::File).unwrap();
+    file.write_at(0, &vec![7; 5 * SECTOR_SIZE]).unwrap();
+    assert_eq!(fs.free_clusters().unwrap(), free - 5);
+
+    file.truncate(SECTOR_SIZE + 1).unwrap();
+    assert_eq!(file.metadata().size, SECTOR_SIZE + 1);
+    assert_eq!(fs.free_clusters().unwrap(), free - 2);
+    file.truncate(0).unwrap();
+    assert_eq!(fs.free_clusters().unwrap(), free);
+
+    // writing past the end leaves zeros in between
+    file.write_at(10, b"x").unwrap();
+    let mut buf = [1; 11];
+    assert_eq!(file.read_at(0, &mut buf), Ok(11));
+    assert_eq!(&buf[..10], &[0; 10]);
+}
+
+#[test_case]
+fn large_gaps_are_zero_filled() {
+    let fs = formatted(FatType::Fat16);
+    let file = fs.root().create("SPARSE.BIN", InodeKind::File).unwrap();
+    // gaps much larger than the kernel heap
+    let gap = 1024 * 1024;
+    file.write_at(gap, b"x").unwrap();
+    file.truncate(2 * gap).unwrap();
+    assert_eq!(file.metadata().size, 2 * gap);
+
+    let mut buf = [1; SECTOR_SIZE];
+    for offset in (0..2 * gap).step_by(SECTOR_SIZE) {
+        assert_eq!(file.read_at(offset, &mut buf), Ok(SECTOR_SIZE));
+        assert_eq!(buf[0], if offset == gap { b'x' } else { 0 });
+        assert!(buf[1..].iter().all(|&byte| byte == 0));
+    }
+}
+
+#[test_case]
+fn full_root_directory() {
+    let fs = formatted(FatType::Fat12);
+    let root = fs.root();
+    // the FAT12 root holds 64 entries
+    for i in 0..64 {
+        root.create(&format!("F{}", i), InodeKind::File).unwrap();
+    }
+    assert_eq!(
+        root.create("ONEMORE", InodeKind::File).err(),
+        Some(VfsError::NoSpace)
+    );
+}
+
+#[test_case]
+fn partitioned_disk() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("disk", 9000));
+    let mut mbr = [0u8; SECTOR_SIZE];
+    let entry = &mut mbr[446..462];
+    entry[4] = 0x06; // FAT16
+    entry[8..12].copy_from_slice(&64u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&8192u32.to_le_bytes());
+    mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+    disk.write_sectors(0, &mbr).unwrap();
+
+    let partitions = partition::read_mbr(&disk).unwrap();
+    assert_eq!(partitions.len(), 1);
+    let partition = &partitions[0];
+    assert_eq!(partition.name(), "diskp1");
+    assert_eq!(partition.start(), 64);
+    assert_eq!(partition.sector_count(), 8192);
+
+    format(partition, FatType::Fat16);
+    let mut boot = [0; SECTOR_SIZE];
+    disk.read_sectors(64, &mut boot).unwrap();
+    assert_eq!(&boot[3..11], b"mkfs.fat");
+
+    let partition: Arc<dyn BlockDevice> = Arc::new(partitions.into_iter().next().unwrap());
+    assert_eq!(FatFs::new(partition).unwrap().fat_type(), FatType::Fat16);
+    // the whole disk starts with the MBR, not a file system
+    assert_eq!(FatFs::new(disk).err(), Some(VfsError::Unsupported));
+}
+
+#[test_case]
+fn unformatted_disk_is_rejected() {
+    let disk = Arc::new(RamDisk::new("blank", 128));
+    assert!(partition::read_mbr(&(disk.clone() as Arc<dyn BlockDevice>))
+        .unwrap()
+        .is_empty());
+    assert_eq!(FatFs::new(disk).err(), Some(VfsError::Unsupported));
+}
@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::framebuffer::{
+    self, Console, FramebufferInfo, LinearFramebuffer, PixelFormat, DEFAULT_HEIGHT, DEFAULT_WIDTH,
+};
+use mold_os::graphics::{self, Mode};
+use mold_os::psf::Font;
+use mold_os::vga_buffer::{self, Color};
+use mold_os::{clrscr, memory, pci, println, setcolor};
+use x86_64::PhysAddr;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    pci::init();
+    framebuffer::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// A framebuffer in ordinary memory, for a console of its own.
+fn memory_framebuffer(width: usize, height: usize) -> FramebufferInfo {
+    let info = FramebufferInfo {
+        address: PhysAddr::new(0),
+        width,
+        height,
+        stride: width,
+        bytes_per_pixel: 4,
+        format: PixelFormat::Bgr,
+    };
+    let address = memory::allocate_dma(info.size().div_ceil(4096)).expect("out of memory");
+    FramebufferInfo { address, ..info }
+}
+
+const WHITE: (u8, u8, u8) = (255, 255, 255);
+const BLACK: (u8, u8, u8) = (0, 0, 0);
+
+/// The colour of a pixel in the cell at `row`, `col`.
+fn cell_pixel(row: usize, col: usize, x: usize, y: usize) -> (u8, u8, u8) {
+    framebuffer::with_console(|console| {
+        let (width, height) = (console.font().width(), console.font().height());
+        console
+            .framebuffer()
+            .pixel(col * width + x, row * height + y)
+            .unwrap()
+    })
+    .unwrap()
+}
+
+#[test_case]
+fn console_is_on_the_framebuffer() {
+    assert!(framebuffer::is_active());
+    framebuffer::with_console(|console| {
+        let info = console.framebuffer().info();
+        assert_eq!((info.width, info.height), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+        assert_eq!(info.format, PixelFormat::Bgr);
+        assert!(info.stride >= info.width);
+        // the VGA font is 8x16
+        assert_eq!((console.columns(), console.rows()), (128, 48));
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn text_is_drawn_at_a_position() {
+    clrscr!();
+    // a full block fills its whole cell
+    framebuffer::with_console(|console| console.write_at(2, 5, 0xDB));
+    assert_eq!(cell_pixel(2, 5, 0, 0), WHITE);
+    assert_eq!(cell_pixel(2, 5, 7, 15), WHITE);
+    assert_eq!(cell_pixel(2, 4, 7, 15), BLACK);
+
+    setcolor!(Color::Yellow, Color::Blue);
+    vga_buffer::write_text_at(3, 0, " ");
+    setcolor!(Color::White, Color::Black);
+    assert_eq!(cell_pixel(3, 0, 4, 8), (0, 0, 170));
+}
+
+#[test_case]
+fn printing_scrolls() {
+    clrscr!();
+    let rows = framebuffer::with_console(|console| console.rows()).unwrap();
+    framebuffer::with_console(|console| console.write_at(rows - 1, 0, 0xDB));
+    println!();
+    assert_eq!(cell_pixel(rows - 2, 0, 3, 3), WHITE);
+    assert_eq!(cell_pixel(rows - 1, 0, 3, 3), BLACK);
+
+    for _ in 0..200 {
+        println!("test_println_many output");
+    }
+}
+
+#[test_case]
+fn vga_modes_are_refused() {
+    graphics::set_mode(Mode::Graphics320x200);
+    assert_eq!(graphics::mode(), Mode::Text);
+    assert!(graphics::framebuffer().is_none());
+}
+
+/// A PSF2 font with two 8x16 glyphs, blank for ` ` and `?` and filled for
+/// `█`, so that only a font looked up by Unicode character draws 0xDB.
+fn block_font() -> Font {
+    let mut data = vec![0x72, 0xB5, 0x4A, 0x86];
+    for field in [0u32, 32, 1, 2, 16, 16, 8] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(&[0x00; 16]);
+    data.extend_from_slice(&[0xFF; 16]);
+    data.extend_from_slice(b" ?\xFF");
+    data.extend_from_slice("█".as_bytes());
+    data.push(0xFF);
+    Font::parse(data.leak()).unwrap()
+}
+
+#[test_case]
+fn bytes_are_code_page_437_in_unicode_fonts() {
+    let info = memory_framebuffer(64, 32);
+    let mut console = Console::new(LinearFramebuffer::map(info).unwrap(), block_font());
+    console.write_at(1, 2, 0xDB);
+    let (x, y) = (2 * 8, 16);
+    assert_eq!(console.framebuffer().pixel(x, y), Some(WHITE));
+    assert_eq!(console.framebuffer().pixel(x + 7, y + 15), Some(WHITE));
+    assert_eq!(console.framebuffer().pixel(x - 1, y), Some(BLACK));
+}
@@ -0,0 +1,162 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::graphics::{self, Mode};
+use mold_os::vga_buffer::{self, Color};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    graphics::set_mode(Mode::Text);
+    mold_os::test_panic_handler(info)
+}
+
+fn text_cell(row: usize, col: usize) -> u16 {
+    unsafe { (0xb8000 as *const u16).add(row * 80 + col).read_volatile() }
+}
+
+#[test_case]
+fn starts_in_text_mode() {
+    assert_eq!(graphics::mode(), Mode::Text);
+    assert!(graphics::framebuffer().is_none());
+}
+
+#[test_case]
+fn mode_13h_pixels() {
+    graphics::set_mode(Mode::Graphics320x200);
+    let fb = graphics::framebuffer().unwrap();
+    assert_eq!((fb.width(), fb.height()), (320, 200));
+    assert_eq!(fb.pixel(0, 0), Some(Color::Black as u8));
+
+    fb.set_pixel(10, 20, 200);
+    assert_eq!(fb.pixel(10, 20), Some(200));
+    assert_eq!(fb.pixel(320, 0), None);
+    // clipped rather than wrapped onto the next line
+    fb.set_pixel(320, 0, 5);
+    assert_eq!(fb.pixel(0, 1), Some(0));
+
+    fb.line(0, 0, 9, 9, 3);
+    assert!((0..10).all(|i| fb.pixel(i, i) == Some(3)));
+    fb.line(319, 199, 310, 199, 4);
+    assert!((310..320).all(|x| fb.pixel(x, 199) == Some(4)));
+
+    fb.rect(100, 100, 10, 5, 7);
+    assert_eq!(fb.pixel(100, 100), Some(7));
+    assert_eq!(fb.pixel(109, 104), Some(7));
+    assert_eq!(fb.pixel(105, 102), Some(0));
+    fb.fill_rect(100, 100, 10, 5, 8);
+    assert_eq!(fb.pixel(105, 102), Some(8));
+
+    fb.blit(0, 50, 2, &[1, 0, 0, 2], Some(0));
+    assert_eq!(fb.pixel(0, 50), Some(1));
+    assert_eq!(fb.pixel(1, 51), Some(2));
+    assert_eq!(fb.pixel(1, 50), Some(0));
+
+    fb.clear(9);
+    assert_eq!(fb.pixel(319, 199), Some(9));
+    graphics::set_mode(Mode::Text);
+}
+
+#[test_case]
+fn mode_12h_pixels() {
+    graphics::set_mode(Mode::Graphics640x480);
+    let fb = graphics::framebuffer().unwrap();
+    assert_eq!((fb.width(), fb.height()), (640, 480));
+
+    // pixels sharing a byte of each plane are kept apart
+    for x in 0..16 {
+        fb.set_pixel(x, 7, x as u8);
+    }
+    assert!((0..16).all(|x| fb.pixel(x, 7) == Some(x as u8)));
+    fb.set_pixel(639, 479, Color::White as u8);
+    assert_eq!(fb.pixel(639, 479), Some(15));
+    // only 16 colours
+    fb.set_pixel(3, 3, 0x1A);
+    assert_eq!(fb.pixel(3, 3), Some(0x0A));
+
+    fb.fill_rect(630, 0, 100, 10, 2);
+    assert_eq!(fb.pixel(639, 9), Some(2));
+    assert_eq!(fb.pixel(629, 9), Some(0));
+    graphics::set_mode(Mode::Text);
+}
+
+#[test_case]
+fn coordinates_near_the_limit_are_clipped() {
+    graphics::set_mode(Mode::Graphics640x480);
+    let fb = graphics::framebuffer().unwrap();
+    fb.fill_rect(usize::MAX - 1, usize::MAX - 1, 10, 10, 2);
+    fb.rect(600, usize::MAX, usize::MAX, 2, 3);
+    fb.blit(usize::MAX, 0, 2, &[1, 1, 1, 1], None);
+    fb.draw_char(usize::MAX - 3, usize::MAX - 3, 0xDB, 15, Some(1));
+    fb.draw_text(600, 0, "a line running off the screen", 15, None);
+    assert_eq!(fb.pixel(0, 0), Some(0));
+    assert_eq!(fb.pixel(639, 479), Some(0));
+    graphics::set_mode(Mode::Text);
+}
+
+#[test_case]
+fn lines_with_off_screen_ends_are_clipped() {
+    graphics::set_mode(Mode::Graphics320x200);
+    let fb = graphics::framebuffer().unwrap();
+    fb.line(-1_000_000_000, 100, 1_000_000_000, 100, 5);
+    assert!((0..320).all(|x| fb.pixel(x, 100) == Some(5)));
+    fb.line(isize::MIN, isize::MIN, isize::MAX, isize::MAX, 6);
+    assert!((0..200).all(|i| fb.pixel(i, i) == Some(6)));
+    fb.line(150, isize::MIN, 150, isize::MAX, 7);
+    assert!((0..200).all(|y| fb.pixel(150, y) == Some(7)));
+    // entirely off the screen
+    fb.line(-10, -10, -1, 500, 8);
+    fb.line(isize::MAX, 0, isize::MAX - 1_000_000_000, 199, 8);
+    assert!((0..200).all(|y| fb.pixel(0, y) != Some(8)));
+    graphics::set_mode(Mode::Text);
+}
+
+#[test_case]
+fn palette() {
+    graphics::set_mode(Mode::Graphics320x200);
+    assert_eq!(graphics::palette(Color::White as u8), (255, 255, 255));
+    assert_eq!(graphics::palette(Color::Black as u8), (0, 0, 0));
+    graphics::set_palette(100, 0x80, 0x40, 0xFC);
+    assert_eq!(graphics::palette(100), (0x82, 0x41, 0xFF));
+    graphics::set_mode(Mode::Text);
+}
+
+#[test_case]
+fn text_is_drawn_with_the_vga_font() {
+    graphics::set_mode(Mode::Graphics320x200);
+    let fb = graphics::framebuffer().unwrap();
+    assert_eq!(fb.char_size(), Some((8, 16)));
+    fb.draw_text(0, 0, "  ", 15, Some(1));
+    assert!((0..16).all(|x| fb.pixel(x, 8) == Some(1)));
+    // a full block character covers its whole cell
+    fb.draw_char(20, 0, 0xDB, 15, None);
+    assert!((0..16).all(|y| fb.pixel(20, y) == Some(15) && fb.pixel(27, y) == Some(15)));
+    graphics::set_mode(Mode::Text);
+}
+
+#[test_case]
+fn text_mode_is_restored() {
+    vga_buffer::write_text_at(3, 0, "graphics");
+    let before = text_cell(3, 0);
+    graphics::set_mode(Mode::Graphics640x480);
+    graphics::framebuffer().unwrap().clear(4);
+    graphics::set_mode(Mode::Graphics320x200);
+    graphics::set_mode(Mode::Text);
+    assert_eq!(graphics::mode(), Mode::Text);
+    assert_eq!(text_cell(3, 0), before);
+    assert_eq!(text_cell(3, 0) & 0xFF, b'g' as u16);
+    assert_eq!(graphics::palette(Color::White as u8), (255, 255, 255));
+}
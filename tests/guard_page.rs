@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use mold_os::{exit_qemu, gdt, serial_print, serial_println, stack, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+fn check_guard_hit() -> ! {
+    match Cr2::read().ok().and_then(stack::guard_hit) {
+        Some("guard_page test stack") => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        other => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: unexpected guard hit {:?}\n", other);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    check_guard_hit();
+}
+
+// The page fault can't push its frame onto the overflowed stack, so it turns
+// into a double fault with CR2 still in the guard page.
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    check_guard_hit();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("guard_page::guard_page_hit...\t");
+
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    let stack = stack::allocate("guard_page test stack", 2).expect("stack allocation failed");
+
+    // the stack itself must be usable
+    let bottom: *mut u64 = stack.bottom().as_mut_ptr();
+    unsafe { bottom.write_volatile(42) };
+    assert_eq!(unsafe { bottom.read_volatile() }, 42);
+
+    TEST_IDT.load();
+
+    // recursing on the new stack has to run into the guard page
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) stack.top().as_u64(),
+            overflow = sym stack_overflow,
+            options(noreturn),
+        );
+    }
+}
+
+#[allow(unconditional_recursion)]
+extern "C" fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::initramfs::{self, Archive, Error, FileKind};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn root_directory() {
+    let entries = initramfs::readdir("/").expect("readdir failed");
+    let help = entries.iter().find(|entry| entry.name == "help.txt");
+    let levels = entries.iter().find(|entry| entry.name == "levels");
+    assert_eq!(help.map(|entry| entry.kind), Some(FileKind::File));
+    assert_eq!(levels.map(|entry| entry.kind), Some(FileKind::Directory));
+}
+
+#[test_case]
+fn read_file() {
+    let mut file = initramfs::open("/help.txt").expect("open failed");
+    let size = initramfs::stat("help.txt").unwrap().size;
+    assert_eq!(file.contents().len(), size);
+
+    let mut buf = [0u8; 16];
+    let mut total = 0;
+    loop {
+        let read = file.read(&mut buf);
+        if read == 0 {
+            break;
+        }
+        assert_eq!(&buf[..read], &file.contents()[total..total + read]);
+        total += read;
+    }
+    assert_eq!(total, size);
+}
+
+#[test_case]
+fn level_layouts() {
+    let levels = initramfs::readdir("levels").unwrap();
+    assert!(levels.iter().any(|entry| entry.name == "1.txt"));
+
+    let layout = initramfs::read_to_str("./levels/1.txt").unwrap();
+    assert_eq!(layout.lines().count(), 24);
+    assert!(layout.lines().all(|line| line.len() == 79));
+}
+
+#[test_case]
+fn stat_and_errors() {
+    let stat = initramfs::stat("/levels/").unwrap();
+    assert_eq!(stat.kind, FileKind::Directory);
+    assert_eq!(initramfs::stat("missing").unwrap_err(), Error::NotFound);
+    assert!(matches!(
+        initramfs::open("levels"),
+        Err(Error::IsADirectory)
+    ));
+    assert!(matches!(
+        initramfs::open("levels/99.txt"),
+        Err(Error::NotFound)
+    ));
+    assert_eq!(
+        initramfs::readdir("help.txt").unwrap_err(),
+        Error::NotADirectory
+    );
+}
+
+#[test_case]
+fn corrupt_archive_is_rejected() {
+    static GARBAGE: [u8; 1024] = [0x55; 1024];
+    assert!(matches!(Archive::new(&GARBAGE), Err(Error::Corrupt)));
+
+    static EMPTY: [u8; 1024] = [0; 1024];
+    let archive = Archive::new(&EMPTY).unwrap();
+    assert!(archive.readdir("/").unwrap().is_empty());
+}
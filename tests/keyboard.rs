@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::keyboard::{self, KeyCode, Layout};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+fn main(_boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// Feed scancode set 1 bytes as if they came from the keyboard.
+fn type_bytes(bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        for &byte in bytes {
+            keyboard::handle_scancode(byte);
+        }
+    });
+}
+
+#[test_case]
+fn layout_names() {
+    for layout in Layout::ALL {
+        assert_eq!(Layout::from_name(layout.name()), Some(layout));
+    }
+    assert_eq!(Layout::from_name("klingon"), None);
+    assert_eq!(Layout::Colemak.next(), Layout::Us);
+}
+
+#[test_case]
+fn press_and_release() {
+    keyboard::clear_events();
+    // 'a' down and up
+    type_bytes(&[0x1E, 0x9E]);
+    let press = keyboard::poll_event().unwrap();
+    assert_eq!(
+        (press.code, press.pressed, press.character),
+        (KeyCode::A, true, Some('a'))
+    );
+    let release = keyboard::poll_event().unwrap();
+    assert_eq!(
+        (release.code, release.pressed, release.character),
+        (KeyCode::A, false, None)
+    );
+    assert_eq!(keyboard::poll_event(), None);
+}
+
+#[test_case]
+fn arrows_and_function_keys() {
+    keyboard::clear_events();
+    // extended up arrow, then F5
+    type_bytes(&[0xE0, 0x48, 0x3F]);
+    assert!(keyboard::is_pressed(KeyCode::ArrowUp));
+    let arrow = keyboard::poll_event().unwrap();
+    assert_eq!((arrow.code, arrow.character), (KeyCode::ArrowUp, None));
+    assert_eq!(keyboard::poll_event().unwrap().code, KeyCode::F5);
+
+    type_bytes(&[0xE0, 0xC8, 0xBF]);
+    assert!(!keyboard::is_pressed(KeyCode::ArrowUp));
+    assert!(!keyboard::is_pressed(KeyCode::F5));
+    keyboard::clear_events();
+}
+
+#[test_case]
+fn modifiers_are_reported() {
+    keyboard::clear_events();
+    // shift down, 'a', shift up
+    type_bytes(&[0x2A, 0x1E, 0x9E, 0xAA]);
+    let shift = keyboard::poll_event().unwrap();
+    assert!(shift.modifiers.shift);
+    let letter = keyboard::poll_event().unwrap();
+    assert_eq!(letter.character, Some('A'));
+    assert!(letter.modifiers.shift);
+    keyboard::clear_events();
+    assert!(!keyboard::modifiers().shift);
+}
+
+#[test_case]
+fn lock_keys_toggle() {
+    let before = keyboard::modifiers();
+    // caps lock and scroll lock, pressed and released
+    type_bytes(&[0x3A, 0xBA, 0x46, 0xC6]);
+    let after = keyboard::modifiers();
+    assert_eq!(after.caps_lock, !before.caps_lock);
+    assert_eq!(after.scroll_lock, !before.scroll_lock);
+    type_bytes(&[0x3A, 0xBA, 0x46, 0xC6]);
+    assert_eq!(keyboard::modifiers(), before);
+    keyboard::clear_events();
+}
+
+#[test_case]
+fn layouts_can_be_switched() {
+    keyboard::clear_events();
+    keyboard::set_layout(Layout::German);
+    assert_eq!(keyboard::layout(), Layout::German);
+    // the key labelled Y on a US keyboard is Z on a German one
+    type_bytes(&[0x15, 0x95]);
+    assert_eq!(keyboard::poll_event().unwrap().character, Some('z'));
+
+    keyboard::set_layout(Layout::Dvorak);
+    type_bytes(&[0x10, 0x90]);
+    assert_eq!(keyboard::poll_event().unwrap().character, Some('\''));
+
+    keyboard::set_layout(Layout::Us);
+    keyboard::clear_events();
+    mold_os::console::clear_buffer();
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::interrupts::InterruptIndex;
+use mold_os::mouse::{self, Packet, PacketDecoder, Protocol, Tracker, COLUMNS, ROWS};
+
+entry_point!(main);
+fn main(_boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mouse::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+fn decode(protocol: Protocol, bytes: &[u8]) -> Option<Packet> {
+    let mut decoder = PacketDecoder::new(protocol);
+    bytes.iter().fold(None, |_, &byte| decoder.add_byte(byte))
+}
+
+#[test_case]
+fn mouse_is_on_irq12() {
+    assert_eq!(InterruptIndex::Mouse.as_u8(), 32 + 12);
+}
+
+#[test_case]
+fn mouse_is_found() {
+    // QEMU's PS/2 mouse answers both IntelliMouse sequences
+    assert!(mouse::is_present());
+    assert_eq!(mouse::protocol(), Some(Protocol::FiveButtons));
+}
+
+#[test_case]
+fn standard_packets() {
+    let packet = decode(Protocol::Standard, &[0x09, 5, 3]).unwrap();
+    assert_eq!((packet.dx, packet.dy, packet.wheel), (5, 3, 0));
+    assert!(packet.buttons.left && !packet.buttons.right);
+
+    // negative movement uses the sign bits of the first byte
+    let packet = decode(Protocol::Standard, &[0x3A, 0xFE, 0xF0]).unwrap();
+    assert_eq!((packet.dx, packet.dy), (-2, -16));
+    assert!(packet.buttons.right);
+
+    // overflowing movement is dropped
+    let packet = decode(Protocol::Standard, &[0xC8, 0x10, 0x10]).unwrap();
+    assert_eq!((packet.dx, packet.dy), (0, 0));
+}
+
+#[test_case]
+fn wheel_packets() {
+    let packet = decode(Protocol::Wheel, &[0x0C, 0, 0, 0xFF]).unwrap();
+    assert_eq!(packet.wheel, -1);
+    assert!(packet.buttons.middle);
+
+    let packet = decode(Protocol::FiveButtons, &[0x08, 0, 0, 0x1F]).unwrap();
+    assert_eq!(packet.wheel, -1);
+    assert!(packet.buttons.back && !packet.buttons.forward);
+    let packet = decode(Protocol::FiveButtons, &[0x08, 0, 0, 0x21]).unwrap();
+    assert_eq!(packet.wheel, 1);
+    assert!(packet.buttons.forward);
+}
+
+#[test_case]
+fn decoder_resynchronizes() {
+    let mut decoder = PacketDecoder::new(Protocol::Standard);
+    // a stray movement byte can't start a packet
+    assert_eq!(decoder.add_byte(0x02), None);
+    assert_eq!(decoder.add_byte(0x08), None);
+    assert_eq!(decoder.add_byte(1), None);
+    assert!(decoder.add_byte(1).is_some());
+}
+
+#[test_case]
+fn position_is_clamped_to_the_screen() {
+    let mut tracker = Tracker::new();
+    assert_eq!(tracker.position(), (COLUMNS / 2, ROWS / 2));
+
+    let up_left = Packet {
+        dx: -255,
+        dy: 255,
+        ..Packet::default()
+    };
+    for _ in 0..10 {
+        tracker.update(&up_left);
+    }
+    assert_eq!(tracker.position(), (0, 0));
+
+    let down_right = Packet {
+        dx: 255,
+        dy: -255,
+        ..Packet::default()
+    };
+    for _ in 0..10 {
+        tracker.update(&down_right);
+    }
+    let event = tracker.update(&down_right);
+    assert_eq!((event.column, event.row), (COLUMNS - 1, ROWS - 1));
+}
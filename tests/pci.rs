@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mold_os::pci::{self, Bar, PciAddress, PciDevice, PciDriver};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn host_bridge_is_first() {
+    let devices = pci::devices();
+    let bridge = &devices[0];
+    assert_eq!(bridge.address, PciAddress::new(0, 0, 0));
+    // the i440FX of QEMU's default machine
+    assert_eq!((bridge.vendor_id, bridge.device_id), (0x8086, 0x1237));
+    assert_eq!(bridge.class_name(), "Host bridge");
+}
+
+#[test_case]
+fn multifunction_functions_are_found() {
+    // the PIIX3 has the ISA bridge, IDE and power management functions
+    let isa = pci::find(0x8086, 0x7000).expect("no PIIX3");
+    assert!(isa.is_multifunction());
+    let ide = pci::find(0x8086, 0x7010).expect("no IDE function");
+    assert_eq!(
+        ide.address,
+        PciAddress::new(isa.address.bus, isa.address.device, 1)
+    );
+    assert_eq!(format!("{}", ide.address), "00:01.1");
+}
+
+#[test_case]
+fn bar_sizes_are_probed() {
+    let ide = &pci::find_class(0x01, 0x01)[0];
+    // the bus master DMA registers
+    match ide.bars[4] {
+        Some(Bar::Io { size, .. }) => assert_eq!(size, 16),
+        other => panic!("unexpected BAR4 {:?}", other),
+    }
+
+    let vga = pci::find_class(0x03, 0x00);
+    if let Some(Bar::Memory {
+        size, prefetchable, ..
+    }) = vga.first().and_then(|vga| vga.bars[0])
+    {
+        // the framebuffer of the standard VGA device
+        assert_eq!(size, 16 * 1024 * 1024);
+        assert!(prefetchable);
+    }
+    // probing must leave the BARs as they were
+    let again = pci::scan();
+    let rescanned = again.iter().find(|d| d.address == ide.address).unwrap();
+    assert_eq!(rescanned.bars, ide.bars);
+}
+
+#[test_case]
+fn bus_mastering_is_enabled() {
+    let ide = &pci::find_class(0x01, 0x01)[0];
+    ide.enable_bus_mastering();
+    assert_ne!(ide.command() & pci::COMMAND_BUS_MASTER, 0);
+}
+
+#[test_case]
+fn capabilities_are_listed() {
+    for device in pci::devices() {
+        for &(id, offset) in &device.capabilities {
+            // capabilities live after the standard header
+            assert!(offset >= 0x40);
+            assert_eq!(device.address.read_u8(offset), id);
+        }
+        if let Some(offset) = device.msix() {
+            assert_eq!(device.address.read_u8(offset), pci::CAPABILITY_MSIX);
+        }
+    }
+}
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+fn is_ide(device: &PciDevice) -> bool {
+    device.class == 0x01 && device.subclass == 0x01
+}
+
+fn probe_ide(_device: &PciDevice) {
+    PROBED.fetch_add(1, Ordering::SeqCst);
+}
+
+static IDE_DRIVER: PciDriver = PciDriver {
+    name: "test-ide",
+    matches: is_ide,
+    probe: probe_ide,
+};
+
+#[test_case]
+fn drivers_are_matched() {
+    pci::register_driver(&IDE_DRIVER);
+    assert_eq!(PROBED.load(Ordering::SeqCst), 1);
+}
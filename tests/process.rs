@@ -0,0 +1,242 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::memory;
+use mold_os::process::{self, ProcessError};
+use mold_os::syscall::ECHILD;
+use mold_os::usermode::ExitStatus;
+use mold_os::vfs::{self, OpenFlags};
+
+const TEXT_ADDR: u64 = 0x_2000_0000_0000;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+    mold_os::init_filesystems();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+core::arch::global_asm!(
+    r#"
+.global exit_program, exit_program_end
+exit_program:
+    mov edi, 3
+    mov eax, 2
+    syscall
+exit_program_end:
+
+.global getpid_program, getpid_program_end
+getpid_program:
+    # exit(getpid() * 1000 + getppid())
+    mov eax, 5
+    syscall
+    imul rbx, rax, 1000
+    mov eax, 6
+    syscall
+    lea rdi, [rbx + rax]
+    mov eax, 2
+    syscall
+getpid_program_end:
+
+.global getppid_program, getppid_program_end
+getppid_program:
+    mov eax, 6
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+getppid_program_end:
+
+.global wait_parent_program, wait_parent_program_end
+wait_parent_program:
+    # exit(wait(getppid()))
+    mov eax, 6
+    syscall
+    mov rdi, rax
+    mov eax, 8
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+wait_parent_program_end:
+
+.global spawn_program, spawn_program_end
+spawn_program:
+    # exit(wait(spawn("/tmp/child")))
+    lea rdi, [rip + 2f]
+    mov esi, 10
+    mov eax, 7
+    syscall
+    mov rdi, rax
+    mov eax, 8
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+2:
+    .ascii "/tmp/child"
+spawn_program_end:
+
+.global fault_program, fault_program_end
+fault_program:
+    ud2
+fault_program_end:
+"#
+);
+
+macro_rules! program {
+    ($start:ident, $end:ident) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        let start = &raw const $start;
+        let len = &raw const $end as usize - start as usize;
+        unsafe { core::slice::from_raw_parts(start, len) }
+    }};
+}
+
+/// Build an ELF64 executable with `code` as its only segment.
+fn build_elf(code: &[u8]) -> Vec<u8> {
+    let mut image = vec![0u8; 0x1000];
+    image[0..4].copy_from_slice(b"\x7fELF");
+    image[4..7].copy_from_slice(&[2, 1, 1]);
+    image[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image[18..20].copy_from_slice(&0x3Eu16.to_le_bytes());
+    image[24..32].copy_from_slice(&TEXT_ADDR.to_le_bytes());
+    image[32..40].copy_from_slice(&64u64.to_le_bytes());
+    image[54..56].copy_from_slice(&56u16.to_le_bytes());
+    image[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+    let header = &mut image[64..120];
+    header[0..4].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    header[4..8].copy_from_slice(&5u32.to_le_bytes()); // R + X
+    header[8..16].copy_from_slice(&0x1000u64.to_le_bytes());
+    header[16..24].copy_from_slice(&TEXT_ADDR.to_le_bytes());
+    header[32..40].copy_from_slice(&(code.len() as u64).to_le_bytes());
+    header[40..48].copy_from_slice(&(code.len() as u64).to_le_bytes());
+
+    image.extend_from_slice(code);
+    image
+}
+
+/// Store `image` as `/tmp/child`, the program `spawn_program` starts.
+fn install_child(image: &[u8]) {
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+    let fd = vfs::open("/tmp/child", flags).expect("failed to create /tmp/child");
+    assert_eq!(vfs::write(fd, image), Ok(image.len()));
+    vfs::close(fd).unwrap();
+}
+
+fn frames_in_use() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.frames_in_use())
+}
+
+#[test_case]
+fn wait_returns_exit_code() {
+    let image = build_elf(program!(exit_program, exit_program_end));
+    let pid = process::spawn("exit", &image, &["exit"], &[]).expect("spawn failed");
+    assert_eq!(process::parent(pid).unwrap(), None);
+    assert!(process::children(None).contains(&pid));
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(3));
+
+    // the process is gone once its status was collected
+    assert!(matches!(
+        process::parent(pid),
+        Err(ProcessError::NoSuchProcess)
+    ));
+    assert!(!process::children(None).contains(&pid));
+}
+
+#[test_case]
+fn pids_are_unique() {
+    let image = build_elf(program!(exit_program, exit_program_end));
+    let first = process::spawn("first", &image, &[], &[]).unwrap();
+    let second = process::spawn("second", &image, &[], &[]).unwrap();
+    assert!(second > first);
+    assert_eq!(process::name(second).unwrap(), "second");
+    process::wait(first).unwrap();
+    process::wait(second).unwrap();
+}
+
+#[test_case]
+fn getpid_and_getppid() {
+    let image = build_elf(program!(getpid_program, getpid_program_end));
+    let pid = process::spawn("getpid", &image, &[], &[]).unwrap();
+    let status = process::wait(pid).unwrap();
+    assert_eq!(status, ExitStatus::Exited(pid.as_u64() as i64 * 1000));
+    assert_eq!(process::current(), None);
+}
+
+#[test_case]
+fn process_spawns_and_waits_for_a_child() {
+    install_child(&build_elf(program!(getppid_program, getppid_program_end)));
+    let image = build_elf(program!(spawn_program, spawn_program_end));
+    let pid = process::spawn("spawn", &image, &[], &[]).unwrap();
+    // the child exits with the PID of its parent
+    assert_eq!(
+        process::wait(pid).unwrap(),
+        ExitStatus::Exited(pid.as_u64() as i64)
+    );
+    assert_eq!(process::children(None), []);
+}
+
+#[test_case]
+fn child_cannot_wait_for_its_parent() {
+    install_child(&build_elf(program!(
+        wait_parent_program,
+        wait_parent_program_end
+    )));
+    let image = build_elf(program!(spawn_program, spawn_program_end));
+    let pid = process::spawn("spawn", &image, &[], &[]).unwrap();
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(-ECHILD));
+}
+
+#[test_case]
+fn killed_process_status() {
+    let image = build_elf(program!(fault_program, fault_program_end));
+    let pid = process::spawn("fault", &image, &[], &[]).unwrap();
+    assert_eq!(
+        process::wait(pid).unwrap(),
+        ExitStatus::Killed("invalid opcode")
+    );
+}
+
+#[test_case]
+fn wait_for_unknown_process() {
+    let image = build_elf(program!(exit_program, exit_program_end));
+    let pid = process::spawn("exit", &image, &[], &[]).unwrap();
+    process::wait(pid).unwrap();
+    assert!(matches!(
+        process::wait(pid),
+        Err(ProcessError::NoSuchProcess)
+    ));
+}
+
+#[test_case]
+fn exited_process_returns_its_frames() {
+    let image = build_elf(program!(exit_program, exit_program_end));
+    let before = frames_in_use();
+    let pid = process::spawn("exit", &image, &[], &[]).unwrap();
+    assert!(frames_in_use() > before);
+    process::wait(pid).unwrap();
+    assert_eq!(frames_in_use(), before);
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::keyboard::{self, KeyCode};
+use mold_os::ps2::{self, ScancodeMode, CONFIG_TRANSLATION};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+fn main(_boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+fn config() -> u8 {
+    interrupts::without_interrupts(|| ps2::read_config().unwrap())
+}
+
+#[test_case]
+fn keyboard_and_mouse_are_found() {
+    let devices = ps2::devices().expect("no PS/2 controller");
+    assert!(devices.dual_channel);
+    assert!(devices.keyboard);
+    assert!(devices.mouse);
+    assert_eq!(devices.scancodes, ScancodeMode::Translated);
+    assert_ne!(config() & CONFIG_TRANSLATION, 0);
+}
+
+#[test_case]
+fn untranslated_set_2() {
+    ps2::init(ScancodeMode::Set2);
+    assert_eq!(ps2::devices().unwrap().scancodes, ScancodeMode::Set2);
+    assert_eq!(config() & CONFIG_TRANSLATION, 0);
+
+    // 'a' is 0x1C in set 2, released with an 0xF0 prefix
+    keyboard::clear_events();
+    interrupts::without_interrupts(|| {
+        for byte in [0x1C, 0xF0, 0x1C] {
+            keyboard::handle_scancode(byte);
+        }
+    });
+    let press = keyboard::poll_event().unwrap();
+    assert_eq!((press.code, press.character), (KeyCode::A, Some('a')));
+    assert!(!keyboard::poll_event().unwrap().pressed);
+    mold_os::console::clear_buffer();
+
+    ps2::init(ScancodeMode::Translated);
+    assert_ne!(config() & CONFIG_TRANSLATION, 0);
+}
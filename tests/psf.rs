@@ -0,0 +1,178 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::slice;
+use mold_os::memory;
+use mold_os::psf::{Font, PsfError};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// A PSF1 font with 256 glyphs of height 2: glyph `n` is `[n, !n]`.
+fn psf1(unicode: Option<&[(u16, u16)]>) -> &'static [u8] {
+    let mode = if unicode.is_some() { 0x02 } else { 0x00 };
+    let mut data = vec![0x36, 0x04, mode, 2];
+    for glyph in 0..=255u8 {
+        data.extend_from_slice(&[glyph, !glyph]);
+    }
+    if let Some(unicode) = unicode {
+        for glyph in 0..256 {
+            for &(code, _) in unicode.iter().filter(|&&(_, index)| index == glyph) {
+                data.extend_from_slice(&code.to_le_bytes());
+            }
+            data.extend_from_slice(&0xFFFFu16.to_le_bytes());
+        }
+    }
+    data.leak()
+}
+
+/// A PSF2 font with two 10x2 glyphs: the first for `A`, the second for `é`.
+fn psf2() -> &'static [u8] {
+    let mut data = vec![0x72, 0xB5, 0x4A, 0x86];
+    for field in [0u32, 32, 1, 2, 4, 2, 10] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(&[0xFF, 0xC0, 0x80, 0x40]);
+    data.extend_from_slice(&[0x00, 0x40, 0x01, 0x00]);
+    data.extend_from_slice(b"A\xFF");
+    data.extend_from_slice("é".as_bytes());
+    data.push(0xFE);
+    data.extend_from_slice("e\u{301}".as_bytes());
+    data.push(0xFF);
+    data.leak()
+}
+
+#[test_case]
+fn psf1_glyphs_by_code_point() {
+    let font = Font::parse(psf1(None)).unwrap();
+    assert_eq!((font.width(), font.height()), (8, 2));
+    assert_eq!(font.glyph_count(), 256);
+    assert!(!font.has_unicode_table());
+    assert_eq!(font.glyph_for('A'), Some(&[0x41, 0xBE][..]));
+    assert_eq!(font.glyph(256), None);
+    assert_eq!(font.glyph_for('€'), None);
+
+    let glyph = font.glyph_for('\u{80}').unwrap();
+    assert!(font.is_set(glyph, 0, 0));
+    assert!(!font.is_set(glyph, 1, 0));
+    assert!(!font.is_set(glyph, 0, 1));
+    assert!(font.is_set(glyph, 7, 1));
+}
+
+#[test_case]
+fn psf1_unicode_table() {
+    let font = Font::parse(psf1(Some(&[(0x41, 1), (0x391, 1), (0x2500, 0xC4)]))).unwrap();
+    assert!(font.has_unicode_table());
+    // Latin A and Greek Alpha share a glyph
+    assert_eq!(font.glyph_for('A'), font.glyph(1));
+    assert_eq!(font.glyph_for('Α'), font.glyph(1));
+    assert_eq!(font.glyph_for('─'), font.glyph(0xC4));
+    assert_eq!(font.glyph_for('B'), None);
+}
+
+#[test_case]
+fn psf2_fonts() {
+    let font = Font::parse(psf2()).unwrap();
+    assert_eq!((font.width(), font.height()), (10, 2));
+    assert_eq!(font.bytes_per_row(), 2);
+    let glyph = font.glyph_for('A').unwrap();
+    assert!((0..10).all(|x| font.is_set(glyph, x, 0)));
+    assert!(font.is_set(glyph, 0, 1) && font.is_set(glyph, 9, 1));
+    assert!(!font.is_set(glyph, 1, 1));
+    assert_eq!(font.glyph_for('é'), font.glyph(1));
+    // sequences of combining characters are skipped
+    assert_eq!(font.glyph_for('e'), None);
+}
+
+#[test_case]
+fn bad_fonts() {
+    assert_eq!(Font::parse(b"font").err(), Some(PsfError::BadMagic));
+    assert_eq!(
+        Font::parse(&[0x36, 0x04, 0, 16, 0, 0]).err(),
+        Some(PsfError::Truncated)
+    );
+
+    let mut version_1: Vec<u8> = psf2().to_vec();
+    version_1[4] = 1;
+    assert_eq!(
+        Font::parse(version_1.leak()).err(),
+        Some(PsfError::UnsupportedVersion(1))
+    );
+    assert_eq!(
+        Font::from_bitmaps(&[0; 64], 4, 16, 2, 2).err(),
+        Some(PsfError::BadGlyphSize)
+    );
+    assert_eq!(
+        Font::from_bitmaps(&[0; 64], 1, usize::MAX, usize::MAX, 64).err(),
+        Some(PsfError::BadGlyphSize)
+    );
+}
+
+#[test_case]
+fn truncated_and_oversized_headers() {
+    assert_eq!(
+        Font::parse(&[0x36, 0x04, 0]).err(),
+        Some(PsfError::Truncated)
+    );
+    assert_eq!(Font::parse(&psf2()[..20]).err(), Some(PsfError::Truncated));
+
+    let mut header_size: Vec<u8> = psf2().to_vec();
+    header_size[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        Font::parse(header_size.leak()).err(),
+        Some(PsfError::Truncated)
+    );
+
+    // glyph count and size whose product doesn't fit the data
+    let mut glyph_count: Vec<u8> = psf2().to_vec();
+    glyph_count[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+    glyph_count[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        Font::parse(glyph_count.leak()).err(),
+        Some(PsfError::Truncated)
+    );
+
+    let mut dimensions: Vec<u8> = psf2().to_vec();
+    dimensions[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+    dimensions[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        Font::parse(dimensions.leak()).err(),
+        Some(PsfError::BadGlyphSize)
+    );
+}
+
+#[test_case]
+fn psf1_table_longer_than_the_glyphs() {
+    // separators for more glyphs than a u16 counts, too many for the heap
+    let font = psf1(Some(&[(0x41, 1)]));
+    let len = font.len() + 2 * 70_000 + 2;
+    let start = memory::allocate_dma(len.div_ceil(4096)).unwrap();
+    let data: &'static mut [u8] =
+        unsafe { slice::from_raw_parts_mut(memory::phys_to_virt(start).as_mut_ptr(), len) };
+    data[..font.len()].copy_from_slice(font);
+    data[font.len()..len - 2].fill(0xFF);
+    data[len - 2..].copy_from_slice(&0x42u16.to_le_bytes());
+    let font = Font::parse(data).unwrap();
+    assert_eq!(font.glyph_for('A'), font.glyph(1));
+    assert_eq!(font.glyph_for('B'), None);
+}
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::{acpi, apic, percpu, smp};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_cpus_come_online() {
+    let info = acpi::info().expect("ACPI not initialized");
+    assert_eq!(smp::cpus_online(), info.cpu_count());
+}
+
+#[test_case]
+fn bsp_per_cpu_data() {
+    let cpu = percpu::current();
+    assert_eq!(cpu.cpu_id, 0);
+    assert_eq!(cpu.apic_id, apic::local_apic_id());
+    assert_eq!(percpu::cpu_id(), 0);
+}
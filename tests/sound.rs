@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::sound::{self, Note, SoundError};
+use mold_os::time;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    sound::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn note_frequencies() {
+    assert_eq!(sound::note_frequency('a', 0, 4), Some(440));
+    assert_eq!(sound::note_frequency('a', 0, 5), Some(880));
+    assert_eq!(sound::note_frequency('a', 0, 2), Some(110));
+    assert_eq!(sound::note_frequency('c', 0, 4), Some(262));
+    // B sharp is the C of the next octave
+    assert_eq!(sound::note_frequency('b', 1, 4), Some(523));
+    assert_eq!(sound::note_frequency('c', -1, 4), Some(247));
+    assert_eq!(sound::note_frequency('h', 0, 4), None);
+}
+
+#[test_case]
+fn notation_is_parsed() {
+    // at 120 quarter notes per minute a quarter note lasts 500 ms
+    let notes = sound::parse("a4 c#5/8 r/2 Eb/16. g", 120).unwrap();
+    assert_eq!(
+        notes,
+        vec![
+            Note {
+                frequency: 440,
+                duration_ms: 500
+            },
+            Note {
+                frequency: 554,
+                duration_ms: 250
+            },
+            Note {
+                frequency: 0,
+                duration_ms: 1000
+            },
+            Note {
+                frequency: 311,
+                duration_ms: 187
+            },
+            Note {
+                frequency: 392,
+                duration_ms: 500
+            },
+        ]
+    );
+    assert_eq!(sound::parse("c4 x4", 120), Err(SoundError::InvalidToken(1)));
+    assert_eq!(sound::parse("c4/3", 120), Err(SoundError::InvalidToken(0)));
+    assert_eq!(sound::parse("r4", 120), Err(SoundError::InvalidToken(0)));
+}
+
+#[test_case]
+fn melodies_play_in_the_background() {
+    sound::play_notation("c5/16 e5/16 g5/16", 240).unwrap();
+    assert!(sound::is_playing());
+    // three 62 ms notes
+    time::sleep_ms(400);
+    assert!(!sound::is_playing());
+}
+
+#[test_case]
+fn beep_and_stop() {
+    sound::beep(880, 10_000);
+    assert!(sound::is_playing());
+    sound::stop();
+    time::sleep_ms(2 * sound::UPDATE_INTERVAL_MS);
+    assert!(!sound::is_playing());
+}
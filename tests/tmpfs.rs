@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::vfs::{self, tmpfs::TmpFs, FileSystem, InodeKind, OpenFlags, VfsError};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_filesystems();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+fn write_file(path: &str, data: &[u8]) -> Result<(), VfsError> {
+    let fd = vfs::open(
+        path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+    )?;
+    let result = vfs::write(fd, data);
+    vfs::close(fd)?;
+    result.map(|_| ())
+}
+
+#[test_case]
+fn truncate_grows_and_shrinks() {
+    write_file("/tmp/truncate", b"abcdef").unwrap();
+    vfs::truncate("/tmp/truncate", 3).unwrap();
+    assert_eq!(vfs::read_to_vec("/tmp/truncate").unwrap(), b"abc");
+    vfs::truncate("/tmp/truncate", 5).unwrap();
+    assert_eq!(vfs::read_to_vec("/tmp/truncate").unwrap(), b"abc\0\0");
+
+    let fd = vfs::open("/tmp/truncate", OpenFlags::READ).unwrap();
+    assert_eq!(vfs::ftruncate(fd, 0), Err(VfsError::BadDescriptor));
+    vfs::close(fd).unwrap();
+    vfs::unlink("/tmp/truncate").unwrap();
+}
+
+#[test_case]
+fn rename_files_and_directories() {
+    vfs::mkdir("/tmp/a").unwrap();
+    vfs::mkdir("/tmp/b").unwrap();
+    write_file("/tmp/a/save", b"level 2").unwrap();
+
+    vfs::rename("/tmp/a/save", "/tmp/b/save.old").unwrap();
+    assert_eq!(vfs::stat("/tmp/a/save"), Err(VfsError::NotFound));
+    assert_eq!(vfs::read_to_vec("/tmp/b/save.old").unwrap(), b"level 2");
+
+    // an existing file is replaced
+    write_file("/tmp/b/save", b"level 3").unwrap();
+    vfs::rename("/tmp/b/save", "/tmp/b/save.old").unwrap();
+    assert_eq!(vfs::read_to_vec("/tmp/b/save.old").unwrap(), b"level 3");
+
+    vfs::rename("/tmp/b", "/tmp/a/b").unwrap();
+    assert_eq!(
+        vfs::stat("/tmp/a/b/save.old").unwrap().kind,
+        InodeKind::File
+    );
+    assert_eq!(
+        vfs::rename("/tmp/a", "/tmp/a/b/c"),
+        Err(VfsError::InvalidPath)
+    );
+    write_file("/tmp/note", b"").unwrap();
+    assert_eq!(
+        vfs::rename("/tmp/a/b", "/tmp/note"),
+        Err(VfsError::NotADirectory)
+    );
+    assert_eq!(
+        vfs::rename("/tmp/a/b/save.old", "/tmp/a"),
+        Err(VfsError::IsADirectory)
+    );
+    assert_eq!(
+        vfs::rename("/tmp/a/b/save.old", "/save"),
+        Err(VfsError::CrossDevice)
+    );
+
+    vfs::unlink("/tmp/note").unwrap();
+    vfs::unlink("/tmp/a/b/save.old").unwrap();
+    vfs::rmdir("/tmp/a/b").unwrap();
+    vfs::rmdir("/tmp/a").unwrap();
+}
+
+#[test_case]
+fn unlink_and_rmdir_check_the_kind() {
+    vfs::mkdir("/tmp/dir").unwrap();
+    write_file("/tmp/file", b"x").unwrap();
+    assert_eq!(vfs::unlink("/tmp/dir"), Err(VfsError::IsADirectory));
+    assert_eq!(vfs::rmdir("/tmp/file"), Err(VfsError::NotADirectory));
+    assert_eq!(vfs::mkdir("/tmp/dir"), Err(VfsError::AlreadyExists));
+    vfs::unlink("/tmp/file").unwrap();
+    vfs::rmdir("/tmp/dir").unwrap();
+    assert_eq!(vfs::rmdir("/tmp"), Err(VfsError::DirectoryNotEmpty));
+}
+
+#[test_case]
+fn size_is_accounted() {
+    let fs = Arc::new(TmpFs::with_limit(4096));
+    let root = fs.root();
+    assert_eq!(fs.used(), 0);
+
+    let file = root.create("file", InodeKind::File).unwrap();
+    let empty = fs.used();
+    assert!(empty > 0);
+    file.write_at(0, &[1; 1000]).unwrap();
+    assert_eq!(fs.used(), empty + 1000);
+
+    assert_eq!(file.write_at(0, &vec![0; 8192]), Err(VfsError::NoSpace));
+    assert_eq!(file.metadata().size, 1000);
+
+    file.truncate(10).unwrap();
+    assert_eq!(fs.used(), empty + 10);
+    root.remove("file").unwrap();
+    // an unlinked file still takes up space while it's open
+    assert_eq!(fs.used(), empty + 10 - "file".len());
+    drop(file);
+    assert_eq!(fs.used(), 0);
+}
+
+#[test_case]
+fn rename_in_a_full_tmpfs() {
+    let fs = Arc::new(TmpFs::with_limit(4096));
+    let root = fs.root();
+    let target = root.create("target", InodeKind::File).unwrap();
+    target.write_at(0, b"old").unwrap();
+    let file = root.create("a", InodeKind::File).unwrap();
+    let size = fs.limit() - fs.used();
+    file.write_at(0, &vec![0; size]).unwrap();
+
+    // a longer name doesn't fit, and nothing changes
+    assert_eq!(
+        root.rename("a", &root, "a_longer_name"),
+        Err(VfsError::NoSpace)
+    );
+    assert!(root.lookup("a").is_ok());
+
+    // replacing a file frees its name
+    root.rename("a", &root, "target").unwrap();
+    assert_eq!(root.lookup("a").err(), Some(VfsError::NotFound));
+    assert_eq!(root.lookup("target").unwrap().metadata().size, size);
+    assert_eq!(target.metadata().size, 3);
+}
+
+#[test_case]
+fn full_tmpfs_fails_without_exhausting_the_heap() {
+    vfs::mkdir("/small").unwrap();
+    vfs::mount("/small", Arc::new(TmpFs::with_limit(2048))).unwrap();
+    assert_eq!(
+        write_file("/small/big", &vec![0; 4096]),
+        Err(VfsError::NoSpace)
+    );
+    write_file("/small/fits", &[0; 512]).unwrap();
+    vfs::unmount("/small").unwrap();
+    vfs::rmdir("/small").unwrap();
+}
@@ -0,0 +1,202 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::syscall::{EFAULT, EINVAL, ENOSYS};
+use mold_os::usermode::{self, ExitStatus};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const CODE_ADDR: u64 = 0x40_0000;
+const STACK_ADDR: u64 = 0x80_0000;
+const STACK_PAGES: u64 = 2;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    usermode::map_user_pages(VirtAddr::new(CODE_ADDR), 1, PageTableFlags::WRITABLE)
+        .expect("failed to map user code");
+    usermode::map_user_pages(VirtAddr::new(STACK_ADDR), STACK_PAGES, PageTableFlags::WRITABLE)
+        .expect("failed to map user stack");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+// Small position independent programs, copied to `CODE_ADDR` before they run.
+core::arch::global_asm!(
+    r#"
+.global exit_program, exit_program_end
+exit_program:
+    lea rdi, [rdi + 1]
+    mov eax, 2
+    syscall
+    ud2
+exit_program_end:
+
+.global write_program, write_program_end
+write_program:
+    mov edi, 2
+    lea rsi, [rip + 2f]
+    mov edx, 18
+    mov eax, 1
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+2:
+    .ascii "hello from ring 3\n"
+write_program_end:
+
+.global bad_pointer_program, bad_pointer_program_end
+bad_pointer_program:
+    mov rsi, rdi
+    mov edi, 2
+    mov edx, 8
+    mov eax, 1
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+bad_pointer_program_end:
+
+.global unknown_syscall_program, unknown_syscall_program_end
+unknown_syscall_program:
+    mov eax, 99
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+unknown_syscall_program_end:
+
+.global sleep_program, sleep_program_end
+sleep_program:
+    mov edi, 10
+    mov eax, 4
+    syscall
+    mov rbx, rax
+    mov eax, 3
+    syscall
+    lea rdi, [rbx + rax]
+    mov eax, 2
+    syscall
+sleep_program_end:
+
+.global long_sleep_program, long_sleep_program_end
+long_sleep_program:
+    mov rdi, -1
+    mov eax, 4
+    syscall
+    mov rdi, rax
+    mov eax, 2
+    syscall
+long_sleep_program_end:
+
+.global kernel_read_program, kernel_read_program_end
+kernel_read_program:
+    mov rax, [rdi]
+    mov rdi, rax
+    mov eax, 2
+    syscall
+kernel_read_program_end:
+
+.global privileged_program, privileged_program_end
+privileged_program:
+    cli
+    hlt
+privileged_program_end:
+"#
+);
+
+macro_rules! program {
+    ($start:ident, $end:ident) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        let start = &raw const $start;
+        let len = &raw const $end as usize - start as usize;
+        unsafe { core::slice::from_raw_parts(start, len) }
+    }};
+}
+
+fn run(program: &[u8], arg: u64) -> ExitStatus {
+    unsafe {
+        core::ptr::copy_nonoverlapping(program.as_ptr(), CODE_ADDR as *mut u8, program.len());
+    }
+    let stack_top = VirtAddr::new(STACK_ADDR + STACK_PAGES * 4096);
+    usermode::run(VirtAddr::new(CODE_ADDR), stack_top, arg)
+}
+
+#[test_case]
+fn exit_code_is_returned() {
+    let program = program!(exit_program, exit_program_end);
+    assert_eq!(run(program, 41), ExitStatus::Exited(42));
+}
+
+#[test_case]
+fn write_to_serial() {
+    let program = program!(write_program, write_program_end);
+    assert_eq!(run(program, 0), ExitStatus::Exited(18));
+}
+
+#[test_case]
+fn kernel_pointer_is_rejected() {
+    static SECRET: u64 = 0x5ec2e7;
+    let program = program!(bad_pointer_program, bad_pointer_program_end);
+    // the program passes its argument to `write` as the buffer
+    let status = run(program, &raw const SECRET as u64);
+    assert_eq!(status, ExitStatus::Exited(-EFAULT));
+}
+
+#[test_case]
+fn unknown_syscall() {
+    let program = program!(unknown_syscall_program, unknown_syscall_program_end);
+    assert_eq!(run(program, 0), ExitStatus::Exited(-ENOSYS));
+}
+
+#[test_case]
+fn sleep_and_yield() {
+    let program = program!(sleep_program, sleep_program_end);
+    let start = mold_os::time::uptime_ms();
+    assert_eq!(run(program, 0), ExitStatus::Exited(0));
+    assert!(mold_os::time::uptime_ms() - start >= 10);
+}
+
+#[test_case]
+fn overlong_sleep_is_rejected() {
+    let program = program!(long_sleep_program, long_sleep_program_end);
+    // sleeps `u64::MAX` milliseconds
+    assert_eq!(run(program, 0), ExitStatus::Exited(-EINVAL));
+}
+
+#[test_case]
+fn kernel_memory_access_kills_program() {
+    static SECRET: u64 = 0x5ec2e7;
+    let program = program!(kernel_read_program, kernel_read_program_end);
+    let status = run(program, &raw const SECRET as u64);
+    assert_eq!(status, ExitStatus::Killed("page fault"));
+}
+
+#[test_case]
+fn privileged_instruction_kills_program() {
+    let program = program!(privileged_program, privileged_program_end);
+    assert_eq!(run(program, 0), ExitStatus::Killed("general protection fault"));
+
+    // the kernel keeps running normally afterwards
+    let ticks = mold_os::time::ticks();
+    mold_os::time::sleep_ms(5);
+    assert!(mold_os::time::ticks() > ticks);
+}
@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::vfs::{self, tmpfs::TmpFs, InodeKind, OpenFlags, VfsError};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_filesystems();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+fn names(path: &str) -> Vec<alloc::string::String> {
+    let entries = vfs::readdir(path).unwrap();
+    entries.into_iter().map(|entry| entry.name).collect()
+}
+
+#[test_case]
+fn root_contains_mount_points() {
+    let names = names("/");
+    assert!(names.iter().any(|name| name == "dev"));
+    assert!(names.iter().any(|name| name == "initrd"));
+    assert!(names.iter().any(|name| name == "tmp"));
+}
+
+#[test_case]
+fn normalize_handles_dots() {
+    assert_eq!(vfs::normalize("/a/./b/../c/").unwrap(), ["a", "c"]);
+    assert!(vfs::normalize("/..").unwrap().is_empty());
+    assert_eq!(vfs::normalize("relative"), Err(VfsError::InvalidPath));
+}
+
+#[test_case]
+fn write_and_read_back() {
+    vfs::mkdir("/scratch").unwrap();
+    let fd = vfs::open("/scratch/file", OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(vfs::write(fd, b"hello world"), Ok(11));
+    vfs::seek(fd, 6).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(vfs::read(fd, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"world");
+    vfs::close(fd).unwrap();
+    assert_eq!(vfs::read(fd, &mut buf), Err(VfsError::BadDescriptor));
+
+    let fd = vfs::open(
+        "/scratch/../scratch/./file",
+        OpenFlags::WRITE | OpenFlags::APPEND,
+    )
+    .unwrap();
+    vfs::write(fd, b"!").unwrap();
+    vfs::close(fd).unwrap();
+    assert_eq!(vfs::read_to_vec("/scratch/file").unwrap(), b"hello world!");
+
+    let fd = vfs::open("/scratch/file", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(vfs::fstat(fd).unwrap().size, 0);
+    assert_eq!(vfs::read(fd, &mut buf), Err(VfsError::BadDescriptor));
+    vfs::close(fd).unwrap();
+
+    assert_eq!(vfs::rmdir("/scratch"), Err(VfsError::DirectoryNotEmpty));
+    vfs::unlink("/scratch/file").unwrap();
+    vfs::rmdir("/scratch").unwrap();
+    assert_eq!(vfs::stat("/scratch"), Err(VfsError::NotFound));
+}
+
+#[test_case]
+fn open_errors() {
+    assert_eq!(
+        vfs::open("/missing", OpenFlags::READ),
+        Err(VfsError::NotFound)
+    );
+    assert_eq!(
+        vfs::open("/dev", OpenFlags::WRITE),
+        Err(VfsError::IsADirectory)
+    );
+    assert_eq!(
+        vfs::open("/initrd/new", OpenFlags::WRITE | OpenFlags::CREATE),
+        Err(VfsError::ReadOnly)
+    );
+}
+
+#[test_case]
+fn initrd_is_mounted() {
+    assert_eq!(
+        vfs::stat("/initrd/levels").unwrap().kind,
+        InodeKind::Directory
+    );
+    assert!(names("/initrd").iter().any(|name| name == "help.txt"));
+    let help = vfs::read_to_vec("/initrd/help.txt").unwrap();
+    assert_eq!(
+        help,
+        mold_os::initramfs::open("help.txt").unwrap().contents()
+    );
+    let fd = vfs::open("/initrd/help.txt", OpenFlags::READ_WRITE).unwrap();
+    assert_eq!(vfs::write(fd, b"x"), Err(VfsError::ReadOnly));
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn devices() {
+    assert_eq!(names("/dev"), ["console", "serial0"]);
+    assert_eq!(vfs::stat("/dev/console").unwrap().kind, InodeKind::Device);
+    let fd = vfs::open("/dev/serial0", OpenFlags::WRITE).unwrap();
+    assert_eq!(vfs::write(fd, b"written through /dev/serial0\n"), Ok(29));
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn mount_and_unmount() {
+    vfs::mkdir("/extra").unwrap();
+    vfs::mount("/extra", Arc::new(TmpFs::new())).unwrap();
+    let fd = vfs::open("/extra/inner", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    vfs::close(fd).unwrap();
+    assert_eq!(
+        vfs::stat("/extra/../extra/inner").unwrap().kind,
+        InodeKind::File
+    );
+    assert_eq!(vfs::rmdir("/extra"), Err(VfsError::DirectoryNotEmpty));
+
+    vfs::unmount("/extra").unwrap();
+    assert_eq!(vfs::stat("/extra/inner"), Err(VfsError::NotFound));
+    vfs::rmdir("/extra").unwrap();
+}
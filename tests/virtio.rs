@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mold_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod block_device;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mold_os::block::{BlockDevice, SECTOR_SIZE};
+use mold_os::pci;
+use mold_os::virtio::{self, VirtioBlock};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    mold_os::init();
+    mold_os::init_memory(boot_info);
+    mold_os::init_platform();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mold_os::test_panic_handler(info)
+}
+
+/// The disk `test-args` attaches with `if=virtio`, driven through the
+/// modern transport.
+fn modern_disk() -> Arc<VirtioBlock> {
+    let disk = virtio::disks().into_iter().find(|disk| !disk.is_legacy());
+    disk.expect("modern virtio disk not found")
+}
+
+/// The disk `test-args` attaches with `disable-modern=on`.
+fn legacy_disk() -> Arc<VirtioBlock> {
+    let disk = virtio::disks().into_iter().find(|disk| disk.is_legacy());
+    disk.expect("legacy virtio disk not found")
+}
+
+#[test_case]
+fn disks_are_found_on_pci() {
+    assert!(pci::devices()
+        .iter()
+        .any(|device| device.vendor_id == 0x1AF4));
+    // the 4 MiB images made by the build script
+    assert_eq!(modern_disk().sector_count(), 8192);
+    assert_eq!(legacy_disk().sector_count(), 8192);
+}
+
+#[test_case]
+fn modern_disk_conforms() {
+    block_device::check(&*modern_disk());
+}
+
+#[test_case]
+fn legacy_disk_conforms() {
+    block_device::check(&*legacy_disk());
+}
+
+#[test_case]
+fn many_requests_wrap_the_queue() {
+    // the rings have at most 256 entries
+    for disk in [modern_disk(), legacy_disk()] {
+        let mut sector = [0; SECTOR_SIZE];
+        for i in 0..600u64 {
+            sector[0] = i as u8;
+            disk.write_sectors(1000 + i % 8, &sector).unwrap();
+            sector[0] = 0;
+            disk.read_sectors(1000 + i % 8, &mut sector).unwrap();
+            assert_eq!(sector[0], i as u8);
+        }
+    }
+}